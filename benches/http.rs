@@ -0,0 +1,58 @@
+//! Requests through one shared `HttpClient`, whose pooled connections are
+//! reused, against a new client (and so a new connection) per request.
+//! Run with `cargo bench --bench http`.
+
+use axum::routing::get;
+use axum::Router;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures_util::{stream, StreamExt};
+use safaribooks_rs::api::OreillyApi;
+use safaribooks_rs::cookies::CookieStore;
+use safaribooks_rs::http_client::HttpClient;
+use std::future::Future;
+
+const REQUESTS: usize = 100;
+const WORKERS: usize = 8;
+const ASSET_BYTES: usize = 64 << 10;
+
+fn client() -> HttpClient {
+    HttpClient::from_store(&CookieStore::default())
+        .unwrap()
+        .with_jobs(WORKERS)
+}
+
+/// Make `REQUESTS` requests, `WORKERS` at a time; the bytes read.
+async fn fetch_all<F: Future<Output = usize>>(request: impl Fn() -> F) -> usize {
+    stream::iter(0..REQUESTS)
+        .map(|_| request())
+        .buffer_unordered(WORKERS)
+        .fold(0, |sum, n| async move { sum + n })
+        .await
+}
+
+fn connection_reuse(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let url = runtime.block_on(async {
+        let app = Router::new().route("/asset", get(|| async { vec![b'x'; ASSET_BYTES] }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/asset", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    });
+
+    let mut group = c.benchmark_group("connection_reuse");
+    group.throughput(Throughput::Bytes((REQUESTS * ASSET_BYTES) as u64));
+    let shared = client();
+    group.bench_function("shared_client", |b| {
+        b.to_async(&runtime)
+            .iter(|| fetch_all(|| async { shared.get(&url).await.unwrap().body.len() }))
+    });
+    group.bench_function("client_per_request", |b| {
+        b.to_async(&runtime)
+            .iter(|| fetch_all(|| async { client().get(&url).await.unwrap().body.len() }))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, connection_reuse);
+criterion_main!(benches);
@@ -0,0 +1,195 @@
+//! Throughput of the book pipeline's CPU-bound stages: rewriting a chapter
+//! into XHTML, mapping its links, and packaging the EPUB. Run with
+//! `cargo bench --bench pipeline`; criterion keeps the last run under
+//! target/criterion/ and reports the change against it.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use safaribooks_rs::book::{local_link, resolve_url, rewrite_chapter, AssetIndex, BookOptions};
+use safaribooks_rs::epub::EpubSkeleton;
+use safaribooks_rs::orly::Chapter;
+use safaribooks_rs::package::{package_epub, Compression};
+use std::collections::HashMap;
+use std::fs;
+use std::hint::black_box;
+use tokio_util::sync::CancellationToken;
+
+const SITE: &str = "https://learning.oreilly.com";
+
+/// A chapter of about `kib` KiB with the markup books are made of: links to
+/// other chapters, entities, page markers, figures, listings and tables.
+fn large_chapter(kib: usize) -> String {
+    let mut html = String::from(r#"<div id="sbo-rt-content"><section data-type="chapter">"#);
+    let mut n = 0;
+    while html.len() < kib << 10 {
+        n += 1;
+        html.push_str(&format!(
+            r#"<h2 id="sec{n}">Section {n}</h2>
+<p>Text&nbsp;with <em>emphasis</em>, &ldquo;quotes&rdquo; and a <a href="ch{:02}.html#sec{n}">link to another chapter</a><span data-pdf-page="{n}"/>.</p>
+<figure><img src="assets/fig{n}.png" alt="Figure {n}"/><figcaption>Figure {n}</figcaption></figure>
+<pre data-type="programlisting">fn step_{n}() -&gt; u32 {{
+    {n} * 2
+}}</pre>
+<table><tr><th>Key</th><th>Value</th></tr><tr><td>{n}</td><td><a href="https://example.com/{n}">out</a></td></tr></table>
+"#,
+            n % 40 + 1
+        ));
+    }
+    html + "</section></div>"
+}
+
+fn chapter_files() -> HashMap<String, String> {
+    (1..=40)
+        .map(|n| (format!("ch{n:02}.html"), format!("ch{n:02}.xhtml")))
+        .collect()
+}
+
+fn options() -> BookOptions {
+    BookOptions {
+        jobs: 1,
+        resume: false,
+        credits: false,
+        annotations: Vec::new(),
+        position: None,
+        metadata: Default::default(),
+        confirm_above: None,
+        code_style: Default::default(),
+        table_style: Default::default(),
+        rasterize_svg: false,
+        inline_below: None,
+        split_above: None,
+        stealth: false,
+        cancel: CancellationToken::new(),
+        package: None,
+    }
+}
+
+fn xhtml_transform(c: &mut Criterion) {
+    let chapter: Chapter = serde_json::from_value(serde_json::json!({
+        "title": "Large",
+        "filename": "ch01.html",
+        "content": format!("{SITE}/api/v1/book/123/chapter-content/ch01.html"),
+        "asset_base_url": "https://cdn.example.com/files/"
+    }))
+    .unwrap();
+    let files = chapter_files();
+    let opts = options();
+    let mut group = c.benchmark_group("xhtml_transform");
+    for kib in [64, 1024] {
+        let html = large_chapter(kib);
+        group.throughput(Throughput::Bytes(html.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{kib}KiB")),
+            &html,
+            |b, html| {
+                b.iter(|| {
+                    let mut assets = AssetIndex::new(SITE);
+                    rewrite_chapter(
+                        black_box(html),
+                        &chapter,
+                        &files,
+                        &mut assets,
+                        &mut Vec::new(),
+                        &opts,
+                        &mut Vec::new(),
+                    )
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn link_rewriting(c: &mut Criterion) {
+    let files = chapter_files();
+    let links: Vec<String> = (1..=1000)
+        .map(|n| match n % 4 {
+            0 => format!("ch{:02}.html#sec{n}", n % 40 + 1),
+            1 => format!("{SITE}/library/view/book/123/ch{:02}.html", n % 40 + 1),
+            2 => format!("https://example.com/{n}"),
+            _ => format!("#note{n}"),
+        })
+        .collect();
+    let assets: Vec<String> = (1..=1000)
+        .map(|n| match n % 3 {
+            0 => format!("assets/fig{n}.png"),
+            1 => format!("/library/cover/{n}/"),
+            _ => format!("../images/{n}.jpg"),
+        })
+        .collect();
+    let mut group = c.benchmark_group("link_rewriting");
+    group.throughput(Throughput::Elements(links.len() as u64));
+    group.bench_function("chapter_links", |b| {
+        b.iter(|| {
+            links
+                .iter()
+                .filter_map(|href| local_link(SITE, black_box(href), &files))
+                .count()
+        })
+    });
+    group.throughput(Throughput::Elements(assets.len() as u64));
+    group.bench_function("asset_urls", |b| {
+        b.iter(|| {
+            assets
+                .iter()
+                .map(|path| {
+                    resolve_url(SITE, "https://cdn.example.com/files/", black_box(path)).len()
+                })
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+fn zip_packaging(c: &mut Criterion) {
+    let root = std::env::temp_dir().join(format!("safaribooks-bench-{}", std::process::id()));
+    let skeleton = EpubSkeleton {
+        meta_inf: root.join("META-INF"),
+        oebps: root.join("OEBPS"),
+        epub: root.join("Book.epub"),
+        root: root.clone(),
+        language: "en".to_string(),
+        legacy_names: false,
+    };
+    skeleton.create_dirs().unwrap();
+    skeleton.write_container_xml().unwrap();
+    // Chapters that compress well, images that don't.
+    let paragraph = "<p>Lorem ipsum dolor sit amet, consectetur adipiscing elit.</p>\n";
+    let mut bytes = 0;
+    for n in 1..=50 {
+        let chapter = paragraph.repeat((64 << 10) / paragraph.len());
+        bytes += chapter.len();
+        fs::write(skeleton.oebps.join(format!("ch{n:03}.xhtml")), chapter).unwrap();
+    }
+    fs::create_dir_all(skeleton.oebps.join("Images")).unwrap();
+    let mut rng = fastrand::Rng::with_seed(7);
+    for n in 1..=25 {
+        let image: Vec<u8> = (0..256 << 10).map(|_| rng.u8(..)).collect();
+        bytes += image.len();
+        fs::write(skeleton.oebps.join(format!("Images/{n:03}.jpg")), image).unwrap();
+    }
+
+    let mut group = c.benchmark_group("zip_packaging");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Bytes(bytes as u64));
+    for (label, level, store_images) in [
+        ("stored", 0, false),
+        ("level 1", 1, false),
+        ("level 6", 6, false),
+        ("level 6, images stored", 6, true),
+        ("level 9", 9, false),
+    ] {
+        let compression = Compression {
+            level,
+            store_images,
+        };
+        group.bench_function(label, |b| {
+            b.iter(|| package_epub(&skeleton, &skeleton.epub, compression).unwrap())
+        });
+    }
+    group.finish();
+    fs::remove_dir_all(&root).unwrap();
+}
+
+criterion_group!(benches, xhtml_transform, link_rewriting, zip_packaging);
+criterion_main!(benches);
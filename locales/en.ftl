@@ -0,0 +1,378 @@
+# Console messages. Every message here needs a counterpart in each of the
+# other locales; English stands in for any that is missing.
+
+## Shared
+
+not-available = n/a
+unknown = unknown
+done = Done: { $path }
+wrote = Wrote { $path }
+sidecar-failed = Writing the metadata sidecar failed: { $error }
+not-downloaded = { $book } has not been downloaded yet; download it before refreshing its cover or metadata
+importing-python-download = { $path } was being downloaded by safaribooks.py; keeping its chapters and images
+refreshing-extras = Refreshing the cover and metadata next to { $path }
+cover-failed = Fetching the cover failed (HTTP { $status })
+fetching-cover = Fetching the cover failed
+cover-not-image = The cover is not an image
+writing-failed = Writing { $path }: { $error }
+book-failed = Download of { $book } failed: { $error }
+state-read-failed = Could not read the download state: { $error }
+book-info-failed = Failed to fetch book info: { $error }
+
+## Display
+
+log-details = Details in { $path }
+log-kept = Log kept at { $path }
+log-not-deleted = Could not delete { $path }: { $error }
+output-directory = Output directory:
+
+## Settings and session
+
+invalid-settings = Invalid settings: { $error }
+using-site = Using site { $url }
+using-profile = Using profile "{ $profile }".
+metadata-needs-one-book = Metadata overrides describe one book; give a single BOOKID with them.
+output-needs-one-book = --output names one book's file; give a single BOOKID with it.
+split-parts-to-stdout = Split parts cannot be streamed to stdout; drop --split-parts or --output -.
+all-books-downloaded = All { $count } books downloaded.
+books-failed = { $failed } of { $count } books failed: { $books }
+download-cancelled = Download cancelled; run the same command again to resume it
+site-failing = More than { $percent }% of the recent requests failed; retrying the rest of the book one request at a time in { $pause }
+finished-degraded = The book was finished one request at a time; the pace picks up again once the site stops failing
+site-still-failing = Requests kept failing even one at a time; run the same command again later to resume
+cancelling = Stopping after the requests in flight; press Ctrl-C again to quit at once
+state-unavailable = Download state unavailable: { $error }
+insecure-warning = TLS certificate verification is OFF (--insecure): anyone on the network can read or change the traffic, session cookies included.
+http-client-failed = Failed to build HTTP client: { $error }
+recording-to = Recording HTTP responses to { $path }
+replaying-from = Replaying HTTP responses from { $path }
+http-client-ready = HTTP client initialized with cookies.
+login-confirmed = Login confirmed...
+logged-out-keyring =
+    Logged out. The cookies saved in the OS keyring have expired.
+    Pass fresh cookies with --cookies-file to replace them.
+logged-out =
+    Logged out. Cookies could be stale or invalid.
+    Try refreshing your cookies.json and trying again.
+login-check-failed = Login check failed: { $error }
+account = Account: { $email }
+subscription = Subscription: { $kind } (expires: { $expires })
+subscription-expired = Your subscription has expired; downloads may fail.
+subscription-expiring = { $days ->
+    [one] Your subscription expires in { $days } day; long downloads may stop working.
+   *[other] Your subscription expires in { $days } days; long downloads may stop working.
+}
+
+## Cookies
+
+cookie-source-inline = Inline cookies
+cookie-source-keyring = OS keyring
+keyring-unavailable = OS keyring unavailable ({ $error }); use --no-keyring to silence this.
+cookies-not-found =
+    { $source } not found.
+    This version requires an existing authenticated session (see --cookies-file).
+cookies-read-failed = Failed to read { $source }: { $error }
+no-cookies = { $source }: no cookies found.
+cookies-loaded = Loaded { $count } cookies from { $source }: { $names }
+cookies-encoded = Cookie value(s) with characters not allowed in headers were percent-encoded: { $names }
+cookies-invalid-names = Cookie(s) with invalid names were skipped: { $names }
+cookies-missing =
+    { $source } is missing required cookie(s): { $names }.
+    Export all cookies for { $host } while logged in and try again.
+cookies-saved-keyring = Session cookies saved to the OS keyring.
+cookies-file-unneeded = { $path } is no longer needed and can be deleted.
+keyring-save-failed = Could not save cookies to the OS keyring: { $error }
+session-expired-file = Session expired. Update { $path } and press Enter (q to abort):
+session-expired-paste = Session expired. Paste fresh cookies (JSON or a Cookie header), or press Enter to abort:
+cookies-reloaded = New cookies loaded, resuming download...
+cookies-still-missing = Still missing required cookie(s): { $names }.
+cookies-unreadable = Could not read cookies: { $error }
+
+## Subcommands
+
+catalog-written = Catalog of { $count } books written to { $path }
+catalog-failed = Catalog failed: { $error }
+chapter-list-failed = Failed to fetch the chapter list: { $error }
+chapters-total = { $count ->
+    [one] { $count } chapter, about { $size } in all
+   *[other] { $count } chapters, about { $size } in all
+}
+annotations-fetch-failed = Could not fetch the annotations: { $error }
+annotations-written = { $count ->
+    [one] { $count } annotation written to { $path }
+   *[other] { $count } annotations written to { $path }
+}
+history-failed = Could not fetch the reading history: { $error }
+reading-history = Reading history
+playlist = Playlist: { $name }
+playlists-failed = Could not fetch the playlists: { $error }
+book-suggestions = There is no book { $book }; perhaps one of these:
+book-not-found = Book not found (HTTP 404). Please double-check the book ID provided
+search-failed = Could not search for similar books: { $error }
+scanning-library = No downloads recorded; scanning { $path }
+downloaded = Downloaded
+early-release = Early Release
+early-release-update = Early Release, update available
+update-check-failed = Could not check { $book } for updates: { $error }
+verified = OK: { $path } ({ $count } files checked)
+file-missing = { $path } is missing
+entry-checksum-mismatch = { $name }: checksum mismatch
+entry-unreadable = { $name }: unreadable ({ $error })
+entry-not-in-manifest = { $name }: not in the manifest
+entry-missing-from-epub = { $name }: missing from the EPUB
+verification-failed = { $damaged } of { $count } books failed verification.
+serving = Serving { $path } on http://{ $listen }/ (catalog at /{ $catalog }, downloads at /api/downloads)
+catalog-update-failed = Could not update the catalog: { $error }
+watching = Watching { $path } for book IDs (Ctrl+C to stop).
+queue-read-failed = Could not read the queue: { $error }
+queued-book = Queued book { $book }: starting.
+update-none = Version { $version } is the latest.
+update-available = Version { $version } is available; run self-update to install it.
+update-installed = Updated to version { $version }.
+update-failed = Self-update failed: { $error }
+
+## Downloads
+
+book-progress = Book { $index }/{ $total }: { $book }
+batch-time-left = { $done }/{ $total } books done, about { $left } left in all
+duplicate-books = { $count ->
+    [one] Book given more than once, downloading it once: { $books }
+   *[other] Books given more than once, downloading each once: { $books }
+}
+retrieving-book-info = Retrieving book info...
+book-details =
+    Title: { $title }
+     Authors: { $authors }
+     Publisher: { $publisher }
+     Issued: { $issued }
+     Pages: { $pages }
+already-in-library = This book is already in the library: { $path }
+skip-again = Skip downloading it again? [y/N]
+already-downloaded = Already downloaded: { $path }
+resuming-in = Resuming in { $path }
+last-download-failed = The last download ({ $finished }) failed: { $error }
+last-download-interrupted = The last download was interrupted.
+unknown-error = unknown error
+overwriting = Overwriting { $path }
+directory-exists = Directory exists; using { $path }
+skeleton-ready = EPUB skeleton ready (mimetype + META-INF/container.xml + OEBPS/).
+mirroring-into = Mirroring the raw responses into { $path }
+calibre-added = Added to Calibre (book id { $ids }).
+calibre-has-book = Calibre already has this book; not added again.
+calibre-failed = Could not add the book to Calibre: { $error }
+state-update-failed = Could not update the download state: { $error }
+annotations-to-embed = { $count ->
+    [one] { $count } annotation to embed.
+   *[other] { $count } annotations to embed.
+}
+annotations-failed = Could not fetch your annotations: { $error }
+position-failed = Could not fetch your reading position: { $error }
+video-course = Video course detected, downloading lesson transcripts...
+downloading-chapters = Downloading chapters...
+package-check-passed = Package check passed.
+problem-in = { $path }: { $problem }
+manifest-item-missing = manifest item { $id }: { $href } does not exist
+media-type-mismatch = { $href }: declared as { $declared }, looks like { $actual }
+broken-link = { $href }: links to { $target }, which is not in the book
+spine-empty = the spine is empty
+spine-item-unknown = spine item { $id } is not in the manifest
+spine-item-not-xhtml = spine item { $id } ({ $href }) is { $media_type }, not XHTML
+not-in-manifest = { $href } is not in the manifest
+xml-at-line = line { $line }: { $problem }
+xml-unclosed = line { $line }: unclosed { $markup }
+xml-unclosed-tag = line { $line }: unclosed tag
+xml-misnested = line { $line }: </{ $name }> closes <{ $expected }> from line { $opened }
+xml-stray-end = line { $line }: stray </{ $name }>
+xml-stray-lt = line { $line }: stray '<'
+xml-in-tag = line { $line }: <{ $name }>: { $problem }
+xml-never-closed = line { $line }: <{ $name }> is never closed
+xml-attribute-no-value = attribute without a value: { $attribute }
+xml-attribute-unquoted = unquoted attribute { $attribute }
+xml-attribute-unterminated = unterminated attribute { $attribute }
+xml-bare-ampersand = bare '&'
+xml-undefined-entity = undefined entity &{ $name };
+package-check-problem = Package check: { $problem }
+package-check-problems = { $count ->
+    [one] Package check found { $count } problem; the EPUB may not open everywhere.
+   *[other] Package check found { $count } problems; the EPUB may not open everywhere.
+}
+package-check-strict = { $count ->
+    [one] Package check found { $count } problem.
+   *[other] Package check found { $count } problems.
+}
+package-check-failed = Could not check the package: { $error }
+position-saved = Reading position saved to { $path }
+position-save-failed = Could not save the reading position: { $error }
+workdir-remove-failed = Could not remove the unpacked book: { $error }
+running-epubcheck = Running epubcheck...
+epubcheck-skipped = Skipping epubcheck: { $error }
+epubcheck-error = epubcheck error { $message }
+epubcheck-warning = epubcheck warning { $message }
+epubcheck-info = epubcheck: { $message }
+epubcheck-clean = epubcheck found no errors.
+epubcheck-errors = { $count ->
+    [one] epubcheck found { $count } error.
+   *[other] epubcheck found { $count } errors.
+}
+rebuilding = Rebuilding book { $book } from the mirror of { $mirrored }
+
+## Building the book
+
+chapter-done = Chapter { $index }/{ $total } done: { $title }
+time-left = { $done }/{ $total } done, about { $left } left for this book
+downloading-assets = Downloading { $count } assets...
+toc-unavailable = Table of contents unavailable ({ $error }); using the chapter list.
+writing-parts = Also writing the book as { $parts } parts of at most { $size } each.
+asset-skipped = Skipping asset: { $error }
+assets-missing = { $count ->
+    [one] 1 asset is missing from the book; see { $report }
+   *[other] { $count } assets are missing from the book; see { $report }
+}
+assets-missing-strict = { $count ->
+    [one] 1 asset could not be downloaded (--strict); see { $report }
+   *[other] { $count } assets could not be downloaded (--strict); see { $report }
+}
+keeping-svg = Keeping SVG images: { $error }
+keeping-as-svg = Keeping { $file } as SVG: { $error }
+book-size = Book has { $chapters } chapters, { $images } images, { $pages } pages; about { $size } to download.
+confirm-size = That is more than { $limit }. Download it? [y/N]
+above-threshold = The book is estimated at { $size }, above the { $limit } confirmation threshold; pass --yes (or raise --confirm-above) to download it
+checking-download-size = Disk space looks tight, checking the download size...
+not-enough-space = Not enough disk space: the book needs about { $needed }, only { $free } is free
+course-lessons = { $count ->
+    [one] Course has { $count } lesson.
+   *[other] Course has { $count } lessons.
+}
+lesson-done = Lesson { $index }/{ $total } done: { $title }
+no-transcript = No transcript available for "{ $lesson }".
+
+## Hooks and notifications
+
+webhook-notified = Webhook notified.
+webhook-failed = Webhook failed: { $error }
+hook-finished = Hook command finished.
+hook-failed = Hook command failed: { $error }
+notification-failed = Could not show a notification: { $error }
+notification-book = Book { $book }
+notification-finished = Download finished
+notification-skipped = Already downloaded
+notification-download-failed = Download failed
+
+## Errors
+
+reading-file = Reading { $path }
+writing-file = Writing { $path }
+opening-file = Opening { $path }
+creating-file = Creating { $path }
+removing-file = Removing { $path }
+replacing-file = Replacing { $path }
+moving-file = Moving { $path }
+copying-file = Copying { $path }
+parsing-file = Parsing { $path }
+reading-directory = Reading directory { $path }
+creating-directory = Creating directory { $path }
+removing-directory = Removing directory { $path }
+creating-log-file = Cannot create log file { $path }
+running-program = Running { $program }
+program-failed = { $program } failed ({ $status }){ $detail }
+decoding-json = Decoding JSON response
+http-get = GET { $url }
+http-post = POST { $url }
+http-post-status = POST { $url }: HTTP { $status }
+invalid-boolean = invalid boolean { $value }
+size-unknown-unit = unknown unit { $unit } in { $size } (use K, M or G)
+size-invalid = { $size } is not a size like 500K or 2M
+rate-invalid = invalid rate
+book-id-invalid = { $input } is not a book ID; use the digits from the book's URL (e.g. 9781491958698) or the URL itself
+rate-zero = rate must be above zero: { $rate }
+format-unknown = unknown format { $format } (available: { $formats })
+env-not-boolean = { $name } must be true or false
+env-not-number = { $name } must be a number
+env-not-size = { $name } must be a size like { $example }
+env-not-rate = { $name } must be a rate like { $example }
+env-not-percentage = { $name } must be a percentage from 1 to 100
+env-not-level = { $name } must be a level from 0 to 9
+env-not-one-of = { $name } must be one of: { $values }
+env-invalid = Invalid { $name }
+profile-name-invalid = invalid profile name { $name } (use letters, digits, '-' and '_')
+profile-unknown = unknown profile { $name } (create { $path }; known profiles: { $known })
+profiles-none = none
+legacy-names-with-templates = legacy names follow safaribooks.py and cannot be combined with naming templates
+template-dir-invalid = Invalid directory template
+template-file-invalid = Invalid file name template
+template-file-slash = the file name template cannot contain '/'
+template-unmatched-brace = unmatched '{ "}" }' in { $template }
+template-unclosed-brace = unclosed '{ "{" }' in { $template }
+template-unknown-field = unknown field { "{" }{ $name }{ "}" } (available: { $fields })
+template-empty-component = empty or relative path component in { $template }
+header-malformed = Header must be "Name: value": { $header }
+header-name-invalid = Invalid header name in { $header }
+header-value-invalid = Invalid header value in { $header }
+resolve-malformed = --resolve must be "host:address": { $entry }
+base-url-invalid = Base URL must be an http(s) URL with a host: { $url }
+user-agent-invalid = Invalid User-Agent: { $value }
+certificate-unusable = No usable certificate in { $path }
+certificate-missing = No certificate in { $path }
+profile-status = Profile request returned unexpected status { $status }
+v2-status = Got status { $status } from the v2 API
+unexpected-status = Got status { $status }
+unexpected-status-for = Got status { $status } for { $url }
+chapter-status = Got status { $status } for chapter { $chapter }
+asset-status = Got status { $status } for asset { $url }
+chapter-pagination-loop = Chapter list pagination loops back to { $url }
+listing-pagination-loop = Listing pagination loops back to { $url }
+unsupported-audiobook =
+    Unsupported product type: audiobook.
+    Only books and video courses (as transcripts) can be downloaded.
+unsupported-product = Unsupported product type: "{ $format }".
+range-missing = Partial response without a usable Content-Range
+resumed-elsewhere = Server resumed at byte { $start }, expected { $offset }
+download-stopped = Download stopped at { $written } of { $total } bytes
+fetching-book-info = Failed to fetch book info
+mirror-start-failed = Starting the mirror failed
+mirror-save-failed = Mirroring the response failed
+mirroring-book-info = Failed to mirror book info
+mirror-missing = No mirror in { $path } (download the book with --mirror first)
+mirror-no-book-info = The mirror has no usable book info
+no-recorded-response = No recorded response for { $url } in { $path }
+import-failed = Importing the download failed
+skeleton-failed = EPUB skeleton creation failed
+book-epub-failed = Book EPUB creation failed
+transcript-epub-failed = Transcript EPUB creation failed
+splitting-failed = Splitting the book into parts failed
+writing-part = Writing part { $part }
+packaging-failed = Packaging failed
+packaging-part-failed = Packaging { $path } failed
+epub-already-finished = { $path } was already finished
+metadata-write-failed = Writing the metadata failed
+stdout-write-failed = Writing the EPUB to stdout failed
+container-no-rootfile = container.xml has no rootfile
+zip-entry-missing = Missing { $name }
+checksum-manifest-missing = No checksum manifest (*.epub.sha256) in { $path }
+reading-checksum-manifest = Reading checksum manifest { $path }
+checksum-line-malformed = Malformed line in { $path }: { $line }
+upgrading-state = Upgrading { $path } to version { $version }
+calibre-not-run = Running { $program } (is Calibre installed?)
+calibre-no-answer = calibredb did not say whether the book was added
+epubcheck-path-missing = epubcheck_path { $path } does not exist
+epubcheck-not-found = epubcheck was not found on PATH; install it or set epubcheck_path
+rsvg-not-found = rsvg-convert was not found on PATH; install librsvg to rasterize SVG
+hook-exited = { $command } exited with { $status }
+listening-failed = Listening on { $listen }
+server-failed = Server failed
+release-feed-unreadable = Reading the release feed
+release-no-build = Release { $release } has no build for this platform ({ $asset })
+release-no-checksum = Release { $release } publishes no checksum for { $asset }; not installing it unverified
+checksum-malformed = Malformed checksum file: { $text }
+checksum-mismatch = { $asset } does not match its published checksum (expected { $expected }, got { $actual })
+release-no-signature = Release { $release } publishes no signature for { $asset }; not installing it unverified
+release-key-missing = This build has no release key to verify { $version } with; download it from the releases page instead
+release-key-malformed = Malformed release key
+signature-rejected = Verifying the signature of { $asset }
+signature-malformed = Malformed minisign signature
+signature-prehashed = Unsupported signature algorithm { $tag }; releases must be signed with minisign -l
+signature-other-key = Signed with a key other than the release key
+signature-invalid = The signature does not match the file
+signature-comment-invalid = The signature of the trusted comment does not match
+locating-executable = Locating this executable
+executable-no-name = Executable path has no file name
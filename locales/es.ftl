@@ -0,0 +1,377 @@
+# Mensajes de consola en español.
+
+## Comunes
+
+not-available = n/d
+unknown = desconocido
+done = Listo: { $path }
+wrote = Escrito { $path }
+sidecar-failed = No se pudo escribir el archivo de metadatos: { $error }
+not-downloaded = { $book } aún no se ha descargado; descárguelo antes de actualizar su portada o sus metadatos
+importing-python-download = safaribooks.py estaba descargando { $path }; se conservan sus capítulos e imágenes
+refreshing-extras = Actualizando la portada y los metadatos junto a { $path }
+cover-failed = No se pudo obtener la portada (HTTP { $status })
+fetching-cover = No se pudo obtener la portada
+cover-not-image = La portada no es una imagen
+writing-failed = Escribiendo { $path }: { $error }
+book-failed = Falló la descarga de { $book }: { $error }
+state-read-failed = No se pudo leer el estado de las descargas: { $error }
+book-info-failed = No se pudo obtener la información del libro: { $error }
+
+## Display
+
+log-details = Detalles en { $path }
+log-kept = Registro guardado en { $path }
+log-not-deleted = No se pudo borrar { $path }: { $error }
+output-directory = Directorio de salida:
+
+## Configuración y sesión
+
+invalid-settings = Configuración no válida: { $error }
+using-site = Usando el sitio { $url }
+using-profile = Usando el perfil "{ $profile }".
+metadata-needs-one-book = Los metadatos indicados describen un solo libro; pase un único BOOKID con ellos.
+output-needs-one-book = --output nombra el archivo de un solo libro; pase un único BOOKID con él.
+split-parts-to-stdout = Las partes no se pueden enviar a stdout; quite --split-parts o --output -.
+all-books-downloaded = Se descargaron los { $count } libros.
+books-failed = Fallaron { $failed } de { $count } libros: { $books }
+download-cancelled = Descarga cancelada; ejecuta el mismo comando otra vez para reanudarla
+site-failing = Fallaron más del { $percent }% de las peticiones recientes; se reintentará el resto del libro de una petición en una dentro de { $pause }
+finished-degraded = El libro se terminó de una petición en una; el ritmo volverá a subir cuando el sitio deje de fallar
+site-still-failing = Las peticiones siguieron fallando incluso de una en una; ejecuta el mismo comando más tarde para reanudar la descarga
+cancelling = Deteniendo tras las peticiones en curso; pulsa Ctrl-C otra vez para salir de inmediato
+state-unavailable = Estado de las descargas no disponible: { $error }
+insecure-warning = La verificación de certificados TLS está DESACTIVADA (--insecure): cualquiera en la red puede leer o alterar el tráfico, incluidas las cookies de sesión.
+http-client-failed = No se pudo crear el cliente HTTP: { $error }
+recording-to = Grabando las respuestas HTTP en { $path }
+replaying-from = Reproduciendo las respuestas HTTP de { $path }
+http-client-ready = Cliente HTTP inicializado con las cookies.
+login-confirmed = Sesión confirmada...
+logged-out-keyring =
+    Sesión cerrada. Las cookies guardadas en el llavero del sistema han caducado.
+    Pase cookies nuevas con --cookies-file para reemplazarlas.
+logged-out =
+    Sesión cerrada. Las cookies pueden estar caducadas o no ser válidas.
+    Actualice su cookies.json e inténtelo de nuevo.
+login-check-failed = Falló la comprobación de la sesión: { $error }
+account = Cuenta: { $email }
+subscription = Suscripción: { $kind } (caduca: { $expires })
+subscription-expired = Su suscripción ha caducado; las descargas pueden fallar.
+subscription-expiring = { $days ->
+    [one] Su suscripción caduca en { $days } día; las descargas largas pueden dejar de funcionar.
+   *[other] Su suscripción caduca en { $days } días; las descargas largas pueden dejar de funcionar.
+}
+
+## Cookies
+
+cookie-source-inline = Cookies en línea
+cookie-source-keyring = Llavero del sistema
+keyring-unavailable = Llavero del sistema no disponible ({ $error }); use --no-keyring para no ver este aviso.
+cookies-not-found =
+    No se encontró { $source }.
+    Esta versión necesita una sesión ya autenticada (vea --cookies-file).
+cookies-read-failed = No se pudo leer { $source }: { $error }
+no-cookies = { $source }: no hay cookies.
+cookies-loaded = Cargadas { $count } cookies de { $source }: { $names }
+cookies-encoded = Valores de cookies con caracteres no permitidos en cabeceras, codificados con %: { $names }
+cookies-invalid-names = Se omitieron cookies con nombres no válidos: { $names }
+cookies-missing =
+    A { $source } le faltan cookies obligatorias: { $names }.
+    Exporte todas las cookies de { $host } con la sesión iniciada e inténtelo de nuevo.
+cookies-saved-keyring = Cookies de sesión guardadas en el llavero del sistema.
+cookies-file-unneeded = { $path } ya no hace falta y se puede borrar.
+keyring-save-failed = No se pudieron guardar las cookies en el llavero del sistema: { $error }
+session-expired-file = La sesión ha caducado. Actualice { $path } y pulse Intro (q para cancelar):
+session-expired-paste = La sesión ha caducado. Pegue cookies nuevas (JSON o una cabecera Cookie), o pulse Intro para cancelar:
+cookies-reloaded = Cookies nuevas cargadas, reanudando la descarga...
+cookies-still-missing = Siguen faltando cookies obligatorias: { $names }.
+cookies-unreadable = No se pudieron leer las cookies: { $error }
+
+## Subcomandos
+
+catalog-written = Catálogo de { $count } libros escrito en { $path }
+catalog-failed = Falló el catálogo: { $error }
+chapter-list-failed = No se pudo obtener la lista de capítulos: { $error }
+chapters-total = { $count ->
+    [one] { $count } capítulo, unos { $size } en total
+   *[other] { $count } capítulos, unos { $size } en total
+}
+annotations-fetch-failed = No se pudieron obtener las anotaciones: { $error }
+annotations-written = { $count ->
+    [one] { $count } anotación escrita en { $path }
+   *[other] { $count } anotaciones escritas en { $path }
+}
+history-failed = No se pudo obtener el historial de lectura: { $error }
+reading-history = Historial de lectura
+playlist = Lista: { $name }
+book-suggestions = No existe el libro { $book }; quizá sea uno de estos:
+book-not-found = No se encontró el libro (HTTP 404). Compruebe el ID de libro indicado
+search-failed = No se pudieron buscar libros parecidos: { $error }
+playlists-failed = No se pudieron obtener las listas: { $error }
+scanning-library = No hay descargas registradas; examinando { $path }
+downloaded = Descargados
+early-release = Early Release
+early-release-update = Early Release, con actualización disponible
+update-check-failed = No se pudo comprobar si { $book } tiene novedades: { $error }
+verified = OK: { $path } ({ $count } archivos comprobados)
+file-missing = Falta { $path }
+entry-checksum-mismatch = { $name }: la suma de comprobación no coincide
+entry-unreadable = { $name }: ilegible ({ $error })
+entry-not-in-manifest = { $name }: no está en el manifiesto
+entry-missing-from-epub = { $name }: falta en el EPUB
+verification-failed = { $damaged } de { $count } libros no superaron la verificación.
+serving = Sirviendo { $path } en http://{ $listen }/ (catálogo en /{ $catalog }, descargas en /api/downloads)
+catalog-update-failed = No se pudo actualizar el catálogo: { $error }
+watching = Vigilando { $path } en busca de IDs de libros (Ctrl+C para parar).
+queue-read-failed = No se pudo leer la cola: { $error }
+queued-book = Libro en cola { $book }: empezando.
+update-none = La versión { $version } es la más reciente.
+update-available = Hay una versión { $version } disponible; ejecute self-update para instalarla.
+update-installed = Actualizado a la versión { $version }.
+update-failed = Falló la actualización: { $error }
+
+## Descargas
+
+book-progress = Libro { $index }/{ $total }: { $book }
+batch-time-left = { $done }/{ $total } libros listos, quedan unos { $left } en total
+duplicate-books = { $count ->
+    [one] Libro indicado más de una vez, se descargará una sola vez: { $books }
+   *[other] Libros indicados más de una vez, se descargará cada uno una sola vez: { $books }
+}
+retrieving-book-info = Obteniendo la información del libro...
+book-details =
+    Título: { $title }
+     Autores: { $authors }
+     Editorial: { $publisher }
+     Publicado: { $issued }
+     Páginas: { $pages }
+already-in-library = Este libro ya está en la biblioteca: { $path }
+skip-again = ¿Omitir descargarlo de nuevo? [y/N]
+already-downloaded = Ya descargado: { $path }
+resuming-in = Reanudando en { $path }
+last-download-failed = La última descarga ({ $finished }) falló: { $error }
+last-download-interrupted = La última descarga se interrumpió.
+unknown-error = error desconocido
+overwriting = Sobrescribiendo { $path }
+directory-exists = El directorio ya existe; se usa { $path }
+skeleton-ready = Esqueleto del EPUB listo (mimetype + META-INF/container.xml + OEBPS/).
+mirroring-into = Guardando copia de las respuestas en { $path }
+calibre-added = Añadido a Calibre (id de libro { $ids }).
+calibre-has-book = Calibre ya tiene este libro; no se añade otra vez.
+calibre-failed = No se pudo añadir el libro a Calibre: { $error }
+state-update-failed = No se pudo actualizar el estado de las descargas: { $error }
+annotations-to-embed = { $count ->
+    [one] { $count } anotación por incluir.
+   *[other] { $count } anotaciones por incluir.
+}
+annotations-failed = No se pudieron obtener sus anotaciones: { $error }
+position-failed = No se pudo obtener su posición de lectura: { $error }
+video-course = Curso en vídeo detectado, descargando las transcripciones de las lecciones...
+downloading-chapters = Descargando los capítulos...
+package-check-passed = El paquete pasó la comprobación.
+problem-in = { $path }: { $problem }
+manifest-item-missing = elemento del manifiesto { $id }: { $href } no existe
+media-type-mismatch = { $href }: declarado como { $declared }, parece { $actual }
+broken-link = { $href }: enlaza a { $target }, que no está en el libro
+spine-empty = el spine está vacío
+spine-item-unknown = el elemento del spine { $id } no está en el manifiesto
+spine-item-not-xhtml = el elemento del spine { $id } ({ $href }) es { $media_type }, no XHTML
+not-in-manifest = { $href } no está en el manifiesto
+xml-at-line = línea { $line }: { $problem }
+xml-unclosed = línea { $line }: { $markup } sin cerrar
+xml-unclosed-tag = línea { $line }: etiqueta sin cerrar
+xml-misnested = línea { $line }: </{ $name }> cierra <{ $expected }> de la línea { $opened }
+xml-stray-end = línea { $line }: </{ $name }> suelta
+xml-stray-lt = línea { $line }: '<' suelto
+xml-in-tag = línea { $line }: <{ $name }>: { $problem }
+xml-never-closed = línea { $line }: <{ $name }> nunca se cierra
+xml-attribute-no-value = atributo sin valor: { $attribute }
+xml-attribute-unquoted = atributo sin comillas { $attribute }
+xml-attribute-unterminated = atributo sin terminar { $attribute }
+xml-bare-ampersand = '&' suelto
+xml-undefined-entity = entidad no definida &{ $name };
+package-check-problem = Comprobación del paquete: { $problem }
+package-check-problems = { $count ->
+    [one] La comprobación del paquete encontró { $count } problema; puede que el EPUB no se abra en todas partes.
+   *[other] La comprobación del paquete encontró { $count } problemas; puede que el EPUB no se abra en todas partes.
+}
+package-check-strict = { $count ->
+    [one] La comprobación del paquete encontró { $count } problema.
+   *[other] La comprobación del paquete encontró { $count } problemas.
+}
+package-check-failed = No se pudo comprobar el paquete: { $error }
+position-saved = Posición de lectura guardada en { $path }
+position-save-failed = No se pudo guardar la posición de lectura: { $error }
+workdir-remove-failed = No se pudo borrar el libro desempaquetado: { $error }
+running-epubcheck = Ejecutando epubcheck...
+epubcheck-skipped = Se omite epubcheck: { $error }
+epubcheck-error = error de epubcheck { $message }
+epubcheck-warning = aviso de epubcheck { $message }
+epubcheck-info = epubcheck: { $message }
+epubcheck-clean = epubcheck no encontró errores.
+epubcheck-errors = { $count ->
+    [one] epubcheck encontró { $count } error.
+   *[other] epubcheck encontró { $count } errores.
+}
+rebuilding = Reconstruyendo el libro { $book } a partir de la copia de { $mirrored }
+
+## Construcción del libro
+
+chapter-done = Capítulo { $index }/{ $total } listo: { $title }
+time-left = { $done }/{ $total } listos, quedan unos { $left } para este libro
+downloading-assets = Descargando { $count } recursos...
+toc-unavailable = Índice no disponible ({ $error }); se usa la lista de capítulos.
+writing-parts = También se escribe el libro en { $parts } partes de { $size } como máximo.
+asset-skipped = Se omite el recurso: { $error }
+assets-missing = { $count ->
+    [one] Falta 1 recurso en el libro; consulta { $report }
+   *[other] Faltan { $count } recursos en el libro; consulta { $report }
+}
+assets-missing-strict = { $count ->
+    [one] No se pudo descargar 1 recurso (--strict); consulta { $report }
+   *[other] No se pudieron descargar { $count } recursos (--strict); consulta { $report }
+}
+keeping-svg = Se mantienen las imágenes SVG: { $error }
+keeping-as-svg = Se mantiene { $file } como SVG: { $error }
+book-size = El libro tiene { $chapters } capítulos, { $images } imágenes, { $pages } páginas; unos { $size } por descargar.
+confirm-size = Eso supera { $limit }. ¿Descargarlo? [y/N]
+above-threshold = Se estima que el libro ocupa { $size }, por encima del umbral de confirmación de { $limit }; pase --yes (o suba --confirm-above) para descargarlo
+checking-download-size = Queda poco espacio en disco, comprobando el tamaño de la descarga...
+not-enough-space = No hay espacio suficiente en disco: el libro necesita unos { $needed } y solo quedan { $free } libres
+course-lessons = { $count ->
+    [one] El curso tiene { $count } lección.
+   *[other] El curso tiene { $count } lecciones.
+}
+lesson-done = Lección { $index }/{ $total } lista: { $title }
+no-transcript = No hay transcripción para "{ $lesson }".
+
+## Hooks y notificaciones
+
+webhook-notified = Webhook avisado.
+webhook-failed = Falló el webhook: { $error }
+hook-finished = El comando del hook terminó.
+hook-failed = Falló el comando del hook: { $error }
+notification-failed = No se pudo mostrar una notificación: { $error }
+notification-book = Libro { $book }
+notification-finished = Descarga terminada
+notification-skipped = Ya descargado
+notification-download-failed = Falló la descarga
+
+## Errores
+
+reading-file = Leyendo { $path }
+writing-file = Escribiendo { $path }
+opening-file = Abriendo { $path }
+creating-file = Creando { $path }
+removing-file = Borrando { $path }
+replacing-file = Reemplazando { $path }
+moving-file = Moviendo { $path }
+copying-file = Copiando { $path }
+parsing-file = Analizando { $path }
+reading-directory = Leyendo el directorio { $path }
+creating-directory = Creando el directorio { $path }
+removing-directory = Borrando el directorio { $path }
+creating-log-file = No se puede crear el registro { $path }
+running-program = Ejecutando { $program }
+program-failed = Falló { $program } ({ $status }){ $detail }
+decoding-json = Decodificando la respuesta JSON
+http-get = GET { $url }
+http-post = POST { $url }
+http-post-status = POST { $url }: HTTP { $status }
+invalid-boolean = valor booleano no válido { $value }
+size-unknown-unit = unidad desconocida { $unit } en { $size } (use K, M o G)
+size-invalid = { $size } no es un tamaño como 500K o 2M
+rate-invalid = velocidad no válida
+book-id-invalid = { $input } no es un ID de libro; use los dígitos de la URL del libro (p. ej., 9781491958698) o la propia URL
+rate-zero = la velocidad debe ser mayor que cero: { $rate }
+format-unknown = formato desconocido { $format } (disponibles: { $formats })
+env-not-boolean = { $name } debe ser true o false
+env-not-number = { $name } debe ser un número
+env-not-size = { $name } debe ser un tamaño como { $example }
+env-not-rate = { $name } debe ser una velocidad como { $example }
+env-not-percentage = { $name } debe ser un porcentaje de 1 a 100
+env-not-level = { $name } debe ser un nivel de 0 a 9
+env-not-one-of = { $name } debe ser uno de: { $values }
+env-invalid = { $name } no es válido
+profile-name-invalid = nombre de perfil no válido { $name } (use letras, dígitos, '-' y '_')
+profile-unknown = perfil desconocido { $name } (cree { $path }; perfiles conocidos: { $known })
+profiles-none = ninguno
+legacy-names-with-templates = los nombres heredados siguen a safaribooks.py y no se pueden combinar con plantillas de nombres
+template-dir-invalid = Plantilla de directorio no válida
+template-file-invalid = Plantilla de nombre de archivo no válida
+template-file-slash = la plantilla de nombre de archivo no puede contener '/'
+template-unmatched-brace = '{ "}" }' sin pareja en { $template }
+template-unclosed-brace = '{ "{" }' sin cerrar en { $template }
+template-unknown-field = campo desconocido { "{" }{ $name }{ "}" } (disponibles: { $fields })
+template-empty-component = componente de ruta vacío o relativo en { $template }
+header-malformed = La cabecera debe ser "Nombre: valor": { $header }
+header-name-invalid = Nombre de cabecera no válido en { $header }
+header-value-invalid = Valor de cabecera no válido en { $header }
+resolve-malformed = --resolve debe ser "host:dirección": { $entry }
+base-url-invalid = La URL base debe ser una URL http(s) con host: { $url }
+user-agent-invalid = User-Agent no válido: { $value }
+certificate-unusable = Ningún certificado utilizable en { $path }
+certificate-missing = Ningún certificado en { $path }
+profile-status = La consulta del perfil devolvió un estado inesperado { $status }
+v2-status = Estado { $status } de la API v2
+unexpected-status = Estado { $status }
+unexpected-status-for = Estado { $status } para { $url }
+chapter-status = Estado { $status } para el capítulo { $chapter }
+asset-status = Estado { $status } para el recurso { $url }
+chapter-pagination-loop = La paginación de la lista de capítulos vuelve a { $url }
+listing-pagination-loop = La paginación del listado vuelve a { $url }
+unsupported-audiobook =
+    Tipo de producto no admitido: audiolibro.
+    Solo se pueden descargar libros y cursos en vídeo (como transcripciones).
+unsupported-product = Tipo de producto no admitido: "{ $format }".
+range-missing = Respuesta parcial sin un Content-Range utilizable
+resumed-elsewhere = El servidor reanudó en el byte { $start }, se esperaba { $offset }
+download-stopped = La descarga se detuvo en { $written } de { $total } bytes
+fetching-book-info = No se pudo obtener la información del libro
+mirror-start-failed = No se pudo iniciar la réplica
+mirror-save-failed = No se pudo replicar la respuesta
+mirroring-book-info = No se pudo replicar la información del libro
+mirror-missing = No hay réplica en { $path } (descargue primero el libro con --mirror)
+mirror-no-book-info = La réplica no tiene información del libro utilizable
+no-recorded-response = No hay respuesta grabada para { $url } en { $path }
+import-failed = No se pudo importar la descarga
+skeleton-failed = No se pudo crear el esqueleto del EPUB
+book-epub-failed = No se pudo crear el EPUB del libro
+transcript-epub-failed = No se pudo crear el EPUB de transcripciones
+splitting-failed = No se pudo dividir el libro en partes
+writing-part = Escribiendo la parte { $part }
+packaging-failed = Falló el empaquetado
+packaging-part-failed = Falló el empaquetado de { $path }
+epub-already-finished = { $path } ya estaba terminado
+metadata-write-failed = No se pudieron escribir los metadatos
+stdout-write-failed = No se pudo escribir el EPUB en la salida estándar
+container-no-rootfile = container.xml no tiene rootfile
+zip-entry-missing = Falta { $name }
+checksum-manifest-missing = No hay manifiesto de sumas (*.epub.sha256) en { $path }
+reading-checksum-manifest = Leyendo el manifiesto de sumas { $path }
+checksum-line-malformed = Línea mal formada en { $path }: { $line }
+upgrading-state = Actualizando { $path } a la versión { $version }
+calibre-not-run = Ejecutando { $program } (¿está instalado Calibre?)
+calibre-no-answer = calibredb no indicó si añadió el libro
+epubcheck-path-missing = epubcheck_path { $path } no existe
+epubcheck-not-found = No se encontró epubcheck en el PATH; instálelo o configure epubcheck_path
+rsvg-not-found = No se encontró rsvg-convert en el PATH; instale librsvg para rasterizar SVG
+hook-exited = { $command } terminó con { $status }
+listening-failed = Escuchando en { $listen }
+server-failed = Falló el servidor
+release-feed-unreadable = Leyendo la lista de versiones
+release-no-build = La versión { $release } no tiene compilación para esta plataforma ({ $asset })
+release-no-checksum = La versión { $release } no publica suma de comprobación para { $asset }; no se instala sin verificar
+checksum-malformed = Archivo de suma de comprobación mal formado: { $text }
+checksum-mismatch = { $asset } no coincide con su suma publicada (se esperaba { $expected }, se obtuvo { $actual })
+release-no-signature = La versión { $release } no publica firma para { $asset }; no se instala sin verificar
+release-key-missing = Esta compilación no tiene clave de publicación con la que verificar { $version }; descárguela desde la página de versiones
+release-key-malformed = Clave de publicación mal formada
+signature-rejected = Verificando la firma de { $asset }
+signature-malformed = Firma minisign mal formada
+signature-prehashed = Algoritmo de firma { $tag } no admitido; las versiones deben firmarse con minisign -l
+signature-other-key = Firmado con una clave distinta de la de publicación
+signature-invalid = La firma no coincide con el archivo
+signature-comment-invalid = La firma del comentario de confianza no coincide
+locating-executable = Localizando este ejecutable
+executable-no-name = La ruta del ejecutable no tiene nombre de archivo
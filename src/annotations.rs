@@ -0,0 +1,158 @@
+//! The reader's highlights and notes on a book: exported by the
+//! `annotations` subcommand, or embedded as an appendix chapter
+//! (`--embed-annotations`).
+
+use crate::epub::{xml_escape, EpubSkeleton, ManifestItem, NavPoint};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// The appendix chapter written into the book.
+pub const APPENDIX_HREF: &str = "annotations.xhtml";
+
+/// How `annotations` prints its export.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    #[default]
+    Markdown,
+    Json,
+}
+
+/// One highlight, note, or highlight with a note.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    #[serde(default)]
+    pub chapter_title: Option<String>,
+    /// The highlighted passage.
+    #[serde(default, alias = "highlight")]
+    pub quote: Option<String>,
+    /// The reader's own text.
+    #[serde(default, alias = "text")]
+    pub note: Option<String>,
+    /// When it was made, ISO 8601.
+    #[serde(default, alias = "created_time")]
+    pub created: Option<String>,
+}
+
+/// Chapters in first-seen order, each with its annotations.
+fn by_chapter(annotations: &[Annotation]) -> Vec<(&str, Vec<&Annotation>)> {
+    let mut chapters: Vec<(&str, Vec<&Annotation>)> = Vec::new();
+    for a in annotations {
+        let title = a.chapter_title.as_deref().unwrap_or("Untitled");
+        match chapters.iter_mut().find(|(t, _)| *t == title) {
+            Some((_, list)) => list.push(a),
+            None => chapters.push((title, vec![a])),
+        }
+    }
+    chapters
+}
+
+/// The export of `annotations` on the book `title`.
+pub fn export(title: &str, annotations: &[Annotation], format: ExportFormat) -> Result<String> {
+    Ok(match format {
+        ExportFormat::Json => serde_json::to_string_pretty(annotations)? + "\n",
+        ExportFormat::Markdown => to_markdown(title, annotations),
+    })
+}
+
+fn to_markdown(title: &str, annotations: &[Annotation]) -> String {
+    let mut out = format!("# {title}\n");
+    for (chapter, list) in by_chapter(annotations) {
+        out.push_str(&format!("\n## {chapter}\n"));
+        for a in list {
+            out.push('\n');
+            if let Some(quote) = &a.quote {
+                for line in quote.lines() {
+                    out.push_str(&format!("> {line}\n"));
+                }
+            }
+            if let Some(note) = &a.note {
+                if a.quote.is_some() {
+                    out.push('\n');
+                }
+                out.push_str(note);
+                out.push('\n');
+            }
+            if let Some(created) = &a.created {
+                out.push_str(&format!("\n*{created}*\n"));
+            }
+        }
+    }
+    out
+}
+
+/// Write the appendix listing `annotations` by chapter and return its
+/// manifest item and table-of-contents entry.
+pub fn write_appendix(
+    skeleton: &EpubSkeleton,
+    annotations: &[Annotation],
+) -> Result<(ManifestItem, NavPoint)> {
+    let mut body = String::from(
+        "<section epub:type=\"appendix\" role=\"doc-appendix\">\n<h1>My annotations</h1>\n",
+    );
+    for (chapter, list) in by_chapter(annotations) {
+        body.push_str(&format!("<h2>{}</h2>\n", xml_escape(chapter)));
+        for a in list {
+            if let Some(quote) = &a.quote {
+                body.push_str(&format!(
+                    "<blockquote><p>{}</p></blockquote>\n",
+                    xml_escape(quote)
+                ));
+            }
+            if let Some(note) = &a.note {
+                body.push_str(&format!("<p>{}</p>\n", xml_escape(note)));
+            }
+        }
+    }
+    body.push_str("</section>");
+    skeleton.write_xhtml(APPENDIX_HREF, "My annotations", &body)?;
+    Ok((
+        ManifestItem {
+            id: "annotations".to_string(),
+            href: APPENDIX_HREF.to_string(),
+            media_type: "application/xhtml+xml".to_string(),
+        },
+        NavPoint {
+            title: "My annotations".to_string(),
+            href: APPENDIX_HREF.to_string(),
+            children: Vec::new(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, Annotation, ExportFormat};
+
+    fn sample() -> Vec<Annotation> {
+        serde_json::from_str(
+            r#"[
+                { "chapter_title": "1. Ownership", "highlight": "Each value has an owner.", "created_time": "2024-03-01" },
+                { "chapter_title": "2. Borrowing", "text": "Re-read this." },
+                { "chapter_title": "1. Ownership", "quote": "Moves\nare cheap.", "note": "Check Copy." }
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn exports_markdown_grouped_by_chapter() {
+        let md = export("Rust", &sample(), ExportFormat::Markdown).unwrap();
+        assert_eq!(
+            md,
+            "# Rust\n\
+             \n## 1. Ownership\n\
+             \n> Each value has an owner.\n\n*2024-03-01*\n\
+             \n> Moves\n> are cheap.\n\nCheck Copy.\n\
+             \n## 2. Borrowing\n\
+             \nRe-read this.\n"
+        );
+    }
+
+    #[test]
+    fn exports_json() {
+        let json = export("Rust", &sample(), ExportFormat::Json).unwrap();
+        let back: Vec<Annotation> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, sample());
+    }
+}
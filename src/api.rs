@@ -0,0 +1,105 @@
+use crate::i18n::t;
+use crate::orly::DEFAULT_BASE_URL;
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::path::Path;
+
+/// A fully-read HTTP response: enough for the API calls and small assets.
+#[derive(Debug, Clone)]
+pub struct ApiResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl ApiResponse {
+    /// Parse the body as JSON.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body).with_context(|| t!("decoding-json"))
+    }
+
+    /// Decode the body as UTF-8 text (lossy, like browsers do).
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Everything the `orly` functions and the download pipelines need from the
+/// network. The real implementation is `HttpClient`; tests use `FixtureApi`.
+pub trait OreillyApi: Sync {
+    /// GET `url` and return the status and full body.
+    fn get(&self, url: &str) -> impl Future<Output = Result<ApiResponse>> + Send;
+
+    /// GET `url` as a link followed from the page `referer` would be; some
+    /// endpoints check the `Referer`. Only `HttpClient` sends it.
+    fn get_from(
+        &self,
+        url: &str,
+        _referer: &str,
+    ) -> impl Future<Output = Result<ApiResponse>> + Send {
+        self.get(url)
+    }
+
+    /// GET `url` (from the page `referer`, if any) into the file `dest`
+    /// when the status is 200, without holding the whole body in memory;
+    /// returns the status.
+    fn download(
+        &self,
+        url: &str,
+        referer: Option<&str>,
+        dest: &Path,
+    ) -> impl Future<Output = Result<u16>> + Send {
+        async move {
+            let res = match referer {
+                Some(referer) => self.get_from(url, referer).await?,
+                None => self.get(url).await?,
+            };
+            if res.status == 200 {
+                std::fs::write(dest, &res.body)
+                    .with_context(|| t!("writing-file", path = dest.display().to_string()))?;
+            }
+            Ok(res.status)
+        }
+    }
+
+    /// Size of the body at `url` without downloading it, if the server tells.
+    fn content_length(&self, _url: &str) -> impl Future<Output = Result<Option<u64>>> + Send {
+        async { Ok(None) }
+    }
+
+    /// Site root the API URLs are built on, without a trailing slash.
+    fn base_url(&self) -> &str {
+        DEFAULT_BASE_URL
+    }
+}
+
+/// Offline API serving canned bodies by exact URL; anything else is a 404.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FixtureApi {
+    responses: std::collections::HashMap<String, ApiResponse>,
+}
+
+#[cfg(test)]
+impl FixtureApi {
+    pub fn with(mut self, url: &str, status: u16, body: impl Into<Vec<u8>>) -> Self {
+        self.responses.insert(
+            url.to_string(),
+            ApiResponse {
+                status,
+                body: body.into(),
+            },
+        );
+        self
+    }
+}
+
+#[cfg(test)]
+impl OreillyApi for FixtureApi {
+    async fn get(&self, url: &str) -> Result<ApiResponse> {
+        Ok(self.responses.get(url).cloned().unwrap_or(ApiResponse {
+            status: 404,
+            body: Vec::new(),
+        }))
+    }
+}
@@ -0,0 +1,2125 @@
+use crate::annotations::{self, Annotation};
+use crate::api::OreillyApi;
+use crate::checksum::{sha256_file, short_hash};
+use crate::code::{self, CodeStyle, Drawing};
+use crate::display::{Display, DownloadEvent};
+use crate::epub::{
+    free_space, media_type_for, EpubSkeleton, Landmark, ManifestItem, MetadataOverrides, NavPoint,
+    PackageMetadata, PageTarget,
+};
+use crate::figures;
+use crate::html::{HeadingLevels, Token, Tokenizer, XhtmlWriter};
+use crate::i18n::t;
+use crate::legacy;
+use crate::orly::{
+    download_asset, fetch_chapter_content, fetch_chapters, fetch_toc, iso_date, BookInfo, Chapter,
+    ReadingPosition, Stylesheet, TocEntry,
+};
+use crate::package::EpubStream;
+use crate::split;
+use crate::svg::{self, Rasterizer};
+use crate::table::{self, TableStyle};
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
+
+/// The generated stylesheet every chapter links, for listings and tables.
+const LAYOUT_CSS: &str = "Styles/layout.css";
+
+/// Assets referenced by the chapters, keyed by source URL so each is
+/// downloaded once no matter how many chapters use it.
+pub struct AssetIndex {
+    /// Site root that root-relative paths resolve against.
+    site: String,
+    /// Source URL -> href relative to OEBPS/.
+    by_url: HashMap<String, String>,
+    /// (url, href) in first-seen order.
+    ordered: Vec<(String, String)>,
+    /// Source URL -> the page that first used it, sent as the `Referer`
+    /// when fetching it.
+    referers: HashMap<String, String>,
+    /// Name assets as safaribooks.py does rather than by hash.
+    legacy_names: bool,
+}
+
+impl AssetIndex {
+    pub fn new(site: &str) -> Self {
+        Self {
+            site: site.to_string(),
+            by_url: HashMap::new(),
+            ordered: Vec::new(),
+            referers: HashMap::new(),
+            legacy_names: false,
+        }
+    }
+
+    fn with_legacy_names(mut self, on: bool) -> Self {
+        self.legacy_names = on;
+        self
+    }
+
+    /// Record the page `page` as where the assets from the `first`th on
+    /// were found.
+    fn found_on(&mut self, first: usize, page: &str) {
+        for (url, _) in &self.ordered[first..] {
+            self.referers
+                .entry(url.clone())
+                .or_insert_with(|| page.to_string());
+        }
+    }
+
+    /// Assets are stored under a hash of their URL: chapters often use
+    /// different figures with the same file name.
+    fn add(&mut self, url: String, dir: &str, path: &str) -> String {
+        if let Some(href) = self.by_url.get(&url) {
+            return href.clone();
+        }
+        let legacy = self
+            .legacy_names
+            .then(|| self.legacy_href(dir, path))
+            .flatten();
+        let href = match (legacy, extension(path)) {
+            (Some(href), _) => href,
+            (None, Some(ext)) => format!("{dir}/{}.{ext}", short_hash(&url)),
+            (None, None) => format!("{dir}/{}", short_hash(&url)),
+        };
+        self.by_url.insert(url.clone(), href.clone());
+        self.ordered.push((url, href.clone()));
+        href
+    }
+
+    /// safaribooks.py's name for an asset: Styles/Style00.css, Style01.css
+    /// and so on in the order first seen, and images under their own file
+    /// name. None (hash it) when another asset has that name already or
+    /// it would need escaping.
+    fn legacy_href(&self, dir: &str, path: &str) -> Option<String> {
+        let href = if dir == "Styles" {
+            let n = self
+                .ordered
+                .iter()
+                .filter(|(_, href)| href.starts_with("Styles/"))
+                .count();
+            format!("Styles/Style{n:02}.css")
+        } else {
+            let name = basename(path);
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+            {
+                return None;
+            }
+            format!("{dir}/{name}")
+        };
+        (!self.ordered.iter().any(|(_, taken)| *taken == href)).then_some(href)
+    }
+
+    fn add_image(&mut self, chapter: &Chapter, path: &str) -> String {
+        let url = resolve_url(&self.site, &chapter.asset_base_url, path);
+        self.add(url, "Images", path)
+    }
+
+    fn add_stylesheet(&mut self, css: &Stylesheet) -> String {
+        let path = if css.full_path.is_empty() {
+            &css.url
+        } else {
+            &css.full_path
+        };
+        self.add(css.url.clone(), "Styles", path)
+    }
+}
+
+/// A chapter (or lesson) as written into the work directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrittenChapter {
+    pub title: String,
+    /// Its document, relative to OEBPS.
+    pub href: String,
+}
+
+/// What a pipeline produced, for the final report and the output format.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildStats {
+    /// Chapters (or lessons) written.
+    pub chapters: usize,
+    /// The same chapters, in reading order.
+    pub written: Vec<WrittenChapter>,
+    /// Assets downloaded.
+    pub assets: usize,
+    /// Assets that could not be downloaded and were left out.
+    pub failed_assets: usize,
+}
+
+/// An asset that could not be downloaded, as the missing-assets report
+/// lists it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct MissingAsset {
+    /// The page that uses it: its chapter, or the SVG figure it is part of.
+    page: Option<String>,
+    url: String,
+    /// Where in the EPUB it would have been.
+    href: String,
+    error: String,
+}
+
+/// The report of the assets left out, in the book directory; removed once a
+/// resumed download gets all of them.
+pub const MISSING_REPORT: &str = "missing-assets.json";
+
+/// Shown in place of an image that could not be downloaded.
+const MISSING_IMAGE: &str = "Images/missing-image.svg";
+const MISSING_IMAGE_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="320" height="120" viewBox="0 0 320 120"><rect x="1" y="1" width="318" height="118" fill="#f4f4f4" stroke="#999" stroke-dasharray="6 4"/><text x="160" y="66" font-family="sans-serif" font-size="16" fill="#666" text-anchor="middle">Image unavailable</text></svg>
+"##;
+
+/// Disk space of a chapter when nothing better is known.
+const TYPICAL_CHAPTER_BYTES: u64 = 64 * 1024;
+
+/// Disk space of an image or stylesheet whose size the server doesn't tell.
+const TYPICAL_ASSET_BYTES: u64 = 256 * 1024;
+
+/// Knobs of the book pipeline.
+pub struct BookOptions {
+    /// Maximum number of requests in flight.
+    pub jobs: usize,
+    /// Keep assets an earlier run already wrote instead of fetching them again.
+    pub resume: bool,
+    /// Append the generated colophon to the spine.
+    pub credits: bool,
+    /// The reader's annotations, appended as an appendix when any.
+    pub annotations: Vec<Annotation>,
+    /// Where the web reader left off, made the book's starting point (books only).
+    pub position: Option<ReadingPosition>,
+    /// Corrections to the OPF metadata.
+    pub metadata: MetadataOverrides,
+    /// Ask before downloading a book estimated above this many bytes;
+    /// None never asks (`--yes`, queued downloads).
+    pub confirm_above: Option<u64>,
+    /// How program listings are laid out.
+    pub code_style: CodeStyle,
+    /// What becomes of tables too wide for the screen.
+    pub table_style: TableStyle,
+    /// Convert SVG images to PNG, for readers without SVG support.
+    pub rasterize_svg: bool,
+    /// Embed images smaller than this many bytes in the chapters as data URIs.
+    pub inline_below: Option<u64>,
+    /// Also write the book as part EPUBs of at most this many bytes, when
+    /// it is larger.
+    pub split_above: Option<u64>,
+    /// Fetch each chapter's assets right after it, as a browser would
+    /// (`--stealth`), rather than all of them at the end.
+    pub stealth: bool,
+    /// Stops the download at the next chapter or asset; what is written
+    /// stays for a resume.
+    pub cancel: CancellationToken,
+    /// The EPUB the assets are compressed into as they are saved; None
+    /// packages the whole book once it is written.
+    pub package: Option<EpubStream>,
+}
+
+impl BookOptions {
+    /// Fail with "Download cancelled" once `cancel` has been triggered.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            bail!(t!("download-cancelled"));
+        }
+        Ok(())
+    }
+}
+
+/// Download every chapter of a book with its images and stylesheets, rewrite
+/// the links to the local copies and write the package documents.
+pub async fn build_book_epub<A: OreillyApi>(
+    api: &A,
+    ui: &Display,
+    bookid: &str,
+    info: &BookInfo,
+    skeleton: &EpubSkeleton,
+    opts: &BookOptions,
+) -> Result<BuildStats> {
+    let chapters = fetch_chapters(api, info, bookid).await?;
+    let indexed = index_assets(api.base_url(), &chapters);
+    ui.add_work(chapters.len() + indexed.ordered.len());
+    preflight(ui, info, &chapters, indexed, opts)?;
+    if let Some(free) = free_space(&skeleton.root) {
+        check_free_space(api, ui, &chapters, free, opts.jobs).await?;
+    }
+
+    let chapter_files: HashMap<String, String> = chapters
+        .iter()
+        .map(|c| (basename(&c.filename).to_string(), chapter_href(&c.filename)))
+        .collect();
+
+    let mut assets = AssetIndex::new(api.base_url()).with_legacy_names(skeleton.legacy_names);
+    let mut manifest = Vec::new();
+    let mut spine = Vec::new();
+    let mut pages = Vec::new();
+    let mut drawings = Vec::new();
+    // Written once the assets are in, so duplicates can be pointed at one copy.
+    let mut documents = Vec::new();
+    let mut asset_items = Vec::new();
+    let mut missing = Vec::new();
+    // Assets already fetched with their chapter (`--stealth`).
+    let mut fetched = 0;
+
+    // Fetch chapter contents concurrently, but process them in reading order.
+    let mut contents = stream::iter(&chapters)
+        .map(|chapter| async move {
+            // Resuming where safaribooks.py left off: what it saved is kept.
+            let saved = (opts.resume && skeleton.legacy_names)
+                .then(|| legacy::saved_chapter(skeleton, &chapter_href(&chapter.filename), chapter))
+                .flatten();
+            match saved {
+                Some(html) => Ok(html),
+                None => fetch_chapter_content(api, &info.web_url, chapter).await,
+            }
+        })
+        .buffered(opts.jobs.max(1))
+        .enumerate();
+
+    while let Some((i, html)) = contents.next().await {
+        opts.check_cancelled()?;
+        let chapter = &chapters[i];
+        let html = html?;
+        let first_new = assets.ordered.len();
+        let stylesheets: Vec<String> = chapter
+            .stylesheets
+            .iter()
+            .map(|css| assets.add_stylesheet(css))
+            .collect();
+        for image in &chapter.images {
+            assets.add_image(chapter, image);
+        }
+        let body = rewrite_chapter(
+            &html,
+            chapter,
+            &chapter_files,
+            &mut assets,
+            &mut pages,
+            opts,
+            &mut drawings,
+        );
+        assets.found_on(
+            first_new,
+            &resolve_url(api.base_url(), &info.web_url, &chapter.filename),
+        );
+
+        let id = format!("ch_{:04}", i + 1);
+        let href = chapter_href(&chapter.filename);
+        manifest.push(ManifestItem {
+            id: id.clone(),
+            href: href.clone(),
+            media_type: "application/xhtml+xml".to_string(),
+        });
+        documents.push((href, stylesheets, body));
+        spine.push(id);
+        if opts.stealth {
+            // As a browser would: the page, then what it shows.
+            missing.extend(
+                download_assets(api, ui, skeleton, opts, &assets, fetched, &mut asset_items).await,
+            );
+            fetched = assets.ordered.len();
+        }
+        ui.info(&t!(
+            "chapter-done",
+            index = i + 1,
+            total = chapters.len(),
+            title = chapter.title.as_str()
+        ));
+        ui.event(&DownloadEvent::ChapterDone {
+            index: i + 1,
+            total: chapters.len(),
+            title: chapter.title.clone(),
+        });
+        ui.work_done(1);
+    }
+
+    if fetched < assets.ordered.len() {
+        let count = assets.ordered.len() - fetched;
+        ui.info(&t!("downloading-assets", count = count));
+        missing.extend(
+            download_assets(api, ui, skeleton, opts, &assets, fetched, &mut asset_items).await,
+        );
+    }
+    opts.check_cancelled()?;
+    fetch_svg_references(
+        api,
+        ui,
+        skeleton,
+        &mut assets,
+        &mut asset_items,
+        &mut missing,
+        opts,
+    )
+    .await?;
+    // Completion order is arbitrary; keep the manifest reproducible.
+    asset_items.sort_by_key(|(n, _)| *n);
+    let mut kept = dedupe_assets(skeleton, &mut asset_items)?;
+    let stats = BuildStats {
+        chapters: chapters.len(),
+        written: chapters
+            .iter()
+            .map(|chapter| WrittenChapter {
+                title: chapter.title.clone(),
+                href: chapter_href(&chapter.filename),
+            })
+            .collect(),
+        assets: asset_items.len(),
+        failed_assets: missing.len(),
+    };
+    write_missing_report(skeleton, bookid, &missing)?;
+    // Readers show a placeholder, not a broken image, for what is missing:
+    // a figure's alt text and caption where a chapter shows it, an image
+    // standing in for it anywhere else.
+    let missing_images: HashMap<&str, &str> = missing
+        .iter()
+        .filter(|asset| asset.href.starts_with("Images/"))
+        .map(|asset| (asset.href.as_str(), asset.url.as_str()))
+        .collect();
+    let mut missing_figures = Vec::new();
+    if !missing_images.is_empty() {
+        let path = skeleton.asset_path(MISSING_IMAGE)?;
+        fs::write(&path, MISSING_IMAGE_SVG)
+            .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+        manifest.push(ManifestItem {
+            id: "missing_image".to_string(),
+            href: MISSING_IMAGE.to_string(),
+            media_type: "image/svg+xml".to_string(),
+        });
+    } else {
+        remove_stale(skeleton, MISSING_IMAGE)?;
+    }
+    manifest.extend(asset_items.into_iter().map(|(_, item)| item));
+    for (n, drawing) in drawings.iter().enumerate() {
+        let path = skeleton.asset_path(&drawing.href)?;
+        fs::write(&path, &drawing.svg)
+            .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+        manifest.push(ManifestItem {
+            id: format!("drawing_{:04}", n + 1),
+            href: drawing.href.clone(),
+            media_type: "image/svg+xml".to_string(),
+        });
+    }
+    if opts.rasterize_svg {
+        rasterize(ui, skeleton, &mut manifest, &mut kept)?;
+    }
+    let mut layout = code::stylesheet(opts.code_style)
+        .unwrap_or_default()
+        .to_string()
+        + table::STYLESHEET;
+    if !missing_images.is_empty() {
+        layout.push_str(figures::STYLESHEET);
+    }
+    let path = skeleton.asset_path(LAYOUT_CSS)?;
+    fs::write(&path, layout)
+        .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+    manifest.push(ManifestItem {
+        id: "layout_css".to_string(),
+        href: LAYOUT_CSS.to_string(),
+        media_type: "text/css".to_string(),
+    });
+
+    let inlined = match opts.inline_below {
+        Some(below) => inline_images(skeleton, &manifest, below)?,
+        None => HashMap::new(),
+    };
+    for ((href, stylesheets, mut body), chapter) in documents.into_iter().zip(&chapters) {
+        let stylesheets: Vec<String> = stylesheets
+            .into_iter()
+            .filter(|css| !missing.iter().any(|asset| asset.href == *css))
+            .map(|css| kept.get(&css).cloned().unwrap_or(css))
+            .chain([LAYOUT_CSS.to_string()])
+            .collect();
+        for (duplicate, original) in &kept {
+            body = body.replace(&format!("\"{duplicate}\""), &format!("\"{original}\""));
+        }
+        for (image, uri) in &inlined {
+            body = body.replace(&format!("\"{image}\""), &format!("\"{uri}\""));
+        }
+        body = figures::replace_missing(
+            &body,
+            &href,
+            &chapter.title,
+            &missing_images,
+            &mut missing_figures,
+        );
+        for image in missing_images.keys() {
+            body = body.replace(&format!("\"{image}\""), &format!("\"{MISSING_IMAGE}\""));
+        }
+        skeleton.write_document(&href, &chapter.title, &stylesheets, &body)?;
+    }
+    drop_inlined(skeleton, &mut manifest, &inlined)?;
+
+    let mut nav = match fetch_toc(api, info, bookid).await {
+        Ok(toc) if !toc.is_empty() => nav_from_toc(&toc, &chapter_files),
+        Ok(_) => flat_nav(&chapters),
+        Err(e) => {
+            ui.warn(&t!("toc-unavailable", error = e.to_string()));
+            flat_nav(&chapters)
+        }
+    };
+
+    let mut meta = PackageMetadata::from_book_info(bookid, info);
+    meta.apply(&opts.metadata);
+    if missing_figures.is_empty() {
+        remove_stale(skeleton, figures::APPENDIX_HREF)?;
+    } else {
+        let (item, point) = figures::write_appendix(skeleton, &missing_figures)?;
+        spine.push(item.id.clone());
+        manifest.push(item);
+        nav.push(point);
+    }
+    if !opts.annotations.is_empty() {
+        let (item, point) = annotations::write_appendix(skeleton, &opts.annotations)?;
+        spine.push(item.id.clone());
+        manifest.push(item);
+        nav.push(point);
+    }
+    if opts.credits {
+        let item = skeleton.write_colophon(&meta, &iso_date(SystemTime::now()))?;
+        spine.push(item.id.clone());
+        manifest.push(item);
+    }
+    // Readers open a book at its body matter: make that where the web reader
+    // left off, or else the first chapter.
+    let landmarks: Vec<Landmark> = opts
+        .position
+        .as_ref()
+        .and_then(|pos| {
+            let href = chapter_files.get(basename(&pos.chapter_filename))?;
+            Some(Landmark {
+                kind: "bodymatter",
+                title: "Continue reading".to_string(),
+                href: match &pos.fragment {
+                    Some(fragment) => format!("{href}#{fragment}"),
+                    None => href.clone(),
+                },
+            })
+        })
+        .or_else(|| {
+            chapters.first().map(|chapter| Landmark {
+                kind: "bodymatter",
+                title: "Start of content".to_string(),
+                href: chapter_href(&chapter.filename),
+            })
+        })
+        .into_iter()
+        .collect();
+    skeleton.write_toc(&meta, &nav, &landmarks, &pages)?;
+    skeleton.write_opf(&meta, &manifest, &spine)?;
+    let parts = split::write_parts(skeleton, &meta, &manifest, &spine, &nav, opts.split_above)
+        .with_context(|| t!("splitting-failed"))?;
+    if let Some(max) = opts.split_above
+        && parts > 0
+    {
+        ui.info(&t!("writing-parts", parts = parts, size = human_size(max)));
+    }
+    Ok(stats)
+}
+
+/// Download the assets from the `first`th on, adding the ones saved to
+/// `items`. Returns the ones that could not be downloaded.
+async fn download_assets<A: OreillyApi>(
+    api: &A,
+    ui: &Display,
+    skeleton: &EpubSkeleton,
+    opts: &BookOptions,
+    assets: &AssetIndex,
+    first: usize,
+    items: &mut Vec<(usize, ManifestItem)>,
+) -> Vec<MissingAsset> {
+    // Once cancelled, the downloads in flight finish and no more start.
+    let mut downloads = stream::iter(assets.ordered[first..].iter().enumerate())
+        .take_while(|_| future::ready(!opts.cancel.is_cancelled()))
+        .map(|(n, (url, href))| async move {
+            let n = first + n;
+            let saved = if opts.resume && skeleton.has_asset(href) {
+                Ok(())
+            } else {
+                let referer = assets.referers.get(url).map(String::as_str);
+                save_asset(api, skeleton, url, referer, href).await
+            };
+            if saved.is_ok()
+                && let Some(epub) = &opts.package
+            {
+                epub.add(&skeleton.root, &skeleton.oebps.join(href)).await;
+            }
+            (n, url, href, saved)
+        })
+        .buffer_unordered(opts.jobs.max(1));
+    let mut missing = Vec::new();
+    while let Some((n, url, href, result)) = downloads.next().await {
+        ui.work_done(1);
+        match result {
+            Ok(()) => items.push((n, asset_item(skeleton, n, href))),
+            Err(e) => {
+                ui.warn(&t!("asset-skipped", error = e.to_string()));
+                ui.event(&DownloadEvent::AssetFailed {
+                    url: url.clone(),
+                    error: format!("{e:#}"),
+                });
+                missing.push(MissingAsset {
+                    page: assets.referers.get(url).cloned(),
+                    url: url.clone(),
+                    href: href.clone(),
+                    error: format!("{e:#}"),
+                });
+            }
+        }
+    }
+    missing
+}
+
+/// Remove `href`, written by an earlier run for what was missing then.
+fn remove_stale(skeleton: &EpubSkeleton, href: &str) -> Result<()> {
+    let path = skeleton.oebps.join(href);
+    if path.exists() {
+        fs::remove_file(&path)
+            .with_context(|| t!("removing-file", path = path.display().to_string()))?;
+    }
+    Ok(())
+}
+
+/// Write the missing-assets report, or remove an earlier one when nothing
+/// is missing any more.
+fn write_missing_report(
+    skeleton: &EpubSkeleton,
+    bookid: &str,
+    missing: &[MissingAsset],
+) -> Result<()> {
+    let path = skeleton.root.join(MISSING_REPORT);
+    if missing.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|| t!("removing-file", path = path.display().to_string()))?;
+        }
+        return Ok(());
+    }
+    let report = serde_json::json!({ "book_id": bookid, "missing": missing });
+    fs::write(&path, serde_json::to_string_pretty(&report)? + "\n")
+        .with_context(|| t!("writing-file", path = path.display().to_string()))
+}
+
+/// The manifest item of the `n`th asset, stored at `href`. Figures served
+/// without an extension are still declared as SVG.
+fn asset_item(skeleton: &EpubSkeleton, n: usize, href: &str) -> ManifestItem {
+    let media_type = match media_type_for(href) {
+        "application/octet-stream" if svg::is_svg_file(&skeleton.oebps.join(href)) => {
+            "image/svg+xml"
+        }
+        media_type => media_type,
+    };
+    ManifestItem {
+        id: format!("asset_{:04}", n + 1),
+        href: href.to_string(),
+        media_type: media_type.to_string(),
+    }
+}
+
+/// Download the images and other SVGs that the SVG figures among `items`
+/// refer to, adding them to `items` and pointing the figures at the local
+/// copies. The ones that could not be downloaded are added to `missing`.
+async fn fetch_svg_references<A: OreillyApi>(
+    api: &A,
+    ui: &Display,
+    skeleton: &EpubSkeleton,
+    assets: &mut AssetIndex,
+    items: &mut Vec<(usize, ManifestItem)>,
+    missing: &mut Vec<MissingAsset>,
+    opts: &BookOptions,
+) -> Result<()> {
+    let mut figures: Vec<(String, String)> = items
+        .iter()
+        .filter(|(_, item)| item.media_type == "image/svg+xml")
+        .map(|(n, item)| (assets.ordered[*n].0.clone(), item.href.clone()))
+        .collect();
+    while let Some((url, href)) = figures.pop() {
+        let path = skeleton.oebps.join(&href);
+        // Compressed (.svgz) figures are left as they are.
+        let Ok(text) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let base = &url[..url.rfind('/').map_or(url.len(), |i| i + 1)];
+        let mut local = HashMap::new();
+        for reference in svg::references(&text) {
+            let (file, fragment) = match reference.split_once('#') {
+                Some((file, fragment)) => (file, format!("#{fragment}")),
+                None => (reference.as_str(), String::new()),
+            };
+            // Already pointing at a local copy (a resumed download).
+            if !file.contains('/') && skeleton.has_asset(&format!("Images/{file}")) {
+                continue;
+            }
+            let target = resolve_url(&assets.site, base, file);
+            let known = assets.by_url.contains_key(&target);
+            let target_href = assets.add(target.clone(), "Images", file);
+            if !known {
+                let n = assets.ordered.len() - 1;
+                let saved = if opts.resume && skeleton.has_asset(&target_href) {
+                    Ok(())
+                } else {
+                    save_asset(api, skeleton, &target, Some(&url), &target_href).await
+                };
+                if let Err(e) = saved {
+                    ui.warn(&t!("asset-skipped", error = e.to_string()));
+                    ui.event(&DownloadEvent::AssetFailed {
+                        url: target.clone(),
+                        error: format!("{e:#}"),
+                    });
+                    missing.push(MissingAsset {
+                        page: Some(url.clone()),
+                        url: target,
+                        href: target_href,
+                        error: format!("{e:#}"),
+                    });
+                    continue;
+                }
+                let item = asset_item(skeleton, n, &target_href);
+                if item.media_type == "image/svg+xml" {
+                    figures.push((target, target_href.clone()));
+                }
+                items.push((n, item));
+            }
+            let relative = match target_href.strip_prefix("Images/") {
+                Some(name) => name.to_string(),
+                None => format!("../{target_href}"),
+            };
+            local.insert(reference.clone(), relative + &fragment);
+        }
+        if !local.is_empty() {
+            fs::write(&path, svg::rewrite(&text, &local))
+                .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace the SVG images of `manifest` with PNG drawings of them, adding
+/// each SVG href mapped to its PNG to `renamed`. Without rsvg-convert, or
+/// where it fails, the SVG stays.
+fn rasterize(
+    ui: &Display,
+    skeleton: &EpubSkeleton,
+    manifest: &mut [ManifestItem],
+    renamed: &mut HashMap<String, String>,
+) -> Result<()> {
+    let tool = match Rasterizer::locate() {
+        Ok(tool) => tool,
+        Err(e) => {
+            ui.warn(&t!("keeping-svg", error = format!("{e:#}")));
+            return Ok(());
+        }
+    };
+    for item in manifest
+        .iter_mut()
+        .filter(|item| item.media_type == "image/svg+xml")
+    {
+        let png = format!("{}.png", item.href.trim_end_matches(".svg"));
+        let svg_path = skeleton.oebps.join(&item.href);
+        match tool.convert(&svg_path, &skeleton.oebps.join(&png)) {
+            Ok(()) => {
+                fs::remove_file(&svg_path)
+                    .with_context(|| t!("removing-file", path = svg_path.display().to_string()))?;
+                renamed.insert(std::mem::replace(&mut item.href, png.clone()), png);
+                item.media_type = "image/png".to_string();
+            }
+            Err(e) => ui.warn(&t!(
+                "keeping-as-svg",
+                file = item.href.as_str(),
+                error = format!("{e:#}")
+            )),
+        }
+    }
+    Ok(())
+}
+
+/// Data URIs of the images in `manifest` smaller than `below` bytes, by href.
+fn inline_images(
+    skeleton: &EpubSkeleton,
+    manifest: &[ManifestItem],
+    below: u64,
+) -> Result<HashMap<String, String>> {
+    let mut inlined = HashMap::new();
+    for item in manifest
+        .iter()
+        .filter(|item| item.media_type.starts_with("image/"))
+    {
+        let path = skeleton.oebps.join(&item.href);
+        let size = fs::metadata(&path)
+            .with_context(|| t!("reading-file", path = path.display().to_string()))?
+            .len();
+        if size >= below {
+            continue;
+        }
+        let bytes = fs::read(&path)
+            .with_context(|| t!("reading-file", path = path.display().to_string()))?;
+        inlined.insert(
+            item.href.clone(),
+            format!("data:{};base64,{}", item.media_type, BASE64.encode(bytes)),
+        );
+    }
+    Ok(inlined)
+}
+
+/// Remove the images now inlined in every chapter from `manifest` and
+/// disk; those a stylesheet or SVG figure still refers to stay.
+fn drop_inlined(
+    skeleton: &EpubSkeleton,
+    manifest: &mut Vec<ManifestItem>,
+    inlined: &HashMap<String, String>,
+) -> Result<()> {
+    if inlined.is_empty() {
+        return Ok(());
+    }
+    let referrers: Vec<String> = manifest
+        .iter()
+        .filter(|item| item.media_type == "text/css" || item.media_type == "image/svg+xml")
+        .filter_map(|item| fs::read_to_string(skeleton.oebps.join(&item.href)).ok())
+        .collect();
+    let mut dropped = Vec::new();
+    for href in inlined.keys() {
+        if referrers.iter().any(|text| text.contains(basename(href))) {
+            continue;
+        }
+        let path = skeleton.oebps.join(href);
+        fs::remove_file(&path)
+            .with_context(|| t!("removing-file", path = path.display().to_string()))?;
+        dropped.push(href.as_str());
+    }
+    manifest.retain(|item| !dropped.contains(&item.href.as_str()));
+    Ok(())
+}
+
+/// Keep one copy of each distinct asset content: the same figure is often
+/// served under several URLs. Removes the duplicates from disk and `items`,
+/// returning each duplicate's href mapped to the href of the copy kept.
+fn dedupe_assets(
+    skeleton: &EpubSkeleton,
+    items: &mut Vec<(usize, ManifestItem)>,
+) -> Result<HashMap<String, String>> {
+    let mut by_hash: HashMap<String, String> = HashMap::new();
+    let mut kept = HashMap::new();
+    let mut unique = Vec::with_capacity(items.len());
+    for (n, item) in items.drain(..) {
+        let path = skeleton.oebps.join(&item.href);
+        let hash = sha256_file(&path)?;
+        match by_hash.get(&hash) {
+            Some(original) if *original != item.href => {
+                fs::remove_file(&path)
+                    .with_context(|| t!("removing-file", path = path.display().to_string()))?;
+                kept.insert(item.href, original.clone());
+            }
+            _ => {
+                by_hash.insert(hash, item.href.clone());
+                unique.push((n, item));
+            }
+        }
+    }
+    *items = unique;
+    Ok(kept)
+}
+
+/// Stream an asset, used on the page `referer`, into OEBPS/ through a
+/// ".part" file, so an interrupted run never leaves a truncated asset
+/// under its final name and the next one can pick up where it stopped.
+async fn save_asset<A: OreillyApi>(
+    api: &A,
+    skeleton: &EpubSkeleton,
+    url: &str,
+    referer: Option<&str>,
+    href: &str,
+) -> Result<()> {
+    let path = skeleton.asset_path(href)?;
+    let part = path.with_file_name(format!(
+        "{}.part",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    download_asset(api, url, referer, &part).await?;
+    fs::rename(&part, &path).with_context(|| t!("writing-file", path = path.display().to_string()))
+}
+
+/// Every stylesheet and image the chapters use, each once.
+fn index_assets(site: &str, chapters: &[Chapter]) -> AssetIndex {
+    let mut assets = AssetIndex::new(site);
+    for chapter in chapters {
+        for css in &chapter.stylesheets {
+            assets.add_stylesheet(css);
+        }
+        for image in &chapter.images {
+            assets.add_image(chapter, image);
+        }
+    }
+    assets
+}
+
+/// Rough download size from typical chapter and asset sizes.
+fn estimate_bytes(chapters: usize, assets: usize) -> u64 {
+    chapters as u64 * TYPICAL_CHAPTER_BYTES + assets as u64 * TYPICAL_ASSET_BYTES
+}
+
+/// Say what is about to be downloaded and, when the estimate is above
+/// `opts.confirm_above`, ask first; no answer (not a terminal) means no.
+fn preflight(
+    ui: &Display,
+    info: &BookInfo,
+    chapters: &[Chapter],
+    assets: AssetIndex,
+    opts: &BookOptions,
+) -> Result<()> {
+    let images = assets
+        .by_url
+        .values()
+        .filter(|h| h.starts_with("Images/"))
+        .count();
+    let estimate = estimate_bytes(chapters.len(), assets.ordered.len());
+    ui.info(&t!(
+        "book-size",
+        chapters = chapters.len(),
+        images = images,
+        pages = info
+            .page_count
+            .map_or_else(|| t!("unknown"), |n| n.to_string()),
+        size = human_size(estimate)
+    ));
+    let Some(limit) = opts.confirm_above else {
+        return Ok(());
+    };
+    if estimate <= limit {
+        return Ok(());
+    }
+    let go = ui
+        .prompt(&t!("confirm-size", limit = human_size(limit)))
+        .is_some_and(|a| a.eq_ignore_ascii_case("y") || a.eq_ignore_ascii_case("yes"));
+    if !go {
+        bail!(t!(
+            "above-threshold",
+            size = human_size(estimate),
+            limit = human_size(limit)
+        ));
+    }
+    Ok(())
+}
+
+/// Fail early rather than halfway through with ENOSPC. The book needs room
+/// for the downloaded files plus the EPUB, which is about as large again.
+/// A generous guess settles most cases; only when space looks tight are the
+/// asset sizes asked from the server.
+async fn check_free_space<A: OreillyApi>(
+    api: &A,
+    ui: &Display,
+    chapters: &[Chapter],
+    free: u64,
+    jobs: usize,
+) -> Result<()> {
+    let assets = index_assets(api.base_url(), chapters);
+    let chapter_bytes = chapters.len() as u64 * TYPICAL_CHAPTER_BYTES;
+    let guess = 2 * estimate_bytes(chapters.len(), assets.ordered.len());
+    if guess <= free {
+        return Ok(());
+    }
+
+    ui.info(&t!("checking-download-size"));
+    let asset_bytes: u64 = stream::iter(&assets.ordered)
+        .map(|(url, _)| async move { api.content_length(url).await.ok().flatten() })
+        .buffer_unordered(jobs.max(1))
+        .map(|size| size.unwrap_or(TYPICAL_ASSET_BYTES))
+        .fold(0, |sum, size| async move { sum + size })
+        .await;
+    let needed = 2 * (chapter_bytes + asset_bytes);
+    if needed > free {
+        bail!(t!(
+            "not-enough-space",
+            needed = human_size(needed),
+            free = human_size(free)
+        ));
+    }
+    Ok(())
+}
+
+/// One line of the `chapters` listing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChapterSummary {
+    /// Position in reading order, from 1.
+    pub index: usize,
+    pub title: String,
+    pub filename: String,
+    pub images: usize,
+    /// Chapter plus its images, from the sizes the server reports (typical
+    /// sizes where it doesn't).
+    pub estimated_bytes: u64,
+}
+
+/// Describe each chapter with an estimate of its download size, asking
+/// the server for sizes with up to `jobs` chapters at a time.
+pub async fn summarize_chapters<A: OreillyApi>(
+    api: &A,
+    chapters: &[Chapter],
+    jobs: usize,
+) -> Vec<ChapterSummary> {
+    stream::iter(chapters.iter().enumerate())
+        .map(|(i, chapter)| async move {
+            let size = |url: String, typical: u64| async move {
+                api.content_length(&url)
+                    .await
+                    .ok()
+                    .flatten()
+                    .unwrap_or(typical)
+            };
+            let mut bytes = size(chapter.content.clone(), TYPICAL_CHAPTER_BYTES).await;
+            for image in &chapter.images {
+                let url = resolve_url(api.base_url(), &chapter.asset_base_url, image);
+                bytes += size(url, TYPICAL_ASSET_BYTES).await;
+            }
+            ChapterSummary {
+                index: i + 1,
+                title: chapter.title.clone(),
+                filename: chapter.filename.clone(),
+                images: chapter.images.len(),
+                estimated_bytes: bytes,
+            }
+        })
+        .buffered(jobs.max(1))
+        .collect()
+        .await
+}
+
+/// "1.5 GiB", "640.0 KiB", "12 B".
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Turn the raw chapter HTML into XHTML body markup with local links and
+/// its listings and wide tables laid out as `opts` asks, adding its print
+/// page markers to `pages` and what it draws as images to `drawings`.
+pub fn rewrite_chapter(
+    html: &str,
+    chapter: &Chapter,
+    chapter_files: &HashMap<String, String>,
+    assets: &mut AssetIndex,
+    pages: &mut Vec<PageTarget>,
+    opts: &BookOptions,
+    drawings: &mut Vec<Drawing>,
+) -> String {
+    let mut listings = code::Listings::new(opts.code_style);
+    let mut tables = table::Tables::new(opts.table_style);
+    let mut writer = XhtmlWriter::default();
+    // What each layout stage passes on from a token: usually just the token.
+    let (mut laid_out, mut restyled) = (Vec::new(), Vec::new());
+
+    // Read, rewritten and written a token at a time: only a listing or table
+    // being laid out is held.
+    for token in rewrite_tokens(html, chapter, chapter_files, assets, pages) {
+        listings.push(token, drawings, &mut laid_out);
+        for token in laid_out.drain(..) {
+            tables.push(token, drawings, &mut restyled);
+        }
+        restyled.drain(..).for_each(|token| writer.write(&token));
+    }
+    listings.finish(drawings, &mut laid_out);
+    for token in laid_out {
+        tables.push(token, drawings, &mut restyled);
+    }
+    tables.finish(drawings, &mut restyled);
+    restyled.iter().for_each(|token| writer.write(token));
+    writer.finish()
+}
+
+/// The tokens of the raw chapter HTML without its scripts, with its images
+/// added to `assets`, its links to other chapters made local, its page
+/// markers added to `pages` and its headings in order.
+fn rewrite_tokens<'a>(
+    html: &'a str,
+    chapter: &'a Chapter,
+    chapter_files: &'a HashMap<String, String>,
+    assets: &'a mut AssetIndex,
+    pages: &'a mut Vec<PageTarget>,
+) -> impl Iterator<Item = Token> + 'a {
+    let mut in_script = false;
+    let mut headings = HeadingLevels::default();
+    Tokenizer::new(html).filter_map(move |mut token| {
+        // Scripts are useless (and often invalid) in an EPUB.
+        if token.is_start("script") {
+            in_script = !matches!(
+                token,
+                Token::Start {
+                    self_closing: true,
+                    ..
+                }
+            );
+            return None;
+        }
+        if in_script {
+            in_script = !token.is_end("script");
+            return None;
+        }
+
+        if token.is_start("img") {
+            if let Some(src) = token.attr("src").map(str::to_string) {
+                let href = assets.add_image(chapter, &src);
+                token.set_attr("src", &href);
+            }
+            // Screen readers need an alt; an empty one marks the image as
+            // decorative rather than leaving them to read out the file name.
+            if token.attr("alt").is_none() {
+                let alt = token
+                    .attr("aria-label")
+                    .or(token.attr("title"))
+                    .unwrap_or_default()
+                    .to_string();
+                token.set_attr("alt", &alt);
+            }
+        } else if token.is_start("image") {
+            // SVG <image>: either xlink:href or plain href.
+            for key in ["xlink:href", "href"] {
+                if let Some(src) = token.attr(key).map(str::to_string) {
+                    let href = assets.add_image(chapter, &src);
+                    token.set_attr(key, &href);
+                }
+            }
+        }
+        if let Some((label, id)) = page_marker(&mut token, pages.len() + 1) {
+            pages.push(PageTarget {
+                label,
+                href: format!("{}#{id}", chapter_href(&chapter.filename)),
+            });
+        }
+        if token.is_start("a")
+            && let Some(link) = token
+                .attr("href")
+                .and_then(|h| local_link(&assets.site, h, chapter_files))
+        {
+            token.set_attr("href", &link);
+        }
+        headings.fix(&mut token);
+        Some(token)
+    })
+}
+
+/// The page number and id of a print page marker: an element with
+/// `data-pdf-page`, or an existing `epub:type="pagebreak"`. A marker without
+/// an id gets `pdf-page-{n}`, and becomes a page break for screen readers.
+fn page_marker(token: &mut Token, n: usize) -> Option<(String, String)> {
+    let label = token.attr("data-pdf-page").or_else(|| {
+        token
+            .attr("epub:type")
+            .filter(|t| t.split_whitespace().any(|t| t == "pagebreak"))
+            .and_then(|_| token.attr("title").or(token.attr("aria-label")))
+    })?;
+    let label = label.trim().to_string();
+    if label.is_empty() {
+        return None;
+    }
+    if token.attr("epub:type").is_none() {
+        token.set_attr("epub:type", "pagebreak");
+        token.set_attr("role", "doc-pagebreak");
+    }
+    if token.attr("aria-label").is_none() {
+        token.set_attr("aria-label", &format!("Page {label}"));
+    }
+    let id = match token.attr("id") {
+        Some(id) => id.to_string(),
+        None => {
+            let id = format!("pdf-page-{n}");
+            token.set_attr("id", &id);
+            id
+        }
+    };
+    Some((label, id))
+}
+
+/// Map a link to another chapter of this book onto its local XHTML file.
+pub fn local_link(
+    site: &str,
+    href: &str,
+    chapter_files: &HashMap<String, String>,
+) -> Option<String> {
+    let (path, fragment) = match href.split_once('#') {
+        Some((p, f)) => (p, Some(f)),
+        None => (href, None),
+    };
+    if path.is_empty() {
+        return None;
+    }
+    let is_book_link =
+        !path.contains("://") || path.starts_with(site) && path.contains("/library/view/");
+    if !is_book_link {
+        return None;
+    }
+    let local = chapter_files.get(basename(path))?;
+    Some(match fragment {
+        Some(f) => format!("{local}#{f}"),
+        None => local.clone(),
+    })
+}
+
+/// Resolve an asset path from the chapter payload or markup to an absolute URL.
+/// Root-relative paths resolve against `site`, relative ones against `base`.
+pub fn resolve_url(site: &str, base: &str, path: &str) -> String {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        path.to_string()
+    } else if let Some(rest) = path.strip_prefix("//") {
+        format!("https://{rest}")
+    } else if path.starts_with('/') {
+        format!("{site}{path}")
+    } else {
+        let mut relative = path;
+        while let Some(rest) = relative
+            .strip_prefix("./")
+            .or_else(|| relative.strip_prefix("../"))
+        {
+            relative = rest;
+        }
+        format!("{}/{}", base.trim_end_matches('/'), relative)
+    }
+}
+
+/// Last path segment, without any query string.
+fn basename(path: &str) -> &str {
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Lowercased file extension of `path` ("png" for "img/Fig.PNG?v=2"), if it looks like one.
+fn extension(path: &str) -> Option<String> {
+    let (_, ext) = basename(path).rsplit_once('.')?;
+    (!ext.is_empty() && ext.len() <= 5 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .then(|| ext.to_ascii_lowercase())
+}
+
+/// Local XHTML file name of a chapter, e.g. "ch01.html" -> "ch01.xhtml".
+pub fn chapter_href(filename: &str) -> String {
+    let name = basename(filename);
+    let stem = name
+        .strip_suffix(".html")
+        .or_else(|| name.strip_suffix(".htm"))
+        .or_else(|| name.strip_suffix(".xhtml"))
+        .unwrap_or(name);
+    format!("{stem}.xhtml")
+}
+
+/// Nested navigation from the API table of contents.
+fn nav_from_toc(toc: &[TocEntry], chapter_files: &HashMap<String, String>) -> Vec<NavPoint> {
+    toc.iter()
+        .map(|entry| {
+            let file = chapter_files
+                .get(basename(&entry.href))
+                .cloned()
+                .unwrap_or_else(|| chapter_href(&entry.href));
+            let href = if entry.fragment.is_empty() {
+                file
+            } else {
+                format!("{file}#{}", entry.fragment)
+            };
+            NavPoint {
+                title: entry.label.clone(),
+                href,
+                children: nav_from_toc(&entry.children, chapter_files),
+            }
+        })
+        .collect()
+}
+
+/// One navigation point per chapter, for books without a usable TOC.
+fn flat_nav(chapters: &[Chapter]) -> Vec<NavPoint> {
+    chapters
+        .iter()
+        .map(|c| NavPoint {
+            title: c.title.clone(),
+            href: chapter_href(&c.filename),
+            children: Vec::new(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_book_epub, chapter_href, check_free_space, estimate_bytes, extension, human_size,
+        index_assets, preflight, resolve_url, rewrite_chapter, rewrite_tokens, summarize_chapters,
+        AssetIndex, BookOptions, MISSING_IMAGE, MISSING_REPORT, TYPICAL_ASSET_BYTES,
+        TYPICAL_CHAPTER_BYTES,
+    };
+    use crate::annotations::Annotation;
+    use crate::api::FixtureApi;
+    use crate::checksum::short_hash;
+    use crate::config::Naming;
+    use crate::display::Display;
+    use crate::epub::{EpubSkeleton, NameLimit};
+    use crate::figures;
+    use crate::orly::{BookInfo, Chapter, ReadingPosition};
+    use crate::package::{package_epub, Compression, EpubStream};
+    use serde_json::json;
+    use std::fs;
+    use tokio_util::sync::CancellationToken;
+
+    const BASE: &str = "https://learning.oreilly.com/api/v1/book/123";
+
+    fn fixture_api() -> FixtureApi {
+        FixtureApi::default()
+            .with(
+                &format!("{BASE}/chapter/"),
+                200,
+                json!({
+                    "next": null,
+                    "results": [
+                        {
+                            "title": "Chapter 1",
+                            "filename": "ch01.html",
+                            "content": format!("{BASE}/chapter-content/ch01.html"),
+                            "asset_base_url": "https://cdn.example.com/files/",
+                            "images": ["assets/fig1.png"],
+                            "stylesheets": [{ "url": "https://cdn.example.com/epub.css", "full_path": "epub.css" }]
+                        },
+                        {
+                            "title": "Chapter 2",
+                            "filename": "ch02.html",
+                            "content": format!("{BASE}/chapter-content/ch02.html"),
+                            "asset_base_url": "https://cdn.example.com/files/",
+                            "images": ["other/copy.png"],
+                            "stylesheets": [{ "url": "https://cdn.example.com/epub.css", "full_path": "epub.css" }]
+                        }
+                    ]
+                })
+                .to_string(),
+            )
+            .with(
+                &format!("{BASE}/chapter-content/ch01.html"),
+                200,
+                r#"<div id="sbo-rt-content"><p>See <a href="ch02.html#sec">next</a>.</p><img src="assets/fig1.png" alt="Figure"><script>alert(1)</script></div>"#,
+            )
+            .with(
+                &format!("{BASE}/chapter-content/ch02.html"),
+                200,
+                r#"<p>Second&nbsp;chapter<span data-pdf-page="12"/></p><img src="other/copy.png" alt="Again">"#,
+            )
+            .with("https://cdn.example.com/files/assets/fig1.png", 200, vec![0x89, b'P', b'N', b'G'])
+            .with("https://cdn.example.com/files/other/copy.png", 200, vec![0x89, b'P', b'N', b'G'])
+            .with("https://cdn.example.com/epub.css", 200, "p { margin: 0 }")
+    }
+
+    #[tokio::test]
+    async fn inlines_small_images() {
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "Fixture Book",
+            "web_url": "https://learning.oreilly.com/library/view/fixture-book/123/",
+            "chapters": format!("{BASE}/chapter/")
+        }))
+        .unwrap();
+        let base = std::env::temp_dir().join(format!("safaribooks-inline-{}", std::process::id()));
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::default(),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+        let opts = BookOptions {
+            jobs: 1,
+            resume: false,
+            credits: false,
+            annotations: Vec::new(),
+            position: None,
+            metadata: Default::default(),
+            confirm_above: None,
+            code_style: Default::default(),
+            table_style: Default::default(),
+            rasterize_svg: false,
+            inline_below: Some(1024),
+            split_above: None,
+            stealth: false,
+            cancel: CancellationToken::new(),
+            package: None,
+        };
+        build_book_epub(
+            &fixture_api(),
+            &Display::for_tests(),
+            "123",
+            &info,
+            &skeleton,
+            &opts,
+        )
+        .await
+        .unwrap();
+        let ch01 = fs::read_to_string(skeleton.oebps.join("ch01.xhtml")).unwrap();
+        assert!(ch01.contains(r#"<img src="data:image/png;base64,iVBORw==" alt="Figure"/>"#));
+        assert!(!skeleton
+            .oebps
+            .join("Images")
+            .read_dir()
+            .unwrap()
+            .any(|_| true));
+        let opf = fs::read_to_string(skeleton.oebps.join("content.opf")).unwrap();
+        assert!(!opf.contains("image/png"));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_when_cancelled() {
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "Fixture Book",
+            "web_url": "https://learning.oreilly.com/library/view/fixture-book/123/",
+            "chapters": format!("{BASE}/chapter/")
+        }))
+        .unwrap();
+        let base = std::env::temp_dir().join(format!("safaribooks-cancel-{}", std::process::id()));
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::default(),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+        let opts = BookOptions {
+            jobs: 1,
+            resume: false,
+            credits: false,
+            annotations: Vec::new(),
+            position: None,
+            metadata: Default::default(),
+            confirm_above: None,
+            code_style: Default::default(),
+            table_style: Default::default(),
+            rasterize_svg: false,
+            inline_below: None,
+            split_above: None,
+            stealth: true,
+            cancel: CancellationToken::new(),
+            package: None,
+        };
+        opts.cancel.cancel();
+        let err = build_book_epub(
+            &fixture_api(),
+            &Display::for_tests(),
+            "123",
+            &info,
+            &skeleton,
+            &opts,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().starts_with("Download cancelled"), "{err}");
+        // Nothing was fetched, and no half-built package was written.
+        assert!(!skeleton.oebps.join("ch01.xhtml").exists());
+        assert!(!skeleton.oebps.join("content.opf").exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn packages_the_assets_while_downloading() {
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "Fixture Book",
+            "web_url": "https://learning.oreilly.com/library/view/fixture-book/123/",
+            "chapters": format!("{BASE}/chapter/")
+        }))
+        .unwrap();
+        let base =
+            std::env::temp_dir().join(format!("safaribooks-streamed-{}", std::process::id()));
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::default(),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+        skeleton.write_container_xml().unwrap();
+        let mut opts = BookOptions {
+            jobs: 2,
+            resume: false,
+            credits: false,
+            annotations: Vec::new(),
+            position: None,
+            metadata: Default::default(),
+            confirm_above: None,
+            code_style: Default::default(),
+            table_style: Default::default(),
+            rasterize_svg: false,
+            inline_below: None,
+            split_above: None,
+            stealth: false,
+            cancel: CancellationToken::new(),
+            package: Some(EpubStream::create(&skeleton.epub, Compression::default()).unwrap()),
+        };
+        build_book_epub(
+            &fixture_api(),
+            &Display::for_tests(),
+            "123",
+            &info,
+            &skeleton,
+            &opts,
+        )
+        .await
+        .unwrap();
+        let mut streamed = opts.package.take().unwrap().finish(&skeleton).unwrap();
+        assert!(streamed
+            .iter()
+            .any(|(name, _)| name.starts_with("OEBPS/Images/")));
+
+        // The same book as packaging it in one pass at the end makes.
+        let mut whole = package_epub(&skeleton, &skeleton.epub, Compression::default()).unwrap();
+        streamed.sort();
+        whole.sort();
+        assert_eq!(streamed, whole);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_where_safaribooks_py_left_off() {
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "Fixture Book",
+            "web_url": "https://learning.oreilly.com/library/view/fixture-book/123/",
+            "chapters": format!("{BASE}/chapter/")
+        }))
+        .unwrap();
+        let base = std::env::temp_dir().join(format!("safaribooks-python-{}", std::process::id()));
+        let naming = Naming {
+            legacy: true,
+            ..Naming::default()
+        };
+        let skeleton = EpubSkeleton::plan(&base, &naming, NameLimit::default(), "123", &info);
+        skeleton.create_dirs().unwrap();
+        fs::create_dir_all(skeleton.oebps.join("Images")).unwrap();
+        fs::write(
+            skeleton.oebps.join("ch01.xhtml"),
+            "<!DOCTYPE html>\n<html><head>\n<style type=\"text/css\">\
+             #sbo-rt-content *{text-indent:0pt!important;}</style>\n</head>\n\
+             <body><div id=\"sbo-rt-content\"><p>From Python</p>\
+             <img src=\"Images/fig1.png\" alt=\"Figure\"/></div></body>\n</html>",
+        )
+        .unwrap();
+        fs::write(skeleton.oebps.join("Images/fig1.png"), b"\x89PNG saved").unwrap();
+        let opts = BookOptions {
+            jobs: 1,
+            resume: true,
+            credits: false,
+            annotations: Vec::new(),
+            position: None,
+            metadata: Default::default(),
+            confirm_above: None,
+            code_style: Default::default(),
+            table_style: Default::default(),
+            rasterize_svg: false,
+            inline_below: None,
+            split_above: None,
+            stealth: false,
+            cancel: CancellationToken::new(),
+            package: None,
+        };
+        // Neither the saved chapter nor its image is fetched again.
+        let api = fixture_api()
+            .with(&format!("{BASE}/chapter-content/ch01.html"), 500, "")
+            .with("https://cdn.example.com/files/assets/fig1.png", 500, "");
+        let stats = build_book_epub(&api, &Display::for_tests(), "123", &info, &skeleton, &opts)
+            .await
+            .unwrap();
+        assert_eq!(stats.failed_assets, 0);
+        let ch01 = fs::read_to_string(skeleton.oebps.join("ch01.xhtml")).unwrap();
+        assert!(ch01.contains("<p>From Python</p>"), "{ch01}");
+        assert!(ch01.contains(r#"src="Images/fig1.png""#), "{ch01}");
+        assert_eq!(
+            fs::read(skeleton.oebps.join("Images/fig1.png")).unwrap(),
+            b"\x89PNG saved"
+        );
+        assert!(skeleton.oebps.join("Styles/Style00.css").is_file());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finishes_without_missing_assets_and_reports_them() {
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "Fixture Book",
+            "web_url": "https://learning.oreilly.com/library/view/fixture-book/123/",
+            "chapters": format!("{BASE}/chapter/")
+        }))
+        .unwrap();
+        let base = std::env::temp_dir().join(format!("safaribooks-missing-{}", std::process::id()));
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::default(),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+        let opts = BookOptions {
+            jobs: 1,
+            resume: true,
+            credits: false,
+            annotations: Vec::new(),
+            position: None,
+            metadata: Default::default(),
+            confirm_above: None,
+            code_style: Default::default(),
+            table_style: Default::default(),
+            rasterize_svg: false,
+            inline_below: None,
+            split_above: None,
+            stealth: false,
+            cancel: CancellationToken::new(),
+            package: None,
+        };
+        let broken = fixture_api().with("https://cdn.example.com/files/assets/fig1.png", 404, "");
+        let stats = build_book_epub(
+            &broken,
+            &Display::for_tests(),
+            "123",
+            &info,
+            &skeleton,
+            &opts,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.failed_assets, 1);
+        let ch01 = fs::read_to_string(skeleton.oebps.join("ch01.xhtml")).unwrap();
+        assert!(
+            ch01.contains(r#"<span class="missing-figure" id="missing-figure-1">"#),
+            "{ch01}"
+        );
+        let appendix = fs::read_to_string(skeleton.oebps.join(figures::APPENDIX_HREF)).unwrap();
+        assert!(appendix.contains(r#"<a href="ch01.xhtml#missing-figure-1">"#));
+        assert!(appendix.contains("https://cdn.example.com/files/assets/fig1.png"));
+        let opf = fs::read_to_string(skeleton.oebps.join("content.opf")).unwrap();
+        assert!(opf.contains(r#"href="missing-figures.xhtml""#));
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(skeleton.root.join(MISSING_REPORT)).unwrap())
+                .unwrap();
+        assert!(report["missing"][0]["href"]
+            .as_str()
+            .unwrap()
+            .starts_with("Images/"));
+        assert_eq!(
+            report,
+            json!({
+                "book_id": "123",
+                "missing": [{
+                    "page": "https://learning.oreilly.com/library/view/fixture-book/123/ch01.html",
+                    "url": "https://cdn.example.com/files/assets/fig1.png",
+                    "href": report["missing"][0]["href"],
+                    "error": report["missing"][0]["error"],
+                }]
+            })
+        );
+
+        // Once a resumed download gets it, the report goes away.
+        build_book_epub(
+            &fixture_api(),
+            &Display::for_tests(),
+            "123",
+            &info,
+            &skeleton,
+            &opts,
+        )
+        .await
+        .unwrap();
+        assert!(!skeleton.root.join(MISSING_REPORT).exists());
+        let ch01 = fs::read_to_string(skeleton.oebps.join("ch01.xhtml")).unwrap();
+        assert!(!ch01.contains("missing-figure"));
+        assert!(!skeleton.oebps.join(figures::APPENDIX_HREF).exists());
+        assert!(!skeleton.oebps.join(MISSING_IMAGE).exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn brings_in_what_svg_figures_refer_to() {
+        let figure = r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><image xlink:href="parts/photo.png"/><use href="diagram#box"/></svg>"#;
+        let api = FixtureApi::default()
+            .with(
+                &format!("{BASE}/chapter/"),
+                200,
+                json!({
+                    "results": [{
+                        "title": "Chapter 1",
+                        "filename": "ch01.html",
+                        "content": format!("{BASE}/chapter-content/ch01.html"),
+                        "asset_base_url": "https://cdn.example.com/files/"
+                    }]
+                })
+                .to_string(),
+            )
+            .with(
+                &format!("{BASE}/chapter-content/ch01.html"),
+                200,
+                r#"<img src="figs/flow.svg" alt="Flow"><svg><circle r="1"/></svg>"#,
+            )
+            .with("https://cdn.example.com/files/figs/flow.svg", 200, figure)
+            .with("https://cdn.example.com/files/figs/parts/photo.png", 200, vec![0x89, b'P', b'N', b'G'])
+            .with(
+                "https://cdn.example.com/files/figs/diagram",
+                200,
+                r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg"><rect id="box"/></svg>"#,
+            );
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "SVG Book",
+            "web_url": "https://learning.oreilly.com/library/view/svg-book/123/",
+            "chapters": format!("{BASE}/chapter/")
+        }))
+        .unwrap();
+        let base =
+            std::env::temp_dir().join(format!("safaribooks-svg-book-{}", std::process::id()));
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::default(),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+        let opts = BookOptions {
+            jobs: 1,
+            resume: false,
+            credits: false,
+            annotations: Vec::new(),
+            position: None,
+            metadata: Default::default(),
+            confirm_above: None,
+            code_style: Default::default(),
+            table_style: Default::default(),
+            rasterize_svg: false,
+            inline_below: None,
+            split_above: None,
+            stealth: false,
+            cancel: CancellationToken::new(),
+            package: None,
+        };
+        let stats = build_book_epub(&api, &Display::for_tests(), "123", &info, &skeleton, &opts)
+            .await
+            .unwrap();
+        assert_eq!((stats.assets, stats.failed_assets), (3, 0));
+
+        let flow = format!(
+            "Images/{}.svg",
+            short_hash("https://cdn.example.com/files/figs/flow.svg")
+        );
+        let photo = short_hash("https://cdn.example.com/files/figs/parts/photo.png");
+        let diagram = short_hash("https://cdn.example.com/files/figs/diagram");
+        let svg = fs::read_to_string(skeleton.oebps.join(&flow)).unwrap();
+        assert!(svg.contains(&format!(r#"<image xlink:href="{photo}.png"/>"#)));
+        assert!(svg.contains(&format!(r##"<use href="{diagram}#box"/>"##)));
+        let opf = fs::read_to_string(skeleton.oebps.join("content.opf")).unwrap();
+        assert!(opf.contains(&format!(
+            r#"href="Images/{diagram}" media-type="image/svg+xml""#
+        )));
+        assert!(opf
+            .contains(r#"href="ch01.xhtml" media-type="application/xhtml+xml" properties="svg""#));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn builds_book_offline_from_fixtures() {
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "Fixture Book",
+            "web_url": "https://learning.oreilly.com/library/view/fixture-book/123/",
+            "chapters": format!("{BASE}/chapter/"),
+            "toc": format!("{BASE}/toc/")
+        }))
+        .unwrap();
+        let base = std::env::temp_dir().join(format!("safaribooks-book-{}", std::process::id()));
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::default(),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+
+        let opts = BookOptions {
+            jobs: 2,
+            resume: false,
+            credits: true,
+            annotations: vec![Annotation {
+                chapter_title: Some("Intro".to_string()),
+                quote: Some("A <b> tag".to_string()),
+                ..Default::default()
+            }],
+            position: Some(ReadingPosition {
+                chapter_filename: "https://learning.oreilly.com/api/v1/book/123/chapter/ch02.html"
+                    .to_string(),
+                fragment: Some("sec".to_string()),
+                progress: Some(0.5),
+                updated: None,
+            }),
+            metadata: Default::default(),
+            confirm_above: Some(1 << 30),
+            code_style: Default::default(),
+            table_style: Default::default(),
+            rasterize_svg: false,
+            inline_below: None,
+            split_above: None,
+            stealth: false,
+            cancel: CancellationToken::new(),
+            package: None,
+        };
+        let stats = build_book_epub(
+            &fixture_api(),
+            &Display::for_tests(),
+            "123",
+            &info,
+            &skeleton,
+            &opts,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.chapters, 2);
+        assert_eq!(stats.failed_assets, 0);
+
+        let ch01 = fs::read_to_string(skeleton.oebps.join("ch01.xhtml")).unwrap();
+        assert!(ch01.contains(r#"<a href="ch02.xhtml#sec">"#));
+        let fig = format!(
+            "Images/{}.png",
+            short_hash("https://cdn.example.com/files/assets/fig1.png")
+        );
+        let css = format!(
+            "Styles/{}.css",
+            short_hash("https://cdn.example.com/epub.css")
+        );
+        assert!(ch01.contains(&format!(r#"<img src="{fig}" alt="Figure"/>"#)));
+        assert!(ch01.contains(&format!(r#"href="{css}""#)));
+        assert!(ch01.contains(r#"href="Styles/layout.css""#));
+        assert!(!ch01.contains("alert"));
+        let ch02 = fs::read_to_string(skeleton.oebps.join("ch02.xhtml")).unwrap();
+        assert!(ch02.contains("Second\u{a0}chapter"));
+        assert!(skeleton.oebps.join(&fig).exists());
+        // The same bytes under another URL are stored once.
+        assert!(ch02.contains(&format!(r#"<img src="{fig}" alt="Again"/>"#)));
+        assert_eq!(
+            fs::read_dir(skeleton.oebps.join("Images")).unwrap().count(),
+            1
+        );
+        assert_eq!(stats.assets, 2);
+
+        let opf = fs::read_to_string(skeleton.oebps.join("content.opf")).unwrap();
+        assert!(opf.contains(&format!(r#"href="{css}" media-type="text/css""#)));
+        assert!(opf.contains(r#"<itemref idref="ch_0001"/>"#));
+        assert!(
+            opf.contains(r#"<meta property="schema:accessibilityFeature">alternativeText</meta>"#)
+        );
+        assert!(opf.ends_with("<itemref idref=\"colophon\"/>\n</spine>\n</package>\n"));
+        let colophon = fs::read_to_string(skeleton.oebps.join("colophon.xhtml")).unwrap();
+        assert!(colophon.contains(concat!("with safaribooks-rs ", env!("CARGO_PKG_VERSION"))));
+        // The TOC endpoint isn't in the fixtures: navigation falls back to chapters.
+        let nav = fs::read_to_string(skeleton.oebps.join("nav.xhtml")).unwrap();
+        assert!(nav.contains(r#"<a href="ch02.xhtml">Chapter 2</a>"#));
+        assert!(nav.contains(r#"<a href="annotations.xhtml">My annotations</a>"#));
+        assert!(
+            nav.contains(r#"<a epub:type="bodymatter" href="ch02.xhtml#sec">Continue reading</a>"#)
+        );
+        assert!(nav.contains(r#"<a epub:type="toc" href="nav.xhtml#toc">"#));
+        assert!(nav.contains(r#"<li><a href="ch02.xhtml#pdf-page-1">12</a></li>"#));
+        let ncx = fs::read_to_string(skeleton.oebps.join("toc.ncx")).unwrap();
+        assert!(ncx.contains(r#"<pageTarget id="pagetarget-1" type="normal" value="12""#));
+        assert!(ch02.contains(r#"id="pdf-page-1""#));
+        let appendix = fs::read_to_string(skeleton.oebps.join("annotations.xhtml")).unwrap();
+        assert!(
+            appendix.contains("<h2>Intro</h2>\n<blockquote><p>A &lt;b&gt; tag</p></blockquote>")
+        );
+        skeleton.write_container_xml().unwrap();
+        assert_eq!(
+            crate::validate::validate(&skeleton).unwrap(),
+            Vec::<String>::new()
+        );
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn resolves_asset_urls() {
+        const SITE: &str = "https://learning.oreilly.com";
+        let base = "https://cdn.example.com/files/";
+        assert_eq!(
+            resolve_url(SITE, base, "assets/a.png"),
+            "https://cdn.example.com/files/assets/a.png"
+        );
+        assert_eq!(
+            resolve_url(SITE, base, "../assets/a.png"),
+            "https://cdn.example.com/files/assets/a.png"
+        );
+        assert_eq!(
+            resolve_url(SITE, base, "/library/cover/123/"),
+            "https://learning.oreilly.com/library/cover/123/"
+        );
+        assert_eq!(
+            resolve_url(SITE, base, "https://x.com/a.png"),
+            "https://x.com/a.png"
+        );
+    }
+
+    #[test]
+    fn same_file_names_get_distinct_hrefs() {
+        let mut assets = AssetIndex::new("https://learning.oreilly.com");
+        let a = assets.add(
+            "https://cdn.example.com/a/fig.PNG".into(),
+            "Images",
+            "a/fig.PNG",
+        );
+        let b = assets.add(
+            "https://cdn.example.com/b/fig.PNG".into(),
+            "Images",
+            "b/fig.PNG",
+        );
+        assert_ne!(a, b);
+        assert!(a.starts_with("Images/") && a.ends_with(".png"));
+        assert_eq!(
+            assets.add("https://cdn.example.com/a/fig.PNG".into(), "Images", "x"),
+            a
+        );
+        assert_eq!(extension("style?v=2"), None);
+        assert_eq!(extension("s/main.css?v=2"), Some("css".to_string()));
+    }
+
+    #[test]
+    fn legacy_names_follow_safaribooks_py() {
+        let mut assets = AssetIndex::new("https://learning.oreilly.com").with_legacy_names(true);
+        let mut add = |url: &str, dir: &str| assets.add(url.into(), dir, url);
+        assert_eq!(
+            add("https://cdn.example.com/epub.css", "Styles"),
+            "Styles/Style00.css"
+        );
+        assert_eq!(
+            add("https://cdn.example.com/a/fig_1.png", "Images"),
+            "Images/fig_1.png"
+        );
+        assert_eq!(
+            add("https://cdn.example.com/other.css?v=2", "Styles"),
+            "Styles/Style01.css"
+        );
+        // Taken, or not a plain file name: hashed as usual.
+        let b = add("https://cdn.example.com/b/fig_1.png", "Images");
+        assert!(b != "Images/fig_1.png" && b.ends_with(".png"));
+        assert!(!add("https://cdn.example.com/a%20b.png", "Images").contains("%20"));
+    }
+
+    #[test]
+    fn assets_refer_to_the_chapter_that_first_used_them() {
+        let mut assets = AssetIndex::new("https://learning.oreilly.com");
+        let shared = "https://cdn.example.com/logo.png".to_string();
+        assets.add(shared.clone(), "Images", "logo.png");
+        assets.found_on(0, "https://learning.oreilly.com/library/view/x/1/ch01.html");
+        let first = assets.ordered.len();
+        assets.add(shared.clone(), "Images", "logo.png");
+        assets.add(
+            "https://cdn.example.com/fig2.png".into(),
+            "Images",
+            "fig2.png",
+        );
+        assets.found_on(
+            first,
+            "https://learning.oreilly.com/library/view/x/1/ch02.html",
+        );
+        assert_eq!(
+            assets.referers[&shared],
+            "https://learning.oreilly.com/library/view/x/1/ch01.html"
+        );
+        assert_eq!(
+            assets.referers["https://cdn.example.com/fig2.png"],
+            "https://learning.oreilly.com/library/view/x/1/ch02.html"
+        );
+    }
+
+    #[test]
+    fn chapter_names_become_xhtml() {
+        assert_eq!(chapter_href("ch01.html"), "ch01.xhtml");
+        assert_eq!(chapter_href("text/part01.htm"), "part01.xhtml");
+        assert_eq!(chapter_href("cover"), "cover.xhtml");
+    }
+
+    #[tokio::test]
+    async fn refuses_books_larger_than_free_space() {
+        let chapters: Vec<Chapter> = serde_json::from_value(json!([{
+            "title": "One",
+            "filename": "ch01.html",
+            "content": format!("{BASE}/ch01.html"),
+            "images": ["a.png", "b.png"]
+        }]))
+        .unwrap();
+        let (api, ui) = (FixtureApi::default(), Display::for_tests());
+        assert!(check_free_space(&api, &ui, &chapters, 10 << 20, 1)
+            .await
+            .is_ok());
+        let err = check_free_space(&api, &ui, &chapters, 100 << 10, 1)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Not enough disk space"));
+
+        // Within the confirmation threshold, or with none, nothing is asked.
+        let info: BookInfo =
+            serde_json::from_value(json!({ "title": "T", "web_url": "u", "page_count": 12 }))
+                .unwrap();
+        let mut opts = BookOptions {
+            jobs: 1,
+            resume: false,
+            credits: false,
+            annotations: Vec::new(),
+            position: None,
+            metadata: Default::default(),
+            confirm_above: Some(estimate_bytes(1, 2)),
+            code_style: Default::default(),
+            table_style: Default::default(),
+            rasterize_svg: false,
+            inline_below: None,
+            split_above: None,
+            stealth: false,
+            cancel: CancellationToken::new(),
+            package: None,
+        };
+        let assets = || index_assets(BASE, &chapters);
+        assert!(preflight(&ui, &info, &chapters, assets(), &opts).is_ok());
+        opts.confirm_above = None;
+        assert!(preflight(&ui, &info, &chapters, assets(), &opts).is_ok());
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(12), "12 B");
+    }
+
+    #[tokio::test]
+    async fn summarizes_chapters() {
+        let api = fixture_api();
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "Fixture Book",
+            "web_url": "https://learning.oreilly.com/library/view/-/123/",
+            "chapters": format!("{BASE}/chapter/")
+        }))
+        .unwrap();
+        let chapters = crate::orly::fetch_chapters(&api, &info, "123")
+            .await
+            .unwrap();
+        let summary = summarize_chapters(&api, &chapters, 2).await;
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[1].index, 2);
+        assert_eq!(summary[1].filename, "ch02.html");
+        assert_eq!(summary[1].images, 1);
+        // The fixtures report no sizes: typical ones stand in.
+        assert_eq!(
+            summary[0].estimated_bytes,
+            TYPICAL_CHAPTER_BYTES + TYPICAL_ASSET_BYTES
+        );
+    }
+
+    /// A chapter of about `kib` KiB with what books are made of: prose with
+    /// entities, cross-chapter links, figures, listings and tables (some of
+    /// them too wide for the screen) and page markers.
+    fn large_chapter(kib: usize) -> String {
+        let mut html = String::from(r#"<div id="sbo-rt-content"><section data-type="chapter">"#);
+        let mut n = 0;
+        while html.len() < kib << 10 {
+            n += 1;
+            html.push_str(&format!(
+                r#"<h2 id="sec{n}">Section {n}</h2>
+<p>Text&nbsp;with <em>emphasis</em>, &ldquo;quotes&rdquo; and a <a href="ch{:02}.html#sec{n}">link to another chapter</a><span data-pdf-page="{n}"/>.</p>
+<figure><img src="assets/fig{n}.png" alt="Figure {n}"/><figcaption>Figure {n}</figcaption></figure>
+<pre data-type="programlisting">fn step_{n}() -&gt; u32 {{
+    {n} * 2
+}}</pre>
+<table><tr><th>Key</th><th>Value</th></tr><tr><td>{n}</td><td><a href="https://example.com/{n}">out</a></td></tr></table>
+"#,
+                n % 40 + 1
+            ));
+            if n % 10 == 0 {
+                let wide = "x".repeat(90);
+                html.push_str(&format!(
+                    "<pre id=\"ex{n}\">let <b>a</b> = \"{wide}\";</pre>\
+                     <table><tr><th>A</th><th>B</th><th>C</th><th>D</th><th>E</th><th>F</th><th>G</th></tr>\
+                     <tr><td>1</td><td>2</td><td>3</td><td>4</td><td>5</td><td>6</td><td>{n}</td></tr></table>"
+                ));
+            }
+        }
+        html + "</section></div>"
+    }
+
+    #[test]
+    fn streamed_chapters_match_the_token_list_pipeline() {
+        use crate::code::{self, CodeStyle};
+        use crate::html::serialize_xhtml;
+        use crate::table::{self, TableStyle};
+
+        let html = large_chapter(1024);
+        let chapter: Chapter = serde_json::from_value(json!({
+            "title": "Large",
+            "filename": "ch01.html",
+            "content": format!("{BASE}/chapter-content/ch01.html"),
+            "asset_base_url": "https://cdn.example.com/files/"
+        }))
+        .unwrap();
+        let chapter_files = (1..=40)
+            .map(|n| (format!("ch{n:02}.html"), format!("ch{n:02}.xhtml")))
+            .collect();
+        // Listings and tables are both drawn in document order when streamed,
+        // so their numbers only match the token list's if one is not drawn.
+        for (code_style, table_style) in [
+            (CodeStyle::Scroll, TableStyle::Scroll),
+            (CodeStyle::Wrap, TableStyle::Split),
+            (CodeStyle::Image, TableStyle::Split),
+            (CodeStyle::Small, TableStyle::Image),
+        ] {
+            let opts = BookOptions {
+                jobs: 1,
+                resume: false,
+                credits: false,
+                annotations: Vec::new(),
+                position: None,
+                metadata: Default::default(),
+                confirm_above: None,
+                code_style,
+                table_style,
+                rasterize_svg: false,
+                inline_below: None,
+                split_above: None,
+                stealth: false,
+                cancel: CancellationToken::new(),
+                package: None,
+            };
+            let site = "https://learning.oreilly.com";
+            let (mut assets, mut pages, mut drawings) = (AssetIndex::new(site), vec![], vec![]);
+            let streamed = rewrite_chapter(
+                &html,
+                &chapter,
+                &chapter_files,
+                &mut assets,
+                &mut pages,
+                &opts,
+                &mut drawings,
+            );
+
+            let (mut list_assets, mut list_pages, mut list_drawings) =
+                (AssetIndex::new(site), vec![], vec![]);
+            let tokens: Vec<_> = rewrite_tokens(
+                &html,
+                &chapter,
+                &chapter_files,
+                &mut list_assets,
+                &mut list_pages,
+            )
+            .collect();
+            let tokens = code::restyle(tokens, code_style, &mut list_drawings);
+            let tokens = table::restyle(tokens, table_style, &mut list_drawings);
+            assert!(
+                streamed == serialize_xhtml(&tokens),
+                "{code_style:?}, {table_style:?}"
+            );
+            assert_eq!(pages.len(), list_pages.len());
+            let draws = code_style == CodeStyle::Image || table_style == TableStyle::Image;
+            assert_eq!(!drawings.is_empty(), draws);
+            assert_eq!(
+                drawings.iter().map(|d| &d.svg).collect::<Vec<_>>(),
+                list_drawings.iter().map(|d| &d.svg).collect::<Vec<_>>()
+            );
+        }
+    }
+}
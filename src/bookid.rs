@@ -0,0 +1,111 @@
+//! The book a command-line argument names: the ID itself, an ISBN with
+//! dashes, a "urn:orm:book:<id>" or the book's web URL. Checked before any
+//! request is made; the URL's title part is kept to suggest books when the
+//! ID turns out not to exist.
+
+use crate::i18n::t;
+
+/// A book named on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookRef {
+    /// The ID the API knows the book by, e.g. "9781491958698".
+    pub id: String,
+    /// The title part of a ".../library/view/<slug>/<id>/" URL.
+    pub slug: Option<String>,
+}
+
+impl BookRef {
+    /// Words to search the catalog for when the ID is unknown: the URL's
+    /// title, or else the ID (an ISBN finds other editions).
+    pub fn search_terms(&self) -> String {
+        match &self.slug {
+            Some(slug) => slug.replace(['-', '_'], " "),
+            None => self.id.clone(),
+        }
+    }
+}
+
+/// Whether `id` looks like an O'Reilly book ID: letters and digits only,
+/// with at least one digit.
+fn is_book_id(id: &str) -> bool {
+    id.len() <= 32
+        && id.chars().all(|c| c.is_ascii_alphanumeric())
+        && id.chars().any(|c| c.is_ascii_digit())
+}
+
+/// `BOOKID`: the book `input` names, or why it can't be one.
+pub fn parse(input: &str) -> Result<BookRef, String> {
+    let input = input.trim();
+    let (id, slug) = if let Some((_, rest)) = input.split_once("/library/view/") {
+        let mut parts = rest.split(['/', '?', '#']);
+        let slug = parts.next().filter(|s| !s.is_empty() && *s != "-");
+        (parts.next().unwrap_or_default(), slug)
+    } else if let Some(id) = input.strip_prefix("urn:orm:book:") {
+        (id, None)
+    } else {
+        (input, None)
+    };
+    // ISBNs are often written with dashes.
+    let undashed = id.replace('-', "");
+    let id = if undashed.len() != id.len()
+        && matches!(undashed.len(), 10 | 13)
+        && undashed.chars().all(|c| c.is_ascii_digit() || c == 'X')
+    {
+        undashed
+    } else {
+        id.to_string()
+    };
+    if !is_book_id(&id) {
+        return Err(t!("book-id-invalid", input = format!("{input:?}")));
+    }
+    Ok(BookRef {
+        id,
+        slug: slug.map(String::from),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, BookRef};
+
+    #[test]
+    fn accepts_ids_isbns_urns_and_urls() {
+        let id = |input: &str| parse(input).map(|b| b.id);
+        assert_eq!(id("9781491958698").as_deref(), Ok("9781491958698"));
+        assert_eq!(id(" 978-1-491-95869-8 ").as_deref(), Ok("9781491958698"));
+        assert_eq!(
+            id("urn:orm:book:0636920049555").as_deref(),
+            Ok("0636920049555")
+        );
+
+        let url = parse(
+            "https://learning.oreilly.com/library/view/learning-python-5th/9781449355722/ch01.html",
+        )
+        .unwrap();
+        assert_eq!(
+            url,
+            BookRef {
+                id: "9781449355722".to_string(),
+                slug: Some("learning-python-5th".to_string()),
+            }
+        );
+        assert_eq!(url.search_terms(), "learning python 5th");
+        assert_eq!(
+            parse("https://learning.oreilly.com/library/view/-/9781449355722/")
+                .unwrap()
+                .search_terms(),
+            "9781449355722"
+        );
+
+        for bad in [
+            "",
+            "learning-python",
+            "9781491958698/",
+            "book",
+            "https://learning.oreilly.com/library/view/x/",
+            "https://www.oreilly.com/",
+        ] {
+            assert!(parse(bad).is_err(), "{bad:?}");
+        }
+    }
+}
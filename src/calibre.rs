@@ -0,0 +1,100 @@
+//! Adding finished books to a Calibre library with `calibredb`, for `--add-to-calibre`.
+
+use crate::i18n::t;
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+/// What `calibredb add` did with the book.
+#[derive(Debug, PartialEq)]
+pub enum Added {
+    /// New library entries, by Calibre book id.
+    Books(Vec<u64>),
+    /// Calibre's duplicate check matched a book already in the library.
+    Duplicate,
+}
+
+/// The calibredb program; Calibre puts it on PATH.
+pub const CALIBREDB: &str = "calibredb";
+
+/// Add `epub` to the library at `library` (Calibre's current library when
+/// None). Calibre skips books whose title and authors it already has.
+pub fn add_to_calibre(program: &Path, library: Option<&Path>, epub: &Path) -> Result<Added> {
+    let mut cmd = Command::new(program);
+    cmd.arg("add");
+    if let Some(library) = library {
+        cmd.arg("--library-path").arg(library);
+    }
+    let output = cmd
+        .arg(epub)
+        .output()
+        .with_context(|| t!("calibre-not-run", program = program.display().to_string()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let last = stderr.lines().rev().find(|l| !l.trim().is_empty());
+        bail!(t!(
+            "program-failed",
+            program = "calibredb",
+            status = output.status.to_string(),
+            detail = last.map(|l| format!(": {}", l.trim())).unwrap_or_default()
+        ));
+    }
+    parse_output(&format!("{stdout}\n{stderr}")).with_context(|| t!("calibre-no-answer"))
+}
+
+fn parse_output(output: &str) -> Option<Added> {
+    if output.contains("already exist in the database") {
+        return Some(Added::Duplicate);
+    }
+    let ids = output
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("Added book ids:"))?;
+    Some(Added::Books(
+        ids.split(',')
+            .filter_map(|id| id.trim().parse().ok())
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_to_calibre, parse_output, Added};
+
+    #[test]
+    fn parses_calibredb_output() {
+        assert_eq!(
+            parse_output("Backing up metadata\nAdded book ids: 12, 13\n"),
+            Some(Added::Books(vec![12, 13]))
+        );
+        assert_eq!(
+            parse_output(
+                "The following books were not added as they already exist in the database \
+                 (see --duplicates option):\n  Fluent Python\n"
+            ),
+            Some(Added::Duplicate)
+        );
+        assert_eq!(parse_output("Usage: calibredb add"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn passes_the_library_path() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("safaribooks-calibre-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("calibredb");
+        fs::write(
+            &script,
+            "#!/bin/sh\n[ \"$2\" = --library-path ] && [ \"$3\" = /lib ] && echo 'Added book ids: 7'\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let added = add_to_calibre(&script, Some("/lib".as_ref()), "x.epub".as_ref()).unwrap();
+        assert_eq!(added, Added::Books(vec![7]));
+        assert!(add_to_calibre(&script, None, "x.epub".as_ref()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
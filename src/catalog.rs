@@ -0,0 +1,230 @@
+//! An OPDS 1.2 acquisition feed of the books root, so e-reader apps
+//! (KOReader and the like) can browse and fetch the library over HTTP
+//! when the root is served as static files.
+
+use crate::checksum::short_hash;
+use crate::epub::xml_escape;
+use crate::i18n::t;
+use crate::library::{scan, LibraryBook};
+use crate::orly::iso_datetime;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// Name of the feed written at the top of the books root.
+pub const CATALOG_FILE: &str = "catalog.xml";
+
+/// Directory (next to the feed) holding the extracted cover images.
+const COVERS_DIR: &str = "covers";
+
+const FEED_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+/// Write `<root>/catalog.xml` listing every EPUB below `root`, extracting
+/// their covers to `<root>/covers/`. Returns the feed path and book count.
+pub fn write_catalog(root: &Path) -> Result<(PathBuf, usize)> {
+    let books = scan(root);
+    let covers = root.join(COVERS_DIR);
+    let mut entries = String::new();
+    for book in &books {
+        let Ok(rel) = book.path.strip_prefix(root) else {
+            continue;
+        };
+        let cover = match book.read_cover() {
+            Ok(Some(data)) => {
+                let (_, media_type) = book.cover.as_ref().expect("cover was read");
+                let ext = cover_extension(media_type);
+                let name = format!("{}.{ext}", short_hash(&rel.to_string_lossy()));
+                fs::create_dir_all(&covers).with_context(|| {
+                    t!("creating-directory", path = covers.display().to_string())
+                })?;
+                let path = covers.join(&name);
+                fs::write(&path, data)
+                    .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+                Some((format!("{COVERS_DIR}/{name}"), media_type.as_str()))
+            }
+            // A missing or unreadable cover only costs the thumbnail.
+            _ => None,
+        };
+        entries.push_str(&entry(book, &url_path(rel), cover));
+    }
+
+    let feed = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/" xmlns:opds="http://opds-spec.org/2010/catalog">
+<id>urn:safaribooks-rs:catalog:{id}</id>
+<title>{title}</title>
+<updated>{updated}</updated>
+<author><name>{name}</name></author>
+<link rel="self" href="{CATALOG_FILE}" type="{FEED_TYPE}"/>
+<link rel="start" href="{CATALOG_FILE}" type="{FEED_TYPE}"/>
+{entries}</feed>
+"#,
+        id = short_hash(&root.to_string_lossy()),
+        title = xml_escape(&format!(
+            "Books in {}",
+            root.file_name().unwrap_or_default().to_string_lossy()
+        )),
+        updated = iso_datetime(SystemTime::now()),
+        name = env!("CARGO_PKG_NAME"),
+    );
+    let path = root.join(CATALOG_FILE);
+    fs::write(&path, feed)
+        .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+    Ok((path, books.len()))
+}
+
+/// The Atom entry of one book; `href` is its path relative to the feed.
+fn entry(book: &LibraryBook, href: &str, cover: Option<(String, &str)>) -> String {
+    let id = book
+        .identifiers
+        .iter()
+        .find(|id| id.starts_with("urn:"))
+        .or(book.identifiers.first())
+        .map_or_else(|| format!("urn:safaribooks-rs:{href}"), |id| id.clone());
+    let mut out = format!(
+        "<entry>\n<title>{}</title>\n<id>{}</id>\n<updated>{}</updated>\n",
+        xml_escape(&book.title),
+        xml_escape(&id),
+        iso_datetime(book.modified)
+    );
+    for author in &book.authors {
+        out.push_str(&format!(
+            "<author><name>{}</name></author>\n",
+            xml_escape(author)
+        ));
+    }
+    for (tag, value) in [
+        ("dc:language", &book.language),
+        ("dc:publisher", &book.publisher),
+        ("dc:issued", &book.date),
+    ] {
+        if let Some(value) = value {
+            out.push_str(&format!("<{tag}>{}</{tag}>\n", xml_escape(value)));
+        }
+    }
+    for subject in &book.subjects {
+        let subject = xml_escape(subject);
+        out.push_str(&format!(
+            "<category term=\"{subject}\" label=\"{subject}\"/>\n"
+        ));
+    }
+    if let Some(description) = &book.description {
+        // The description is HTML: escaped once more, as Atom wants.
+        out.push_str(&format!(
+            "<content type=\"html\">{}</content>\n",
+            xml_escape(description)
+        ));
+    }
+    out.push_str(&format!(
+        "<link rel=\"http://opds-spec.org/acquisition\" href=\"{}\" type=\"application/epub+zip\"/>\n",
+        xml_escape(href)
+    ));
+    if let Some((cover, media_type)) = cover {
+        for rel in [
+            "http://opds-spec.org/image",
+            "http://opds-spec.org/image/thumbnail",
+        ] {
+            out.push_str(&format!(
+                "<link rel=\"{rel}\" href=\"{}\" type=\"{}\"/>\n",
+                xml_escape(&cover),
+                xml_escape(media_type)
+            ));
+        }
+    }
+    out.push_str("</entry>\n");
+    out
+}
+
+/// File extension for a cover image of `media_type`.
+pub fn cover_extension(media_type: &str) -> &'static str {
+    match media_type {
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/svg+xml" => "svg",
+        "image/webp" => "webp",
+        _ => "jpg",
+    }
+}
+
+/// Relative URL of `path`: '/'-separated, each segment percent-encoded.
+pub fn url_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy()),
+            _ => None,
+        })
+        .map(|segment| {
+            segment
+                .bytes()
+                .map(|b| match b {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                        (b as char).to_string()
+                    }
+                    _ => format!("%{b:02X}"),
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{url_path, write_catalog};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    #[test]
+    fn encodes_hrefs() {
+        assert_eq!(
+            url_path(Path::new("Café Book (123)/123.epub")),
+            "Caf%C3%A9%20Book%20%28123%29/123.epub"
+        );
+    }
+
+    #[test]
+    fn lists_books_with_covers() {
+        let root = std::env::temp_dir().join(format!("safaribooks-catalog-{}", std::process::id()));
+        fs::create_dir_all(root.join("Rust (123)")).unwrap();
+        let mut zip = ZipWriter::new(File::create(root.join("Rust (123)/123.epub")).unwrap());
+        let opts = SimpleFileOptions::default();
+        zip.start_file("META-INF/container.xml", opts).unwrap();
+        zip.write_all(br#"<rootfile full-path="OEBPS/content.opf"/>"#)
+            .unwrap();
+        zip.start_file("OEBPS/content.opf", opts).unwrap();
+        zip.write_all(
+            br#"<package><metadata>
+<dc:identifier id="bookid">123</dc:identifier>
+<dc:title>Rust &amp; You</dc:title>
+<dc:creator>Jane Doe</dc:creator>
+<dc:subject>Programming</dc:subject>
+</metadata><manifest>
+<item id="cover" href="Images/cover.png" media-type="image/png" properties="cover-image"/>
+</manifest></package>"#,
+        )
+        .unwrap();
+        zip.start_file("OEBPS/Images/cover.png", opts).unwrap();
+        zip.write_all(b"\x89PNG").unwrap();
+        zip.finish().unwrap();
+        fs::write(root.join("notes.epub"), b"not a zip").unwrap();
+
+        let (path, count) = write_catalog(&root).unwrap();
+        assert_eq!(count, 1);
+        let feed = fs::read_to_string(path).unwrap();
+        assert!(feed.contains("<title>Rust &amp; You</title>"));
+        assert!(feed.contains("<author><name>Jane Doe</name></author>"));
+        assert!(feed.contains(r#"<category term="Programming" label="Programming"/>"#));
+        assert!(feed.contains(r#"href="Rust%20%28123%29/123.epub" type="application/epub+zip""#));
+        let cover = feed
+            .split("rel=\"http://opds-spec.org/image\" href=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+        assert_eq!(fs::read(root.join(cover)).unwrap(), b"\x89PNG");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
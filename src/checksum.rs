@@ -0,0 +1,229 @@
+//! SHA-256 digests of the files that make up a book, and the manifest
+//! (`<book>.epub.sha256`, in `sha256sum` format) that `verify` checks.
+
+use crate::i18n::t;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+/// Lowercase hex SHA-256 of the file at `path`, read in chunks.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let file =
+        File::open(path).with_context(|| t!("opening-file", path = path.display().to_string()))?;
+    sha256_reader(file).with_context(|| t!("reading-file", path = path.display().to_string()))
+}
+
+/// Lowercase hex SHA-256 of `data`.
+pub fn sha256_bytes(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn sha256_reader(mut reader: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// First 12 hex digits of the SHA-256 of `text`: short, stable names.
+pub fn short_hash(text: &str) -> String {
+    let mut digest = sha256_bytes(text.as_bytes());
+    digest.truncate(12);
+    digest
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The manifest of `epub`: "123.epub" -> "123.epub.sha256".
+pub fn manifest_path(epub: &Path) -> PathBuf {
+    let mut name = epub.file_name().unwrap_or_default().to_os_string();
+    name.push(".sha256");
+    epub.with_file_name(name)
+}
+
+/// Write the manifest of `epub`: a line per archive entry (`entries` maps
+/// entry names to digests), then one for the EPUB itself. Entry names are
+/// relative to the book directory, so `sha256sum -c` works there too.
+pub fn write_manifest(epub: &Path, entries: &[(String, String)]) -> Result<()> {
+    let mut out = String::new();
+    for (name, digest) in entries {
+        out.push_str(&format!("{digest}  {name}\n"));
+    }
+    let name = epub.file_name().unwrap_or_default().to_string_lossy();
+    out.push_str(&format!("{}  {name}\n", sha256_file(epub)?));
+    let path = manifest_path(epub);
+    fs::write(&path, out).with_context(|| t!("writing-file", path = path.display().to_string()))
+}
+
+/// Outcome of checking one EPUB against its manifest.
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub epub: PathBuf,
+    /// Files whose digest was compared.
+    pub checked: usize,
+    /// Human-readable mismatches; empty when the book is intact.
+    pub problems: Vec<String>,
+}
+
+/// Check a download against its manifest(s). `path` may be an EPUB, its
+/// manifest, or a book directory holding one or more of them.
+pub fn verify(path: &Path) -> Result<Vec<Verification>> {
+    let manifests: Vec<PathBuf> = if path.is_dir() {
+        let mut found: Vec<_> = fs::read_dir(path)
+            .with_context(|| t!("reading-directory", path = path.display().to_string()))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.to_string_lossy().ends_with(".epub.sha256"))
+            .collect();
+        found.sort();
+        found
+    } else if path.extension().is_some_and(|e| e == "sha256") {
+        vec![path.to_path_buf()]
+    } else {
+        vec![manifest_path(path)]
+    };
+    if manifests.is_empty() {
+        bail!(t!(
+            "checksum-manifest-missing",
+            path = path.display().to_string()
+        ));
+    }
+    manifests.iter().map(|m| verify_manifest(m)).collect()
+}
+
+fn verify_manifest(manifest: &Path) -> Result<Verification> {
+    let raw = fs::read_to_string(manifest).with_context(|| {
+        t!(
+            "reading-checksum-manifest",
+            path = manifest.display().to_string()
+        )
+    })?;
+    let epub = manifest.with_extension("");
+    let epub_name = epub.file_name().unwrap_or_default().to_string_lossy();
+    let mut expected = BTreeMap::new();
+    for line in raw.lines().filter(|l| !l.trim().is_empty()) {
+        let Some((digest, name)) = line.split_once("  ") else {
+            bail!(t!(
+                "checksum-line-malformed",
+                path = manifest.display().to_string(),
+                line = format!("{line:?}")
+            ));
+        };
+        expected.insert(name.to_string(), digest.to_string());
+    }
+
+    let mut result = Verification {
+        epub: epub.clone(),
+        ..Verification::default()
+    };
+    if !epub.is_file() {
+        result
+            .problems
+            .push(t!("file-missing", path = epub.display().to_string()));
+        return Ok(result);
+    }
+    if let Some(digest) = expected.remove(epub_name.as_ref()) {
+        result.checked += 1;
+        if sha256_file(&epub)? != digest {
+            result
+                .problems
+                .push(t!("entry-checksum-mismatch", name = epub_name.as_ref()));
+        }
+    }
+
+    let file =
+        File::open(&epub).with_context(|| t!("opening-file", path = epub.display().to_string()))?;
+    let mut zip = match ZipArchive::new(file) {
+        Ok(zip) => zip,
+        Err(e) => {
+            result.problems.push(t!(
+                "entry-unreadable",
+                name = epub_name.as_ref(),
+                error = e.to_string()
+            ));
+            return Ok(result);
+        }
+    };
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let name = entry.name()?.to_string();
+        let Some(digest) = expected.remove(&name) else {
+            result
+                .problems
+                .push(t!("entry-not-in-manifest", name = name));
+            continue;
+        };
+        result.checked += 1;
+        match sha256_reader(entry) {
+            Ok(actual) if actual == digest => {}
+            Ok(_) => result
+                .problems
+                .push(t!("entry-checksum-mismatch", name = name)),
+            Err(e) => {
+                result
+                    .problems
+                    .push(t!("entry-unreadable", name = name, error = e.to_string()))
+            }
+        }
+    }
+    for name in expected.keys() {
+        result
+            .problems
+            .push(t!("entry-missing-from-epub", name = name.as_str()));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sha256_file, short_hash, verify, write_manifest};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    #[test]
+    fn hashes_files() {
+        let path = std::env::temp_dir().join(format!("safaribooks-sha-{}", std::process::id()));
+        fs::write(&path, b"abc").unwrap();
+        assert_eq!(
+            sha256_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(short_hash("abc"), "ba7816bf8f01");
+    }
+
+    #[test]
+    fn verifies_against_the_manifest() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-verify-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let epub = dir.join("123.epub");
+        let mut zip = ZipWriter::new(File::create(&epub).unwrap());
+        zip.start_file("mimetype", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"application/epub+zip").unwrap();
+        zip.finish().unwrap();
+
+        let digest = super::sha256_bytes(b"application/epub+zip");
+        write_manifest(&epub, &[("mimetype".to_string(), digest)]).unwrap();
+        let report = verify(&dir).unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].checked, 2);
+        assert!(report[0].problems.is_empty(), "{:?}", report[0].problems);
+
+        // Truncation shows up as a mismatch of the EPUB itself.
+        let data = fs::read(&epub).unwrap();
+        fs::write(&epub, &data[..data.len() - 4]).unwrap();
+        let report = verify(&epub).unwrap();
+        assert!(report[0].problems[0].contains("123.epub: checksum mismatch"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,39 +1,1107 @@
-use clap::Parser;
+use crate::annotations::ExportFormat;
+use crate::bookid::{self, BookRef};
+use crate::code::CodeStyle;
+use crate::config::ByteSize;
+use crate::display::{ColorChoice, OutputFormat};
+use crate::epub::IfExists;
+use crate::format::Format;
+use crate::http_client::AuthMode;
+use crate::i18n;
+use crate::sidecar::SidecarFormat;
+use crate::table::TableStyle;
+use crate::throttle::Rate;
+use crate::{serve, watch};
+use clap::{ArgAction, ArgGroup, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
-/// Minimal SafariBooks port (cookies only).
+/// Download O'Reilly books, and video courses as their transcripts, to
+/// read offline as EPUB (or plain text, see --format).
+///
+/// Signs in with cookies from a file, stdin or the system keyring, sent as
+/// cookies or as a bearer token (--auth). Subcommands list, verify and
+/// rebuild books, export annotations, or keep running to serve the
+/// library (serve) or download queued IDs (watch).
+///
+/// Settings can also come from SAFARIBOOKS_* environment variables and the
+/// config file; command-line flags take precedence over both.
 #[derive(Parser, Debug, PartialEq)]
-#[command(version)]
+#[command(version, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// Book digits ID from the O'Reilly URL.
-    pub bookid: String,
+    /// Book (or video course) digits ID from the O'Reilly URL, or the URL
+    /// itself; give several to download them in one run (see
+    /// --parallel-books).
+    #[arg(required = true, value_name = "BOOKID", value_parser = bookid::parse)]
+    pub bookids: Vec<BookRef>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
 
     /// Do not delete the log file on success.
     #[arg(long = "preserve-log")]
     pub preserve_log: bool,
+
+    /// Write the log to FILE instead of logs/info_<BOOKID>.log under the data directory.
+    #[arg(long = "log-file", value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Use the named profile (own cookies, output dir and settings) from the config directory.
+    #[arg(long = "profile", value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Read cookies from FILE instead of cookies.json next to the executable ("-" for stdin).
+    #[arg(long = "cookies-file", value_name = "FILE")]
+    pub cookies_file: Option<PathBuf>,
+
+    /// Site root to use instead of https://learning.oreilly.com (white-labeled or proxied access).
+    #[arg(long = "base-url", value_name = "URL")]
+    pub base_url: Option<String>,
+
+    /// How API and asset requests authenticate (bearer also sends orm-jwt as a bearer token).
+    #[arg(long = "auth", value_name = "MODE", value_enum)]
+    pub auth: Option<AuthMode>,
+
+    /// User-Agent to send; "random" picks one of a few current browsers
+    /// for the run (default: desktop Chrome).
+    #[arg(long = "user-agent", value_name = "UA")]
+    pub user_agent: Option<String>,
+
+    /// Extra request header, "Name: value" (e.g. for an authenticating
+    /// proxy); repeat for several.
+    #[arg(long = "header", value_name = "HEADER")]
+    pub header: Vec<String>,
+
+    /// Also trust the CA certificates in this PEM file, e.g. a corporate
+    /// proxy's.
+    #[arg(long = "ca-cert", value_name = "PEM")]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Skip TLS certificate verification. Unsafe: anyone on the network can
+    /// then read your session cookies.
+    #[arg(long = "insecure")]
+    pub insecure: bool,
+
+    /// Connect to HOST at ADDRESS instead of what DNS says, like curl's
+    /// --resolve ("HOST:ADDRESS", or "HOST:PORT:ADDRESS" with the port
+    /// ignored); repeat for several hosts.
+    #[arg(long = "resolve", value_name = "HOST:ADDRESS")]
+    pub resolve: Vec<String>,
+
+    /// Connect over IPv4 only.
+    #[arg(short = '4', long = "ipv4", conflicts_with = "ipv6")]
+    pub ipv4: bool,
+
+    /// Connect over IPv6 only.
+    #[arg(short = '6', long = "ipv6")]
+    pub ipv6: bool,
+
+    /// Don't read or save session cookies in the OS keyring; use cookies.json only.
+    #[arg(long = "no-keyring")]
+    pub no_keyring: bool,
+
+    /// Root directory for downloaded books (default: Books/ next to the executable).
+    #[arg(long = "output-dir", value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Write the EPUB to FILE instead of the book directory; "-" streams it
+    /// to stdout (messages then go to stderr).
+    #[arg(long = "output", value_name = "FILE")]
+    pub output: Option<PathBuf>,
+
+    /// Book directory under the output dir, e.g. "{author}/{title} ({isbn})".
+    /// Fields: title, bookid, isbn, author, publisher, year.
+    #[arg(long = "dir-template", value_name = "TEMPLATE")]
+    pub dir_template: Option<String>,
+
+    /// EPUB file name (without .epub) inside the book directory, e.g. "{title}".
+    #[arg(long = "file-template", value_name = "TEMPLATE")]
+    pub file_template: Option<String>,
+
+    /// Longest file or directory name to create, in bytes (UTF-16 units on Windows).
+    /// Defaults to the output filesystem's limit, e.g. 143 on eCryptfs.
+    #[arg(long = "name-max", value_name = "N")]
+    pub name_max: Option<usize>,
+
+    /// Transliterate non-ASCII characters in directory and file names ("Café" -> "Cafe").
+    #[arg(long = "ascii-names")]
+    pub ascii_names: bool,
+
+    /// Name book directories, chapters, images and stylesheets the way
+    /// safaribooks.py does, to resume or compare books it downloaded.
+    #[arg(long = "legacy-names", conflicts_with_all = ["dir_template", "file_template"])]
+    pub legacy_names: bool,
+
+    /// What to do if the book directory already exists (skip only skips a complete EPUB).
+    #[arg(long = "if-exists", value_name = "POLICY", value_enum)]
+    pub if_exists: Option<IfExists>,
+
+    /// Run epubcheck (from PATH or the epubcheck_path setting) on the finished EPUB.
+    #[arg(long = "epubcheck")]
+    pub epubcheck: bool,
+
+    /// Fail the run if assets could not be downloaded, or the package check
+    /// or epubcheck reports errors.
+    #[arg(long = "strict")]
+    pub strict: bool,
+
+    /// Don't end the book with the generated colophon (tool, download date, source URL).
+    #[arg(long = "no-credits")]
+    pub no_credits: bool,
+
+    /// Add your highlights and notes on the book as an appendix chapter.
+    #[arg(long = "embed-annotations")]
+    pub embed_annotations: bool,
+
+    /// Open the book where you left off in the web reader, and save that
+    /// position next to the EPUB (<name>.bookmark.json).
+    #[arg(long = "bookmark")]
+    pub bookmark: bool,
+
+    /// Also keep the chapter HTML, stylesheets, images and API responses
+    /// exactly as downloaded, under raw/ in the book directory, so the EPUB
+    /// can be rebuilt later without downloading again.
+    #[arg(long = "mirror")]
+    pub mirror: bool,
+
+    /// How to lay out program listings for small screens.
+    #[arg(long = "code-style", value_name = "STYLE", value_enum)]
+    pub code_style: Option<CodeStyle>,
+
+    /// What to do with tables too wide for the screen.
+    #[arg(long = "table-style", value_name = "STYLE", value_enum)]
+    pub table_style: Option<TableStyle>,
+
+    /// Convert SVG figures to PNG (needs rsvg-convert), for EPUB 2 and
+    /// Kindle readers that cannot show SVG.
+    #[arg(long = "rasterize-svg")]
+    pub rasterize_svg: bool,
+
+    /// Embed images smaller than SIZE (e.g. 4K) in the chapters as data URIs.
+    #[arg(long = "inline-images", value_name = "SIZE")]
+    pub inline_images: Option<ByteSize>,
+
+    /// Also write the book as several EPUBs ("Title - Part 1.epub", ...) of
+    /// at most SIZE (e.g. 200M) each, when it is larger than that.
+    #[arg(long = "split-parts", value_name = "SIZE")]
+    pub split_parts: Option<ByteSize>,
+
+    /// Also write metadata next to the EPUB: json (metadata.json, the full
+    /// API metadata and provenance), opf (calibre's metadata.opf) or both,
+    /// comma-separated. The cover is copied out beside them.
+    #[arg(
+        long = "sidecar",
+        value_name = "FORMATS",
+        value_enum,
+        value_delimiter = ','
+    )]
+    pub sidecar: Vec<SidecarFormat>,
+
+    /// What to deliver the book as: "epub" (the default), or "txt" for its
+    /// text alone.
+    #[arg(long = "format", value_name = "FORMAT")]
+    pub format: Option<Format>,
+
+    /// Deflate level of the EPUB, 0 (store, fastest) to 9 (smallest).
+    #[arg(long = "compression", value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(0..=9))]
+    pub compression: Option<u8>,
+
+    /// Store images in the EPUB without deflating them: JPEG and PNG barely
+    /// shrink, so this packages image-heavy books much faster.
+    #[arg(long = "store-images")]
+    pub store_images: bool,
+
+    /// Keep the unpacked book (OEBPS/, META-INF/) next to the EPUB instead
+    /// of deleting it once packaged.
+    #[arg(long = "keep-workdir")]
+    pub keep_workdir: bool,
+
+    /// Only fetch the cover of a book already downloaded, as cover.<ext>
+    /// next to its EPUB; the book itself is not downloaded again.
+    #[arg(long = "cover-only", conflicts_with = "output")]
+    pub cover_only: bool,
+
+    /// Only write the metadata sidecars (--sidecar, default json and opf)
+    /// of a book already downloaded, from its current API metadata.
+    #[arg(long = "metadata-only", conflicts_with = "output")]
+    pub metadata_only: bool,
+
+    /// Title to write into the EPUB instead of the one from the API.
+    #[arg(long = "set-title", value_name = "TITLE")]
+    pub set_title: Option<String>,
+
+    /// Author to write into the EPUB instead of the API's; repeat for several.
+    #[arg(long = "set-author", value_name = "NAME")]
+    pub set_author: Vec<String>,
+
+    /// Series the book belongs to (EPUB 3 collection and calibre series).
+    #[arg(long = "series", value_name = "NAME")]
+    pub series: Option<String>,
+
+    /// Position of the book in its --series, e.g. 2 or 2.5.
+    #[arg(long = "series-index", value_name = "N", requires = "series")]
+    pub series_index: Option<f64>,
+
+    /// Without --series, guess the series from the title when the API names
+    /// none: "Head First Go" is in Head First, "Fluent Python, 2nd Edition"
+    /// is the second of Fluent Python.
+    #[arg(long = "series-from-title")]
+    pub series_from_title: bool,
+
+    /// Comma-separated subjects replacing the API topics, e.g. "rust,systems".
+    #[arg(long = "tags", value_name = "TAGS", value_delimiter = ',')]
+    pub tags: Vec<String>,
+
+    /// Add the finished EPUB to a Calibre library with calibredb (default: Calibre's
+    /// current library); books Calibre already has are not added twice.
+    #[arg(long = "add-to-calibre", value_name = "LIBRARY", num_args = 0..=1)]
+    pub add_to_calibre: Option<Option<PathBuf>>,
+
+    /// POST a JSON report ({"event", "book_id", "path", "error"}) to URL after each download.
+    #[arg(long = "webhook", value_name = "URL")]
+    pub webhook: Option<String>,
+
+    /// Run CMD through the shell with the finished EPUB's path as its last argument.
+    #[arg(long = "hook-command", value_name = "CMD")]
+    pub hook_command: Option<String>,
+
+    /// Show a desktop notification when the download finishes or fails.
+    #[arg(long = "notify")]
+    pub notify: bool,
+
+    /// Number of concurrent requests per book.
+    #[arg(short = 'j', long = "jobs", value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// Read at most RATE bytes per second across all downloads, e.g. 500K or 2M.
+    #[arg(long = "limit-rate", value_name = "RATE")]
+    pub limit_rate: Option<Rate>,
+
+    /// Pace requests like a person reading: one at a time, after a random
+    /// pause, each chapter followed by its images. Much slower.
+    #[arg(long = "stealth")]
+    pub stealth: bool,
+
+    /// When more than PCT percent of the recent requests fail (default 50),
+    /// wait, then download the rest of the book one request at a time with
+    /// long pauses instead of giving up; 100 never does.
+    #[arg(long = "degrade-above", value_name = "PCT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub degrade_above: Option<u8>,
+
+    /// Ask before downloading a book estimated larger than SIZE (default 1G).
+    #[arg(long = "confirm-above", value_name = "SIZE")]
+    pub confirm_above: Option<ByteSize>,
+
+    /// Don't ask before large downloads.
+    #[arg(short = 'y', long = "yes")]
+    pub yes: bool,
+
+    /// Download up to N of the given books at once. They share one budget
+    /// of --jobs requests, so the server sees no more traffic than for one book.
+    #[arg(long = "parallel-books", value_name = "N")]
+    pub parallel_books: Option<usize>,
+
+    /// Print errors only (no banner or progress); suited to cron jobs.
+    #[arg(short = 'q', long = "quiet", conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Show more diagnostics on the terminal (-v); -vv also traces every request.
+    #[arg(short = 'v', long = "verbose", action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// When to color the output (auto: only on a terminal without NO_COLOR set).
+    #[arg(long = "color", value_name = "WHEN", value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Same as --color never.
+    #[arg(long = "no-color", conflicts_with = "color")]
+    pub no_color: bool,
+
+    /// Language of the messages (e.g. "es"); by default LC_ALL, LC_MESSAGES
+    /// or LANG decides, falling back to English.
+    #[arg(long = "lang", value_name = "LANG", value_parser = i18n::parse_lang)]
+    pub lang: Option<String>,
+
+    /// Print human-readable text, or one JSON event per line for scripts and GUIs.
+    #[arg(long = "output-format", value_name = "FORMAT", value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+
+    /// Log every HTTP request and response (cookies and tokens redacted) to the log file.
+    #[arg(long = "debug-http")]
+    pub debug_http: bool,
+
+    /// Save every HTTP response under DIR (for bug reports and offline test runs).
+    #[arg(long = "record", value_name = "DIR", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Serve every HTTP response from a DIR made by --record; no network or cookies needed.
+    #[arg(long = "replay", value_name = "DIR")]
+    pub replay: Option<PathBuf>,
+}
+
+/// Things to do instead of downloading a book.
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Check a downloaded book against its SHA-256 manifest.
+    Verify {
+        /// The .epub file, its .sha256 manifest, or the book directory.
+        path: PathBuf,
+    },
+    /// Write an OPDS catalog (catalog.xml) of the books in the output directory,
+    /// for e-reader apps browsing it over HTTP.
+    Catalog,
+    /// Serve the output directory and its catalog over HTTP, with an API to queue
+    /// downloads: POST /api/downloads {"book_id": "..."}.
+    Serve {
+        /// Address to listen on; use 0.0.0.0:PORT to reach it from other devices.
+        #[arg(long = "listen", value_name = "ADDR", default_value = serve::DEFAULT_LISTEN)]
+        listen: SocketAddr,
+    },
+    /// List a book's chapters in reading order with their file names and
+    /// estimated download sizes, without downloading it.
+    Chapters {
+        /// Book ID from the O'Reilly URL.
+        bookid: String,
+        /// Print a JSON array instead of a table.
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// Export your highlights and notes on a book.
+    Annotations {
+        /// Book ID from the O'Reilly URL.
+        bookid: String,
+        #[arg(long = "format", value_enum, default_value_t = ExportFormat::Markdown)]
+        format: ExportFormat,
+        /// Write to FILE instead of stdout.
+        #[arg(short = 'o', long = "output", value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// List titles from your O'Reilly account, or the ones already downloaded,
+    /// as "ID  # title" lines, ready to paste into a download command or a
+    /// watch queue.
+    #[command(group(ArgGroup::new("source").required(true).multiple(true)))]
+    List {
+        /// Titles you read recently, most recent first.
+        #[arg(long = "history", group = "source")]
+        history: bool,
+        /// Your playlists and the titles in them.
+        #[arg(long = "playlists", group = "source")]
+        playlists: bool,
+        /// Books already downloaded, with their ISBN, size and download date,
+        /// and whether an Early Release has been updated since.
+        #[arg(long = "downloaded", group = "source")]
+        downloaded: bool,
+    },
+    /// Build the EPUB in a book directory again from its --mirror, offline,
+    /// with this version's formatting and packaging.
+    Rebuild {
+        /// The book directory holding raw/.
+        dir: PathBuf,
+    },
+    /// Keep running and download the book IDs added to a queue file (one per
+    /// line) or dropped as files into a queue directory.
+    Watch {
+        /// Queue file, or directory of request files.
+        path: PathBuf,
+        /// Seconds between checks of the queue.
+        #[arg(long = "interval", value_name = "SECONDS", default_value_t = watch::DEFAULT_INTERVAL_SECS)]
+        interval: u64,
+    },
+    /// Replace this program with the latest release's build for this
+    /// platform, checked against its published SHA-256 and signature.
+    SelfUpdate {
+        /// Only say whether a newer version is out.
+        #[arg(long = "check")]
+        check: bool,
+    },
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Args;
+    use super::{Args, ByteSize, Command, ExportFormat, Format, Rate};
+    use crate::code::CodeStyle;
+    use crate::display::{ColorChoice, OutputFormat};
+    use crate::epub::IfExists;
+    use crate::sidecar::SidecarFormat;
+    use crate::table::TableStyle;
     use clap::{CommandFactory, Parser};
+    use std::path::PathBuf;
+
+    /// The IDs of the books `args` names.
+    fn ids(args: &Args) -> Vec<&str> {
+        args.bookids.iter().map(|b| b.id.as_str()).collect()
+    }
 
     #[test]
     fn parses_positional_bookid_only() {
         // safaribooks-rs 9781491958698
         let args = Args::try_parse_from(["safaribooks-rs", "9781491958698"]).unwrap();
-        assert_eq!(args.bookid, "9781491958698");
+        assert_eq!(ids(&args), ["9781491958698"]);
         assert!(!args.preserve_log);
     }
 
+    #[test]
+    fn rejects_what_is_not_a_book_id() {
+        // safaribooks-rs https://learning.oreilly.com/library/view/learning-python-5th/9781449355722/
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "https://learning.oreilly.com/library/view/learning-python-5th/9781449355722/",
+        ])
+        .unwrap();
+        assert_eq!(ids(&args), ["9781449355722"]);
+        // safaribooks-rs learning-python
+        assert!(Args::try_parse_from(["safaribooks-rs", "learning-python"]).is_err());
+    }
+
+    #[test]
+    fn parses_several_bookids() {
+        // safaribooks-rs --parallel-books 2 9781491958698 9781098103828
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--parallel-books",
+            "2",
+            "9781491958698",
+            "9781098103828",
+        ])
+        .unwrap();
+        assert_eq!(ids(&args), ["9781491958698", "9781098103828"]);
+        assert_eq!(args.parallel_books, Some(2));
+    }
+
+    #[test]
+    fn parses_confirmation_flags() {
+        // safaribooks-rs --confirm-above 500M -y 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--confirm-above",
+            "500M",
+            "-y",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(args.confirm_above, Some(ByteSize(500 << 20)));
+        assert!(args.yes);
+    }
+
+    #[test]
+    fn parses_limit_rate() {
+        // safaribooks-rs --limit-rate 2M 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "--limit-rate", "2M", "9781491958698"])
+            .unwrap();
+        assert_eq!(args.limit_rate, Some(Rate(2 * 1024 * 1024)));
+        assert!(Args::try_parse_from(["safaribooks-rs", "--limit-rate", "2X", "1"]).is_err());
+    }
+
+    #[test]
+    fn parses_user_agent() {
+        // safaribooks-rs --user-agent random 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--user-agent", "random", "9781491958698"])
+                .unwrap();
+        assert_eq!(args.user_agent.as_deref(), Some("random"));
+    }
+
+    #[test]
+    fn parses_headers() {
+        // safaribooks-rs --header "X-A: 1" --header "X-B: 2" 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--header",
+            "X-A: 1",
+            "--header",
+            "X-B: 2",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(args.header, ["X-A: 1", "X-B: 2"]);
+    }
+
+    #[test]
+    fn parses_ca_cert_and_insecure() {
+        // safaribooks-rs 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "9781491958698"]).unwrap();
+        assert_eq!(args.ca_cert, None);
+        assert!(!args.insecure);
+
+        // safaribooks-rs --ca-cert proxy.pem --insecure 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--ca-cert",
+            "proxy.pem",
+            "--insecure",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(args.ca_cert, Some(PathBuf::from("proxy.pem")));
+        assert!(args.insecure);
+    }
+
+    #[test]
+    fn parses_resolve_and_ip_family() {
+        // safaribooks-rs --resolve cdn.oreillystatic.com:203.0.113.7 -4 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--resolve",
+            "cdn.oreillystatic.com:203.0.113.7",
+            "-4",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(args.resolve, ["cdn.oreillystatic.com:203.0.113.7"]);
+        assert!(args.ipv4 && !args.ipv6);
+        assert!(Args::try_parse_from(["safaribooks-rs", "-4", "-6", "9781491958698"]).is_err());
+    }
+
+    #[test]
+    fn parses_stealth() {
+        // safaribooks-rs 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "9781491958698"]).unwrap();
+        assert!(!args.stealth);
+
+        // safaribooks-rs --stealth 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "--stealth", "9781491958698"]).unwrap();
+        assert!(args.stealth);
+    }
+
+    #[test]
+    fn parses_degrade_above() {
+        // safaribooks-rs --stealth 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "--stealth", "9781491958698"]).unwrap();
+        assert_eq!(args.degrade_above, None);
+
+        // safaribooks-rs --degrade-above 80 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--degrade-above", "80", "9781491958698"])
+                .unwrap();
+        assert_eq!(args.degrade_above, Some(80));
+        assert!(Args::try_parse_from(["safaribooks-rs", "--degrade-above", "0", "1"]).is_err());
+    }
+
     #[test]
     fn parses_with_preserve_log_flag() {
         // safaribooks-rs --preserve-log 9781491958698
         let args =
             Args::try_parse_from(["safaribooks-rs", "--preserve-log", "9781491958698"]).unwrap();
-        assert_eq!(args.bookid, "9781491958698");
+        assert_eq!(ids(&args), ["9781491958698"]);
         assert!(args.preserve_log);
     }
 
+    #[test]
+    fn parses_record_and_replay_dirs() {
+        // safaribooks-rs --record fixtures 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--record", "fixtures", "9781491958698"])
+                .unwrap();
+        assert_eq!(
+            args.record.as_deref(),
+            Some(std::path::Path::new("fixtures"))
+        );
+        assert_eq!(args.replay, None);
+
+        // safaribooks-rs --record a --replay b 9781491958698
+        let err = Args::try_parse_from([
+            "safaribooks-rs",
+            "--record",
+            "a",
+            "--replay",
+            "b",
+            "9781491958698",
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("cannot be used with"));
+    }
+
+    #[test]
+    fn parses_cookies_file_including_stdin() {
+        // safaribooks-rs --cookies-file /tmp/c.json 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--cookies-file",
+            "/tmp/c.json",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.cookies_file.as_deref(),
+            Some(std::path::Path::new("/tmp/c.json"))
+        );
+
+        // safaribooks-rs --cookies-file - 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "--cookies-file", "-", "9781491958698"])
+            .unwrap();
+        assert_eq!(
+            args.cookies_file.as_deref(),
+            Some(std::path::Path::new("-"))
+        );
+    }
+
+    #[test]
+    fn parses_no_keyring_flag() {
+        // safaribooks-rs --no-keyring 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--no-keyring", "9781491958698"]).unwrap();
+        assert!(args.no_keyring);
+        let args = Args::try_parse_from(["safaribooks-rs", "9781491958698"]).unwrap();
+        assert!(!args.no_keyring);
+    }
+
+    #[test]
+    fn parses_profile_name() {
+        // safaribooks-rs --profile work 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--profile", "work", "9781491958698"]).unwrap();
+        assert_eq!(args.profile.as_deref(), Some("work"));
+    }
+
+    #[test]
+    fn parses_naming_templates() {
+        // safaribooks-rs --dir-template "{author}/{title}" --file-template "{title}" 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--dir-template",
+            "{author}/{title}",
+            "--file-template",
+            "{title}",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(args.dir_template.as_deref(), Some("{author}/{title}"));
+        assert_eq!(args.file_template.as_deref(), Some("{title}"));
+
+        // safaribooks-rs --name-max 143 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--name-max", "143", "9781491958698"]).unwrap();
+        assert_eq!(args.name_max, Some(143));
+
+        // safaribooks-rs --ascii-names 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--ascii-names", "9781491958698"]).unwrap();
+        assert!(args.ascii_names);
+        assert!(!args.legacy_names);
+
+        // safaribooks-rs --legacy-names 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--legacy-names", "9781491958698"]).unwrap();
+        assert!(args.legacy_names);
+        assert!(Args::try_parse_from([
+            "safaribooks-rs",
+            "--legacy-names",
+            "--dir-template",
+            "{title}",
+            "1"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn parses_if_exists_policy() {
+        // safaribooks-rs --if-exists skip 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "--if-exists", "skip", "9781491958698"])
+            .unwrap();
+        assert_eq!(args.if_exists, Some(IfExists::Skip));
+        assert!(Args::try_parse_from(["safaribooks-rs", "--if-exists", "merge", "1"]).is_err());
+    }
+
+    #[test]
+    fn parses_epubcheck_and_strict_flags() {
+        // safaribooks-rs --epubcheck --strict 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--epubcheck", "--strict", "9781491958698"])
+                .unwrap();
+        assert!(args.epubcheck);
+        assert!(args.strict);
+        assert!(!args.no_credits);
+
+        // safaribooks-rs --no-credits 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--no-credits", "9781491958698"]).unwrap();
+        assert!(args.no_credits);
+    }
+
+    #[test]
+    fn parses_hooks() {
+        // safaribooks-rs --webhook URL --hook-command CMD 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--webhook",
+            "https://ntfy.example/books",
+            "--hook-command",
+            "cp -t ~/Sync",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(args.webhook.as_deref(), Some("https://ntfy.example/books"));
+        assert_eq!(args.hook_command.as_deref(), Some("cp -t ~/Sync"));
+        assert!(!args.notify);
+
+        // safaribooks-rs --notify 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "--notify", "9781491958698"]).unwrap();
+        assert!(args.notify);
+    }
+
+    #[test]
+    fn parses_metadata_overrides() {
+        // safaribooks-rs --set-title T --set-author A --set-author B --series S --series-index 2 --tags x,y 1
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--set-title",
+            "T",
+            "--set-author",
+            "A",
+            "--set-author",
+            "B",
+            "--series",
+            "S",
+            "--series-index",
+            "2",
+            "--tags",
+            "x,y",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(args.set_title.as_deref(), Some("T"));
+        assert_eq!(args.set_author, ["A", "B"]);
+        assert_eq!(args.series.as_deref(), Some("S"));
+        assert_eq!(args.series_index, Some(2.0));
+        assert_eq!(args.tags, ["x", "y"]);
+        assert!(Args::try_parse_from(["safaribooks-rs", "--series-index", "2", "1"]).is_err());
+        assert!(!args.series_from_title);
+
+        // safaribooks-rs --series-from-title 9781491958698 9781492052593
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--series-from-title",
+            "9781491958698",
+            "9781492052593",
+        ])
+        .unwrap();
+        assert!(args.series_from_title);
+    }
+
+    #[test]
+    fn parses_add_to_calibre_with_optional_library() {
+        // safaribooks-rs --add-to-calibre 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "9781491958698", "--add-to-calibre"]).unwrap();
+        assert_eq!(args.add_to_calibre, Some(None));
+
+        // safaribooks-rs --add-to-calibre ~/Calibre 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--add-to-calibre",
+            "/home/me/Calibre",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.add_to_calibre,
+            Some(Some(PathBuf::from("/home/me/Calibre")))
+        );
+        assert_eq!(ids(&args), ["9781491958698"]);
+    }
+
+    #[test]
+    fn parses_debug_http_flag() {
+        // safaribooks-rs --debug-http 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--debug-http", "9781491958698"]).unwrap();
+        assert!(args.debug_http);
+    }
+
+    #[test]
+    fn parses_verbosity_flags() {
+        // safaribooks-rs -vv 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "-vv", "9781491958698"]).unwrap();
+        assert_eq!(args.verbose, 2);
+        assert!(!args.quiet);
+
+        // safaribooks-rs -q 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "-q", "9781491958698"]).unwrap();
+        assert!(args.quiet);
+
+        let err =
+            Args::try_parse_from(["safaribooks-rs", "-q", "-v", "9781491958698"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn parses_color_choice() {
+        let args = Args::try_parse_from(["safaribooks-rs", "9781491958698"]).unwrap();
+        assert_eq!(args.color, ColorChoice::Auto);
+
+        // safaribooks-rs --color never 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--color", "never", "9781491958698"]).unwrap();
+        assert_eq!(args.color, ColorChoice::Never);
+
+        let args = Args::try_parse_from(["safaribooks-rs", "--no-color", "9781491958698"]).unwrap();
+        assert!(args.no_color);
+        assert!(Args::try_parse_from(["safaribooks-rs", "--color", "rainbow", "1"]).is_err());
+    }
+
+    #[test]
+    fn parses_language() {
+        // safaribooks-rs --lang es_ES 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--lang", "es_ES", "9781491958698"]).unwrap();
+        assert_eq!(args.lang.as_deref(), Some("es"));
+        assert!(Args::try_parse_from(["safaribooks-rs", "--lang", "tlh", "1"]).is_err());
+    }
+
+    #[test]
+    fn parses_output_format() {
+        // safaribooks-rs --output-format json 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--output-format", "json", "9781491958698"])
+                .unwrap();
+        assert_eq!(args.output_format, OutputFormat::Json);
+    }
+
+    #[test]
+    fn parses_verify_subcommand() {
+        // safaribooks-rs verify Books/x/123.epub
+        let args = Args::try_parse_from(["safaribooks-rs", "verify", "Books/x/123.epub"]).unwrap();
+        assert!(args.bookids.is_empty());
+        assert_eq!(
+            args.command,
+            Some(Command::Verify {
+                path: "Books/x/123.epub".into()
+            })
+        );
+
+        // safaribooks-rs --output-dir Books catalog
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--output-dir", "Books", "catalog"]).unwrap();
+        assert_eq!(args.command, Some(Command::Catalog));
+
+        // safaribooks-rs serve --listen 0.0.0.0:9000
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "serve", "--listen", "0.0.0.0:9000"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::Serve {
+                listen: "0.0.0.0:9000".parse().unwrap()
+            })
+        );
+        let args = Args::try_parse_from(["safaribooks-rs", "serve"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Serve { listen }) if listen.port() == 8080));
+
+        // safaribooks-rs --if-exists skip watch queue.txt --interval 60
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--if-exists",
+            "skip",
+            "watch",
+            "queue.txt",
+            "--interval",
+            "60",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::Watch {
+                path: "queue.txt".into(),
+                interval: 60
+            })
+        );
+    }
+
+    #[test]
+    fn parses_chapters_subcommand() {
+        // safaribooks-rs chapters 9781491958698 --json
+        let args = Args::try_parse_from(["safaribooks-rs", "chapters", "9781491958698", "--json"])
+            .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::Chapters {
+                bookid: "9781491958698".to_string(),
+                json: true,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_annotations_subcommand() {
+        // safaribooks-rs annotations 9781491958698 --format json -o notes.json
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "annotations",
+            "9781491958698",
+            "--format",
+            "json",
+            "-o",
+            "notes.json",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::Annotations {
+                bookid: "9781491958698".to_string(),
+                format: ExportFormat::Json,
+                output: Some(PathBuf::from("notes.json")),
+            })
+        );
+
+        // safaribooks-rs --embed-annotations 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "--embed-annotations", "9781491958698"])
+            .unwrap();
+        assert!(args.embed_annotations);
+        assert!(!args.bookmark);
+
+        // safaribooks-rs --bookmark 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "--bookmark", "9781491958698"]).unwrap();
+        assert!(args.bookmark);
+        assert!(!args.mirror);
+    }
+
+    #[test]
+    fn parses_code_style() {
+        // safaribooks-rs --code-style wrap 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--code-style", "wrap", "9781491958698"])
+                .unwrap();
+        assert_eq!(args.code_style, Some(CodeStyle::Wrap));
+
+        // safaribooks-rs --table-style split 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--table-style", "split", "9781491958698"])
+                .unwrap();
+        assert_eq!(args.table_style, Some(TableStyle::Split));
+        assert!(!args.rasterize_svg);
+
+        // safaribooks-rs --rasterize-svg 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--rasterize-svg", "9781491958698"]).unwrap();
+        assert!(args.rasterize_svg);
+
+        // safaribooks-rs --inline-images 4K 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--inline-images", "4K", "9781491958698"])
+                .unwrap();
+        assert_eq!(args.inline_images, Some(ByteSize(4 << 10)));
+
+        // safaribooks-rs --split-parts 200M 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--split-parts", "200M", "9781491958698"])
+                .unwrap();
+        assert_eq!(args.split_parts, Some(ByteSize(200 << 20)));
+
+        // safaribooks-rs --sidecar json,opf 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--sidecar", "json,opf", "9781491958698"])
+                .unwrap();
+        assert_eq!(args.sidecar, [SidecarFormat::Json, SidecarFormat::Opf]);
+
+        // safaribooks-rs --compression 0 --store-images 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--compression",
+            "0",
+            "--store-images",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert_eq!(args.compression, Some(0));
+        assert!(args.store_images);
+        assert!(!args.keep_workdir);
+        assert_eq!(args.format, None);
+
+        // safaribooks-rs --format epub 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--format", "epub", "9781491958698"]).unwrap();
+        assert_eq!(args.format, Some(Format::default()));
+        // safaribooks-rs --format txt 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--format", "txt", "9781491958698"]).unwrap();
+        assert_eq!(args.format.map(|f| f.0.name()), Some("txt"));
+        // safaribooks-rs --format mobi 9781491958698
+        assert!(
+            Args::try_parse_from(["safaribooks-rs", "--format", "mobi", "9781491958698"]).is_err()
+        );
+
+        // safaribooks-rs --keep-workdir 9781491958698
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--keep-workdir", "9781491958698"]).unwrap();
+        assert!(args.keep_workdir);
+        assert!(Args::try_parse_from(["safaribooks-rs", "--compression", "10", "1"]).is_err());
+
+        // safaribooks-rs --output - 9781491958698 > book.epub
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--output", "-", "9781491958698"]).unwrap();
+        assert_eq!(args.output, Some(PathBuf::from("-")));
+        assert!(Args::try_parse_from(["safaribooks-rs", "--code-style", "tiny", "1"]).is_err());
+    }
+
+    #[test]
+    fn parses_cover_and_metadata_only() {
+        // safaribooks-rs --cover-only --metadata-only 9781491958698
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--cover-only",
+            "--metadata-only",
+            "9781491958698",
+        ])
+        .unwrap();
+        assert!(args.cover_only && args.metadata_only);
+        assert!(Args::try_parse_from([
+            "safaribooks-rs",
+            "--metadata-only",
+            "--output",
+            "-",
+            "9781491958698"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn parses_mirror() {
+        // safaribooks-rs --mirror 9781491958698
+        let args = Args::try_parse_from(["safaribooks-rs", "--mirror", "9781491958698"]).unwrap();
+        assert!(args.mirror);
+
+        // safaribooks-rs rebuild "Books/Rust (123)"
+        let args = Args::try_parse_from(["safaribooks-rs", "rebuild", "Books/Rust (123)"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::Rebuild {
+                dir: PathBuf::from("Books/Rust (123)")
+            })
+        );
+    }
+
+    #[test]
+    fn parses_self_update() {
+        // safaribooks-rs self-update --check
+        let args = Args::try_parse_from(["safaribooks-rs", "self-update", "--check"]).unwrap();
+        assert_eq!(args.command, Some(Command::SelfUpdate { check: true }));
+    }
+
+    #[test]
+    fn parses_list_subcommand() {
+        // safaribooks-rs list --history --playlists
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "list", "--history", "--playlists"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::List {
+                history: true,
+                playlists: true,
+                downloaded: false
+            })
+        );
+        // safaribooks-rs list --downloaded
+        let args = Args::try_parse_from(["safaribooks-rs", "list", "--downloaded"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::List {
+                history: false,
+                playlists: false,
+                downloaded: true
+            })
+        );
+        // Something has to be listed.
+        assert!(Args::try_parse_from(["safaribooks-rs", "list"]).is_err());
+    }
+
     #[test]
     fn error_when_missing_bookid() {
         // safaribooks-rs --preserve-log
@@ -0,0 +1,296 @@
+//! `--code-style`: how program listings (`<pre>`) are laid out, since a
+//! listing sized for print runs off the side of a phone screen.
+
+use crate::epub::xml_escape;
+use crate::html::Token;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// Where `wrap` breaks a line, in characters.
+const WRAP_COLUMNS: usize = 60;
+
+/// Listings wider than this are drawn as images by `image`.
+const IMAGE_COLUMNS: usize = 80;
+
+/// Size of a character cell in drawings, in SVG units.
+pub const CHAR_WIDTH: usize = 6;
+pub const LINE_HEIGHT: usize = 12;
+pub const PADDING: usize = 8;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeStyle {
+    /// As published: long lines scroll, where the reader lets them.
+    #[default]
+    Scroll,
+    /// Break lines longer than 60 characters, marking each break with ↩.
+    Wrap,
+    /// A smaller font for listings.
+    Small,
+    /// Draw listings wider than 80 characters as images that scale to the
+    /// screen; the code stays in their alt text.
+    Image,
+}
+
+/// Markup drawn as an image, to write under OEBPS/.
+pub struct Drawing {
+    pub href: String,
+    pub svg: String,
+}
+
+/// The CSS for `style`.
+pub fn stylesheet(style: CodeStyle) -> Option<&'static str> {
+    match style {
+        CodeStyle::Scroll => None,
+        CodeStyle::Wrap => Some(
+            "pre { white-space: pre-wrap; overflow-wrap: anywhere; }\n\
+             .code-wrap { color: #888; }\n",
+        ),
+        CodeStyle::Small => Some("pre { font-size: 0.75em; line-height: 1.25; }\n"),
+        CodeStyle::Image => {
+            Some("img.code-listing { display: block; max-width: 100%; height: auto; }\n")
+        }
+    }
+}
+
+/// Lay out the listings of a chapter in `style`, adding the listings it
+/// draws to `drawings`.
+pub fn restyle(tokens: Vec<Token>, style: CodeStyle, drawings: &mut Vec<Drawing>) -> Vec<Token> {
+    let mut listings = Listings::new(style);
+    let mut out = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        listings.push(token, drawings, &mut out);
+    }
+    listings.finish(drawings, &mut out);
+    out
+}
+
+/// `restyle` a token at a time. Only a listing that may be drawn is held
+/// back, until its end tag.
+pub struct Listings {
+    style: CodeStyle,
+    /// How many `<pre>` are open.
+    depth: usize,
+    /// Column of the current line of a wrapped listing.
+    column: usize,
+    /// The listing being read, for `CodeStyle::Image`.
+    listing: Vec<Token>,
+}
+
+impl Listings {
+    pub fn new(style: CodeStyle) -> Self {
+        Listings {
+            style,
+            depth: 0,
+            column: 0,
+            listing: Vec::new(),
+        }
+    }
+
+    /// Lay out `token`, pushing what it becomes, if anything yet, to `out`.
+    pub fn push(&mut self, token: Token, drawings: &mut Vec<Drawing>, out: &mut Vec<Token>) {
+        let opens = opens_pre(&token);
+        if opens {
+            self.depth += 1;
+            self.column = 0;
+        } else if token.is_end("pre") {
+            self.depth = self.depth.saturating_sub(1);
+        }
+        match self.style {
+            CodeStyle::Scroll | CodeStyle::Small => out.push(token),
+            CodeStyle::Wrap => match token {
+                Token::Text(text) if self.depth > 0 => wrap_text(&text, &mut self.column, out),
+                token => out.push(token),
+            },
+            CodeStyle::Image if self.listing.is_empty() && !opens => out.push(token),
+            CodeStyle::Image => {
+                self.listing.push(token);
+                if self.depth == 0 {
+                    draw_if_wide(std::mem::take(&mut self.listing), drawings, out);
+                }
+            }
+        }
+    }
+
+    /// Push what is still held back: a listing never closed.
+    pub fn finish(self, drawings: &mut Vec<Drawing>, out: &mut Vec<Token>) {
+        if !self.listing.is_empty() {
+            draw_if_wide(self.listing, drawings, out);
+        }
+    }
+}
+
+fn opens_pre(token: &Token) -> bool {
+    token.is_start("pre")
+        && !matches!(
+            token,
+            Token::Start {
+                self_closing: true,
+                ..
+            }
+        )
+}
+
+/// Push `text` broken at `WRAP_COLUMNS`, carrying the column of the current
+/// line across the tokens of a highlighted listing.
+fn wrap_text(text: &str, column: &mut usize, out: &mut Vec<Token>) {
+    let mut chunk = String::new();
+    for c in text.chars() {
+        if c == '\n' {
+            *column = 0;
+        } else {
+            if *column >= WRAP_COLUMNS {
+                if !chunk.is_empty() {
+                    out.push(Token::Text(std::mem::take(&mut chunk)));
+                }
+                out.extend([
+                    Token::Start {
+                        name: "span".to_string(),
+                        attrs: vec![
+                            ("class".to_string(), "code-wrap".to_string()),
+                            ("aria-hidden".to_string(), "true".to_string()),
+                        ],
+                        self_closing: false,
+                    },
+                    Token::Text("\u{21a9}".to_string()),
+                    Token::End("span".to_string()),
+                ]);
+                chunk.push('\n');
+                *column = 0;
+            }
+            *column += 1;
+        }
+        chunk.push(c);
+    }
+    if !chunk.is_empty() {
+        out.push(Token::Text(chunk));
+    }
+}
+
+/// Push `listing`, from `<pre>` to `</pre>`, as it is if no line is wider
+/// than `IMAGE_COLUMNS`, and as a drawing of it otherwise.
+fn draw_if_wide(listing: Vec<Token>, drawings: &mut Vec<Drawing>, out: &mut Vec<Token>) {
+    let text: String = listing
+        .iter()
+        .filter_map(|t| match t {
+            Token::Text(text) => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    let lines = listing_lines(&text);
+    if lines.iter().all(|l| l.chars().count() <= IMAGE_COLUMNS) {
+        out.extend(listing);
+        return;
+    }
+
+    let href = format!("Images/listing-{:04}.svg", drawings.len() + 1);
+    let mut attrs = vec![
+        ("src".to_string(), href.clone()),
+        ("alt".to_string(), text.trim_end().to_string()),
+        ("class".to_string(), "code-listing".to_string()),
+    ];
+    // Links to the listing keep working.
+    if let Some(id) = listing[0].attr("id") {
+        attrs.push(("id".to_string(), id.to_string()));
+    }
+    out.push(Token::Start {
+        name: "img".to_string(),
+        attrs,
+        self_closing: true,
+    });
+    drawings.push(Drawing {
+        href,
+        svg: draw(&lines),
+    });
+}
+
+/// The lines of a listing as drawn, tabs expanded.
+fn listing_lines(text: &str) -> Vec<String> {
+    text.trim_end_matches('\n')
+        .lines()
+        .map(|l| l.replace('\t', "    "))
+        .collect()
+}
+
+/// An SVG drawing `lines` in a monospace font. Each line is stretched to
+/// its character count, so the drawing keeps its width whatever font the
+/// reader has.
+fn draw(lines: &[String]) -> String {
+    let columns = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let width = 2 * PADDING + columns * CHAR_WIDTH;
+    let height = 2 * PADDING + lines.len() * LINE_HEIGHT;
+    let mut svg = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">\n\
+         <rect width=\"100%\" height=\"100%\" fill=\"#f8f8f8\"/>\n\
+         <g font-family=\"monospace\" font-size=\"10\" fill=\"#222\" xml:space=\"preserve\">\n"
+    );
+    for (n, line) in lines.iter().enumerate() {
+        let chars = line.chars().count();
+        if line.trim().is_empty() {
+            continue;
+        }
+        svg.push_str(&format!(
+            "<text x=\"{PADDING}\" y=\"{}\" textLength=\"{}\" lengthAdjust=\"spacingAndGlyphs\">{}</text>\n",
+            PADDING + (n + 1) * LINE_HEIGHT - 3,
+            chars * CHAR_WIDTH,
+            xml_escape(line)
+        ));
+    }
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{restyle, CodeStyle};
+    use crate::html::{serialize_xhtml, tokenize};
+
+    fn restyled(html: &str, style: CodeStyle) -> (String, Vec<super::Drawing>) {
+        let mut drawings = Vec::new();
+        let tokens = restyle(tokenize(html), style, &mut drawings);
+        (serialize_xhtml(&tokens), drawings)
+    }
+
+    #[test]
+    fn wraps_long_lines_with_markers() {
+        let long = format!("{}{}", "a".repeat(40), "b".repeat(30));
+        let html = format!(
+            "<p>{long}</p><pre><span>{}</span>{}\nshort</pre>",
+            &long[..40],
+            &long[40..]
+        );
+        let (out, drawings) = restyled(&html, CodeStyle::Wrap);
+        assert!(drawings.is_empty());
+        assert_eq!(
+            out,
+            format!(
+                "<p>{long}</p><pre><span>{}</span>{}<span class=\"code-wrap\" aria-hidden=\"true\">\u{21a9}</span>\n{}\nshort</pre>",
+                "a".repeat(40),
+                "b".repeat(20),
+                "b".repeat(10)
+            )
+        );
+        let (out, _) = restyled(&html, CodeStyle::Small);
+        assert_eq!(out, serialize_xhtml(&tokenize(&html)));
+    }
+
+    #[test]
+    fn draws_wide_listings() {
+        let wide = "x".repeat(90);
+        let html = format!(
+            "<pre>fn main() {{}}</pre><pre id=\"ex1\"><b>let</b> a = \"{wide}\";\n\ta &lt; b</pre><p>after</p>"
+        );
+        let (out, drawings) = restyled(&html, CodeStyle::Image);
+        assert_eq!(
+            out,
+            format!(
+                "<pre>fn main() {{}}</pre><img src=\"Images/listing-0001.svg\" alt=\"let a = &quot;{wide}&quot;;\n\ta &lt; b\" class=\"code-listing\" id=\"ex1\"/><p>after</p>"
+            )
+        );
+        assert_eq!(drawings.len(), 1);
+        assert_eq!(drawings[0].href, "Images/listing-0001.svg");
+        assert!(drawings[0].svg.contains(r#"width="622""#));
+        assert!(drawings[0].svg.contains(">    a &lt; b</text>"));
+    }
+}
@@ -1,4 +1,35 @@
+use crate::cli::Args;
+use crate::code::CodeStyle;
+use crate::epub::IfExists;
+use crate::format::{BookFormat, Format};
+use crate::http_client::{AuthMode, IpFamily};
+use crate::i18n::t;
+use crate::orly::DEFAULT_BASE_URL;
+use crate::package::Compression;
+use crate::sidecar::SidecarFormat;
+use crate::table::TableStyle;
+use crate::throttle::Rate;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Concurrent requests per book when nothing else is configured.
+pub const DEFAULT_JOBS: usize = 4;
+
+/// Estimated book size above which the download asks first (1 GiB).
+const DEFAULT_CONFIRM_ABOVE: u64 = 1 << 30;
+
+/// Book directory under the output dir: "<title> (<bookid>)".
+pub const DEFAULT_DIR_TEMPLATE: &str = "{title} ({bookid})";
+
+/// EPUB file name (without extension) inside the book directory.
+pub const DEFAULT_FILE_TEMPLATE: &str = "{bookid}";
+
+/// Placeholders a naming template may use.
+const TEMPLATE_FIELDS: &[&str] = &["title", "bookid", "isbn", "author", "publisher", "year"];
 
 pub fn cookies_file() -> PathBuf {
     let exe = std::env::current_exe().unwrap();
@@ -9,3 +40,1298 @@ pub fn books_root() -> PathBuf {
     let exe = std::env::current_exe().unwrap();
     exe.parent().unwrap_or(Path::new(".")).join("Books")
 }
+
+/// Per-user configuration directory: $XDG_CONFIG_HOME (or ~/.config, or
+/// %APPDATA% on Windows) + "safaribooks-rs".
+pub fn config_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .or_else(|| std::env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("safaribooks-rs")
+}
+
+/// Per-user data directory: $XDG_DATA_HOME (or ~/.local/share, or
+/// %LOCALAPPDATA% on Windows) + "safaribooks-rs".
+pub fn data_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .or_else(|| std::env::var_os("LOCALAPPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/share")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("safaribooks-rs")
+}
+
+/// Where a download of `book_id` is logged unless `--log-file` says otherwise.
+pub fn default_log_file(book_id: &str) -> PathBuf {
+    data_dir().join("logs").join(format!("info_{book_id}.log"))
+}
+
+/// The download-state database.
+pub fn state_db_path() -> PathBuf {
+    data_dir().join("state.sqlite3")
+}
+
+/// The config file, overridable with SAFARIBOOKS_CONFIG.
+pub fn config_file() -> PathBuf {
+    std::env::var_os("SAFARIBOOKS_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| config_dir().join("config.json"))
+}
+
+/// Directory of a named profile: its `config.json` and `cookies.json`.
+pub fn profile_dir(name: &str) -> PathBuf {
+    config_dir().join("profiles").join(name)
+}
+
+/// Names of the existing profiles, sorted.
+pub fn list_profiles() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(config_dir().join("profiles")) else {
+        return Vec::new();
+    };
+    let mut names: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Profile names double as directory names and keyring accounts.
+fn validate_profile_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(t!("profile-name-invalid", name = format!("{name:?}")));
+    }
+    Ok(())
+}
+
+/// User settings, layered field by field: defaults < config file <
+/// profile config file < environment (SAFARIBOOKS_*) < CLI flags.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Named profile (own cookies, output dir and settings).
+    pub profile: Option<String>,
+    /// Path of the cookies JSON ("-" for stdin).
+    pub cookies_file: Option<PathBuf>,
+    /// Inline cookies: a raw `Cookie` header ("a=1; b=2") or cookies JSON.
+    pub cookies: Option<String>,
+    /// Site root for white-labeled or proxied access (default learning.oreilly.com).
+    pub base_url: Option<String>,
+    /// User-Agent to send, or "random" for one of a few browsers.
+    pub user_agent: Option<String>,
+    /// Extra request headers, "Name: value".
+    pub headers: Option<Vec<String>>,
+    /// PEM file of extra CA certificates to trust.
+    pub ca_cert: Option<PathBuf>,
+    /// Skip TLS certificate verification.
+    pub insecure: Option<bool>,
+    /// "host:address" entries used instead of DNS.
+    pub resolve: Option<Vec<String>>,
+    /// Connect over "ipv4" or "ipv6" only, or "any".
+    pub ip_family: Option<IpFamily>,
+    /// How requests authenticate: "cookies" or "bearer".
+    pub auth: Option<AuthMode>,
+    /// Keep session cookies in the OS keyring.
+    pub keyring: Option<bool>,
+    /// Root directory where books are written.
+    pub output_dir: Option<PathBuf>,
+    /// Concurrent requests per book.
+    pub jobs: Option<usize>,
+    /// Books downloaded at once when several are given.
+    pub parallel_books: Option<usize>,
+    /// Most bytes per second read across all downloads ("2M", "500K").
+    pub limit_rate: Option<Rate>,
+    /// One request at a time, after a random pause (`--stealth`).
+    pub stealth: Option<bool>,
+    /// When more than this percentage of the recent requests fail, start
+    /// the book over one request at a time; 100 never does.
+    pub degrade_above: Option<u8>,
+    /// Log file path (default under the data directory).
+    pub log_file: Option<PathBuf>,
+    /// Keep the log file after a successful run.
+    pub preserve_log: Option<bool>,
+    /// Book directory template, e.g. "{author}/{title} ({isbn})".
+    pub dir_template: Option<String>,
+    /// EPUB file name template, e.g. "{title}".
+    pub file_template: Option<String>,
+    /// Longest file name to create (default: what the filesystem reports, or 255).
+    pub name_max: Option<usize>,
+    /// Transliterate names to ASCII ("Café" -> "Cafe").
+    pub ascii_names: Option<bool>,
+    /// Lay books out as safaribooks.py does; takes no templates.
+    pub legacy_names: Option<bool>,
+    /// What to do when the book directory exists: skip, overwrite, resume or rename.
+    pub if_exists: Option<IfExists>,
+    /// Run epubcheck on the finished EPUB.
+    pub epubcheck: Option<bool>,
+    /// The epubcheck program (or its .jar) when it isn't on PATH.
+    pub epubcheck_path: Option<PathBuf>,
+    /// Fail the run when assets are missing, or the package check or
+    /// epubcheck reports errors.
+    pub strict: Option<bool>,
+    /// End the book with a generated colophon (tool, download date, source).
+    pub credits: Option<bool>,
+    /// Append the reader's highlights and notes as an appendix.
+    pub embed_annotations: Option<bool>,
+    /// Start the book where the web reader left off, and save that position
+    /// next to the EPUB.
+    pub bookmark: Option<bool>,
+    /// Keep the responses the book was built from under raw/ in its directory.
+    pub mirror: Option<bool>,
+    /// How program listings are laid out: scroll, wrap, small or image.
+    pub code_style: Option<CodeStyle>,
+    /// What becomes of tables too wide for the screen: scroll, split or image.
+    pub table_style: Option<TableStyle>,
+    /// Convert SVG images to PNG with rsvg-convert.
+    pub rasterize_svg: Option<bool>,
+    /// Embed images smaller than this ("4K") in the chapters as data URIs.
+    pub inline_images: Option<ByteSize>,
+    /// Also write books larger than this ("200M") as several part EPUBs.
+    pub split_parts: Option<ByteSize>,
+    /// Metadata files written next to the EPUB: ["json", "opf"].
+    pub sidecar: Option<Vec<SidecarFormat>>,
+    /// Guess the series from titles like "Head First Go" or "Fluent
+    /// Python, 2nd Edition" when the API names none.
+    pub series_from_title: Option<bool>,
+    /// What the book is delivered as ("epub").
+    pub format: Option<Format>,
+    /// Deflate level of the EPUB: 0 stores, 9 is smallest.
+    pub compression: Option<u8>,
+    /// Store images in the EPUB rather than deflate them.
+    pub store_images: Option<bool>,
+    /// Keep the unpacked book after packaging it.
+    pub keep_workdir: Option<bool>,
+    /// Ask before downloading a book estimated larger than this ("1G").
+    pub confirm_above: Option<ByteSize>,
+    /// URL POSTed a JSON report after each download.
+    pub webhook_url: Option<String>,
+    /// Shell command run with the path of each finished EPUB.
+    pub hook_command: Option<String>,
+    /// Show a desktop notification when each download ends.
+    pub notify: Option<bool>,
+}
+
+impl Settings {
+    /// Read the JSON config file; a missing file is an empty layer.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let raw = fs::read_to_string(path)
+            .with_context(|| t!("reading-file", path = path.display().to_string()))?;
+        serde_json::from_str(&raw)
+            .with_context(|| t!("parsing-file", path = path.display().to_string()))
+    }
+
+    /// Read the SAFARIBOOKS_* variables through `var` (std::env::var in production).
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let get = |name: &str| var(name).filter(|v| !v.is_empty());
+        Ok(Self {
+            profile: get("SAFARIBOOKS_PROFILE"),
+            cookies_file: get("SAFARIBOOKS_COOKIES_FILE").map(PathBuf::from),
+            cookies: get("SAFARIBOOKS_COOKIES"),
+            base_url: get("SAFARIBOOKS_BASE_URL"),
+            user_agent: get("SAFARIBOOKS_USER_AGENT"),
+            ca_cert: get("SAFARIBOOKS_CA_CERT").map(PathBuf::from),
+            insecure: get("SAFARIBOOKS_INSECURE")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_INSECURE"))
+                })
+                .transpose()?,
+            // One header per line.
+            headers: get("SAFARIBOOKS_HEADERS").map(|v| {
+                v.lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty())
+                    .map(String::from)
+                    .collect()
+            }),
+            // Separated by commas or whitespace.
+            resolve: get("SAFARIBOOKS_RESOLVE").map(|v| {
+                v.split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|e| !e.is_empty())
+                    .map(String::from)
+                    .collect()
+            }),
+            ip_family: get("SAFARIBOOKS_IP_FAMILY")
+                .map(|v| {
+                    IpFamily::from_str(&v, true).map_err(|_| {
+                        anyhow!(t!(
+                            "env-not-one-of",
+                            name = "SAFARIBOOKS_IP_FAMILY",
+                            values = "any, ipv4, ipv6"
+                        ))
+                    })
+                })
+                .transpose()?,
+            auth: get("SAFARIBOOKS_AUTH")
+                .map(|v| {
+                    AuthMode::from_str(&v, true).map_err(|_| {
+                        anyhow!(t!(
+                            "env-not-one-of",
+                            name = "SAFARIBOOKS_AUTH",
+                            values = "cookies, bearer"
+                        ))
+                    })
+                })
+                .transpose()?,
+            keyring: get("SAFARIBOOKS_KEYRING")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_KEYRING"))
+                })
+                .transpose()?,
+            output_dir: get("SAFARIBOOKS_OUTPUT_DIR").map(PathBuf::from),
+            jobs: get("SAFARIBOOKS_JOBS")
+                .map(|v| {
+                    v.parse()
+                        .with_context(|| t!("env-not-number", name = "SAFARIBOOKS_JOBS"))
+                })
+                .transpose()?,
+            parallel_books: get("SAFARIBOOKS_PARALLEL_BOOKS")
+                .map(|v| {
+                    v.parse()
+                        .with_context(|| t!("env-not-number", name = "SAFARIBOOKS_PARALLEL_BOOKS"))
+                })
+                .transpose()?,
+            limit_rate: get("SAFARIBOOKS_LIMIT_RATE")
+                .map(|v| {
+                    v.parse().with_context(|| {
+                        t!(
+                            "env-not-rate",
+                            name = "SAFARIBOOKS_LIMIT_RATE",
+                            example = "2M"
+                        )
+                    })
+                })
+                .transpose()?,
+            stealth: get("SAFARIBOOKS_STEALTH")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_STEALTH"))
+                })
+                .transpose()?,
+            degrade_above: get("SAFARIBOOKS_DEGRADE_ABOVE")
+                .map(|v| {
+                    v.parse()
+                        .ok()
+                        .filter(|p| (1..=100).contains(p))
+                        .with_context(|| {
+                            t!("env-not-percentage", name = "SAFARIBOOKS_DEGRADE_ABOVE")
+                        })
+                })
+                .transpose()?,
+            log_file: get("SAFARIBOOKS_LOG_FILE").map(PathBuf::from),
+            preserve_log: get("SAFARIBOOKS_PRESERVE_LOG")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_PRESERVE_LOG"))
+                })
+                .transpose()?,
+            dir_template: get("SAFARIBOOKS_DIR_TEMPLATE"),
+            file_template: get("SAFARIBOOKS_FILE_TEMPLATE"),
+            name_max: get("SAFARIBOOKS_NAME_MAX")
+                .map(|v| {
+                    v.parse()
+                        .with_context(|| t!("env-not-number", name = "SAFARIBOOKS_NAME_MAX"))
+                })
+                .transpose()?,
+            ascii_names: get("SAFARIBOOKS_ASCII_NAMES")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_ASCII_NAMES"))
+                })
+                .transpose()?,
+            legacy_names: get("SAFARIBOOKS_LEGACY_NAMES")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_LEGACY_NAMES"))
+                })
+                .transpose()?,
+            if_exists: get("SAFARIBOOKS_IF_EXISTS")
+                .map(|v| {
+                    IfExists::from_str(&v, true).map_err(|_| {
+                        anyhow!(t!(
+                            "env-not-one-of",
+                            name = "SAFARIBOOKS_IF_EXISTS",
+                            values = "skip, overwrite, resume, rename"
+                        ))
+                    })
+                })
+                .transpose()?,
+            epubcheck: get("SAFARIBOOKS_EPUBCHECK")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_EPUBCHECK"))
+                })
+                .transpose()?,
+            epubcheck_path: get("SAFARIBOOKS_EPUBCHECK_PATH").map(PathBuf::from),
+            strict: get("SAFARIBOOKS_STRICT")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_STRICT"))
+                })
+                .transpose()?,
+            credits: get("SAFARIBOOKS_CREDITS")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_CREDITS"))
+                })
+                .transpose()?,
+            embed_annotations: get("SAFARIBOOKS_EMBED_ANNOTATIONS")
+                .map(|v| {
+                    parse_bool(&v).with_context(|| {
+                        t!("env-not-boolean", name = "SAFARIBOOKS_EMBED_ANNOTATIONS")
+                    })
+                })
+                .transpose()?,
+            bookmark: get("SAFARIBOOKS_BOOKMARK")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_BOOKMARK"))
+                })
+                .transpose()?,
+            mirror: get("SAFARIBOOKS_MIRROR")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_MIRROR"))
+                })
+                .transpose()?,
+            code_style: get("SAFARIBOOKS_CODE_STYLE")
+                .map(|v| {
+                    CodeStyle::from_str(&v, true).map_err(|_| {
+                        anyhow!(t!(
+                            "env-not-one-of",
+                            name = "SAFARIBOOKS_CODE_STYLE",
+                            values = "scroll, wrap, small, image"
+                        ))
+                    })
+                })
+                .transpose()?,
+            table_style: get("SAFARIBOOKS_TABLE_STYLE")
+                .map(|v| {
+                    TableStyle::from_str(&v, true).map_err(|_| {
+                        anyhow!(t!(
+                            "env-not-one-of",
+                            name = "SAFARIBOOKS_TABLE_STYLE",
+                            values = "scroll, split, image"
+                        ))
+                    })
+                })
+                .transpose()?,
+            rasterize_svg: get("SAFARIBOOKS_RASTERIZE_SVG")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_RASTERIZE_SVG"))
+                })
+                .transpose()?,
+            inline_images: get("SAFARIBOOKS_INLINE_IMAGES")
+                .map(|v| {
+                    v.parse().with_context(|| {
+                        t!(
+                            "env-not-size",
+                            name = "SAFARIBOOKS_INLINE_IMAGES",
+                            example = "4K"
+                        )
+                    })
+                })
+                .transpose()?,
+            split_parts: get("SAFARIBOOKS_SPLIT_PARTS")
+                .map(|v| {
+                    v.parse().with_context(|| {
+                        t!(
+                            "env-not-size",
+                            name = "SAFARIBOOKS_SPLIT_PARTS",
+                            example = "200M"
+                        )
+                    })
+                })
+                .transpose()?,
+            sidecar: get("SAFARIBOOKS_SIDECAR")
+                .map(|v| {
+                    v.split(',')
+                        .map(|format| {
+                            SidecarFormat::from_str(format.trim(), true).map_err(|_| {
+                                anyhow!(t!(
+                                    "env-not-one-of",
+                                    name = "SAFARIBOOKS_SIDECAR",
+                                    values = "json, opf, \"json,opf\""
+                                ))
+                            })
+                        })
+                        .collect::<Result<Vec<_>>>()
+                })
+                .transpose()?,
+            series_from_title: get("SAFARIBOOKS_SERIES_FROM_TITLE")
+                .map(|v| {
+                    parse_bool(&v).with_context(|| {
+                        t!("env-not-boolean", name = "SAFARIBOOKS_SERIES_FROM_TITLE")
+                    })
+                })
+                .transpose()?,
+            format: get("SAFARIBOOKS_FORMAT")
+                .map(|v| {
+                    v.parse()
+                        .with_context(|| t!("env-invalid", name = "SAFARIBOOKS_FORMAT"))
+                })
+                .transpose()?,
+            compression: get("SAFARIBOOKS_COMPRESSION")
+                .map(|v| {
+                    v.parse()
+                        .ok()
+                        .filter(|level| *level <= 9)
+                        .with_context(|| t!("env-not-level", name = "SAFARIBOOKS_COMPRESSION"))
+                })
+                .transpose()?,
+            store_images: get("SAFARIBOOKS_STORE_IMAGES")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_STORE_IMAGES"))
+                })
+                .transpose()?,
+            keep_workdir: get("SAFARIBOOKS_KEEP_WORKDIR")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_KEEP_WORKDIR"))
+                })
+                .transpose()?,
+            confirm_above: get("SAFARIBOOKS_CONFIRM_ABOVE")
+                .map(|v| {
+                    v.parse().with_context(|| {
+                        t!(
+                            "env-not-size",
+                            name = "SAFARIBOOKS_CONFIRM_ABOVE",
+                            example = "500M"
+                        )
+                    })
+                })
+                .transpose()?,
+            webhook_url: get("SAFARIBOOKS_WEBHOOK_URL"),
+            hook_command: get("SAFARIBOOKS_HOOK_COMMAND"),
+            notify: get("SAFARIBOOKS_NOTIFY")
+                .map(|v| {
+                    parse_bool(&v)
+                        .with_context(|| t!("env-not-boolean", name = "SAFARIBOOKS_NOTIFY"))
+                })
+                .transpose()?,
+        })
+    }
+
+    /// The layer given on the command line.
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            profile: args.profile.clone(),
+            cookies_file: args.cookies_file.clone(),
+            cookies: None,
+            base_url: args.base_url.clone(),
+            user_agent: args.user_agent.clone(),
+            headers: (!args.header.is_empty()).then(|| args.header.clone()),
+            ca_cert: args.ca_cert.clone(),
+            insecure: args.insecure.then_some(true),
+            resolve: (!args.resolve.is_empty()).then(|| args.resolve.clone()),
+            ip_family: if args.ipv4 {
+                Some(IpFamily::Ipv4)
+            } else if args.ipv6 {
+                Some(IpFamily::Ipv6)
+            } else {
+                None
+            },
+            auth: args.auth,
+            keyring: args.no_keyring.then_some(false),
+            output_dir: args.output_dir.clone(),
+            jobs: args.jobs,
+            parallel_books: args.parallel_books,
+            limit_rate: args.limit_rate,
+            stealth: args.stealth.then_some(true),
+            degrade_above: args.degrade_above,
+            log_file: args.log_file.clone(),
+            preserve_log: args.preserve_log.then_some(true),
+            dir_template: args.dir_template.clone(),
+            file_template: args.file_template.clone(),
+            name_max: args.name_max,
+            ascii_names: args.ascii_names.then_some(true),
+            legacy_names: args.legacy_names.then_some(true),
+            if_exists: args.if_exists,
+            epubcheck: args.epubcheck.then_some(true),
+            epubcheck_path: None,
+            strict: args.strict.then_some(true),
+            credits: args.no_credits.then_some(false),
+            embed_annotations: args.embed_annotations.then_some(true),
+            bookmark: args.bookmark.then_some(true),
+            mirror: args.mirror.then_some(true),
+            code_style: args.code_style,
+            table_style: args.table_style,
+            rasterize_svg: args.rasterize_svg.then_some(true),
+            inline_images: args.inline_images,
+            split_parts: args.split_parts,
+            sidecar: (!args.sidecar.is_empty()).then(|| args.sidecar.clone()),
+            series_from_title: args.series_from_title.then_some(true),
+            format: args.format,
+            compression: args.compression,
+            store_images: args.store_images.then_some(true),
+            keep_workdir: args.keep_workdir.then_some(true),
+            confirm_above: args.confirm_above,
+            webhook_url: args.webhook.clone(),
+            hook_command: args.hook_command.clone(),
+            notify: args.notify.then_some(true),
+        }
+    }
+
+    /// Overlay `over` on top of `self`: every field `over` sets wins.
+    /// The two cookie sources move together so a higher layer naming a
+    /// cookies file isn't shadowed by inline cookies from a lower one.
+    pub fn merge(self, over: Self) -> Self {
+        let (cookies, cookies_file) = if over.cookies.is_some() || over.cookies_file.is_some() {
+            (over.cookies, over.cookies_file)
+        } else {
+            (self.cookies, self.cookies_file)
+        };
+        Self {
+            profile: over.profile.or(self.profile),
+            cookies_file,
+            cookies,
+            base_url: over.base_url.or(self.base_url),
+            user_agent: over.user_agent.or(self.user_agent),
+            headers: over.headers.or(self.headers),
+            ca_cert: over.ca_cert.or(self.ca_cert),
+            insecure: over.insecure.or(self.insecure),
+            resolve: over.resolve.or(self.resolve),
+            ip_family: over.ip_family.or(self.ip_family),
+            auth: over.auth.or(self.auth),
+            keyring: over.keyring.or(self.keyring),
+            output_dir: over.output_dir.or(self.output_dir),
+            jobs: over.jobs.or(self.jobs),
+            parallel_books: over.parallel_books.or(self.parallel_books),
+            limit_rate: over.limit_rate.or(self.limit_rate),
+            stealth: over.stealth.or(self.stealth),
+            degrade_above: over.degrade_above.or(self.degrade_above),
+            log_file: over.log_file.or(self.log_file),
+            preserve_log: over.preserve_log.or(self.preserve_log),
+            dir_template: over.dir_template.or(self.dir_template),
+            file_template: over.file_template.or(self.file_template),
+            name_max: over.name_max.or(self.name_max),
+            ascii_names: over.ascii_names.or(self.ascii_names),
+            legacy_names: over.legacy_names.or(self.legacy_names),
+            if_exists: over.if_exists.or(self.if_exists),
+            epubcheck: over.epubcheck.or(self.epubcheck),
+            epubcheck_path: over.epubcheck_path.or(self.epubcheck_path),
+            strict: over.strict.or(self.strict),
+            credits: over.credits.or(self.credits),
+            embed_annotations: over.embed_annotations.or(self.embed_annotations),
+            bookmark: over.bookmark.or(self.bookmark),
+            mirror: over.mirror.or(self.mirror),
+            code_style: over.code_style.or(self.code_style),
+            table_style: over.table_style.or(self.table_style),
+            rasterize_svg: over.rasterize_svg.or(self.rasterize_svg),
+            inline_images: over.inline_images.or(self.inline_images),
+            split_parts: over.split_parts.or(self.split_parts),
+            sidecar: over.sidecar.or(self.sidecar),
+            series_from_title: over.series_from_title.or(self.series_from_title),
+            format: over.format.or(self.format),
+            compression: over.compression.or(self.compression),
+            store_images: over.store_images.or(self.store_images),
+            keep_workdir: over.keep_workdir.or(self.keep_workdir),
+            confirm_above: over.confirm_above.or(self.confirm_above),
+            webhook_url: over.webhook_url.or(self.webhook_url),
+            hook_command: over.hook_command.or(self.hook_command),
+            notify: over.notify.or(self.notify),
+        }
+    }
+
+    /// Load every layer for this invocation.
+    pub fn load(args: &Args) -> Result<Self> {
+        let file = Self::from_file(&config_file())?;
+        let env = Self::from_env(|name| std::env::var(name).ok())?;
+        let cli = Self::from_args(args);
+
+        let chosen = cli
+            .profile
+            .as_ref()
+            .or(env.profile.as_ref())
+            .or(file.profile.as_ref());
+        let profile = match chosen {
+            Some(name) => Self::profile_layer(name)?,
+            None => Self::default(),
+        };
+        Ok(file.merge(profile).merge(env).merge(cli))
+    }
+
+    /// The config file of profile `name`, which must exist.
+    fn profile_layer(name: &str) -> Result<Self> {
+        validate_profile_name(name)?;
+        let dir = profile_dir(name);
+        if !dir.is_dir() {
+            let known = list_profiles();
+            bail!(t!(
+                "profile-unknown",
+                name = format!("{name:?}"),
+                path = dir.display().to_string(),
+                known = if known.is_empty() {
+                    t!("profiles-none")
+                } else {
+                    known.join(", ")
+                }
+            ));
+        }
+        let layer = Self::from_file(&dir.join("config.json"))?;
+        Ok(Self {
+            profile: Some(name.to_string()),
+            ..layer
+        })
+    }
+
+    /// cookies.json of the profile, or next to the executable.
+    pub fn default_cookies_file(&self) -> PathBuf {
+        match &self.profile {
+            Some(name) => profile_dir(name).join("cookies.json"),
+            None => cookies_file(),
+        }
+    }
+
+    pub fn output_dir(&self) -> PathBuf {
+        self.output_dir.clone().unwrap_or_else(books_root)
+    }
+
+    /// Requests in flight per book; `--stealth` makes only one at a time.
+    pub fn jobs(&self) -> usize {
+        if self.stealth() {
+            return 1;
+        }
+        self.jobs.unwrap_or(DEFAULT_JOBS).max(1)
+    }
+
+    pub fn insecure(&self) -> bool {
+        self.insecure.unwrap_or(false)
+    }
+
+    pub fn stealth(&self) -> bool {
+        self.stealth.unwrap_or(false)
+    }
+
+    /// Half the recent requests failing means the site is, not a few assets.
+    pub fn degrade_above(&self) -> u8 {
+        self.degrade_above.unwrap_or(50)
+    }
+
+    pub fn parallel_books(&self) -> usize {
+        self.parallel_books.unwrap_or(1).max(1)
+    }
+
+    pub fn base_url(&self) -> &str {
+        self.base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+    }
+
+    pub fn auth(&self) -> AuthMode {
+        self.auth.unwrap_or_default()
+    }
+
+    /// Host of `base_url()`, for messages.
+    pub fn site_host(&self) -> &str {
+        let rest = self.base_url().split_once("://").map_or("", |(_, r)| r);
+        rest.split(['/', ':']).next().unwrap_or(rest)
+    }
+
+    pub fn log_file(&self, book_id: &str) -> PathBuf {
+        self.log_file
+            .clone()
+            .unwrap_or_else(|| default_log_file(book_id))
+    }
+
+    pub fn preserve_log(&self) -> bool {
+        self.preserve_log.unwrap_or(false)
+    }
+
+    pub fn if_exists(&self) -> IfExists {
+        self.if_exists.unwrap_or_default()
+    }
+
+    pub fn epubcheck(&self) -> bool {
+        self.epubcheck.unwrap_or(false)
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict.unwrap_or(false)
+    }
+
+    pub fn embed_annotations(&self) -> bool {
+        self.embed_annotations.unwrap_or(false)
+    }
+
+    pub fn bookmark(&self) -> bool {
+        self.bookmark.unwrap_or(false)
+    }
+
+    pub fn mirror(&self) -> bool {
+        self.mirror.unwrap_or(false)
+    }
+
+    pub fn code_style(&self) -> CodeStyle {
+        self.code_style.unwrap_or_default()
+    }
+
+    pub fn table_style(&self) -> TableStyle {
+        self.table_style.unwrap_or_default()
+    }
+
+    pub fn rasterize_svg(&self) -> bool {
+        self.rasterize_svg.unwrap_or(false)
+    }
+
+    /// Images below this many bytes are inlined; None inlines none.
+    pub fn inline_images(&self) -> Option<u64> {
+        self.inline_images.map(|s| s.0)
+    }
+
+    /// Largest size of a part EPUB; None writes the book whole only.
+    pub fn split_parts(&self) -> Option<u64> {
+        self.split_parts.map(|s| s.0)
+    }
+
+    /// The metadata files to write next to the EPUB; none by default.
+    pub fn sidecar(&self) -> &[SidecarFormat] {
+        self.sidecar.as_deref().unwrap_or_default()
+    }
+
+    pub fn series_from_title(&self) -> bool {
+        self.series_from_title.unwrap_or(false)
+    }
+
+    pub fn keep_workdir(&self) -> bool {
+        self.keep_workdir.unwrap_or(false)
+    }
+
+    pub fn format(&self) -> &'static dyn BookFormat {
+        self.format.unwrap_or_default().0
+    }
+
+    /// How the EPUB is zipped.
+    pub fn compression(&self) -> Compression {
+        Compression {
+            level: self
+                .compression
+                .map_or(Compression::default().level, |l| l.min(9)),
+            store_images: self.store_images.unwrap_or(false),
+        }
+    }
+
+    pub fn confirm_above(&self) -> u64 {
+        self.confirm_above.map_or(DEFAULT_CONFIRM_ABOVE, |s| s.0)
+    }
+
+    pub fn credits(&self) -> bool {
+        self.credits.unwrap_or(true)
+    }
+
+    pub fn notify(&self) -> bool {
+        self.notify.unwrap_or(false)
+    }
+
+    pub fn use_keyring(&self) -> bool {
+        self.keyring.unwrap_or(true)
+    }
+
+    /// The parsed output layout templates.
+    pub fn naming(&self) -> Result<Naming> {
+        let legacy = self.legacy_names.unwrap_or(false);
+        if legacy && (self.dir_template.is_some() || self.file_template.is_some()) {
+            bail!(t!("legacy-names-with-templates"));
+        }
+        let dir = self.dir_template.as_deref().unwrap_or(DEFAULT_DIR_TEMPLATE);
+        let file = self
+            .file_template
+            .as_deref()
+            .unwrap_or(DEFAULT_FILE_TEMPLATE);
+        let dir = NamingTemplate::parse(dir).with_context(|| t!("template-dir-invalid"))?;
+        let file = NamingTemplate::parse(file).with_context(|| t!("template-file-invalid"))?;
+        if file.components.len() != 1 {
+            bail!(t!("template-file-slash"));
+        }
+        Ok(Naming {
+            dir,
+            file,
+            ascii: self.ascii_names.unwrap_or(false),
+            legacy,
+        })
+    }
+}
+
+/// Where a book lands: its directory (relative to the output dir) and EPUB name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Naming {
+    pub dir: NamingTemplate,
+    pub file: NamingTemplate,
+    /// Transliterate field values to ASCII.
+    pub ascii: bool,
+    /// safaribooks.py's names: its title escaping with the default
+    /// templates (the same as its layout), and assets named like it does.
+    pub legacy: bool,
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Settings::default()
+            .naming()
+            .expect("default templates are valid")
+    }
+}
+
+/// A path template such as "{author}/{title} ({isbn})": literal text and
+/// `{field}` placeholders, with '/' separating directory levels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamingTemplate {
+    components: Vec<Vec<Piece>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Field(String),
+}
+
+impl NamingTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut components = Vec::new();
+        for raw in template.split(['/', '\\']) {
+            let mut pieces = Vec::new();
+            let mut rest = raw;
+            while let Some(start) = rest.find(['{', '}']) {
+                if rest[start..].starts_with('}') {
+                    bail!(t!(
+                        "template-unmatched-brace",
+                        template = format!("{template:?}")
+                    ));
+                }
+                let Some(len) = rest[start + 1..].find('}') else {
+                    bail!(t!(
+                        "template-unclosed-brace",
+                        template = format!("{template:?}")
+                    ));
+                };
+                let name = &rest[start + 1..start + 1 + len];
+                if !TEMPLATE_FIELDS.contains(&name) {
+                    bail!(t!(
+                        "template-unknown-field",
+                        name = name,
+                        fields = TEMPLATE_FIELDS.join(", ")
+                    ));
+                }
+                if start > 0 {
+                    pieces.push(Piece::Text(rest[..start].to_string()));
+                }
+                pieces.push(Piece::Field(name.to_string()));
+                rest = &rest[start + len + 2..];
+            }
+            if !rest.is_empty() {
+                pieces.push(Piece::Text(rest.to_string()));
+            }
+            let literal_only = pieces.iter().all(|p| matches!(p, Piece::Text(_)));
+            if pieces.is_empty() || (literal_only && matches!(raw.trim(), "" | "." | "..")) {
+                bail!(t!(
+                    "template-empty-component",
+                    template = format!("{template:?}")
+                ));
+            }
+            components.push(pieces);
+        }
+        Ok(Self { components })
+    }
+
+    /// Fill in each path component, looking placeholders up through `field`.
+    /// Brackets left empty by a missing field ("Title ()") are dropped.
+    pub fn render(&self, field: impl Fn(&str) -> String) -> Vec<String> {
+        self.components
+            .iter()
+            .map(|pieces| {
+                let mut out = String::new();
+                for piece in pieces {
+                    match piece {
+                        Piece::Text(text) => out.push_str(text),
+                        Piece::Field(name) => out.push_str(&field(name)),
+                    }
+                }
+                for empty in ["()", "[]"] {
+                    out = out.replace(empty, "");
+                }
+                out
+            })
+            .collect()
+    }
+}
+
+fn parse_bool(v: &str) -> Result<bool> {
+    match v.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => bail!(t!("invalid-boolean", value = format!("{v:?}"))),
+    }
+}
+
+/// A byte count: a number with an optional K, M or G suffix (powers of
+/// 1024), e.g. "2M" or "1.5G".
+pub fn parse_bytes(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let scale: u64 = match s[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        unit => bail!(t!(
+            "size-unknown-unit",
+            unit = format!("{unit:?}"),
+            size = format!("{s:?}")
+        )),
+    };
+    let value: f64 = digits
+        .parse()
+        .map_err(|_| anyhow!(t!("size-invalid", size = format!("{s:?}"))))?;
+    let bytes = value * scale as f64;
+    // `as` would quietly make a negative size 0 and a huge one u64::MAX.
+    if !(0.0..=u64::MAX as f64).contains(&bytes) {
+        bail!(t!("size-invalid", size = format!("{s:?}")));
+    }
+    Ok(bytes as u64)
+}
+
+/// A size setting written like "500M" (see `parse_bytes`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_bytes(s).map(Self)
+    }
+}
+
+impl TryFrom<String> for ByteSize {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ByteSize, NamingTemplate, Rate, Settings};
+    use crate::cli::Args;
+    use crate::epub::IfExists;
+    use crate::http_client::AuthMode;
+    use crate::sidecar::SidecarFormat;
+    use clap::Parser;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn env(vars: &[(&str, &str)]) -> Settings {
+        let vars: HashMap<_, _> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Settings::from_env(|name| vars.get(name).cloned()).unwrap()
+    }
+
+    #[test]
+    fn env_overrides_file_and_cli_overrides_env() {
+        let file: Settings =
+            serde_json::from_str(r#"{ "output_dir": "/file/books", "jobs": 2 }"#).unwrap();
+        let env = env(&[("SAFARIBOOKS_OUTPUT_DIR", "/env/books")]);
+        let args = Args::try_parse_from(["safaribooks-rs", "--jobs", "8", "123"]).unwrap();
+
+        let s = file.merge(env).merge(Settings::from_args(&args));
+        assert_eq!(s.output_dir(), PathBuf::from("/env/books"));
+        assert_eq!(s.jobs(), 8);
+
+        let s = serde_json::from_str::<Settings>(r#"{ "jobs": 2 }"#)
+            .unwrap()
+            .merge(Settings::default());
+        assert_eq!(s.jobs(), 2);
+    }
+
+    #[test]
+    fn cookie_sources_move_together() {
+        let env = env(&[("SAFARIBOOKS_COOKIES", "orm-jwt=a; groot_sessionid=b")]);
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--cookies-file", "c.json", "123"]).unwrap();
+
+        let s = env.clone().merge(Settings::from_args(&args));
+        assert_eq!(s.cookies, None);
+        assert_eq!(s.cookies_file, Some(PathBuf::from("c.json")));
+
+        let args = Args::try_parse_from(["safaribooks-rs", "123"]).unwrap();
+        let s = env.merge(Settings::from_args(&args));
+        assert_eq!(s.cookies.as_deref(), Some("orm-jwt=a; groot_sessionid=b"));
+    }
+
+    #[test]
+    fn bad_values_are_reported() {
+        let err =
+            Settings::from_env(|name| (name == "SAFARIBOOKS_JOBS").then(|| "many".to_string()))
+                .unwrap_err();
+        assert!(err.to_string().contains("SAFARIBOOKS_JOBS"));
+
+        let err = serde_json::from_str::<Settings>(r#"{ "job": 2 }"#).unwrap_err();
+        assert!(err.to_string().contains("unknown field"));
+    }
+
+    #[test]
+    fn keyring_can_be_disabled_at_any_layer() {
+        assert!(env(&[]).use_keyring());
+        assert!(!env(&[("SAFARIBOOKS_KEYRING", "off")]).use_keyring());
+
+        let args = Args::try_parse_from(["safaribooks-rs", "--no-keyring", "123"]).unwrap();
+        let s = env(&[("SAFARIBOOKS_KEYRING", "1")]).merge(Settings::from_args(&args));
+        assert!(!s.use_keyring());
+    }
+
+    #[test]
+    fn profile_names_are_validated() {
+        assert!(super::validate_profile_name("work_2-x").is_ok());
+        for bad in ["", "../etc", "a b", "x/y"] {
+            assert!(super::validate_profile_name(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn profile_scopes_default_cookies_file() {
+        let s = Settings {
+            profile: Some("work".to_string()),
+            ..Settings::default()
+        };
+        assert!(s
+            .default_cookies_file()
+            .ends_with("profiles/work/cookies.json"));
+        assert!(Settings::default()
+            .default_cookies_file()
+            .ends_with("cookies.json"));
+    }
+
+    #[test]
+    fn base_url_comes_from_any_layer() {
+        let s = Settings::default();
+        assert_eq!(s.base_url(), "https://learning.oreilly.com");
+        assert_eq!(s.site_host(), "learning.oreilly.com");
+
+        let s = env(&[("SAFARIBOOKS_BASE_URL", "https://books.example.edu:8443/")]);
+        assert_eq!(s.base_url(), "https://books.example.edu:8443");
+        assert_eq!(s.site_host(), "books.example.edu");
+    }
+
+    #[test]
+    fn auth_mode_is_parsed() {
+        assert_eq!(Settings::default().auth(), AuthMode::Cookies);
+        assert_eq!(
+            env(&[("SAFARIBOOKS_AUTH", "Bearer")]).auth(),
+            AuthMode::Bearer
+        );
+        let s: Settings = serde_json::from_str(r#"{ "auth": "bearer" }"#).unwrap();
+        assert_eq!(s.auth(), AuthMode::Bearer);
+        assert!(Settings::from_env(|_| Some("token".to_string())).is_err());
+    }
+
+    #[test]
+    fn sidecar_formats_are_parsed() {
+        assert!(Settings::default().sidecar().is_empty());
+        assert_eq!(
+            env(&[("SAFARIBOOKS_SIDECAR", "json, OPF")]).sidecar(),
+            [SidecarFormat::Json, SidecarFormat::Opf]
+        );
+        let s: Settings = serde_json::from_str(r#"{ "sidecar": ["opf"] }"#).unwrap();
+        assert_eq!(s.sidecar(), [SidecarFormat::Opf]);
+    }
+
+    #[test]
+    fn degrade_above_is_a_percentage() {
+        assert_eq!(Settings::default().degrade_above(), 50);
+        assert_eq!(
+            env(&[("SAFARIBOOKS_DEGRADE_ABOVE", "80")]).degrade_above(),
+            80
+        );
+        for bad in ["0", "101", "half"] {
+            assert!(Settings::from_env(|name| {
+                (name == "SAFARIBOOKS_DEGRADE_ABOVE").then(|| bad.to_string())
+            })
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn series_from_title_is_off_by_default() {
+        assert!(!Settings::default().series_from_title());
+        assert!(env(&[("SAFARIBOOKS_SERIES_FROM_TITLE", "true")]).series_from_title());
+        assert!(Settings::from_env(|name| {
+            (name == "SAFARIBOOKS_SERIES_FROM_TITLE").then(|| "maybe".to_string())
+        })
+        .is_err());
+        assert!(Settings::from_env(|name| {
+            (name == "SAFARIBOOKS_SIDECAR").then(|| "yaml".to_string())
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn log_file_defaults_to_data_dir() {
+        let s = Settings::default();
+        assert!(s
+            .log_file("123")
+            .ends_with("safaribooks-rs/logs/info_123.log"));
+        assert!(!s.preserve_log());
+
+        let args = Args::try_parse_from([
+            "safaribooks-rs",
+            "--log-file",
+            "run.log",
+            "--preserve-log",
+            "123",
+        ])
+        .unwrap();
+        let s = env(&[("SAFARIBOOKS_PRESERVE_LOG", "no")]).merge(Settings::from_args(&args));
+        assert_eq!(s.log_file("123"), PathBuf::from("run.log"));
+        assert!(s.preserve_log());
+    }
+
+    #[test]
+    fn epubcheck_and_strict_come_from_any_layer() {
+        let s = Settings::default();
+        assert!(!s.epubcheck() && !s.strict());
+
+        let s: Settings =
+            serde_json::from_str(r#"{ "epubcheck_path": "/opt/epubcheck/epubcheck.jar" }"#)
+                .unwrap();
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--epubcheck", "--strict", "123"]).unwrap();
+        let s = s
+            .merge(env(&[("SAFARIBOOKS_STRICT", "0")]))
+            .merge(Settings::from_args(&args));
+        assert!(s.epubcheck() && s.strict());
+        assert_eq!(
+            s.epubcheck_path,
+            Some(PathBuf::from("/opt/epubcheck/epubcheck.jar"))
+        );
+    }
+
+    #[test]
+    fn hooks_come_from_any_layer() {
+        let s: Settings =
+            serde_json::from_str(r#"{ "webhook_url": "https://gotify.example/message" }"#).unwrap();
+        let args =
+            Args::try_parse_from(["safaribooks-rs", "--hook-command", "rsync -a", "123"]).unwrap();
+        let s = s
+            .merge(env(&[("SAFARIBOOKS_HOOK_COMMAND", "true")]))
+            .merge(Settings::from_args(&args));
+        assert_eq!(
+            s.webhook_url.as_deref(),
+            Some("https://gotify.example/message")
+        );
+        assert_eq!(s.hook_command.as_deref(), Some("rsync -a"));
+        assert!(!s.notify());
+        assert!(s.merge(env(&[("SAFARIBOOKS_NOTIFY", "1")])).notify());
+    }
+
+    #[test]
+    fn naming_templates_are_parsed_and_rendered() {
+        let naming = Settings::default().naming().unwrap();
+        let field = |name: &str| match name {
+            "title" => "Rust".to_string(),
+            "bookid" => "123".to_string(),
+            _ => String::new(),
+        };
+        assert_eq!(naming.dir.render(field), ["Rust (123)"]);
+        assert_eq!(naming.file.render(field), ["123"]);
+
+        let t = NamingTemplate::parse("{author}/{title} ({isbn})").unwrap();
+        assert_eq!(t.render(field), ["", "Rust "]);
+
+        for bad in [
+            "{title",
+            "title}",
+            "{name}",
+            "a//{title}",
+            "../{title}",
+            "/{title}",
+        ] {
+            assert!(NamingTemplate::parse(bad).is_err(), "{bad:?}");
+        }
+        let s = env(&[("SAFARIBOOKS_FILE_TEMPLATE", "{author}/{title}")]);
+        assert!(s.naming().is_err());
+        assert_eq!(env(&[("SAFARIBOOKS_NAME_MAX", "143")]).name_max, Some(143));
+        assert!(!naming.ascii);
+        let s = env(&[("SAFARIBOOKS_ASCII_NAMES", "yes")]);
+        assert!(s.naming().unwrap().ascii);
+
+        assert!(!naming.legacy);
+        let mut s = env(&[("SAFARIBOOKS_LEGACY_NAMES", "true")]);
+        let legacy = s.naming().unwrap();
+        assert!(legacy.legacy);
+        assert_eq!(legacy.dir, naming.dir);
+        s.file_template = Some("{title}".to_string());
+        assert!(s.naming().is_err());
+    }
+
+    #[test]
+    fn if_exists_policy_is_parsed() {
+        assert_eq!(Settings::default().if_exists(), IfExists::Resume);
+        assert_eq!(
+            env(&[("SAFARIBOOKS_IF_EXISTS", "skip")]).if_exists(),
+            IfExists::Skip
+        );
+        let s: Settings = serde_json::from_str(r#"{ "if_exists": "rename" }"#).unwrap();
+        assert_eq!(s.if_exists(), IfExists::Rename);
+        assert!(
+            Settings::from_env(|n| (n == "SAFARIBOOKS_IF_EXISTS").then(|| "x".into())).is_err()
+        );
+    }
+
+    #[test]
+    fn sizes_are_parsed() {
+        assert_eq!(
+            Settings::default().confirm_above(),
+            super::DEFAULT_CONFIRM_ABOVE
+        );
+        let s: Settings = serde_json::from_str(r#"{ "confirm_above": "1.5G" }"#).unwrap();
+        assert_eq!(s.confirm_above(), 3 << 29);
+        assert_eq!("300".parse::<ByteSize>().unwrap(), ByteSize(300));
+        assert!("300 pages".parse::<ByteSize>().is_err());
+        assert!("-1M".parse::<ByteSize>().is_err());
+        assert!("1e400".parse::<ByteSize>().is_err());
+        assert!("NaN".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn limit_rate_is_parsed() {
+        assert_eq!(Settings::default().limit_rate, None);
+        let s: Settings = serde_json::from_str(r#"{ "limit_rate": "512K" }"#).unwrap();
+        assert_eq!(s.limit_rate, Some(Rate(512 * 1024)));
+        assert!(serde_json::from_str::<Settings>(r#"{ "limit_rate": "fast" }"#).is_err());
+        assert_eq!(
+            env(&[("SAFARIBOOKS_LIMIT_RATE", "2M")]).limit_rate,
+            Some(Rate(2 * 1024 * 1024))
+        );
+    }
+
+    #[test]
+    fn defaults_apply_when_unset() {
+        let s = Settings::default();
+        assert_eq!(s.jobs(), super::DEFAULT_JOBS);
+        assert_eq!(s.parallel_books(), 1);
+        assert!(s.use_keyring());
+        assert_eq!(Settings { jobs: Some(0), ..s }.jobs(), 1);
+        assert_eq!(
+            env(&[("SAFARIBOOKS_PARALLEL_BOOKS", "3")]).parallel_books(),
+            3
+        );
+    }
+}
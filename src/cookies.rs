@@ -1,12 +1,27 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{collections::HashMap, fs, path::Path};
+use std::{borrow::Cow, collections::HashMap, fs, io::Read, path::Path};
 
-/// One cookie entry; domain/path could be added later if needed.
-#[derive(Debug, Clone, Deserialize)]
+/// Cookies the site needs to recognise an authenticated session:
+/// the API JWT and the web session id.
+pub const REQUIRED_COOKIES: &[&str] = &["orm-jwt", "groot_sessionid"];
+
+/// The API token cookie; also sent as a bearer token in `--auth bearer` mode.
+pub const JWT_COOKIE: &str = "orm-jwt";
+
+/// Domain assumed for cookies exported without one (e.g. the map format):
+/// every O'Reilly host, but not third-party CDNs.
+pub const DEFAULT_COOKIE_DOMAIN: &str = "oreilly.com";
+
+/// One cookie entry as exported by browsers/extensions.
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CookieEntry {
     pub name: String,
     pub value: String,
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
 }
 
 /// The input JSON can be either a map or a list of cookie entries.
@@ -17,11 +32,64 @@ enum CookiesJson {
     List(Vec<CookieEntry>),
 }
 
-/// Normalized cookie store (name -> value). We keep it simple for now.
-/// If later we need domain/path scoping, we can extend this type.
+/// A cookie with its normalized scope.
+#[derive(Debug, Clone)]
+struct ScopedCookie {
+    name: String,
+    value: String,
+    /// Lowercase, without leading dot; `None` when the export had no domain
+    /// (the store's default domain applies).
+    domain: Option<String>,
+    path: String,
+}
+
+impl ScopedCookie {
+    fn new(name: String, value: String, domain: Option<String>, path: Option<String>) -> Self {
+        let domain = domain
+            .map(|d| d.trim_start_matches('.').to_ascii_lowercase())
+            .filter(|d| !d.is_empty());
+        let path = path
+            .filter(|p| p.starts_with('/'))
+            .unwrap_or_else(|| "/".to_string());
+        Self {
+            name,
+            value,
+            domain,
+            path,
+        }
+    }
+
+    /// RFC 6265 domain-match (5.1.3) and path-match (5.1.4).
+    fn matches(&self, default_domain: &str, host: &str, path: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let domain = self.domain.as_deref().unwrap_or(default_domain);
+        let domain_ok = host == domain
+            || host
+                .strip_suffix(domain)
+                .is_some_and(|prefix| prefix.ends_with('.'));
+        let path_ok = path == self.path
+            || path.starts_with(&self.path)
+                && (self.path.ends_with('/') || path[self.path.len()..].starts_with('/'));
+        domain_ok && path_ok
+    }
+}
+
+/// Normalized cookie store. Cookies keep their domain/path so each request
+/// only carries the cookies scoped to its host and path.
 #[derive(Debug, Clone)]
 pub struct CookieStore {
-    map: HashMap<String, String>,
+    cookies: Vec<ScopedCookie>,
+    /// Domain of cookies exported without one.
+    default_domain: String,
+}
+
+impl Default for CookieStore {
+    fn default() -> Self {
+        Self {
+            cookies: Vec::new(),
+            default_domain: DEFAULT_COOKIE_DOMAIN.to_string(),
+        }
+    }
 }
 
 impl CookieStore {
@@ -29,22 +97,31 @@ impl CookieStore {
     pub fn from_value(v: Value) -> anyhow::Result<Self> {
         // Try to deserialize into either a map or a list.
         let cj: CookiesJson = serde_json::from_value(v)?;
-        let mut map = HashMap::new();
+        let mut store = Self::default();
 
         match cj {
             CookiesJson::Map(m) => {
                 // Direct mapping: { "name": "value", ... }
-                map.extend(m);
+                for (name, value) in m {
+                    store.insert(ScopedCookie::new(name, value, None, None));
+                }
             }
             CookiesJson::List(list) => {
-                // Keep last occurrence on duplicates.
+                // Keep last occurrence on duplicates (same name, domain and path).
                 for e in list {
-                    map.insert(e.name, e.value);
+                    store.insert(ScopedCookie::new(e.name, e.value, e.domain, e.path));
                 }
             }
         }
 
-        Ok(Self { map })
+        Ok(store)
+    }
+
+    fn insert(&mut self, cookie: ScopedCookie) {
+        self.cookies.retain(|c| {
+            !(c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path)
+        });
+        self.cookies.push(cookie);
     }
 
     /// Load cookies from a file path.
@@ -54,33 +131,223 @@ impl CookieStore {
         Self::from_value(v)
     }
 
+    /// Parse inline cookies: either cookies JSON (map or list) or a raw
+    /// `Cookie` header such as "a=1; b=2".
+    pub fn from_raw(raw: &str) -> anyhow::Result<Self> {
+        let raw = raw.trim();
+        if raw.starts_with('{') || raw.starts_with('[') {
+            return Self::from_value(serde_json::from_str(raw)?);
+        }
+        let mut store = Self::default();
+        for pair in raw.split(';') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+            store.insert(ScopedCookie::new(
+                name.trim().to_string(),
+                value.trim().to_string(),
+                None,
+                None,
+            ));
+        }
+        Ok(store)
+    }
+
+    /// Load cookies from any reader (e.g. stdin), so they never touch the disk.
+    pub fn load_from_reader(reader: impl Read) -> anyhow::Result<Self> {
+        let v: Value = serde_json::from_reader(reader)?;
+        Self::from_value(v)
+    }
+
+    /// Value of the cookie `name` that a request to `host` + `path` would carry.
+    pub fn value_for(&self, name: &str, host: &str, path: &str) -> Option<&str> {
+        self.cookies
+            .iter()
+            .rev()
+            .find(|c| c.name == name && c.matches(&self.default_domain, host, path))
+            .map(|c| c.value.as_str())
+    }
+
+    /// Apply a `Set-Cookie` response header received from `host`, e.g. a
+    /// refreshed token. An empty value or `Max-Age=0` deletes the cookie.
+    /// Returns the cookie name, or None when the header is malformed.
+    pub fn apply_set_cookie(&mut self, header: &str, host: &str) -> Option<String> {
+        let mut parts = header.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let (name, value) = (name.trim(), value.trim());
+        if !is_valid_cookie_name(name) {
+            return None;
+        }
+        let (mut domain, mut path, mut expired) = (None, None, value.is_empty());
+        for attr in parts {
+            let (key, val) = attr.split_once('=').unwrap_or((attr, ""));
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" => domain = Some(val.trim().to_string()),
+                "path" => path = Some(val.trim().to_string()),
+                "max-age" => expired |= val.trim().parse::<i64>().is_ok_and(|n| n <= 0),
+                _ => {}
+            }
+        }
+        // Host-only cookies belong to the host that set them.
+        let domain = domain.or_else(|| Some(host.to_string()));
+        let cookie = ScopedCookie::new(name.to_string(), value.to_string(), domain, path);
+        if expired {
+            self.cookies.retain(|c| {
+                !(c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path)
+            });
+        } else {
+            // Replace a same-named cookie the browser stored without a domain.
+            self.cookies
+                .retain(|c| !(c.name == cookie.name && c.domain.is_none()));
+            self.insert(cookie);
+        }
+        Some(name.to_string())
+    }
+
+    /// Scope cookies exported without a domain to `domain` instead of
+    /// `DEFAULT_COOKIE_DOMAIN`, e.g. for a proxied site.
+    pub fn set_default_domain(&mut self, domain: &str) {
+        self.default_domain = domain.trim_start_matches('.').to_ascii_lowercase();
+    }
+
+    /// Serialize as a cookies JSON list, keeping each cookie's scope.
+    /// `from_raw` (or `from_value`) reads it back.
+    pub fn to_json(&self) -> String {
+        let entries: Vec<_> = self
+            .cookies
+            .iter()
+            .map(|c| CookieEntry {
+                name: c.name.clone(),
+                value: c.value.clone(),
+                domain: c.domain.clone(),
+                path: Some(c.path.clone()),
+            })
+            .collect();
+        serde_json::to_string(&entries).expect("cookie entries always serialize")
+    }
+
     /// Number of cookies.
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.cookies.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.cookies.is_empty()
     }
 
-    /// Return a sorted list of cookie names (safe to log).
+    /// Return a sorted, de-duplicated list of cookie names (safe to log).
     pub fn cookie_names(&self) -> Vec<String> {
-        let mut names: Vec<_> = self.map.keys().cloned().collect();
+        let mut names: Vec<_> = self.cookies.iter().map(|c| c.name.clone()).collect();
         names.sort();
+        names.dedup();
         names
     }
 
-    /// Render the `Cookie` header value, e.g.: "a=1; b=2".
-    /// Deterministic order (by name) to help testing and reproducibility.
+    /// Names from `REQUIRED_COOKIES` that are absent or empty.
+    pub fn missing_required(&self) -> Vec<&'static str> {
+        REQUIRED_COOKIES
+            .iter()
+            .copied()
+            .filter(|name| {
+                !self
+                    .cookies
+                    .iter()
+                    .any(|c| c.name == *name && !c.value.is_empty())
+            })
+            .collect()
+    }
+
+    /// Render the `Cookie` header value for the main site, e.g.: "a=1; b=2".
+    #[cfg(test)]
     pub fn to_header_value(&self) -> String {
-        let mut pairs: Vec<_> = self.map.iter().collect();
-        pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.header_for("learning.oreilly.com", "/")
+    }
+
+    /// Render the `Cookie` header value for a request to `host` + `path`,
+    /// including only the cookies scoped to it.
+    /// Deterministic order (by name) to help testing and reproducibility.
+    /// Values are made header-safe (see `encode_cookie_value`) and cookies
+    /// whose names aren't valid tokens are left out.
+    pub fn header_for(&self, host: &str, path: &str) -> String {
+        let mut pairs: Vec<_> = self
+            .cookies
+            .iter()
+            .filter(|c| {
+                is_valid_cookie_name(&c.name) && c.matches(&self.default_domain, host, path)
+            })
+            .collect();
+        pairs.sort_by(|a, b| a.name.cmp(&b.name));
         pairs
             .into_iter()
-            .map(|(k, v)| format!("{k}={v}"))
+            .map(|c| format!("{}={}", c.name, encode_cookie_value(&c.value)))
             .collect::<Vec<_>>()
             .join("; ")
     }
+
+    /// Sorted names of cookies dropped from the header for invalid names.
+    pub fn invalid_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .cookies
+            .iter()
+            .filter(|c| !is_valid_cookie_name(&c.name))
+            .map(|c| c.name.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Sorted names of cookies whose values had to be percent-encoded.
+    pub fn encoded_values(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .cookies
+            .iter()
+            .filter(|c| {
+                is_valid_cookie_name(&c.name)
+                    && matches!(encode_cookie_value(&c.value), Cow::Owned(_))
+            })
+            .map(|c| c.name.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// RFC 6265 `cookie-octet`: printable US-ASCII minus DQUOTE, comma, semicolon and backslash.
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
+
+/// Cookie names must be RFC 7230 tokens.
+fn is_valid_cookie_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Percent-encode every byte outside `cookie-octet`, keeping a surrounding
+/// pair of double quotes (which RFC 6265 allows). Borrowed when already valid.
+fn encode_cookie_value(value: &str) -> Cow<'_, str> {
+    let (open, inner, close) = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => ("\"", inner, "\""),
+        None => ("", value, ""),
+    };
+    if inner.bytes().all(is_cookie_octet) {
+        return Cow::Borrowed(value);
+    }
+    let mut out = String::with_capacity(value.len() + 8);
+    out.push_str(open);
+    for b in inner.bytes() {
+        if is_cookie_octet(b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out.push_str(close);
+    Cow::Owned(out)
 }
 
 #[cfg(test)]
@@ -128,6 +395,156 @@ mod tests {
         assert_eq!(store.to_header_value(), "sess=NEW");
     }
 
+    #[test]
+    fn reports_missing_required_cookies() {
+        let v = json!({ "orm-jwt": "token", "groot_sessionid": "", "other": "x" });
+        let store = CookieStore::from_value(v).unwrap();
+        assert_eq!(store.missing_required(), vec!["groot_sessionid"]);
+
+        let v = json!({ "orm-jwt": "token", "groot_sessionid": "sid" });
+        let store = CookieStore::from_value(v).unwrap();
+        assert!(store.missing_required().is_empty());
+    }
+
+    #[test]
+    fn header_value_encodes_illegal_bytes() {
+        let v = json!({
+            "OptanonConsent": "isGpcEnabled=0&datestamp=Mon Jan 01 2024, 10:00",
+            "quoted": "\"a b\"",
+            "plain": "abc",
+            "bad name": "x"
+        });
+        let store = CookieStore::from_value(v).unwrap();
+        assert_eq!(
+            store.to_header_value(),
+            "OptanonConsent=isGpcEnabled=0&datestamp=Mon%20Jan%2001%202024%2C%2010:00; \
+             plain=abc; quoted=\"a%20b\""
+        );
+        assert_eq!(store.encoded_values(), vec!["OptanonConsent", "quoted"]);
+        assert_eq!(store.invalid_names(), vec!["bad name"]);
+    }
+
+    #[test]
+    fn non_ascii_values_are_utf8_percent_encoded() {
+        let store = CookieStore::from_value(json!({ "lang": "é\n" })).unwrap();
+        assert_eq!(store.to_header_value(), "lang=%C3%A9%0A");
+    }
+
+    #[test]
+    fn cookies_are_scoped_by_domain_and_path() {
+        let v = json!([
+            { "name": "sess", "value": "abc", "domain": ".oreilly.com", "path": "/" },
+            { "name": "api", "value": "1", "domain": "api.oreilly.com" },
+            { "name": "lib", "value": "2", "domain": "learning.oreilly.com", "path": "/library" },
+            { "name": "cdn", "value": "3", "domain": "cdn.example.net" },
+            { "name": "nodomain", "value": "4" }
+        ]);
+        let store = CookieStore::from_value(v).unwrap();
+
+        assert_eq!(
+            store.header_for("learning.oreilly.com", "/library/view/x/"),
+            "lib=2; nodomain=4; sess=abc"
+        );
+        assert_eq!(
+            store.header_for("learning.oreilly.com", "/libraryx"),
+            "nodomain=4; sess=abc"
+        );
+        assert_eq!(
+            store.header_for("api.oreilly.com", "/v1/"),
+            "api=1; nodomain=4; sess=abc"
+        );
+        assert_eq!(store.header_for("cdn.example.net", "/img.png"), "cdn=3");
+        assert_eq!(store.header_for("evil-oreilly.com", "/"), "");
+    }
+
+    #[test]
+    fn default_domain_applies_to_cookies_without_one() {
+        let v = json!([
+            { "name": "sess", "value": "abc" },
+            { "name": "lib", "value": "1", "domain": "learning.oreilly.com" }
+        ]);
+        let mut store = CookieStore::from_value(v).unwrap();
+        store.set_default_domain("Books.Proxy.Example.edu");
+
+        assert_eq!(store.header_for("books.proxy.example.edu", "/"), "sess=abc");
+        assert_eq!(store.header_for("learning.oreilly.com", "/"), "lib=1");
+    }
+
+    #[test]
+    fn set_cookie_replaces_and_deletes() {
+        let v = json!([
+            { "name": "orm-jwt", "value": "old" },
+            { "name": "gone", "value": "x", "domain": "learning.oreilly.com" }
+        ]);
+        let mut store = CookieStore::from_value(v).unwrap();
+
+        let name = store.apply_set_cookie(
+            "orm-jwt=new; Domain=.oreilly.com; Path=/; Secure; HttpOnly",
+            "learning.oreilly.com",
+        );
+        assert_eq!(name.as_deref(), Some("orm-jwt"));
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            store.value_for("orm-jwt", "api.oreilly.com", "/"),
+            Some("new")
+        );
+
+        store.apply_set_cookie("gone=; Max-Age=0", "learning.oreilly.com");
+        assert_eq!(store.value_for("gone", "learning.oreilly.com", "/"), None);
+        assert_eq!(
+            store.apply_set_cookie("garbage", "learning.oreilly.com"),
+            None
+        );
+    }
+
+    #[test]
+    fn same_name_on_different_domains_is_kept() {
+        let v = json!([
+            { "name": "sess", "value": "A", "domain": "learning.oreilly.com" },
+            { "name": "sess", "value": "B", "domain": "api.oreilly.com" }
+        ]);
+        let store = CookieStore::from_value(v).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.cookie_names(), vec!["sess"]);
+        assert_eq!(store.header_for("api.oreilly.com", "/"), "sess=B");
+    }
+
+    #[test]
+    fn loads_from_reader() {
+        let raw = br#"[{ "name": "sess", "value": "abc" }]"#;
+        let store = CookieStore::load_from_reader(&raw[..]).unwrap();
+        assert_eq!(store.cookie_names(), vec!["sess"]);
+    }
+
+    #[test]
+    fn loads_from_raw_header_or_json() {
+        let store = CookieStore::from_raw(" orm-jwt=a.b.c; groot_sessionid=xyz ;junk").unwrap();
+        assert_eq!(store.cookie_names(), vec!["groot_sessionid", "orm-jwt"]);
+        assert_eq!(
+            store.to_header_value(),
+            "groot_sessionid=xyz; orm-jwt=a.b.c"
+        );
+
+        let store = CookieStore::from_raw(r#"{"sess": "abc"}"#).unwrap();
+        assert_eq!(store.to_header_value(), "sess=abc");
+    }
+
+    #[test]
+    fn json_round_trip_keeps_scope() {
+        let v = json!([
+            { "name": "sess", "value": "a b", "domain": ".oreilly.com" },
+            { "name": "lib", "value": "2", "domain": "learning.oreilly.com", "path": "/library" }
+        ]);
+        let store = CookieStore::from_value(v).unwrap();
+        let back = CookieStore::from_raw(&store.to_json()).unwrap();
+        assert_eq!(back.len(), 2);
+        assert_eq!(
+            back.header_for("learning.oreilly.com", "/library/x"),
+            store.header_for("learning.oreilly.com", "/library/x")
+        );
+        assert_eq!(back.header_for("api.oreilly.com", "/"), "sess=a%20b");
+    }
+
     #[test]
     fn invalid_json_fails() {
         let v = serde_json::Value::String("not-json-shape".to_string());
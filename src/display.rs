@@ -1,35 +1,360 @@
+use crate::config;
+use crate::http_client::HTTP_LOG_TARGET;
+use crate::i18n::t;
+use crate::logfile::{LogWriter, RotatingLog, LOG_BACKUPS, MAX_LOG_BYTES};
+use crate::progress::{format_duration, Progress, BOOK_WINDOW};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
 use colored::*;
-use std::{fs::File, path::PathBuf};
-use tracing::{error, info};
+use serde::Serialize;
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tracing::subscriber::DefaultGuard;
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+/// When to use ANSI colors (`--color`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Only on a terminal, and not when NO_COLOR is set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Resolve against the environment: whether stdout is a terminal and
+    /// whether NO_COLOR (https://no-color.org) is set to a non-empty value.
+    pub fn enabled(self, is_terminal: bool, no_color: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => is_terminal && !no_color,
+        }
+    }
+
+    fn enabled_here(self, stderr: bool) -> bool {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+        let is_terminal = if stderr {
+            std::io::stderr().is_terminal()
+        } else {
+            std::io::stdout().is_terminal()
+        };
+        self.enabled(is_terminal, no_color)
+    }
+}
+
+/// What goes to stdout (`--output-format`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable messages.
+    #[default]
+    Text,
+    /// One JSON event per line (NDJSON) for GUIs and scripts.
+    Json,
+}
+
+/// Structured progress events, emitted as NDJSON with `--output-format json`
+/// and sent to every `Display::subscribe`r.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DownloadEvent {
+    /// A message that text mode prints as "[*]" (info) or "[-]" (warn).
+    Log {
+        level: &'static str,
+        message: String,
+    },
+    BookStarted {
+        book_id: String,
+        title: String,
+        format: String,
+    },
+    ChapterDone {
+        index: usize,
+        total: usize,
+        title: String,
+    },
+    AssetFailed {
+        url: String,
+        error: String,
+    },
+    BookFinished {
+        path: String,
+        chapters: usize,
+        assets: usize,
+        failed_assets: usize,
+    },
+    /// `--if-exists skip` found a complete EPUB; nothing was downloaded.
+    BookSkipped {
+        path: String,
+    },
+    /// Steps done of a book (chapters and assets) or of a batch (books),
+    /// and the seconds left at the recent pace.
+    Progress {
+        scope: &'static str,
+        done: usize,
+        total: usize,
+        eta_seconds: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Events a subscriber may fall behind by before it misses some.
+const EVENT_BUFFER: usize = 256;
+
+/// How much is printed (`-q`, default, `-v`, `-vv`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Errors only, no banner.
+    Quiet,
+    #[default]
+    Normal,
+    /// Also show diagnostic events (throttling, token refreshes, ...).
+    Verbose,
+    /// Everything, including per-request traces.
+    Debug,
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Self::Quiet,
+            (false, 0) => Self::Normal,
+            (false, 1) => Self::Verbose,
+            (false, _) => Self::Debug,
+        }
+    }
+
+    /// Level of tracing events echoed on stdout.
+    fn stdout_level(self) -> LevelFilter {
+        match self {
+            Self::Quiet | Self::Normal => LevelFilter::ERROR,
+            Self::Verbose => LevelFilter::INFO,
+            Self::Debug => LevelFilter::DEBUG,
+        }
+    }
+
+    /// Level of events written to the log file.
+    fn file_level(self) -> LevelFilter {
+        match self {
+            Self::Debug => LevelFilter::DEBUG,
+            _ => LevelFilter::INFO,
+        }
+    }
+}
+
+/// RUST_LOG when set, otherwise `level`.
+fn env_filter(level: LevelFilter) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy()
+}
+
+#[derive(Clone)]
 pub struct Display {
-    pub log_file: PathBuf,
+    /// Where this run is logged, if anywhere.
+    pub log_file: Option<PathBuf>,
     pub output_dir: Option<PathBuf>,
+    log: Option<Arc<RotatingLog>>,
+    verbosity: Verbosity,
+    format: OutputFormat,
+    /// Print to stderr, stdout carrying the EPUB itself (`--output -`).
+    stderr: bool,
+    /// Where `subscribe`rs get the events, once there are any.
+    events: Option<broadcast::Sender<DownloadEvent>>,
+    /// The book being downloaded, shared with clones until
+    /// `track_progress` gives one its own.
+    progress: Arc<Mutex<Progress>>,
 }
 
-impl Display {
-    pub fn new(book_id: &str) -> Self {
-        let log_file = std::env::current_dir()
-            .unwrap()
-            .join(format!("info_{}.log", book_id));
+/// Keeps a scoped subscriber installed; dropping it restores the previous one.
+/// Empty for the global subscriber, which lives until the process exits.
+#[must_use = "dropping the guard uninstalls a scoped subscriber"]
+pub struct LogGuard {
+    _scoped: Option<DefaultGuard>,
+}
 
-        let d = Self {
-            log_file,
-            output_dir: None,
+/// Configures where a `Display` logs before installing its subscriber.
+pub struct DisplayBuilder {
+    log_file: Option<PathBuf>,
+    stdout: bool,
+    debug_http: bool,
+    global: bool,
+    verbosity: Verbosity,
+    color: ColorChoice,
+    format: OutputFormat,
+    stderr: bool,
+}
+
+impl DisplayBuilder {
+    /// Log file to write (default `logs/info_<book id>.log` under the data
+    /// directory); None disables file logging.
+    pub fn log_file(mut self, path: Option<PathBuf>) -> Self {
+        self.log_file = path;
+        self
+    }
+
+    /// Also echo tracing events on stdout (default on).
+    #[cfg(test)]
+    pub fn stdout(mut self, on: bool) -> Self {
+        self.stdout = on;
+        self
+    }
+
+    /// Also write every request and response to the log file (see `--debug-http`).
+    pub fn debug_http(mut self, on: bool) -> Self {
+        self.debug_http = on;
+        self
+    }
+
+    pub fn color(mut self, color: ColorChoice) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Print everything to stderr, leaving stdout to the EPUB (`--output -`).
+    pub fn stderr(mut self, on: bool) -> Self {
+        self.stderr = on;
+        self
+    }
+
+    /// Install the subscriber for the whole process (default), or only for
+    /// the current thread until the guard is dropped (tests, embedding).
+    #[cfg(test)]
+    pub fn global(mut self, on: bool) -> Self {
+        self.global = on;
+        self
+    }
+
+    /// Create the log file and install the subscriber. When a global
+    /// subscriber already exists it is left alone and tracing goes there.
+    pub fn init(self) -> Result<(Display, LogGuard)> {
+        let log = match &self.log_file {
+            Some(path) => Some(Arc::new(
+                RotatingLog::create(path, MAX_LOG_BYTES, LOG_BACKUPS)
+                    .with_context(|| t!("creating-log-file", path = path.display().to_string()))?,
+            )),
+            None => None,
         };
 
-        let file = File::create(&d.log_file).expect("Cannot create log file");
+        let mut file_filter = env_filter(self.verbosity.file_level());
+        if self.debug_http {
+            file_filter = file_filter.add_directive(
+                format!("{HTTP_LOG_TARGET}=debug")
+                    .parse()
+                    .expect("valid directive"),
+            );
+        }
 
-        tracing_subscriber::registry()
-            .with(EnvFilter::from_default_env())
-            .with(fmt::layer().with_writer(std::io::stdout))
-            .with(fmt::layer().with_writer(file).with_ansi(false))
-            .init();
+        let json = self.format == OutputFormat::Json;
+        let color = !json && self.color.enabled_here(self.stderr);
+        // Windows consoles show ANSI colors once virtual terminal processing
+        // is on; those too old for it get plain text.
+        #[cfg(windows)]
+        let color = color && colored::control::set_virtual_terminal(true).is_ok();
+        colored::control::set_override(color);
+
+        // Display's own messages are already printed; only echo the rest.
+        let stdout_filter = env_filter(self.verbosity.stdout_level()).add_directive(
+            format!("{}=off", module_path!())
+                .parse()
+                .expect("valid directive"),
+        );
+
+        // Keep stdout pure NDJSON in JSON mode.
+        let console = if json || self.stderr {
+            BoxMakeWriter::new(std::io::stderr)
+        } else {
+            BoxMakeWriter::new(std::io::stdout)
+        };
 
-        d.intro();
+        let subscriber = tracing_subscriber::registry()
+            .with(self.stdout.then(|| {
+                fmt::layer()
+                    .with_writer(console)
+                    .with_ansi(color)
+                    .with_filter(stdout_filter)
+            }))
+            .with(log.clone().map(|log| {
+                fmt::layer()
+                    .with_writer(LogWriter(log))
+                    .with_ansi(false)
+                    .with_filter(file_filter)
+            }));
+
+        let guard = if self.global {
+            // Err means a subscriber is already installed: keep using it.
+            let _ = tracing::subscriber::set_global_default(subscriber);
+            LogGuard { _scoped: None }
+        } else {
+            LogGuard {
+                _scoped: Some(tracing::subscriber::set_default(subscriber)),
+            }
+        };
+
+        let d = Display {
+            log_file: self.log_file,
+            output_dir: None,
+            log,
+            verbosity: self.verbosity,
+            format: self.format,
+            stderr: self.stderr,
+            events: None,
+            progress: Arc::new(Mutex::new(Progress::new(0, BOOK_WINDOW))),
+        };
+        if d.verbosity > Verbosity::Quiet && !json {
+            d.intro();
+        }
         info!("** Welcome to SafariBooks (Rust) **");
-        d
+        Ok((d, guard))
+    }
+}
+
+impl Display {
+    /// Start configuring the display of a download of `book_id`.
+    pub fn builder(book_id: &str) -> DisplayBuilder {
+        DisplayBuilder {
+            log_file: Some(config::default_log_file(book_id)),
+            stdout: true,
+            debug_http: false,
+            global: true,
+            verbosity: Verbosity::default(),
+            color: ColorChoice::default(),
+            format: OutputFormat::default(),
+            stderr: false,
+        }
+    }
+
+    /// A Display that prints but leaves tracing and the log file alone.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Self {
+            log_file: None,
+            output_dir: None,
+            log: None,
+            verbosity: Verbosity::default(),
+            format: OutputFormat::default(),
+            stderr: false,
+            events: None,
+            progress: Arc::new(Mutex::new(Progress::new(0, BOOK_WINDOW))),
+        }
     }
 
     pub fn intro(&self) {
@@ -40,23 +365,263 @@ impl Display {
  ___) | (_| |  _| (_| | |  | |  _ <| |_| \__ \ |_
 |____/ \__,_|_|  \__,_|_|  |_|_| \_\\__,_|___/\__|
 "#;
-        println!("{}", banner.yellow());
-        println!("{}", "~".repeat(32));
+        self.print(&banner.yellow().to_string());
+        self.print(&"~".repeat(32));
+    }
+
+    /// Print a line to the console: stdout, unless the EPUB goes there.
+    fn print(&self, line: &str) {
+        if self.stderr {
+            eprintln!("{line}");
+        } else {
+            println!("{line}");
+        }
+    }
+
+    /// Emit a structured event; a no-op in text mode, where the
+    /// accompanying messages already say the same.
+    pub fn event(&self, event: &DownloadEvent) {
+        self.emit(event, true);
+    }
+
+    /// Print `event` in JSON mode when `print`, and send it to the
+    /// subscribers either way.
+    fn emit(&self, event: &DownloadEvent, print: bool) {
+        if print && self.format == OutputFormat::Json {
+            self.print(&serde_json::to_string(event).expect("events serialize"));
+        }
+        if let Some(events) = &self.events {
+            // Nobody listening is fine.
+            let _ = events.send(event.clone());
+        }
+    }
+
+    /// Receive this display's events from now on, and those of its clones
+    /// made after this. For embedding the downloader: a GUI or a bot can
+    /// show its own progress rather than read the console.
+    pub fn subscribe(&mut self) -> broadcast::Receiver<DownloadEvent> {
+        self.events
+            .get_or_insert_with(|| broadcast::channel(EVENT_BUFFER).0)
+            .subscribe()
     }
 
     pub fn info(&self, msg: &str) {
-        println!("{} {}", "[*]".yellow(), msg);
+        let shown = self.verbosity > Verbosity::Quiet;
+        if shown && self.format == OutputFormat::Text {
+            self.print(&format!("{} {}", "[*]".yellow(), msg));
+        }
+        self.emit(
+            &DownloadEvent::Log {
+                level: "info",
+                message: msg.to_string(),
+            },
+            shown,
+        );
         info!("{msg}");
     }
 
+    pub fn warn(&self, msg: &str) {
+        let shown = self.verbosity > Verbosity::Quiet;
+        if shown && self.format == OutputFormat::Text {
+            self.print(&format!("{} {}", "[-]".red(), msg));
+        }
+        self.emit(
+            &DownloadEvent::Log {
+                level: "warn",
+                message: msg.to_string(),
+            },
+            shown,
+        );
+        warn!("{msg}");
+    }
+
+    /// Ask a question on the terminal and return the trimmed answer;
+    /// None when stdin isn't interactive or is closed.
+    pub fn prompt(&self, msg: &str) -> Option<String> {
+        let stdin = std::io::stdin();
+        if !stdin.is_terminal() {
+            return None;
+        }
+        // The question goes to stderr in JSON mode, keeping stdout NDJSON.
+        if self.format == OutputFormat::Json {
+            eprint!("{} ", msg);
+            std::io::stderr().flush().ok()?;
+        } else if self.stderr {
+            eprint!("{} {} ", "[?]".cyan(), msg);
+            std::io::stderr().flush().ok()?;
+        } else {
+            print!("{} {} ", "[?]".cyan(), msg);
+            std::io::stdout().flush().ok()?;
+        }
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim().to_string()),
+        }
+    }
+
     pub fn error_and_exit(&self, msg: &str) -> ! {
+        self.event(&DownloadEvent::Error {
+            message: msg.to_string(),
+        });
         eprintln!("{} {}", "[!]".on_red().white(), msg);
         error!("{msg}");
+        if let Some(log) = &self.log_file {
+            eprintln!(
+                "    {}",
+                t!("log-details", path = log.display().to_string())
+            );
+        }
         std::process::exit(1);
     }
 
+    /// After a successful run the log has served its purpose: delete it
+    /// (with its rotated copies) unless asked to keep it.
+    pub fn finish_log(&self, preserve: bool) {
+        let (Some(log), Some(path)) = (&self.log, &self.log_file) else {
+            return;
+        };
+        if preserve {
+            self.info(&t!("log-kept", path = path.display().to_string()));
+        } else if let Err(e) = log.remove() {
+            self.warn(&t!(
+                "log-not-deleted",
+                path = path.display().to_string(),
+                error = e.to_string()
+            ));
+        }
+    }
+
     pub fn set_output_dir(&mut self, dir: PathBuf) {
         self.output_dir = Some(dir.clone());
-        self.info(&format!("Output directory:\n {}", dir.display()));
+        self.info(&format!("{}\n {}", t!("output-directory"), dir.display()));
+    }
+
+    /// Track the progress of a new book on this display, apart from that
+    /// of the display it was cloned from.
+    pub fn track_progress(&mut self) {
+        self.progress = Arc::new(Mutex::new(Progress::new(0, BOOK_WINDOW)));
+    }
+
+    /// More steps (chapters, assets) found for the book.
+    pub fn add_work(&self, steps: usize) {
+        self.progress.lock().unwrap().add(steps);
+    }
+
+    /// Steps of the book done; every so often, report how many and the
+    /// time left at the recent pace.
+    pub fn work_done(&self, steps: usize) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.step(steps);
+        let Some(left) = progress.remaining() else {
+            return;
+        };
+        if left.is_zero() || !progress.report_due() {
+            return;
+        }
+        let (done, total) = (progress.done(), progress.total());
+        drop(progress);
+        self.info(&t!(
+            "time-left",
+            done = done,
+            total = total,
+            left = format_duration(left)
+        ));
+        self.event(&DownloadEvent::Progress {
+            scope: "book",
+            done,
+            total,
+            eta_seconds: left.as_secs(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColorChoice, Display, DownloadEvent, Verbosity};
+
+    #[test]
+    fn scoped_builders_can_be_initialized_repeatedly() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-display-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("info_123.log");
+
+        for _ in 0..2 {
+            let (ui, _guard) = Display::builder("123")
+                .log_file(Some(log.clone()))
+                .stdout(false)
+                .global(false)
+                .init()
+                .unwrap();
+            assert_eq!(ui.log_file.as_deref(), Some(log.as_path()));
+            tracing::error!("written to the log");
+        }
+        let text = std::fs::read_to_string(&log).unwrap();
+        assert!(text.contains("written to the log"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn color_choice_honors_terminal_and_no_color() {
+        assert!(ColorChoice::Auto.enabled(true, false));
+        assert!(!ColorChoice::Auto.enabled(false, false));
+        assert!(!ColorChoice::Auto.enabled(true, true));
+        assert!(ColorChoice::Always.enabled(false, true));
+        assert!(!ColorChoice::Never.enabled(true, false));
+    }
+
+    #[test]
+    fn events_serialize_as_tagged_json() {
+        let line = serde_json::to_string(&DownloadEvent::ChapterDone {
+            index: 1,
+            total: 3,
+            title: "Intro".to_string(),
+        })
+        .unwrap();
+        assert_eq!(
+            line,
+            r#"{"event":"chapter_done","index":1,"total":3,"title":"Intro"}"#
+        );
+        let line = serde_json::to_string(&DownloadEvent::Log {
+            level: "warn",
+            message: "x".to_string(),
+        })
+        .unwrap();
+        assert_eq!(line, r#"{"event":"log","level":"warn","message":"x"}"#);
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_every_event() {
+        let mut ui = Display::for_tests();
+        ui.verbosity = Verbosity::Quiet;
+        let mut events = ui.subscribe();
+        let book_ui = ui.clone();
+        book_ui.info("hello");
+        book_ui.event(&DownloadEvent::BookSkipped {
+            path: "a.epub".to_string(),
+        });
+        assert_eq!(
+            events.recv().await.unwrap(),
+            DownloadEvent::Log {
+                level: "info",
+                message: "hello".to_string()
+            }
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            DownloadEvent::BookSkipped {
+                path: "a.epub".to_string()
+            }
+        );
+        drop((ui, book_ui));
+        assert!(events.recv().await.is_err());
+    }
+
+    #[test]
+    fn verbosity_from_flags() {
+        assert_eq!(Verbosity::from_flags(false, 0), Verbosity::Normal);
+        assert_eq!(Verbosity::from_flags(false, 1), Verbosity::Verbose);
+        assert_eq!(Verbosity::from_flags(false, 3), Verbosity::Debug);
+        assert_eq!(Verbosity::from_flags(true, 2), Verbosity::Quiet);
     }
 }
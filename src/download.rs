@@ -0,0 +1,1179 @@
+//! One book from book info to finished EPUB, shared by the command-line
+//! run (of one book or several), the long-running modes (`serve`, `watch`)
+//! and `rebuild`.
+
+use crate::annotations::Annotation;
+use crate::api::OreillyApi;
+use crate::book::{build_book_epub, BookOptions, BuildStats, MISSING_REPORT};
+use crate::calibre;
+use crate::catalog::cover_extension;
+use crate::checksum::manifest_path;
+use crate::config::{Naming, Settings};
+use crate::display::{Display, DownloadEvent};
+use crate::epub::{EpubSkeleton, IfExists, MetadataOverrides, NameLimit};
+use crate::format::BookContext;
+use crate::hooks;
+use crate::http_client::HttpClient;
+use crate::i18n::t;
+use crate::legacy;
+use crate::library;
+use crate::mirror::{self, Mirror, Offline};
+use crate::notify;
+use crate::orly::{
+    cover_url, fetch_annotations, fetch_book_info, fetch_reading_position, iso_datetime, BookInfo,
+    ProductType, ReadingPosition,
+};
+use crate::package::EpubStream;
+use crate::progress::{format_duration, Progress, BATCH_WINDOW};
+use crate::sidecar::{self, SidecarFormat};
+use crate::split;
+use crate::state::{BookRecord, StateDb, Status};
+use crate::transcript::build_transcript_epub;
+use crate::validate;
+use anyhow::{bail, Context, Result};
+use futures_util::{future, stream, StreamExt};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+/// Wait this long before starting over one request at a time: the site
+/// may be back by then.
+const DEGRADE_PAUSE: Duration = Duration::from_secs(60);
+
+/// How a download ended without error.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The EPUB was written here.
+    Finished(PathBuf),
+    /// The book was already in the library, here.
+    Skipped(PathBuf),
+}
+
+/// A logged-in client plus the settings every book is downloaded with.
+pub struct Downloader {
+    pub client: HttpClient,
+    pub settings: Settings,
+    pub naming: Naming,
+    pub metadata: MetadataOverrides,
+    /// `--add-to-calibre`: the library, or None for Calibre's current one.
+    pub calibre: Option<Option<PathBuf>>,
+    /// `--yes`: never ask before a large download.
+    pub assume_yes: bool,
+    /// Where downloads are recorded; None when the database can't be opened.
+    pub state: Option<StateDb>,
+    /// `--output`: the EPUB's path instead of the book directory; "-" is
+    /// stdout.
+    pub output: Option<PathBuf>,
+    /// Stops the downloads cleanly: each at its next chapter or asset, with
+    /// what it wrote kept for a resume, and no more books started.
+    pub cancel: CancellationToken,
+    /// `--cover-only` / `--metadata-only`: refresh these of a book already
+    /// downloaded instead of downloading it.
+    pub only: ExtrasOnly,
+}
+
+/// What `--cover-only` and `--metadata-only` fetch for a book already in
+/// the library; the EPUB itself is left as it is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExtrasOnly {
+    /// The cover image, written as cover.<ext> next to the EPUB.
+    pub cover: bool,
+    /// The `--sidecar` files, metadata.json and metadata.opf by default.
+    pub metadata: bool,
+}
+
+impl ExtrasOnly {
+    pub fn any(self) -> bool {
+        self.cover || self.metadata
+    }
+}
+
+/// The books to download, each once and in the order first given, and the
+/// IDs that were given more than once (each listed once).
+pub fn dedup_queue(bookids: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut seen = HashSet::new();
+    let mut queue = Vec::new();
+    let mut duplicates = Vec::new();
+    for bookid in bookids.iter().map(|id| id.trim()) {
+        if seen.insert(bookid) {
+            queue.push(bookid.to_string());
+        } else if !duplicates.iter().any(|d| d == bookid) {
+            duplicates.push(bookid.to_string());
+        }
+    }
+    (queue, duplicates)
+}
+
+impl Downloader {
+    /// Download `bookid` into the output directory, then run the hooks.
+    pub async fn download(&self, ui: &mut Display, bookid: &str) -> Result<Outcome> {
+        ui.track_progress();
+        let mut result = self.download_book(ui, bookid).await;
+        if self.output.as_deref() == Some(Path::new(STDOUT))
+            && let Ok(outcome) = &result
+        {
+            result = stream_to_stdout(outcome).map(|()| outcome.clone());
+        }
+        if let Err(e) = &result {
+            update_state(ui, self.state.as_ref(), |db| {
+                db.failed(bookid, &format!("{e:#}"))
+            });
+        }
+        hooks::fire(
+            ui,
+            self.settings.webhook_url.as_deref(),
+            self.settings.hook_command.as_deref(),
+            bookid,
+            &result,
+        )
+        .await;
+        if self.settings.notify() {
+            notify::send(ui, bookid, &result).await;
+        }
+        result
+    }
+
+    /// The database's record of `bookid`, if any.
+    fn record(&self, ui: &Display, bookid: &str) -> Option<BookRecord> {
+        match self.state.as_ref()?.book(bookid) {
+            Ok(record) => record,
+            Err(e) => {
+                ui.warn(&t!("state-read-failed", error = format!("{e:#}")));
+                None
+            }
+        }
+    }
+
+    /// Download several books (see `dedup_queue`), up to `parallel` at a time. They all go
+    /// through the one client, whose throttle caps the requests in flight
+    /// across books, so running books side by side adds no load.
+    pub async fn download_all(
+        &self,
+        ui: &Display,
+        bookids: &[String],
+        parallel: usize,
+    ) -> Vec<(String, Result<Outcome>)> {
+        let total = bookids.len();
+        let progress = Mutex::new(Progress::new(total, BATCH_WINDOW));
+        let progress = &progress;
+        stream::iter(bookids.iter().enumerate())
+            .take_while(|_| future::ready(!self.cancel.is_cancelled()))
+            .map(|(i, bookid)| async move {
+                ui.info(&t!(
+                    "book-progress",
+                    index = i + 1,
+                    total = total,
+                    book = bookid.as_str()
+                ));
+                let mut book_ui = ui.clone();
+                let result = self.download(&mut book_ui, bookid).await;
+                report_batch(ui, progress);
+                if let Err(e) = &result {
+                    ui.warn(&t!(
+                        "book-failed",
+                        book = bookid.as_str(),
+                        error = format!("{e:#}")
+                    ));
+                }
+                (bookid.clone(), result)
+            })
+            .buffered(parallel.max(1))
+            .collect()
+            .await
+    }
+
+    async fn download_book(&self, ui: &mut Display, bookid: &str) -> Result<Outcome> {
+        let settings = &self.settings;
+        let client = &self.client;
+
+        ui.info(&t!("retrieving-book-info"));
+        let bookinfo = fetch_book_info(client, bookid)
+            .await
+            .with_context(|| t!("fetching-book-info"))?;
+        let na = t!("not-available");
+        ui.info(&t!(
+            "book-details",
+            title = bookinfo.title.as_str(),
+            authors = bookinfo.author_names().join(", "),
+            publisher = bookinfo.publisher().unwrap_or(&na),
+            issued = bookinfo.issued.as_deref().unwrap_or(&na),
+            pages = bookinfo
+                .page_count
+                .map_or_else(|| na.clone(), |n| n.to_string()),
+        ));
+
+        // Detect the product type up front so unsupported products fail clearly.
+        check_supported(&bookinfo)?;
+
+        ui.event(&DownloadEvent::BookStarted {
+            book_id: bookid.to_string(),
+            title: bookinfo.title.clone(),
+            format: bookinfo.format.clone(),
+        });
+
+        let output_dir = settings.output_dir();
+        let limit = NameLimit::detect(&output_dir, settings.name_max);
+        let mut skeleton = EpubSkeleton::plan(&output_dir, &self.naming, limit, bookid, &bookinfo);
+        skeleton.epub.set_extension(settings.format().extension());
+        if let Some(output) = &self.output {
+            skeleton.epub = if output == Path::new(STDOUT) {
+                // Only packaged to be streamed, then removed.
+                std::env::temp_dir().join(format!(
+                    "safaribooks-{bookid}-{}.{}",
+                    std::process::id(),
+                    settings.format().extension()
+                ))
+            } else {
+                output.clone()
+            };
+        }
+        let record = self.record(ui, bookid);
+        let recorded = record
+            .as_ref()
+            .and_then(BookRecord::complete_epub)
+            .map(Path::to_path_buf);
+        if self.only.any() {
+            let epub = recorded
+                .or_else(|| skeleton.epub.is_file().then(|| skeleton.epub.clone()))
+                .or_else(|| {
+                    library::find_existing(&output_dir, bookid, bookinfo.isbn.as_deref())
+                        .into_iter()
+                        .next()
+                })
+                .with_context(|| t!("not-downloaded", book = bookid))?;
+            refresh_extras(client, ui, settings, self.only, bookid, &bookinfo, &epub).await?;
+            return Ok(Outcome::Skipped(epub));
+        }
+        // The same book under another name (an older template, a renamed
+        // file): the database knows where it went, or else the library does.
+        let elsewhere = match &recorded {
+            Some(epub) => (!epub.starts_with(&skeleton.root)).then(|| epub.clone()),
+            None => library::find_existing(&output_dir, bookid, bookinfo.isbn.as_deref())
+                .into_iter()
+                .find(|path| !path.starts_with(&skeleton.root)),
+        };
+        if let Some(existing) = elsewhere {
+            ui.info(&t!(
+                "already-in-library",
+                path = existing.display().to_string()
+            ));
+            let skip = settings.if_exists() == IfExists::Skip
+                || ui
+                    .prompt(&t!("skip-again"))
+                    .is_some_and(|a| a.eq_ignore_ascii_case("y") || a.eq_ignore_ascii_case("yes"));
+            if skip {
+                ui.event(&DownloadEvent::BookSkipped {
+                    path: existing.display().to_string(),
+                });
+                return Ok(Outcome::Skipped(existing));
+            }
+        }
+
+        // A download safaribooks.py left, where it would have put it.
+        if !skeleton.root.exists() && !skeleton.legacy_names {
+            let naming = Naming {
+                legacy: true,
+                ..Naming::default()
+            };
+            let python = EpubSkeleton::plan(&output_dir, &naming, limit, bookid, &bookinfo);
+            if legacy::is_python_download(&python.root) {
+                let epub = match &self.output {
+                    Some(_) => skeleton.epub.clone(),
+                    None => python.epub.clone(),
+                };
+                skeleton = EpubSkeleton { epub, ..python };
+            }
+        }
+        let resume = match settle_existing(
+            ui,
+            settings.if_exists(),
+            record.as_ref(),
+            recorded.as_ref(),
+            bookid,
+            &mut skeleton,
+        )? {
+            ControlFlow::Break(epub) => return Ok(Outcome::Skipped(epub)),
+            ControlFlow::Continue(resume) => resume,
+        };
+        ui.set_output_dir(skeleton.root.clone());
+        update_state(ui, self.state.as_ref(), |db| {
+            db.started(bookid, &bookinfo, &skeleton.root)
+        });
+
+        // Create directories and required files
+        (|| -> Result<()> {
+            skeleton.create_dirs()?;
+            skeleton.write_mimetype()?;
+            skeleton.write_container_xml()?;
+            Ok(())
+        })()
+        .with_context(|| t!("skeleton-failed"))?;
+        ui.info(&t!("skeleton-ready"));
+
+        let raw = if settings.mirror() {
+            Some(
+                mirror::start(&skeleton.root, bookid, client.base_url())
+                    .with_context(|| t!("mirror-start-failed"))?,
+            )
+        } else {
+            None
+        };
+        let api = Mirror::new(client, raw);
+        if settings.mirror() {
+            // The book info came before the book directory did.
+            fetch_book_info(&api, bookid)
+                .await
+                .with_context(|| t!("mirroring-book-info"))?;
+            ui.info(&t!(
+                "mirroring-into",
+                path = skeleton.root.join(mirror::RAW_DIR).display().to_string()
+            ));
+        }
+
+        let (annotations, position) = extras(&api, ui, settings, bookid).await;
+        let mut opts = BookOptions {
+            jobs: settings.jobs(),
+            resume,
+            credits: settings.credits(),
+            annotations,
+            position,
+            metadata: self.metadata.clone(),
+            confirm_above: (!self.assume_yes).then(|| settings.confirm_above()),
+            code_style: settings.code_style(),
+            table_style: settings.table_style(),
+            rasterize_svg: settings.rasterize_svg(),
+            inline_below: settings.inline_images(),
+            split_above: settings.format().split_above(settings),
+            stealth: settings.stealth(),
+            cancel: self.cancel.clone(),
+            package: settings
+                .format()
+                .stream(&skeleton.epub, settings)
+                .with_context(|| t!("packaging-failed"))?,
+        };
+        let stats = self
+            .build_degrading(&api, ui, bookid, &bookinfo, &skeleton, &mut opts)
+            .await?;
+        opts.check_cancelled()?;
+        check_missing(ui, settings, &skeleton, &stats)?;
+        let book = BookContext {
+            ui,
+            settings,
+            book_id: bookid,
+            info: &bookinfo,
+            workdir: &skeleton,
+            chapters: &stats.written,
+            dest: &skeleton.epub,
+        };
+        finish_book(
+            &book,
+            self.state.as_ref(),
+            opts.position.as_ref(),
+            opts.package.take(),
+        )?;
+        if self.output.as_deref() != Some(Path::new(STDOUT)) {
+            write_sidecars(
+                ui,
+                settings,
+                &skeleton.epub,
+                bookid,
+                &bookinfo,
+                api.base_url(),
+            );
+        }
+        let epub_path = &skeleton.epub;
+        if let Some(library) = &self.calibre {
+            match calibre::add_to_calibre(
+                calibre::CALIBREDB.as_ref(),
+                library.as_deref(),
+                epub_path,
+            ) {
+                Ok(calibre::Added::Books(ids)) => ui.info(&t!(
+                    "calibre-added",
+                    ids = ids
+                        .iter()
+                        .map(u64::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+                Ok(calibre::Added::Duplicate) => ui.info(&t!("calibre-has-book")),
+                Err(e) => ui.warn(&t!("calibre-failed", error = format!("{e:#}"))),
+            }
+        }
+        ui.info(&t!("done", path = epub_path.display().to_string()));
+        ui.event(&DownloadEvent::BookFinished {
+            path: epub_path.display().to_string(),
+            chapters: stats.chapters,
+            assets: stats.assets,
+            failed_assets: stats.failed_assets,
+        });
+        Ok(Outcome::Finished(skeleton.epub))
+    }
+
+    /// Build the book; if the site fails as a whole on the way (see
+    /// `Throttle::failing`), stop, wait, and build the rest one request at
+    /// a time with long pauses rather than fail it.
+    async fn build_degrading<A: OreillyApi>(
+        &self,
+        api: &A,
+        ui: &mut Display,
+        bookid: &str,
+        info: &BookInfo,
+        skeleton: &EpubSkeleton,
+        opts: &mut BookOptions,
+    ) -> Result<BuildStats> {
+        let mut degraded = false;
+        loop {
+            let attempt = self.cancel.child_token();
+            opts.cancel = attempt.clone();
+            let result = {
+                let build = build(api, &*ui, bookid, info, skeleton, opts);
+                tokio::pin!(build);
+                tokio::select! {
+                    result = &mut build => result,
+                    () = self.client.until_failing() => {
+                        attempt.cancel();
+                        build.await
+                    }
+                }
+            };
+            let failed = attempt.is_cancelled() || (result.is_err() && self.client.failing());
+            if !failed || self.cancel.is_cancelled() {
+                if degraded && result.is_ok() {
+                    ui.info(&t!("finished-degraded"));
+                }
+                return result;
+            }
+            if degraded {
+                // Already as slow as it gets.
+                bail!(t!("site-still-failing"));
+            }
+            ui.warn(&t!(
+                "site-failing",
+                percent = self.settings.degrade_above(),
+                pause = format_duration(DEGRADE_PAUSE)
+            ));
+            tokio::select! {
+                () = tokio::time::sleep(DEGRADE_PAUSE) => {}
+                () = self.cancel.cancelled() => bail!(t!("download-cancelled")),
+            }
+            self.client.degrade();
+            degraded = true;
+            // What was written is kept; the rest comes one request at a time.
+            opts.resume = true;
+            opts.jobs = 1;
+            opts.stealth = true;
+            // The work is counted again, at the new pace.
+            ui.track_progress();
+        }
+    }
+}
+
+/// What to do with a book directory left by an earlier run (ours or
+/// safaribooks.py's): skip the book, resume it, or start over as
+/// `--if-exists` says. Continue(true) when resuming.
+fn settle_existing(
+    ui: &Display,
+    if_exists: IfExists,
+    record: Option<&BookRecord>,
+    recorded: Option<&PathBuf>,
+    bookid: &str,
+    skeleton: &mut EpubSkeleton,
+) -> Result<ControlFlow<PathBuf, bool>> {
+    let python = legacy::is_python_download(&skeleton.root);
+
+    let mut resume = false;
+    if skeleton.root.exists() {
+        match if_exists {
+            IfExists::Skip
+                if recorded == Some(&skeleton.epub)
+                    || skeleton.is_complete(bookid)
+                    || (python && legacy::is_finished(skeleton)) =>
+            {
+                ui.info(&t!(
+                    "already-downloaded",
+                    path = skeleton.epub.display().to_string()
+                ));
+                ui.event(&DownloadEvent::BookSkipped {
+                    path: skeleton.epub.display().to_string(),
+                });
+                return Ok(ControlFlow::Break(skeleton.epub.clone()));
+            }
+            IfExists::Skip | IfExists::Resume => {
+                ui.info(&t!(
+                    "resuming-in",
+                    path = skeleton.root.display().to_string()
+                ));
+                match record {
+                    Some(BookRecord {
+                        status: Status::Failed,
+                        finished,
+                        error,
+                        ..
+                    }) => ui.info(&t!(
+                        "last-download-failed",
+                        finished = finished.clone().unwrap_or_else(|| t!("not-available")),
+                        error = error.clone().unwrap_or_else(|| t!("unknown-error"))
+                    )),
+                    Some(BookRecord {
+                        status: Status::Running,
+                        ..
+                    }) => ui.info(&t!("last-download-interrupted")),
+                    _ => {}
+                }
+                if python {
+                    ui.info(&t!(
+                        "importing-python-download",
+                        path = skeleton.root.display().to_string()
+                    ));
+                    skeleton.legacy_names = true;
+                    legacy::prepare(skeleton).with_context(|| t!("import-failed"))?;
+                }
+                resume = true;
+            }
+            IfExists::Overwrite => {
+                ui.info(&t!(
+                    "overwriting",
+                    path = skeleton.root.display().to_string()
+                ));
+                std::fs::remove_dir_all(&skeleton.root).with_context(|| {
+                    t!(
+                        "removing-directory",
+                        path = skeleton.root.display().to_string()
+                    )
+                })?;
+            }
+            IfExists::Rename => {
+                *skeleton = skeleton.renamed();
+                ui.info(&t!(
+                    "directory-exists",
+                    path = skeleton.root.display().to_string()
+                ));
+            }
+        }
+    }
+    Ok(ControlFlow::Continue(resume))
+}
+
+/// Point out the assets the book was finished without, or with --strict
+/// fail instead; either way the book directory's report lists them.
+fn check_missing(
+    ui: &Display,
+    settings: &Settings,
+    skeleton: &EpubSkeleton,
+    stats: &BuildStats,
+) -> Result<()> {
+    if stats.failed_assets == 0 {
+        return Ok(());
+    }
+    let report = skeleton.root.join(MISSING_REPORT).display().to_string();
+    if settings.strict() {
+        bail!(t!(
+            "assets-missing-strict",
+            count = stats.failed_assets,
+            report = report
+        ));
+    }
+    ui.warn(&t!(
+        "assets-missing",
+        count = stats.failed_assets,
+        report = report
+    ));
+    Ok(())
+}
+
+/// Count a book of the batch as done and, once there is a pace to go by
+/// and books are left, say how long they will take.
+fn report_batch(ui: &Display, progress: &Mutex<Progress>) {
+    let mut progress = progress.lock().unwrap();
+    progress.step(1);
+    let Some(left) = progress.remaining().filter(|left| !left.is_zero()) else {
+        return;
+    };
+    let (done, total) = (progress.done(), progress.total());
+    drop(progress);
+    ui.info(&t!(
+        "batch-time-left",
+        done = done,
+        total = total,
+        left = format_duration(left)
+    ));
+    ui.event(&DownloadEvent::Progress {
+        scope: "batch",
+        done,
+        total,
+        eta_seconds: left.as_secs(),
+    });
+}
+
+/// Write the `--sidecar` metadata next to `epub`, built from `source`; a
+/// failure there costs a warning, not the book.
+fn write_sidecars(
+    ui: &Display,
+    settings: &Settings,
+    epub: &Path,
+    bookid: &str,
+    info: &BookInfo,
+    source: &str,
+) {
+    if settings.sidecar().is_empty() {
+        return;
+    }
+    if let Err(e) = sidecars(ui, settings.sidecar(), epub, bookid, info, source) {
+        ui.warn(&t!("sidecar-failed", error = format!("{e:#}")));
+    }
+}
+
+fn sidecars(
+    ui: &Display,
+    formats: &[SidecarFormat],
+    epub: &Path,
+    bookid: &str,
+    info: &BookInfo,
+    source: &str,
+) -> Result<()> {
+    let provenance = sidecar::Provenance {
+        book_id: bookid,
+        web_url: &info.web_url,
+        source,
+        created: &iso_datetime(SystemTime::now()),
+        api: &info.raw,
+    };
+    for path in sidecar::write(epub, formats, &provenance)? {
+        ui.info(&t!("wrote", path = path.display().to_string()));
+    }
+    Ok(())
+}
+
+/// `--cover-only` / `--metadata-only`: write the fresh cover and metadata
+/// of the book next to its `epub`.
+async fn refresh_extras<A: OreillyApi>(
+    api: &A,
+    ui: &Display,
+    settings: &Settings,
+    only: ExtrasOnly,
+    bookid: &str,
+    info: &BookInfo,
+    epub: &Path,
+) -> Result<()> {
+    ui.info(&t!("refreshing-extras", path = epub.display().to_string()));
+    if only.metadata {
+        let formats = match settings.sidecar() {
+            [] => &[SidecarFormat::Json, SidecarFormat::Opf][..],
+            formats => formats,
+        };
+        sidecars(ui, formats, epub, bookid, info, api.base_url())
+            .with_context(|| t!("metadata-write-failed"))?;
+    }
+    if only.cover {
+        let dir = epub.parent().unwrap_or(Path::new("."));
+        let path = fetch_cover(api, bookid, info, dir).await?;
+        ui.info(&t!("wrote", path = path.display().to_string()));
+    }
+    Ok(())
+}
+
+/// Download the book's cover into `dir` as cover.<ext>, returning its path.
+async fn fetch_cover<A: OreillyApi>(
+    api: &A,
+    bookid: &str,
+    info: &BookInfo,
+    dir: &Path,
+) -> Result<PathBuf> {
+    let url = info
+        .cover
+        .clone()
+        .unwrap_or_else(|| cover_url(api.base_url(), bookid));
+    let res = api.get(&url).await.with_context(|| t!("fetching-cover"))?;
+    if res.status != 200 {
+        bail!(t!("cover-failed", status = res.status));
+    }
+    let media_type = validate::sniff(&res.body).with_context(|| t!("cover-not-image"))?;
+    let path = dir.join(format!("cover.{}", cover_extension(media_type)));
+    fs::write(&path, &res.body)
+        .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+    Ok(path)
+}
+
+/// The `--output` that streams the EPUB to stdout.
+pub const STDOUT: &str = "-";
+
+/// Copy the EPUB of `outcome` to stdout; a temporary one, packaged only
+/// for this, is removed afterwards.
+fn stream_to_stdout(outcome: &Outcome) -> Result<()> {
+    let (Outcome::Finished(epub) | Outcome::Skipped(epub)) = outcome;
+    let mut file = fs::File::open(epub)
+        .with_context(|| t!("opening-file", path = epub.display().to_string()))?;
+    let mut stdout = std::io::stdout().lock();
+    std::io::copy(&mut file, &mut stdout).with_context(|| t!("stdout-write-failed"))?;
+    stdout.flush().with_context(|| t!("stdout-write-failed"))?;
+    if matches!(outcome, Outcome::Finished(_)) && epub.starts_with(std::env::temp_dir()) {
+        let _ = fs::remove_file(epub);
+        let _ = fs::remove_file(manifest_path(epub));
+    }
+    Ok(())
+}
+
+/// Apply `update` to the state database, if there is one; a failure
+/// there costs a warning, not the download.
+fn update_state(
+    ui: &Display,
+    state: Option<&StateDb>,
+    update: impl FnOnce(&StateDb) -> Result<()>,
+) {
+    if let Some(db) = state
+        && let Err(e) = update(db)
+    {
+        ui.warn(&t!("state-update-failed", error = format!("{e:#}")));
+    }
+}
+
+/// Books and video courses (as transcripts) are all that can be built.
+fn check_supported(info: &BookInfo) -> Result<()> {
+    match info.product_type() {
+        ProductType::Book | ProductType::Video => Ok(()),
+        ProductType::Audiobook => bail!(t!("unsupported-audiobook")),
+        ProductType::Other(format) => bail!(t!("unsupported-product", format = format)),
+    }
+}
+
+/// The annotations and reading position the settings ask to build in; a
+/// failure to fetch them only costs a warning.
+async fn extras<A: OreillyApi>(
+    api: &A,
+    ui: &Display,
+    settings: &Settings,
+    bookid: &str,
+) -> (Vec<Annotation>, Option<ReadingPosition>) {
+    let annotations = if settings.embed_annotations() {
+        match fetch_annotations(api, bookid).await {
+            Ok(annotations) => {
+                ui.info(&t!("annotations-to-embed", count = annotations.len()));
+                annotations
+            }
+            Err(e) => {
+                ui.warn(&t!("annotations-failed", error = format!("{e:#}")));
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    let position = if settings.bookmark() {
+        match fetch_reading_position(api, bookid).await {
+            Ok(position) => position,
+            Err(e) => {
+                ui.warn(&t!("position-failed", error = format!("{e:#}")));
+                None
+            }
+        }
+    } else {
+        None
+    };
+    (annotations, position)
+}
+
+/// Write the book's chapters (or lesson transcripts) and package documents.
+async fn build<A: OreillyApi>(
+    api: &A,
+    ui: &Display,
+    bookid: &str,
+    info: &BookInfo,
+    skeleton: &EpubSkeleton,
+    opts: &BookOptions,
+) -> Result<BuildStats> {
+    match info.product_type() {
+        ProductType::Video => {
+            // Video course: assemble the lesson transcripts into the EPUB.
+            ui.info(&t!("video-course"));
+            build_transcript_epub(api, ui, bookid, info, skeleton, opts)
+                .await
+                .with_context(|| t!("transcript-epub-failed"))
+        }
+        _ => {
+            ui.info(&t!("downloading-chapters"));
+            build_book_epub(api, ui, bookid, info, skeleton, opts)
+                .await
+                .with_context(|| t!("book-epub-failed"))
+        }
+    }
+}
+
+/// Check the written book and deliver it in the chosen format, then
+/// record it as complete.
+fn finish_book(
+    book: &BookContext,
+    state: Option<&StateDb>,
+    position: Option<&ReadingPosition>,
+    streamed: Option<EpubStream>,
+) -> Result<()> {
+    let BookContext {
+        ui,
+        settings,
+        book_id: bookid,
+        workdir: skeleton,
+        ..
+    } = *book;
+    match validate::validate(skeleton) {
+        Ok(problems) if problems.is_empty() => ui.info(&t!("package-check-passed")),
+        Ok(problems) => {
+            for problem in &problems {
+                ui.warn(&t!("package-check-problem", problem = problem.to_string()));
+            }
+            if settings.strict() {
+                bail!(t!("package-check-strict", count = problems.len()));
+            }
+            ui.warn(&t!("package-check-problems", count = problems.len()));
+        }
+        Err(e) => ui.warn(&t!("package-check-failed", error = e.to_string())),
+    }
+
+    let entries = settings.format().write(book, streamed)?;
+    if let Some(position) = position {
+        match skeleton.write_bookmark(position) {
+            Ok(path) => ui.info(&t!("position-saved", path = path.display().to_string())),
+            Err(e) => ui.warn(&t!("position-save-failed", error = format!("{e:#}"))),
+        }
+    }
+    update_state(ui, state, |db| {
+        db.finished(bookid, &skeleton.epub, &entries)
+    });
+    if !settings.keep_workdir() {
+        // The EPUB holds all of it; keeping both doubles the disk used.
+        if let Err(e) = skeleton
+            .remove_workdir()
+            .and_then(|()| split::remove_layouts(skeleton))
+        {
+            ui.warn(&t!("workdir-remove-failed", error = format!("{e:#}")));
+        }
+    }
+    Ok(())
+}
+
+/// `rebuild`: write the EPUB in `dir` again from its `--mirror`, without
+/// the network, and return its path.
+pub async fn rebuild(
+    ui: &Display,
+    settings: &Settings,
+    state: Option<&StateDb>,
+    naming: &Naming,
+    metadata: &MetadataOverrides,
+    dir: &Path,
+) -> Result<PathBuf> {
+    let (mirrored, responses) = mirror::open(dir)?;
+    let bookid = mirrored.book_id.as_str();
+    ui.info(&t!(
+        "rebuilding",
+        book = bookid,
+        mirrored = mirrored.mirrored.as_str()
+    ));
+    let api = Offline::new(&mirrored, responses);
+    let bookinfo = fetch_book_info(&api, bookid)
+        .await
+        .with_context(|| t!("mirror-no-book-info"))?;
+    check_supported(&bookinfo)?;
+
+    // Keep the directory, and name the EPUB as a download would now.
+    let parent = dir.parent().unwrap_or(dir);
+    let limit = NameLimit::detect(parent, settings.name_max);
+    let skeleton =
+        EpubSkeleton::plan(parent, naming, limit, bookid, &bookinfo).relocated(dir.to_path_buf());
+    // Nothing of the previous build is reused: all of it is in the mirror.
+    for old in [&skeleton.meta_inf, &skeleton.oebps] {
+        if old.exists() {
+            fs::remove_dir_all(old)
+                .with_context(|| t!("removing-directory", path = old.display().to_string()))?;
+        }
+    }
+    (|| -> Result<()> {
+        skeleton.create_dirs()?;
+        skeleton.write_mimetype()?;
+        skeleton.write_container_xml()?;
+        Ok(())
+    })()
+    .with_context(|| t!("skeleton-failed"))?;
+    update_state(ui, state, |db| db.started(bookid, &bookinfo, dir));
+
+    let (annotations, position) = extras(&api, ui, settings, bookid).await;
+    let opts = BookOptions {
+        jobs: settings.jobs(),
+        resume: false,
+        credits: settings.credits(),
+        annotations,
+        position,
+        metadata: metadata.clone(),
+        // Nothing is downloaded.
+        confirm_above: None,
+        code_style: settings.code_style(),
+        table_style: settings.table_style(),
+        rasterize_svg: settings.rasterize_svg(),
+        inline_below: settings.inline_images(),
+        split_above: settings.format().split_above(settings),
+        stealth: settings.stealth(),
+        // The mirror is on disk; a rebuild is quick.
+        cancel: CancellationToken::new(),
+        package: None,
+    };
+    let stats = build(&api, ui, bookid, &bookinfo, &skeleton, &opts).await?;
+    check_missing(ui, settings, &skeleton, &stats)?;
+    let book = BookContext {
+        ui,
+        settings,
+        book_id: bookid,
+        info: &bookinfo,
+        workdir: &skeleton,
+        chapters: &stats.written,
+        dest: &skeleton.epub,
+    };
+    finish_book(&book, state, opts.position.as_ref(), None)?;
+    let raw = dir.join(mirror::RAW_DIR).display().to_string();
+    write_sidecars(ui, settings, &skeleton.epub, bookid, &bookinfo, &raw);
+    Ok(skeleton.epub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build, dedup_queue, rebuild, refresh_extras, settle_existing, ExtrasOnly};
+    use crate::api::FixtureApi;
+    use crate::book::BookOptions;
+    use crate::config::{Naming, Settings};
+    use crate::display::Display;
+    use crate::epub::{EpubSkeleton, IfExists, NameLimit};
+    use crate::mirror::{self, Mirror};
+    use crate::orly::{fetch_book_info, DEFAULT_BASE_URL};
+    use serde_json::json;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::ops::ControlFlow;
+    use tokio_util::sync::CancellationToken;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    #[tokio::test]
+    async fn rebuilds_from_the_mirror() {
+        let book = format!("{DEFAULT_BASE_URL}/api/v1/book/123");
+        let api = FixtureApi::default()
+            .with(
+                &book,
+                200,
+                json!({
+                    "title": "Mirrored",
+                    "web_url": "https://learning.oreilly.com/library/view/mirrored/123/",
+                    "chapters": format!("{book}/chapter/")
+                })
+                .to_string(),
+            )
+            .with(
+                &format!("{book}/chapter/"),
+                200,
+                json!({
+                    "next": null,
+                    "results": [{
+                        "title": "Chapter 1",
+                        "filename": "ch01.html",
+                        "content": format!("{book}/chapter-content/ch01.html"),
+                        "stylesheets": [{ "url": "https://cdn.example.com/epub.css" }]
+                    }]
+                })
+                .to_string(),
+            )
+            .with(
+                &format!("{book}/chapter-content/ch01.html"),
+                200,
+                "<p>Kept</p>",
+            )
+            .with("https://cdn.example.com/epub.css", 200, "p { margin: 0 }");
+
+        let ui = Display::for_tests();
+        let base = std::env::temp_dir().join(format!("safaribooks-rebuild-{}", std::process::id()));
+        let naming = Naming::default();
+        let mut settings = Settings {
+            keep_workdir: Some(true),
+            ..Default::default()
+        };
+        let opts = BookOptions {
+            jobs: 2,
+            resume: false,
+            credits: false,
+            annotations: Vec::new(),
+            position: None,
+            metadata: Default::default(),
+            confirm_above: None,
+            code_style: Default::default(),
+            table_style: Default::default(),
+            rasterize_svg: false,
+            inline_below: None,
+            split_above: None,
+            stealth: false,
+            cancel: CancellationToken::new(),
+            package: None,
+        };
+        let mirror = Mirror::new(&api, None);
+        let info = fetch_book_info(&mirror, "123").await.unwrap();
+        let skeleton = EpubSkeleton::plan(&base, &naming, NameLimit::default(), "123", &info);
+        skeleton.create_dirs().unwrap();
+        let raw = mirror::start(&skeleton.root, "123", DEFAULT_BASE_URL).unwrap();
+        let mirror = Mirror::new(&api, Some(raw));
+        fetch_book_info(&mirror, "123").await.unwrap();
+        build(&mirror, &ui, "123", &info, &skeleton, &opts)
+            .await
+            .unwrap();
+
+        // Without the mirror's responses the build from scratch would fail.
+        fs::remove_dir_all(&skeleton.oebps).unwrap();
+        let epub = rebuild(
+            &ui,
+            &settings,
+            None,
+            &naming,
+            &Default::default(),
+            &skeleton.root,
+        )
+        .await
+        .unwrap();
+        assert_eq!(epub, skeleton.epub);
+        assert!(epub.exists());
+        let ch01 = fs::read_to_string(skeleton.oebps.join("ch01.xhtml")).unwrap();
+        assert!(ch01.contains("<p>Kept</p>"));
+        // The book's stylesheet and the generated layout.css.
+        assert_eq!(
+            fs::read_dir(skeleton.oebps.join("Styles")).unwrap().count(),
+            2
+        );
+
+        // By default only the EPUB (and the mirror) is left.
+        settings.keep_workdir = None;
+        let rebuilt = rebuild(
+            &ui,
+            &settings,
+            None,
+            &naming,
+            &Default::default(),
+            &skeleton.root,
+        )
+        .await
+        .unwrap();
+        assert!(rebuilt.exists());
+        assert!(!skeleton.oebps.exists() && !skeleton.meta_inf.exists());
+        assert!(skeleton.root.join(mirror::RAW_DIR).is_dir());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refreshes_the_cover_and_metadata_of_a_downloaded_book() {
+        let api = FixtureApi::default().with(
+            &format!("{DEFAULT_BASE_URL}/library/cover/123/"),
+            200,
+            b"\xFF\xD8\xFF\xE0 jpeg".to_vec(),
+        );
+        let dir = std::env::temp_dir().join(format!("safaribooks-extras-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let epub = dir.join("Book.epub");
+        let mut zip = ZipWriter::new(File::create(&epub).unwrap());
+        let opts = SimpleFileOptions::default();
+        zip.start_file("META-INF/container.xml", opts).unwrap();
+        zip.write_all(br#"<container><rootfile full-path="content.opf"/></container>"#)
+            .unwrap();
+        zip.start_file("content.opf", opts).unwrap();
+        zip.write_all(b"<package><metadata><dc:title>Old</dc:title></metadata></package>")
+            .unwrap();
+        zip.finish().unwrap();
+        let info = serde_json::from_value(json!({
+            "title": "Book",
+            "web_url": "https://learning.oreilly.com/library/view/book/123/"
+        }))
+        .unwrap();
+
+        let only = ExtrasOnly {
+            cover: true,
+            metadata: true,
+        };
+        let ui = Display::for_tests();
+        refresh_extras(&api, &ui, &Settings::default(), only, "123", &info, &epub)
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read(dir.join("cover.jpg")).unwrap(),
+            b"\xFF\xD8\xFF\xE0 jpeg"
+        );
+        assert!(dir.join("metadata.json").is_file() && dir.join("metadata.opf").is_file());
+
+        // A login page instead of the cover is not written as one.
+        let api = FixtureApi::default().with(
+            &format!("{DEFAULT_BASE_URL}/library/cover/123/"),
+            200,
+            "<html>Sign in</html>",
+        );
+        let cover_only = ExtrasOnly {
+            cover: true,
+            metadata: false,
+        };
+        assert!(refresh_extras(
+            &api,
+            &ui,
+            &Settings::default(),
+            cover_only,
+            "123",
+            &info,
+            &epub
+        )
+        .await
+        .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_a_finished_python_download_alone_when_skipping() {
+        let info = serde_json::from_value(json!({
+            "title": "Python Book",
+            "web_url": "https://learning.oreilly.com/library/view/python-book/123/"
+        }))
+        .unwrap();
+        let base = std::env::temp_dir().join(format!("safaribooks-skip-py-{}", std::process::id()));
+        let naming = Naming {
+            legacy: true,
+            ..Naming::default()
+        };
+        let plan = || EpubSkeleton::plan(&base, &naming, NameLimit::default(), "123", &info);
+        let planned = plan();
+        fs::create_dir_all(planned.oebps.join("Styles")).unwrap();
+        fs::write(
+            planned.oebps.join("ch01.xhtml"),
+            "<html><head><style>#sbo-rt-content *{text-indent:0pt!important;}</style></head>\
+             <body><p>Saved</p></body></html>",
+        )
+        .unwrap();
+        let style = planned.oebps.join("Styles/Style00.css");
+        fs::write(&style, "p {}").unwrap();
+        fs::write(&planned.epub, "PK finished").unwrap();
+        let ui = Display::for_tests();
+
+        let mut skeleton = plan();
+        let settled = settle_existing(&ui, IfExists::Skip, None, None, "123", &mut skeleton);
+        assert_eq!(settled.unwrap(), ControlFlow::Break(planned.epub.clone()));
+        assert!(style.is_file());
+
+        // Unfinished, it is taken over and resumed instead.
+        fs::remove_file(&planned.epub).unwrap();
+        let mut skeleton = plan();
+        let settled = settle_existing(&ui, IfExists::Skip, None, None, "123", &mut skeleton);
+        assert_eq!(settled.unwrap(), ControlFlow::Continue(true));
+        assert!(skeleton.legacy_names && !style.exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn dedups_the_queue() {
+        let given: Vec<String> = ["123", "456", " 123", "789", "456", "123"]
+            .map(String::from)
+            .to_vec();
+        let (queue, duplicates) = dedup_queue(&given);
+        assert_eq!(queue, ["123", "456", "789"]);
+        assert_eq!(duplicates, ["123", "456"]);
+    }
+}
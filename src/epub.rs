@@ -1,43 +1,486 @@
+use crate::config::{Naming, NamingTemplate};
+use crate::i18n::t;
+use crate::orly::{BookInfo, ReadingPosition};
+use crate::series;
 use anyhow::{Context, Result};
+use clap::ValueEnum;
+use deunicode::deunicode;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
 use unicode_normalization::UnicodeNormalization;
 
+/// The longest file name the output filesystem accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameLimit {
+    /// UTF-8 bytes, as on most Unix filesystems (255; 143 on eCryptfs).
+    Bytes(usize),
+    /// UTF-16 code units, as on NTFS and exFAT.
+    Utf16(usize),
+}
+
+impl Default for NameLimit {
+    fn default() -> Self {
+        if cfg!(windows) {
+            Self::Utf16(255)
+        } else {
+            Self::Bytes(255)
+        }
+    }
+}
+
+impl NameLimit {
+    /// The limit for names created under `dir`: `configured` if given,
+    /// otherwise what the filesystem reports, otherwise 255. Names count
+    /// in UTF-16 units on Windows and on NTFS, exFAT or FAT mounted here.
+    pub fn detect(dir: &Path, configured: Option<usize>) -> Self {
+        let found = fs_name_limit(dir).unwrap_or_default();
+        match configured {
+            Some(max) => found.with_max(max),
+            None => found,
+        }
+    }
+
+    fn with_max(self, max: usize) -> Self {
+        match self {
+            Self::Bytes(_) => Self::Bytes(max),
+            Self::Utf16(_) => Self::Utf16(max),
+        }
+    }
+
+    fn max(self) -> usize {
+        match self {
+            Self::Bytes(max) | Self::Utf16(max) => max,
+        }
+    }
+
+    fn measure(self, s: &str) -> usize {
+        match self {
+            Self::Bytes(_) => s.len(),
+            Self::Utf16(_) => s.encode_utf16().count(),
+        }
+    }
+
+    /// The longest prefix of `s` measuring at most `max`, on a char boundary.
+    fn truncate(self, s: &str, max: usize) -> &str {
+        match self {
+            Self::Bytes(_) => truncate_utf8_by_byte(s, max),
+            Self::Utf16(_) => {
+                let mut units = 0;
+                for (i, ch) in s.char_indices() {
+                    units += ch.len_utf16();
+                    if units > max {
+                        return &s[..i];
+                    }
+                }
+                s
+            }
+        }
+    }
+}
+
+/// The name limit of the filesystem holding `dir` (or its nearest
+/// existing parent).
+#[cfg(unix)]
+fn fs_name_limit(dir: &Path) -> Option<NameLimit> {
+    let existing = dir.ancestors().find(|d| d.exists())?;
+    let existing = if existing.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        existing
+    };
+    // These report NAME_MAX in bytes (up to 3 per UTF-16 unit), which
+    // would let through names Windows cannot open.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Ok(fs) = rustix::fs::statfs(existing)
+        && counts_utf16(fs.f_type as u64)
+    {
+        return Some(NameLimit::Utf16(255));
+    }
+    let max = rustix::fs::statvfs(existing).ok()?.f_namemax;
+    let max = usize::try_from(max).ok().filter(|&m| m > 0)?;
+    Some(NameLimit::Bytes(max))
+}
+
+#[cfg(not(unix))]
+fn fs_name_limit(_dir: &Path) -> Option<NameLimit> {
+    None
+}
+
+/// Whether a Linux filesystem (by statfs magic) names files in UTF-16:
+/// NTFS (ntfs3 and the old driver), exFAT, FAT.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn counts_utf16(magic: u64) -> bool {
+    const NTFS3: u64 = 0x7366_746e;
+    const NTFS: u64 = 0x5346_544e;
+    const EXFAT: u64 = 0x2011_bab0;
+    const MSDOS: u64 = 0x4d44;
+    matches!(magic, NTFS3 | NTFS | EXFAT | MSDOS)
+}
+
+/// Windows refuses paths longer than MAX_PATH (260 UTF-16 units) unless
+/// they are extended-length (`\\?\C:\...`), and a deep book directory plus
+/// `OEBPS/Images/<name>` gets there quickly. Such a directory gets the
+/// prefix; shorter ones keep their familiar form.
+#[cfg(windows)]
+fn long_path(dir: PathBuf) -> PathBuf {
+    /// Room kept for the files inside the book directory.
+    const INSIDE: usize = 100;
+    let Ok(absolute) = std::path::absolute(&dir) else {
+        return dir;
+    };
+    match absolute.to_str() {
+        Some(s) if s.encode_utf16().count() + INSIDE >= 260 => PathBuf::from(verbatim(s)),
+        _ => dir,
+    }
+}
+
+#[cfg(not(windows))]
+fn long_path(dir: PathBuf) -> PathBuf {
+    dir
+}
+
+/// The extended-length form of an absolute, normalized Windows path.
+#[cfg(any(windows, test))]
+fn verbatim(path: &str) -> String {
+    if path.starts_with(r"\\?\") {
+        path.to_string()
+    } else if let Some(share) = path.strip_prefix(r"\\") {
+        format!(r"\\?\UNC\{share}")
+    } else {
+        format!(r"\\?\{path}")
+    }
+}
+
+/// Bytes available to us on the filesystem holding `dir`, if it can be told.
+#[cfg(unix)]
+pub fn free_space(dir: &Path) -> Option<u64> {
+    let existing = dir.ancestors().find(|d| d.exists())?;
+    let stat = rustix::fs::statvfs(existing).ok()?;
+    Some(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Written next to the EPUB once it was packaged, before the state
+/// database (`state`) took over; still marks those books as complete.
+const STATE_FILE: &str = ".safaribooks-state.json";
+
+/// What to do when the book directory already exists.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IfExists {
+    /// Do nothing if the EPUB there is complete; otherwise resume.
+    Skip,
+    /// Delete the directory and download from scratch.
+    Overwrite,
+    /// Keep the files already downloaded and fetch only what is missing.
+    #[default]
+    Resume,
+    /// Download into a new "<name> (2)" directory next to it.
+    Rename,
+}
+
+/// Contents of the state file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct BookState {
+    book_id: String,
+    /// File name of the EPUB inside the book directory.
+    epub: String,
+    /// Its size in bytes when it was written.
+    size: u64,
+}
+
 pub struct EpubSkeleton {
-    /// Books/<book_title (book_id)>/
+    /// Books/<book_title (book_id)>/ by default; see `config::Naming`.
     pub root: PathBuf,
     pub meta_inf: PathBuf,
     pub oebps: PathBuf,
+    /// The packaged book, inside `root`.
+    pub epub: PathBuf,
+    /// Language of the content, for `xml:lang` and the text direction.
+    pub language: String,
+    /// Name the assets as safaribooks.py does (see `config::Naming`).
+    pub legacy_names: bool,
+}
+
+/// An entry of the package manifest (OEBPS/content.opf).
+#[derive(Clone)]
+pub struct ManifestItem {
+    pub id: String,
+    /// Path relative to OEBPS/.
+    pub href: String,
+    pub media_type: String,
+}
+
+/// A navigation point of the table of contents; children mirror nesting.
+pub struct NavPoint {
+    pub title: String,
+    /// Path relative to OEBPS/, optionally with a #fragment.
+    pub href: String,
+    pub children: Vec<NavPoint>,
+}
+
+/// A landmark of the nav document: a place readers can jump to by role.
+pub struct Landmark {
+    /// Its `epub:type`, e.g. "bodymatter".
+    pub kind: &'static str,
+    pub title: String,
+    /// Path relative to OEBPS/, optionally with a #fragment.
+    pub href: String,
+}
+
+/// A page of the print edition, from the page markers in the chapters.
+pub struct PageTarget {
+    /// The printed page number, e.g. "12" or "xiv".
+    pub label: String,
+    /// Path relative to OEBPS/ with the #fragment of the marker.
+    pub href: String,
+}
+
+/// The generated credits page appended to the spine (see `--no-credits`).
+pub const COLOPHON_HREF: &str = "colophon.xhtml";
+
+/// Metadata written into the OPF <metadata> block.
+#[derive(Clone)]
+pub struct PackageMetadata {
+    pub identifier: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub language: String,
+    pub source: Option<String>,
+    pub isbn: Option<String>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    pub description: Option<String>,
+    pub subjects: Vec<String>,
+    pub rights: Option<String>,
+    pub series: Option<String>,
+    /// Position in the series, e.g. 2 or 2.5.
+    pub series_index: Option<f64>,
+}
+
+/// Corrections to the API metadata given on the command line.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MetadataOverrides {
+    pub title: Option<String>,
+    /// Replace the API authors when not empty.
+    pub authors: Vec<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    /// Guess the series from the title when neither the API nor --series
+    /// names one.
+    pub series_from_title: bool,
+    /// Replace the API topics when not empty.
+    pub tags: Vec<String>,
+}
+
+impl MetadataOverrides {
+    /// Whether these are the values of one book, everything but
+    /// `series_from_title`.
+    pub fn names_values(&self) -> bool {
+        *self
+            != Self {
+                series_from_title: self.series_from_title,
+                ..Self::default()
+            }
+    }
+}
+
+impl PackageMetadata {
+    /// Collect the OPF metadata from the API book info.
+    pub fn from_book_info(bookid: &str, info: &BookInfo) -> Self {
+        Self {
+            identifier: bookid.to_string(),
+            title: info.title.clone(),
+            authors: info.author_names(),
+            language: info.language(),
+            source: Some(info.web_url.clone()),
+            isbn: info.isbn.clone(),
+            publisher: info.publisher().map(str::to_string),
+            date: info.issued.clone(),
+            description: info.description.clone(),
+            subjects: info.subject_names(),
+            rights: info.rights.clone(),
+            series: info.series.as_ref().map(|s| s.name.trim().to_string()),
+            series_index: info.series.as_ref().and_then(|s| s.index),
+        }
+    }
+
+    /// Apply the user's corrections on top of the API values.
+    pub fn apply(&mut self, overrides: &MetadataOverrides) {
+        if let Some(title) = &overrides.title {
+            self.title = title.clone();
+        }
+        if !overrides.authors.is_empty() {
+            self.authors = overrides.authors.clone();
+        }
+        if overrides.series.is_some() {
+            self.series = overrides.series.clone();
+            self.series_index = overrides.series_index;
+        } else if self.series.is_none()
+            && overrides.series_from_title
+            && let Some((series, index)) = series::from_title(&self.title)
+        {
+            self.series = Some(series);
+            self.series_index = index;
+        }
+        if !overrides.tags.is_empty() {
+            self.subjects = overrides.tags.clone();
+        }
+    }
 }
 
 impl EpubSkeleton {
-    /// Plan the output directory structure using the sanitized title + bookid.
-    pub fn plan(base_books_dir: &Path, title: &str, bookid: &str) -> Self {
-        // Maximum number of bytes in a filename.
-        const MAX_BYTES: usize = 255;
-        let clean_title = sanitize_filename(title);
-        let root_name = if !clean_title.is_empty() {
-            // Title length should take into account the bookid, space, and () characters.
-            let title_max_length = MAX_BYTES.saturating_sub(3 + bookid.len());
-            let truncated_title = truncate_utf8_by_byte(&clean_title, title_max_length);
-            format!("{} ({})", truncated_title, bookid)
+    /// Plan the output layout from the naming templates; every placeholder
+    /// value is sanitized, and an over-long title is shortened to fit `limit`.
+    pub fn plan(
+        base_books_dir: &Path,
+        naming: &Naming,
+        limit: NameLimit,
+        bookid: &str,
+        info: &BookInfo,
+    ) -> Self {
+        let clean = |value: &str| {
+            if naming.ascii {
+                sanitize_filename(&deunicode(value))
+            } else {
+                sanitize_filename(value)
+            }
+        };
+        let title = if naming.legacy {
+            clean(&legacy_title(&info.title))
         } else {
-            format!("({})", bookid)
+            clean(&info.title)
         };
-        let root_dir = base_books_dir.join(root_name);
+        let field = |name: &str, title: &str| match name {
+            "title" => title.to_string(),
+            "bookid" => clean(bookid),
+            "isbn" => info.isbn.as_deref().map(clean).unwrap_or_default(),
+            "author" => info
+                .authors
+                .first()
+                .map(|a| clean(&a.name))
+                .unwrap_or_default(),
+            "publisher" => info.publisher().map(clean).unwrap_or_default(),
+            "year" => info
+                .issued
+                .as_deref()
+                .and_then(|d| d.get(..4))
+                .map(clean)
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let render = |template: &NamingTemplate, max: usize| -> Vec<String> {
+            let full = template.render(|name| field(name, &title));
+            let bare = template.render(|name| field(name, ""));
+            full.into_iter()
+                .zip(bare)
+                .enumerate()
+                .map(|(i, (full, bare))| {
+                    let component = if limit.measure(&full) <= max {
+                        full
+                    } else {
+                        // Shorten the title first so the other fields survive.
+                        let room = max.saturating_sub(limit.measure(&bare));
+                        let short = limit.truncate(&title, room);
+                        let fitted = template.render(|name| field(name, short)).swap_remove(i);
+                        limit.truncate(&fitted, max).to_string()
+                    };
+                    let component = sanitize_filename(&component);
+                    if component.is_empty() {
+                        bookid.to_string()
+                    } else {
+                        component
+                    }
+                })
+                .collect()
+        };
+
+        let root_dir = render(&naming.dir, limit.max())
+            .into_iter()
+            .fold(base_books_dir.to_path_buf(), |dir, c| dir.join(c));
+        let root_dir = long_path(root_dir);
+        let file_name = render(&naming.file, limit.max().saturating_sub(".epub".len())).concat();
         Self {
             meta_inf: root_dir.join("META-INF"),
             oebps: root_dir.join("OEBPS"),
+            epub: root_dir.join(format!("{file_name}.epub")),
             root: root_dir,
+            language: info.language(),
+            legacy_names: naming.legacy,
         }
     }
 
+    /// The same layout in the first free "<root> (N)" sibling directory.
+    pub fn renamed(&self) -> Self {
+        let name = self.root.file_name().unwrap_or_default().to_string_lossy();
+        let root = (2..)
+            .map(|n| self.root.with_file_name(format!("{name} ({n})")))
+            .find(|dir| !dir.exists())
+            .expect("some suffix is free");
+        self.relocated(root)
+    }
+
+    /// The same layout, EPUB name included, in `root`.
+    pub fn relocated(&self, root: PathBuf) -> Self {
+        Self {
+            meta_inf: root.join("META-INF"),
+            oebps: root.join("OEBPS"),
+            epub: root.join(self.epub.file_name().unwrap_or_default()),
+            root,
+            language: self.language.clone(),
+            legacy_names: self.legacy_names,
+        }
+    }
+
+    /// Whether an old state file says the EPUB was written, and it is still there unchanged.
+    pub fn is_complete(&self, bookid: &str) -> bool {
+        let Ok(raw) = fs::read_to_string(self.root.join(STATE_FILE)) else {
+            return false;
+        };
+        let Ok(state) = serde_json::from_str::<BookState>(&raw) else {
+            return false;
+        };
+        let size = fs::metadata(&self.epub).map(|m| m.len()).ok();
+        state.book_id == bookid
+            && Some(state.epub.as_str()) == self.epub.file_name().and_then(|n| n.to_str())
+            && Some(state.size) == size
+    }
+
+    /// Whether an asset was already written by an earlier run.
+    pub fn has_asset(&self, href: &str) -> bool {
+        fs::metadata(self.oebps.join(href)).is_ok_and(|m| m.is_file() && m.len() > 0)
+    }
+
+    /// Delete the unpacked book (META-INF/ and OEBPS/) once it is packaged.
+    pub fn remove_workdir(&self) -> Result<()> {
+        for dir in [&self.meta_inf, &self.oebps] {
+            if dir.exists() {
+                fs::remove_dir_all(dir)
+                    .with_context(|| t!("removing-directory", path = dir.display().to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
     /// Create the directories defined in the struct.
     pub fn create_dirs(&self) -> Result<()> {
-        fs::create_dir_all(&self.oebps)
-            .with_context(|| format!("Creating directory {}", self.oebps.display()))?;
-        fs::create_dir_all(&self.meta_inf)
-            .with_context(|| format!("Creating directory {}", self.meta_inf.display()))?;
+        fs::create_dir_all(&self.oebps).with_context(|| {
+            t!(
+                "creating-directory",
+                path = self.oebps.display().to_string()
+            )
+        })?;
+        fs::create_dir_all(&self.meta_inf).with_context(|| {
+            t!(
+                "creating-directory",
+                path = self.meta_inf.display().to_string()
+            )
+        })?;
         Ok(())
     }
 
@@ -51,7 +494,8 @@ impl EpubSkeleton {
             </rootfiles>
             </container>
             "#;
-        fs::write(&path, xml).with_context(|| format!("Writing file {}", path.display()))?;
+        fs::write(&path, xml)
+            .with_context(|| t!("writing-file", path = path.display().to_string()))?;
         Ok(())
     }
 
@@ -60,9 +504,483 @@ impl EpubSkeleton {
         let path = self.root.join("mimetype");
         // EXACT bytes required by OCF; do not add '\n'.
         fs::write(&path, b"application/epub+zip")
-            .with_context(|| format!("Writing file {}", path.display()))?;
+            .with_context(|| t!("writing-file", path = path.display().to_string()))?;
         Ok(())
     }
+
+    /// Write an XHTML document under OEBPS/ wrapping the given body markup.
+    pub fn write_xhtml(&self, href: &str, title: &str, body: &str) -> Result<()> {
+        self.write_document(href, title, &[], body)
+    }
+
+    /// Write an XHTML document linking the given stylesheets (hrefs relative to OEBPS/).
+    pub fn write_document(
+        &self,
+        href: &str,
+        title: &str,
+        stylesheets: &[String],
+        body: &str,
+    ) -> Result<()> {
+        let path = self.oebps.join(href);
+        let links: String = stylesheets
+            .iter()
+            .map(|css| {
+                format!(
+                    "<link rel=\"stylesheet\" type=\"text/css\" href=\"{}\"/>\n",
+                    xml_escape(css)
+                )
+            })
+            .collect();
+        let lang = xml_escape(&self.language);
+        let (dir, style) = if is_rtl(&self.language) {
+            (" dir=\"rtl\"", RTL_STYLE)
+        } else {
+            ("", "")
+        };
+        let xhtml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="{lang}" lang="{lang}"{dir}>
+<head>
+<title>{}</title>
+{}{style}</head>
+<body>
+{}
+</body>
+</html>
+"#,
+            xml_escape(title),
+            links,
+            body
+        );
+        fs::write(&path, xhtml)
+            .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+        Ok(())
+    }
+
+    /// Write the colophon closing the book (what made it, when and from
+    /// where) and return its manifest item, for the end of the spine.
+    pub fn write_colophon(&self, meta: &PackageMetadata, downloaded: &str) -> Result<ManifestItem> {
+        let mut body = format!(
+            "<section epub:type=\"colophon\" role=\"doc-colophon\">\n<h1>Colophon</h1>\n<p><i>{}</i>",
+            xml_escape(&meta.title)
+        );
+        if !meta.authors.is_empty() {
+            body.push_str(&format!(" by {}", xml_escape(&meta.authors.join(", "))));
+        }
+        body.push_str(".</p>\n");
+        body.push_str(&format!(
+            "<p>Downloaded on {} with {} {}.</p>\n",
+            xml_escape(downloaded),
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        ));
+        if let Some(source) = &meta.source {
+            let source = xml_escape(source);
+            body.push_str(&format!(
+                "<p>Source: <a href=\"{source}\">{source}</a></p>\n"
+            ));
+        }
+        body.push_str("</section>");
+        self.write_xhtml(COLOPHON_HREF, "Colophon", &body)?;
+        Ok(ManifestItem {
+            id: "colophon".to_string(),
+            href: COLOPHON_HREF.to_string(),
+            media_type: "application/xhtml+xml".to_string(),
+        })
+    }
+
+    /// Save the web reader's last position next to the EPUB
+    /// (`<name>.bookmark.json`) for readers and scripts that want it.
+    pub fn write_bookmark(&self, position: &ReadingPosition) -> Result<PathBuf> {
+        let path = self.epub.with_extension("bookmark.json");
+        let json = serde_json::to_string_pretty(position)?;
+        fs::write(&path, json + "\n")
+            .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+        Ok(path)
+    }
+
+    /// Path of an asset under OEBPS/, creating its directory.
+    pub fn asset_path(&self, href: &str) -> Result<PathBuf> {
+        let path = self.oebps.join(href);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| t!("creating-directory", path = parent.display().to_string()))?;
+        }
+        Ok(path)
+    }
+
+    /// Write OEBPS/content.opf. The nav document and NCX are always declared;
+    /// `spine` lists manifest ids in reading order.
+    pub fn write_opf(
+        &self,
+        meta: &PackageMetadata,
+        manifest: &[ManifestItem],
+        spine: &[String],
+    ) -> Result<()> {
+        let path = self.oebps.join("content.opf");
+
+        let mut metadata = String::new();
+        metadata.push_str(&format!(
+            "<dc:identifier id=\"bookid\">{}</dc:identifier>\n",
+            xml_escape(&meta.identifier)
+        ));
+        metadata.push_str(&format!(
+            "<dc:title>{}</dc:title>\n",
+            xml_escape(&meta.title)
+        ));
+        for author in &meta.authors {
+            metadata.push_str(&format!(
+                "<dc:creator>{}</dc:creator>\n",
+                xml_escape(author)
+            ));
+        }
+        metadata.push_str(&format!(
+            "<dc:language>{}</dc:language>\n",
+            xml_escape(&meta.language)
+        ));
+        if let Some(source) = &meta.source {
+            metadata.push_str(&format!("<dc:source>{}</dc:source>\n", xml_escape(source)));
+        }
+        if let Some(isbn) = &meta.isbn {
+            metadata.push_str(&format!(
+                "<dc:identifier>urn:isbn:{}</dc:identifier>\n",
+                xml_escape(isbn)
+            ));
+        }
+        if let Some(publisher) = &meta.publisher {
+            metadata.push_str(&format!(
+                "<dc:publisher>{}</dc:publisher>\n",
+                xml_escape(publisher)
+            ));
+        }
+        if let Some(date) = &meta.date {
+            metadata.push_str(&format!("<dc:date>{}</dc:date>\n", xml_escape(date)));
+        }
+        if let Some(description) = &meta.description {
+            // The API description is HTML; escaping keeps the OPF well-formed.
+            metadata.push_str(&format!(
+                "<dc:description>{}</dc:description>\n",
+                xml_escape(description)
+            ));
+        }
+        for subject in &meta.subjects {
+            metadata.push_str(&format!(
+                "<dc:subject>{}</dc:subject>\n",
+                xml_escape(subject)
+            ));
+        }
+        if let Some(rights) = &meta.rights {
+            metadata.push_str(&format!("<dc:rights>{}</dc:rights>\n", xml_escape(rights)));
+        }
+        if let Some(series) = &meta.series {
+            // EPUB 3 collection, plus the meta tags calibre reads.
+            let series = xml_escape(series);
+            metadata.push_str(&format!(
+                "<meta property=\"belongs-to-collection\" id=\"series\">{series}</meta>\n\
+                 <meta refines=\"#series\" property=\"collection-type\">series</meta>\n\
+                 <meta name=\"calibre:series\" content=\"{series}\"/>\n"
+            ));
+            if let Some(index) = meta.series_index {
+                metadata.push_str(&format!(
+                    "<meta refines=\"#series\" property=\"group-position\">{index}</meta>\n\
+                     <meta name=\"calibre:series_index\" content=\"{index}\"/>\n"
+                ));
+            }
+        }
+
+        metadata.push_str(&accessibility_metadata(manifest));
+
+        let mut items = String::from(
+            "<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n\
+             <item id=\"ncx\" href=\"toc.ncx\" media-type=\"application/x-dtbncx+xml\"/>\n",
+        );
+        for item in manifest {
+            // Content documents with inline SVG have to say so.
+            let properties = if item.media_type == "application/xhtml+xml"
+                && fs::read_to_string(self.oebps.join(&item.href)).is_ok_and(|x| x.contains("<svg"))
+            {
+                " properties=\"svg\""
+            } else {
+                ""
+            };
+            items.push_str(&format!(
+                "<item id=\"{}\" href=\"{}\" media-type=\"{}\"{properties}/>\n",
+                xml_escape(&item.id),
+                xml_escape(&item.href),
+                xml_escape(&item.media_type)
+            ));
+        }
+
+        let itemrefs: String = spine
+            .iter()
+            .map(|id| format!("<itemref idref=\"{}\"/>\n", xml_escape(id)))
+            .collect();
+        let lang = xml_escape(&meta.language);
+        let (dir, progression) = if is_rtl(&meta.language) {
+            (" dir=\"rtl\"", " page-progression-direction=\"rtl\"")
+        } else {
+            ("", "")
+        };
+
+        let opf = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="bookid" xml:lang="{lang}"{dir}>
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+{metadata}</metadata>
+<manifest>
+{items}</manifest>
+<spine toc="ncx"{progression}>
+{itemrefs}</spine>
+</package>
+"#
+        );
+        fs::write(&path, opf)
+            .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+        Ok(())
+    }
+
+    /// Write both the EPUB 3 nav document (its landmarks are the table of
+    /// contents plus `landmarks`) and the EPUB 2 NCX for older readers, each
+    /// with a page list when the book has print `pages`.
+    pub fn write_toc(
+        &self,
+        meta: &PackageMetadata,
+        toc: &[NavPoint],
+        landmarks: &[Landmark],
+        pages: &[PageTarget],
+    ) -> Result<()> {
+        fn nav_list(points: &[NavPoint], out: &mut String) {
+            out.push_str("<ol>\n");
+            for p in points {
+                out.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a>",
+                    xml_escape(&p.href),
+                    xml_escape(&p.title)
+                ));
+                if !p.children.is_empty() {
+                    out.push('\n');
+                    nav_list(&p.children, out);
+                }
+                out.push_str("</li>\n");
+            }
+            out.push_str("</ol>\n");
+        }
+
+        fn ncx_points(points: &[NavPoint], order: &mut usize, out: &mut String) {
+            for p in points {
+                *order += 1;
+                out.push_str(&format!(
+                    "<navPoint id=\"navpoint-{0}\" playOrder=\"{0}\">\n\
+                     <navLabel><text>{1}</text></navLabel>\n\
+                     <content src=\"{2}\"/>\n",
+                    order,
+                    xml_escape(&p.title),
+                    xml_escape(&p.href)
+                ));
+                ncx_points(&p.children, order, out);
+                out.push_str("</navPoint>\n");
+            }
+        }
+
+        let mut list = String::new();
+        nav_list(toc, &mut list);
+        let mut body = format!(
+            "<nav epub:type=\"toc\" role=\"doc-toc\" id=\"toc\" aria-label=\"Table of Contents\">\n\
+             <h1>Table of Contents</h1>\n{list}</nav>"
+        );
+        body.push_str(
+            "\n<nav epub:type=\"landmarks\" id=\"landmarks\" aria-label=\"Landmarks\" hidden=\"hidden\">\n<ol>\n\
+             <li><a epub:type=\"toc\" href=\"nav.xhtml#toc\">Table of Contents</a></li>\n",
+        );
+        for l in landmarks {
+            body.push_str(&format!(
+                "<li><a epub:type=\"{}\" href=\"{}\">{}</a></li>\n",
+                l.kind,
+                xml_escape(&l.href),
+                xml_escape(&l.title)
+            ));
+        }
+        body.push_str("</ol>\n</nav>");
+        if !pages.is_empty() {
+            body.push_str(
+                "\n<nav epub:type=\"page-list\" role=\"doc-pagelist\" id=\"page-list\" aria-label=\"Pages\" hidden=\"hidden\">\n<ol>\n",
+            );
+            for page in pages {
+                body.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a></li>\n",
+                    xml_escape(&page.href),
+                    xml_escape(&page.label)
+                ));
+            }
+            body.push_str("</ol>\n</nav>");
+        }
+        self.write_xhtml("nav.xhtml", &meta.title, &body)?;
+
+        let mut points = String::new();
+        let mut order = 0;
+        ncx_points(toc, &mut order, &mut points);
+        let mut page_list = String::new();
+        if !pages.is_empty() {
+            page_list.push_str("<pageList>\n<navLabel><text>Pages</text></navLabel>\n");
+            for (n, page) in pages.iter().enumerate() {
+                order += 1;
+                // Front matter is numbered in roman numerals; `value` is
+                // only for arabic page numbers.
+                let kind = if page.label.bytes().all(|b| b.is_ascii_digit()) {
+                    format!("type=\"normal\" value=\"{}\"", page.label)
+                } else {
+                    "type=\"front\"".to_string()
+                };
+                page_list.push_str(&format!(
+                    "<pageTarget id=\"pagetarget-{}\" {kind} playOrder=\"{order}\">\n\
+                     <navLabel><text>{}</text></navLabel>\n\
+                     <content src=\"{}\"/>\n\
+                     </pageTarget>\n",
+                    n + 1,
+                    xml_escape(&page.label),
+                    xml_escape(&page.href)
+                ));
+            }
+            page_list.push_str("</pageList>\n");
+        }
+        let ncx = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1" xml:lang="{}">
+<head>
+<meta name="dtb:uid" content="{}"/>
+</head>
+<docTitle><text>{}</text></docTitle>
+<navMap>
+{points}</navMap>
+{page_list}</ncx>
+"#,
+            xml_escape(&meta.language),
+            xml_escape(&meta.identifier),
+            xml_escape(&meta.title)
+        );
+        let path = self.oebps.join("toc.ncx");
+        fs::write(&path, ncx)
+            .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+        Ok(())
+    }
+}
+
+/// Right-to-left text for readers that ignore `dir`; code keeps reading
+/// left to right.
+const RTL_STYLE: &str = "<style type=\"text/css\">\n\
+     html { direction: rtl; }\n\
+     pre, code, kbd, samp { direction: ltr; unicode-bidi: embed; text-align: left; }\n\
+     </style>\n";
+
+/// The schema.org accessibility metadata of a book with `manifest`: the
+/// text is always there, images only in some books, and every book gets a
+/// navigable table of contents in reading order.
+fn accessibility_metadata(manifest: &[ManifestItem]) -> String {
+    let images = manifest
+        .iter()
+        .any(|item| item.media_type.starts_with("image/"));
+    let mut modes = vec!["textual"];
+    let mut features = vec!["structuralNavigation", "tableOfContents", "readingOrder"];
+    if images {
+        modes.push("visual");
+        features.push("alternativeText");
+    }
+    let mut out = String::new();
+    for mode in &modes {
+        out.push_str(&format!(
+            "<meta property=\"schema:accessMode\">{mode}</meta>\n"
+        ));
+    }
+    out.push_str(&format!(
+        "<meta property=\"schema:accessModeSufficient\">{}</meta>\n",
+        modes.join(",")
+    ));
+    for feature in features {
+        out.push_str(&format!(
+            "<meta property=\"schema:accessibilityFeature\">{feature}</meta>\n"
+        ));
+    }
+    out.push_str(
+        "<meta property=\"schema:accessibilityHazard\">unknown</meta>\n\
+         <meta property=\"schema:accessibilitySummary\">Converted from the O'Reilly web \
+         reader: headings are kept in order and images carry the publisher's alternative \
+         text where it has any.</meta>\n",
+    );
+    out
+}
+
+/// Whether `lang` (a BCP 47 tag) is written right to left.
+fn is_rtl(lang: &str) -> bool {
+    let primary = lang.split(['-', '_']).next().unwrap_or_default();
+    matches!(
+        primary.to_ascii_lowercase().as_str(),
+        "ar" | "arc" | "ckb" | "dv" | "fa" | "he" | "iw" | "ps" | "sd" | "syr" | "ug" | "ur" | "yi"
+    ) || ["-arab", "-hebr"]
+        .iter()
+        .any(|script| lang.to_ascii_lowercase().contains(script))
+}
+
+/// Guess the manifest media type from a file extension.
+pub fn media_type_for(href: &str) -> &'static str {
+    let ext = href.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    match ext.as_str() {
+        "xhtml" | "html" | "htm" => "application/xhtml+xml",
+        "css" => "text/css",
+        "ncx" => "application/x-dtbncx+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Escape text for use in XML content and attribute values.
+pub fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// The title as safaribooks.py puts it in the book directory's name: a
+/// subtitle after a colon past the 15th character dropped, the characters
+/// it escapes replaced by '_', and only the text up to the second comma
+/// kept, without the comma.
+fn legacy_title(title: &str) -> String {
+    let mut title = title.to_string();
+    if let Some(colon) = title.find(':') {
+        if title[..colon].chars().count() > 15 {
+            title.truncate(colon);
+        } else if cfg!(windows) {
+            title = title.replace(':', ",");
+        }
+    }
+    let escaped: String = title
+        .chars()
+        .map(|c| {
+            if "~#%&*{}\\<>?/`'\"|+:".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    escaped.split(',').take(2).collect()
 }
 
 /// Sanitize a filename component for cross‑platform compatibility.
@@ -71,7 +989,8 @@ impl EpubSkeleton {
 /// - Replace illegal characters: <>:"/\\|?*
 /// - Remove control characters
 /// - Collapse whitespace
-/// - Trim whitespace
+/// - Trim whitespace, and trailing dots (Windows drops them)
+/// - Prefix Windows device names (CON, NUL, COM1, ...) with '_'
 fn sanitize_filename(input: &str) -> String {
     // Normalize to NFC to ensure consistency - characters displayed the same are stored the same.
     let mut s = input.nfc().collect::<String>();
@@ -103,7 +1022,34 @@ fn sanitize_filename(input: &str) -> String {
             prev_was_whitespace = false;
         }
     }
-    cleaned.trim().to_string()
+    let cleaned = cleaned.trim().trim_end_matches(['.', ' ']);
+
+    // "NUL", "nul.txt" and "COM1 .tar.gz" all open the device on Windows.
+    let stem = cleaned.split('.').next().unwrap_or("").trim_end();
+    if is_windows_device_name(stem) {
+        format!("_{cleaned}")
+    } else {
+        cleaned.to_string()
+    }
+}
+
+fn is_windows_device_name(stem: &str) -> bool {
+    const DEVICES: &[&str] = &["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+    let upper = stem.to_ascii_uppercase();
+    if DEVICES.contains(&upper.as_str()) {
+        return true;
+    }
+    // COM0-9 and LPT0-9, including the superscript digits Windows also accepts.
+    match upper.get(..3) {
+        Some("COM" | "LPT") => {
+            let mut rest = upper[3..].chars();
+            matches!(
+                (rest.next(), rest.next()),
+                (Some('0'..='9' | '¹' | '²' | '³'), None)
+            )
+        }
+        _ => false,
+    }
 }
 
 /// Truncate a UTF‑8 string safely without splitting codepoints.
@@ -124,3 +1070,297 @@ fn truncate_utf8_by_byte(s: &str, max_bytes: usize) -> &str {
 
     &s[..end]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        accessibility_metadata, is_rtl, sanitize_filename, verbatim, BookState, EpubSkeleton,
+        ManifestItem, MetadataOverrides, NameLimit, PackageMetadata, STATE_FILE,
+    };
+    use crate::config::{Naming, NamingTemplate};
+    use crate::orly::BookInfo;
+    use std::path::Path;
+
+    #[test]
+    fn sanitizes_for_windows() {
+        assert_eq!(sanitize_filename("a <b>:  c?"), "a _b__ c_");
+        assert_eq!(
+            sanitize_filename("NUL Pointers Explained."),
+            "NUL Pointers Explained"
+        );
+        assert_eq!(sanitize_filename("Dots . . ."), "Dots");
+        assert_eq!(sanitize_filename("nul"), "_nul");
+        assert_eq!(sanitize_filename("Com1.txt"), "_Com1.txt");
+        assert_eq!(sanitize_filename("LPT²"), "_LPT²");
+        assert_eq!(sanitize_filename("COM10"), "COM10");
+        assert_eq!(sanitize_filename("C:\\Books"), "C__Books");
+        assert_eq!(sanitize_filename("..."), "");
+    }
+
+    #[test]
+    fn name_limits_count_bytes_or_utf16_units() {
+        let bytes = NameLimit::Bytes(143);
+        let utf16 = NameLimit::Utf16(255);
+        let title = "é".repeat(200);
+        assert_eq!(bytes.measure(&title), 400);
+        assert_eq!(utf16.measure(&title), 200);
+        assert_eq!(bytes.truncate(&title, 143).len(), 142);
+        assert_eq!(utf16.truncate("a😀b", 2), "a");
+        assert_eq!(
+            NameLimit::detect(Path::new("/nonexistent/x"), Some(100)).max(),
+            100
+        );
+        #[cfg(target_os = "linux")]
+        {
+            assert!(super::counts_utf16(0x2011_bab0));
+            assert!(!super::counts_utf16(0xef53)); // ext4
+        }
+    }
+
+    #[test]
+    fn extends_long_windows_paths() {
+        assert_eq!(verbatim(r"C:\Books\x"), r"\\?\C:\Books\x");
+        assert_eq!(verbatim(r"\\nas\books\x"), r"\\?\UNC\nas\books\x");
+        assert_eq!(verbatim(r"\\?\C:\Books"), r"\\?\C:\Books");
+    }
+
+    #[test]
+    fn plans_ascii_names_from_templates() {
+        let info: BookInfo = serde_json::from_value(serde_json::json!({
+            "title": "Café: Ünïcode",
+            "web_url": "https://learning.oreilly.com/library/view/x/123/",
+            "authors": [{ "name": "José Núñez" }]
+        }))
+        .unwrap();
+        let naming = Naming {
+            dir: NamingTemplate::parse("{author}/{title} ({isbn})").unwrap(),
+            file: NamingTemplate::parse("{title}").unwrap(),
+            ascii: true,
+            legacy: false,
+        };
+        let skeleton = EpubSkeleton::plan(
+            Path::new("Books"),
+            &naming,
+            NameLimit::Bytes(255),
+            "123",
+            &info,
+        );
+        assert_eq!(skeleton.root, Path::new("Books/Jose Nunez/Cafe_ Unicode"));
+        assert_eq!(skeleton.epub, skeleton.root.join("Cafe_ Unicode.epub"));
+    }
+
+    #[test]
+    fn plans_legacy_names_like_safaribooks_py() {
+        let naming = Naming {
+            legacy: true,
+            ..Naming::default()
+        };
+        let plan = |title: &str| {
+            let info: BookInfo = serde_json::from_value(serde_json::json!({
+                "title": title,
+                "web_url": "https://learning.oreilly.com/library/view/x/123/"
+            }))
+            .unwrap();
+            EpubSkeleton::plan(
+                Path::new("Books"),
+                &naming,
+                NameLimit::Bytes(255),
+                "123",
+                &info,
+            )
+        };
+        let skeleton = plan("Fluent Python, 2nd Edition");
+        assert_eq!(
+            skeleton.root,
+            Path::new("Books/Fluent Python 2nd Edition (123)")
+        );
+        assert_eq!(skeleton.epub, skeleton.root.join("123.epub"));
+        assert!(skeleton.legacy_names);
+        assert_eq!(
+            plan("Designing Data-Intensive Applications: The Big Ideas").root,
+            Path::new("Books/Designing Data-Intensive Applications (123)")
+        );
+        assert_eq!(
+            plan("C++ & You, Part 1, Volume 2").root,
+            Path::new("Books/C__ _ You Part 1 (123)")
+        );
+    }
+
+    #[test]
+    fn overrides_replace_the_api_metadata() {
+        let info: BookInfo = serde_json::from_value(serde_json::json!({
+            "title": "Wrong Title",
+            "web_url": "https://learning.oreilly.com/library/view/x/123/",
+            "authors": [{ "name": "Someone" }],
+            "topics": [{ "name": "Programming" }]
+        }))
+        .unwrap();
+        let mut meta = PackageMetadata::from_book_info("123", &info);
+        meta.apply(&MetadataOverrides {
+            title: Some("Right & Proper".to_string()),
+            series: Some("Rust Essentials".to_string()),
+            series_index: Some(2.5),
+            tags: vec!["rust".to_string(), "systems".to_string()],
+            ..MetadataOverrides::default()
+        });
+        assert_eq!(meta.authors, ["Someone"]);
+        assert_eq!(meta.subjects, ["rust", "systems"]);
+
+        let base = std::env::temp_dir().join(format!("safaribooks-meta-{}", std::process::id()));
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::Bytes(255),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+        skeleton.write_opf(&meta, &[], &[]).unwrap();
+        let opf = std::fs::read_to_string(skeleton.oebps.join("content.opf")).unwrap();
+        assert!(opf.contains("<dc:title>Right &amp; Proper</dc:title>"));
+        assert!(opf.contains(r#"<meta name="calibre:series" content="Rust Essentials"/>"#));
+        assert!(opf.contains(r##"<meta refines="#series" property="group-position">2.5</meta>"##));
+        assert!(!opf.contains("Programming"));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn series_comes_from_the_api_or_the_title() {
+        let info = |json| serde_json::from_value::<BookInfo>(json).unwrap();
+        let guess = MetadataOverrides {
+            series_from_title: true,
+            ..MetadataOverrides::default()
+        };
+        assert!(!guess.names_values());
+
+        let mut meta = PackageMetadata::from_book_info(
+            "123",
+            &info(serde_json::json!({
+                "title": "Head First Go",
+                "web_url": "https://learning.oreilly.com/library/view/x/123/",
+                "series": { "name": "Programming Languages", "position": 3 }
+            })),
+        );
+        meta.apply(&guess);
+        assert_eq!(meta.series.as_deref(), Some("Programming Languages"));
+        assert_eq!(meta.series_index, Some(3.0));
+
+        let fluent = info(serde_json::json!({
+            "title": "Fluent Python, 2nd Edition",
+            "web_url": "https://learning.oreilly.com/library/view/x/123/"
+        }));
+        let mut meta = PackageMetadata::from_book_info("123", &fluent);
+        meta.apply(&MetadataOverrides::default());
+        assert_eq!(meta.series, None);
+        meta.apply(&guess);
+        assert_eq!(meta.series.as_deref(), Some("Fluent Python"));
+        assert_eq!(meta.series_index, Some(2.0));
+    }
+
+    #[test]
+    fn describes_accessibility() {
+        let text = accessibility_metadata(&[]);
+        assert!(text.contains(r#"<meta property="schema:accessModeSufficient">textual</meta>"#));
+        assert!(!text.contains("visual"));
+        let illustrated = accessibility_metadata(&[ManifestItem {
+            id: "img".to_string(),
+            href: "Images/a.png".to_string(),
+            media_type: "image/png".to_string(),
+        }]);
+        assert!(illustrated.contains(r#"<meta property="schema:accessMode">visual</meta>"#));
+        assert!(illustrated
+            .contains(r#"<meta property="schema:accessModeSufficient">textual,visual</meta>"#));
+        assert!(illustrated
+            .contains(r#"<meta property="schema:accessibilityFeature">alternativeText</meta>"#));
+    }
+
+    #[test]
+    fn writes_language_and_direction() {
+        assert!(is_rtl("ar"));
+        assert!(is_rtl("he-IL"));
+        assert!(is_rtl("ku-Arab"));
+        assert!(!is_rtl("en-US"));
+        assert!(!is_rtl("pt_BR"));
+
+        let base = std::env::temp_dir().join(format!("safaribooks-lang-{}", std::process::id()));
+        let info: BookInfo = serde_json::from_value(serde_json::json!({
+            "title": "Lang",
+            "web_url": "https://learning.oreilly.com/library/view/x/123/",
+            "language": "ar"
+        }))
+        .unwrap();
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::Bytes(255),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+        let meta = PackageMetadata::from_book_info("123", &info);
+        assert_eq!(meta.language, "ar");
+        skeleton
+            .write_xhtml("ch01.xhtml", "One", "<p>x</p>")
+            .unwrap();
+        skeleton.write_opf(&meta, &[], &[]).unwrap();
+        let ch01 = std::fs::read_to_string(skeleton.oebps.join("ch01.xhtml")).unwrap();
+        assert!(ch01.contains(r#"xml:lang="ar" lang="ar" dir="rtl">"#));
+        assert!(ch01.contains("direction: rtl"));
+        let opf = std::fs::read_to_string(skeleton.oebps.join("content.opf")).unwrap();
+        assert!(opf.contains("<dc:language>ar</dc:language>"));
+        assert!(opf.contains(r#"<spine toc="ncx" page-progression-direction="rtl">"#));
+
+        // Without a language from the API the book is English, left to right.
+        let info: BookInfo = serde_json::from_value(serde_json::json!({
+            "title": "Lang",
+            "web_url": "https://learning.oreilly.com/library/view/x/123/"
+        }))
+        .unwrap();
+        let meta = PackageMetadata::from_book_info("123", &info);
+        assert_eq!(meta.language, "en");
+        skeleton.write_opf(&meta, &[], &[]).unwrap();
+        let opf = std::fs::read_to_string(skeleton.oebps.join("content.opf")).unwrap();
+        assert!(opf.contains(r#"<spine toc="ncx">"#));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn state_file_marks_the_book_complete() {
+        let base = std::env::temp_dir().join(format!("safaribooks-state-{}", std::process::id()));
+        let info: BookInfo = serde_json::from_value(serde_json::json!({
+            "title": "State",
+            "web_url": "https://learning.oreilly.com/library/view/x/123/"
+        }))
+        .unwrap();
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::Bytes(255),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+        assert!(!skeleton.is_complete("123"));
+
+        std::fs::write(&skeleton.epub, b"zip").unwrap();
+        let state = BookState {
+            book_id: "123".to_string(),
+            epub: "123.epub".to_string(),
+            size: 3,
+        };
+        std::fs::write(
+            skeleton.root.join(STATE_FILE),
+            serde_json::to_string(&state).unwrap(),
+        )
+        .unwrap();
+        assert!(skeleton.is_complete("123"));
+        assert!(!skeleton.is_complete("456"));
+        std::fs::write(&skeleton.epub, b"truncated zip").unwrap();
+        assert!(!skeleton.is_complete("123"));
+
+        let renamed = skeleton.renamed();
+        assert_eq!(renamed.root, base.join("State (123) (2)"));
+        assert_eq!(renamed.epub, renamed.root.join("123.epub"));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
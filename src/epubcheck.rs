@@ -0,0 +1,221 @@
+//! Running the external epubcheck tool on a finished EPUB, for `--epubcheck`.
+
+use crate::i18n::t;
+use anyhow::{bail, Context, Result};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How bad an epubcheck message is, as it labels them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Usage,
+    Info,
+    Warning,
+    Error,
+    Fatal,
+}
+
+impl Severity {
+    fn parse(label: &str) -> Option<Self> {
+        Some(match label {
+            "USAGE" => Severity::Usage,
+            "INFO" => Severity::Info,
+            "WARNING" => Severity::Warning,
+            "ERROR" => Severity::Error,
+            "FATAL" => Severity::Fatal,
+            _ => return None,
+        })
+    }
+
+    pub fn is_error(self) -> bool {
+        self >= Severity::Error
+    }
+}
+
+/// One epubcheck message, e.g. "RSC-005: OEBPS/ch01.xhtml(12,5): ...".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// An epubcheck installation: a launcher script/binary, or `java -jar` on its jar.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Epubcheck {
+    program: PathBuf,
+    args: Vec<OsString>,
+}
+
+impl Epubcheck {
+    /// The configured program (or .jar), else `epubcheck` on PATH.
+    pub fn locate(configured: Option<&Path>) -> Result<Self> {
+        Self::locate_in(configured, std::env::var_os("PATH"))
+    }
+
+    fn locate_in(configured: Option<&Path>, path_var: Option<OsString>) -> Result<Self> {
+        if let Some(path) = configured {
+            if !path.is_file() {
+                bail!(t!(
+                    "epubcheck-path-missing",
+                    path = path.display().to_string()
+                ));
+            }
+            let jar = path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("jar"));
+            return Ok(if jar {
+                Self {
+                    program: PathBuf::from("java"),
+                    args: vec!["-jar".into(), path.into()],
+                }
+            } else {
+                Self {
+                    program: path.to_path_buf(),
+                    args: Vec::new(),
+                }
+            });
+        }
+        let names: &[&str] = if cfg!(windows) {
+            &["epubcheck.exe", "epubcheck.bat", "epubcheck.cmd"]
+        } else {
+            &["epubcheck"]
+        };
+        path_var
+            .iter()
+            .flat_map(std::env::split_paths)
+            .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+            .find(|candidate| candidate.is_file())
+            .map(|program| Self {
+                program,
+                args: Vec::new(),
+            })
+            .with_context(|| t!("epubcheck-not-found"))
+    }
+
+    /// Check `epub` and return what epubcheck reported.
+    pub fn run(&self, epub: &Path) -> Result<Vec<Finding>> {
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .arg(epub)
+            .output()
+            .with_context(|| {
+                t!(
+                    "running-program",
+                    program = self.program.display().to_string()
+                )
+            })?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let findings = parse_output(&format!("{stdout}\n{stderr}"), epub);
+        if !output.status.success() && !findings.iter().any(|f| f.severity.is_error()) {
+            let last = stderr.lines().rev().find(|l| !l.trim().is_empty());
+            bail!(t!(
+                "program-failed",
+                program = "epubcheck",
+                status = output.status.to_string(),
+                detail = last.map(|l| format!(": {}", l.trim())).unwrap_or_default()
+            ));
+        }
+        Ok(findings)
+    }
+}
+
+/// The messages in epubcheck's output, with the EPUB's own path dropped
+/// from their locations.
+fn parse_output(output: &str, epub: &Path) -> Vec<Finding> {
+    let prefix = format!("{}/", epub.display());
+    output
+        .lines()
+        .filter_map(|line| {
+            let (label, rest) = line.split_once('(')?;
+            let severity = Severity::parse(label.trim())?;
+            let (code, message) = rest.split_once("):")?;
+            let message = message.trim().replacen(&prefix, "", 1);
+            Some(Finding {
+                severity,
+                message: format!("{code}: {message}"),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_output, Epubcheck, Finding, Severity};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn parses_messages() {
+        let output = "Validating using EPUB version 3.3 rules.\n\
+            ERROR(RSC-005): /tmp/b/123.epub/OEBPS/ch01.xhtml(12,5): Error while parsing file\n\
+            WARNING(OPF-085): /tmp/b/123.epub/OEBPS/content.opf(3,40): Invalid UUID\n\
+            Messages: 0 fatals / 1 error / 1 warning / 0 infos\n";
+        assert_eq!(
+            parse_output(output, Path::new("/tmp/b/123.epub")),
+            vec![
+                Finding {
+                    severity: Severity::Error,
+                    message: "RSC-005: OEBPS/ch01.xhtml(12,5): Error while parsing file"
+                        .to_string(),
+                },
+                Finding {
+                    severity: Severity::Warning,
+                    message: "OPF-085: OEBPS/content.opf(3,40): Invalid UUID".to_string(),
+                },
+            ]
+        );
+        assert!(Severity::Fatal.is_error() && !Severity::Warning.is_error());
+    }
+
+    #[test]
+    fn locates_the_tool() {
+        let dir =
+            std::env::temp_dir().join(format!("safaribooks-epubcheck-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let jar = dir.join("epubcheck.jar");
+        fs::write(&jar, b"").unwrap();
+
+        let found = Epubcheck::locate_in(Some(&jar), None).unwrap();
+        assert_eq!(found.program, PathBuf::from("java"));
+        assert!(Epubcheck::locate_in(Some(&dir.join("missing")), None).is_err());
+        assert!(Epubcheck::locate_in(None, Some(dir.clone().into())).is_err());
+
+        let name = if cfg!(windows) {
+            "epubcheck.bat"
+        } else {
+            "epubcheck"
+        };
+        fs::write(dir.join(name), b"").unwrap();
+        let found = Epubcheck::locate_in(None, Some(dir.clone().into())).unwrap();
+        assert_eq!(found.program, dir.join(name));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_the_tool() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir =
+            std::env::temp_dir().join(format!("safaribooks-epubcheck-run-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("epubcheck");
+        fs::write(
+            &script,
+            "#!/bin/sh\necho \"FATAL(RSC-002): $1: Required file is missing\" >&2\nexit 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let tool = Epubcheck::locate_in(Some(&script), None).unwrap();
+        let findings = tool.run(&dir.join("x.epub")).unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Fatal);
+
+        fs::write(&script, "#!/bin/sh\necho 'java not found' >&2\nexit 2\n").unwrap();
+        let err = tool.run(&dir.join("x.epub")).unwrap_err();
+        assert!(err.to_string().ends_with(": java not found"), "{err}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//! Figures that could not be downloaded: each is replaced by a placeholder
+//! showing its alt text and caption, and an appendix lists them all with
+//! the URLs they were to come from.
+
+use crate::epub::{xml_escape, EpubSkeleton, ManifestItem, NavPoint};
+use crate::html::{is_void, serialize_xhtml, tokenize, Token};
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// The appendix chapter written into the book.
+pub const APPENDIX_HREF: &str = "missing-figures.xhtml";
+
+/// Styles of the placeholders, added to the layout stylesheet.
+pub const STYLESHEET: &str = ".missing-figure { display: block; margin: 1em 0; padding: 1em; \
+     border: 1px dashed #888; text-align: center; }\n\
+     .missing-figure-note { display: block; font-weight: bold; }\n\
+     .missing-figure-caption { display: block; font-style: italic; }\n";
+
+/// A placeholder put in a chapter.
+pub struct MissingFigure {
+    /// The chapter, relative to OEBPS/.
+    pub chapter: String,
+    pub chapter_title: String,
+    /// The placeholder's id in the chapter.
+    pub id: String,
+    pub url: String,
+    pub alt: String,
+    pub caption: String,
+}
+
+/// Replace the images of the chapter `body` that could not be downloaded
+/// (`missing` maps their hrefs to their URLs) with placeholders, adding
+/// one entry per placeholder to `figures`.
+pub fn replace_missing(
+    body: &str,
+    chapter: &str,
+    chapter_title: &str,
+    missing: &HashMap<&str, &str>,
+    figures: &mut Vec<MissingFigure>,
+) -> String {
+    if !missing.keys().any(|href| body.contains(href)) {
+        return body.to_string();
+    }
+    let tokens = tokenize(body);
+    let url_of = |token: &Token| {
+        token
+            .is_start("img")
+            .then(|| token.attr("src"))
+            .flatten()
+            .and_then(|src| missing.get(src).copied())
+    };
+    let captions = captions(&tokens, |token| url_of(token).is_some());
+
+    let mut out = Vec::with_capacity(tokens.len());
+    for (i, token) in tokens.iter().enumerate() {
+        let Some(url) = url_of(token) else {
+            out.push(token.clone());
+            continue;
+        };
+        let figure = MissingFigure {
+            chapter: chapter.to_string(),
+            chapter_title: chapter_title.to_string(),
+            id: format!("missing-figure-{}", figures.len() + 1),
+            url: url.to_string(),
+            alt: token.attr("alt").unwrap_or_default().trim().to_string(),
+            caption: captions.get(&i).cloned().unwrap_or_default(),
+        };
+        out.extend(placeholder(&figure));
+        figures.push(figure);
+    }
+    serialize_xhtml(&out)
+}
+
+/// The caption of the figure each of the `wanted` images is in: the text
+/// of its `<figcaption>`, or of the `<h6>` O'Reilly puts in a
+/// `<div class="figure">`. Keyed by the images' token index.
+fn captions(tokens: &[Token], wanted: impl Fn(&Token) -> bool) -> HashMap<usize, String> {
+    // Open elements, each with its figure's index in `found` if it is one.
+    let mut open: Vec<(&str, Option<usize>)> = Vec::new();
+    let mut found: Vec<(Vec<usize>, String)> = Vec::new();
+    // The figure whose caption is being read, and how deep the caption is.
+    let mut caption: Option<(usize, usize)> = None;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Start {
+                name, self_closing, ..
+            } => {
+                let figure = open.iter().rev().find_map(|(_, figure)| *figure);
+                if wanted(token)
+                    && let Some(figure) = figure
+                {
+                    found[figure].0.push(i);
+                }
+                if *self_closing || is_void(name) {
+                    continue;
+                }
+                let is_figure = name.eq_ignore_ascii_case("figure")
+                    || (name.eq_ignore_ascii_case("div")
+                        && token
+                            .attr("class")
+                            .is_some_and(|class| class.split_whitespace().any(|c| c == "figure")));
+                if is_figure {
+                    found.push((Vec::new(), String::new()));
+                    open.push((name, Some(found.len() - 1)));
+                    continue;
+                }
+                open.push((name, None));
+                if caption.is_none()
+                    && let Some(figure) = figure
+                    && (name.eq_ignore_ascii_case("figcaption") || name.eq_ignore_ascii_case("h6"))
+                {
+                    caption = Some((figure, open.len()));
+                }
+            }
+            Token::End(name) => {
+                if let Some(at) = open.iter().rposition(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                    open.truncate(at);
+                }
+                if caption.is_some_and(|(_, depth)| open.len() < depth) {
+                    caption = None;
+                }
+            }
+            Token::Text(text) => {
+                if let Some((figure, _)) = caption {
+                    found[figure].1.push_str(text);
+                }
+            }
+            Token::Comment(_) => {}
+        }
+    }
+    found
+        .into_iter()
+        .flat_map(|(images, caption)| {
+            let caption = caption.split_whitespace().collect::<Vec<_>>().join(" ");
+            images.into_iter().map(move |i| (i, caption.clone()))
+        })
+        .collect()
+}
+
+fn span(class: &str) -> Token {
+    Token::Start {
+        name: "span".to_string(),
+        attrs: vec![("class".to_string(), class.to_string())],
+        self_closing: false,
+    }
+}
+
+fn end(name: &str) -> Token {
+    Token::End(name.to_string())
+}
+
+/// The placeholder of `figure`, linking to its entry in the appendix.
+fn placeholder(figure: &MissingFigure) -> Vec<Token> {
+    let mut tokens = vec![
+        Token::Start {
+            name: "span".to_string(),
+            attrs: vec![
+                ("class".to_string(), "missing-figure".to_string()),
+                ("id".to_string(), figure.id.clone()),
+            ],
+            self_closing: false,
+        },
+        span("missing-figure-note"),
+        Token::Start {
+            name: "a".to_string(),
+            attrs: vec![("href".to_string(), format!("{APPENDIX_HREF}#{}", figure.id))],
+            self_closing: false,
+        },
+        Token::Text("Figure not available".to_string()),
+        end("a"),
+        end("span"),
+    ];
+    if !figure.alt.is_empty() {
+        tokens.push(Token::Text(figure.alt.clone()));
+    }
+    if !figure.caption.is_empty() {
+        tokens.extend([
+            span("missing-figure-caption"),
+            Token::Text(figure.caption.clone()),
+            end("span"),
+        ]);
+    }
+    tokens.push(end("span"));
+    tokens
+}
+
+/// Write the appendix listing `figures` and return its manifest item and
+/// table-of-contents entry.
+pub fn write_appendix(
+    skeleton: &EpubSkeleton,
+    figures: &[MissingFigure],
+) -> Result<(ManifestItem, NavPoint)> {
+    let mut body = String::from(
+        "<section epub:type=\"appendix\" role=\"doc-appendix\">\n<h1>Missing figures</h1>\n\
+         <p>These figures could not be downloaded; the chapters show a placeholder instead.</p>\n\
+         <ol>\n",
+    );
+    for figure in figures {
+        let label = [&figure.caption, &figure.alt]
+            .into_iter()
+            .find(|text| !text.is_empty())
+            .map_or_else(|| "Untitled figure".to_string(), |text| xml_escape(text));
+        body.push_str(&format!(
+            "<li id=\"{id}\"><p><a href=\"{chapter}#{id}\">{label}</a> ({title})</p>\n\
+             <p><a href=\"{url}\">{url}</a></p></li>\n",
+            id = figure.id,
+            chapter = xml_escape(&figure.chapter),
+            title = xml_escape(&figure.chapter_title),
+            url = xml_escape(&figure.url),
+        ));
+    }
+    body.push_str("</ol>\n</section>");
+    skeleton.write_xhtml(APPENDIX_HREF, "Missing figures", &body)?;
+    Ok((
+        ManifestItem {
+            id: "missing_figures".to_string(),
+            href: APPENDIX_HREF.to_string(),
+            media_type: "application/xhtml+xml".to_string(),
+        },
+        NavPoint {
+            title: "Missing figures".to_string(),
+            href: APPENDIX_HREF.to_string(),
+            children: Vec::new(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::replace_missing;
+    use std::collections::HashMap;
+
+    #[test]
+    fn replaces_missing_images_with_their_alt_text_and_caption() {
+        let body = "<figure><img src=\"Images/a.png\" alt=\"A diagram\"/>\
+            <figcaption>Figure 1-1. The <em>whole</em> thing</figcaption></figure>\
+            <div class=\"figure\"><img src=\"Images/b.png\"/><h6>Figure 1-2. Kept</h6></div>\
+            <p>Inline <img src=\"Images/c.png\" alt=\"icon\"/> here.</p>";
+        let missing = HashMap::from([
+            ("Images/a.png", "https://cdn.example.com/a.png"),
+            ("Images/c.png", "https://cdn.example.com/c.png"),
+        ]);
+        let mut figures = Vec::new();
+        let out = replace_missing(body, "ch01.xhtml", "One", &missing, &mut figures);
+
+        assert_eq!(figures.len(), 2);
+        assert_eq!(figures[0].id, "missing-figure-1");
+        assert_eq!(figures[0].alt, "A diagram");
+        assert_eq!(figures[0].caption, "Figure 1-1. The whole thing");
+        assert_eq!(figures[1].url, "https://cdn.example.com/c.png");
+        assert_eq!(figures[1].caption, "");
+        assert!(out.contains(
+            "<span class=\"missing-figure\" id=\"missing-figure-1\">\
+             <span class=\"missing-figure-note\">\
+             <a href=\"missing-figures.xhtml#missing-figure-1\">Figure not available</a></span>\
+             A diagram<span class=\"missing-figure-caption\">Figure 1-1. The whole thing</span></span>"
+        ));
+        assert!(out.contains("<img src=\"Images/b.png\"/>"));
+        assert!(!out.contains("a.png") && !out.contains("c.png"));
+    }
+}
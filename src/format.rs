@@ -0,0 +1,459 @@
+//! What a downloaded book is delivered as. Every pipeline writes the book
+//! into its work directory (see `EpubSkeleton`); a `BookFormat` turns that
+//! into the file the user asked for with `--format`. A new format is one
+//! more implementation in `FORMATS`.
+
+use crate::book::WrittenChapter;
+use crate::checksum::sha256_bytes;
+use crate::config::Settings;
+use crate::display::Display;
+use crate::epub::EpubSkeleton;
+use crate::epubcheck::{self, Epubcheck};
+use crate::html::{Token, Tokenizer};
+use crate::i18n::t;
+use crate::orly::BookInfo;
+use crate::package::{package_epub, EpubStream};
+use crate::split;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A book the pipeline has written, for a format to deliver.
+pub struct BookContext<'a> {
+    pub ui: &'a Display,
+    pub settings: &'a Settings,
+    pub book_id: &'a str,
+    pub info: &'a BookInfo,
+    /// The work directory the book was written into.
+    pub workdir: &'a EpubSkeleton,
+    /// The chapters written, in reading order.
+    pub chapters: &'a [WrittenChapter],
+    /// Where the result goes.
+    pub dest: &'a Path,
+}
+
+/// A kind of file the written book can be turned into.
+pub trait BookFormat: Sync {
+    /// Its `--format` name, e.g. "epub".
+    fn name(&self) -> &'static str;
+
+    /// Extension of the file it writes.
+    fn extension(&self) -> &'static str;
+
+    /// The largest part, in bytes, to lay the book out in for
+    /// `--split-parts`; formats that aren't split leave it `None`.
+    fn split_above(&self, _settings: &Settings) -> Option<u64> {
+        None
+    }
+
+    /// Start writing `dest` while the book is still being downloaded, for
+    /// formats that can; `write` gets it back to finish.
+    fn stream(&self, _dest: &Path, _settings: &Settings) -> Result<Option<EpubStream>> {
+        Ok(None)
+    }
+
+    /// Write `book` to its destination, finishing what `stream` started if
+    /// it did. Returns every entry written with its SHA-256, as the state
+    /// database records them.
+    fn write(
+        &self,
+        book: &BookContext,
+        streamed: Option<EpubStream>,
+    ) -> Result<Vec<(String, String)>>;
+}
+
+/// The work directory zipped as it is, and its parts with `--split-parts`.
+pub struct Epub;
+
+impl BookFormat for Epub {
+    fn name(&self) -> &'static str {
+        "epub"
+    }
+
+    fn extension(&self) -> &'static str {
+        "epub"
+    }
+
+    fn split_above(&self, settings: &Settings) -> Option<u64> {
+        settings.split_parts()
+    }
+
+    fn stream(&self, dest: &Path, settings: &Settings) -> Result<Option<EpubStream>> {
+        EpubStream::create(dest, settings.compression()).map(Some)
+    }
+
+    fn write(
+        &self,
+        book: &BookContext,
+        streamed: Option<EpubStream>,
+    ) -> Result<Vec<(String, String)>> {
+        let compression = book.settings.compression();
+        let entries = match streamed {
+            Some(epub) => epub.finish(book.workdir),
+            None => package_epub(book.workdir, book.dest, compression),
+        }
+        .with_context(|| t!("packaging-failed"))?;
+        for part in split::parts(book.workdir) {
+            package_epub(&part, &part.epub, compression).with_context(|| {
+                t!(
+                    "packaging-part-failed",
+                    path = part.epub.display().to_string()
+                )
+            })?;
+            book.ui
+                .info(&t!("wrote", path = part.epub.display().to_string()));
+        }
+        if book.settings.epubcheck() {
+            run_epubcheck(book.ui, book.settings, book.dest)?;
+        }
+        Ok(entries)
+    }
+}
+
+/// Run epubcheck on the packaged book; with --strict its errors (or not
+/// being able to run it) fail the download.
+fn run_epubcheck(ui: &Display, settings: &Settings, epub: &Path) -> Result<()> {
+    let strict = settings.strict();
+    ui.info(&t!("running-epubcheck"));
+    let findings = match Epubcheck::locate(settings.epubcheck_path.as_deref())
+        .and_then(|tool| tool.run(epub))
+    {
+        Ok(findings) => findings,
+        Err(e) if strict => return Err(e),
+        Err(e) => {
+            ui.warn(&t!("epubcheck-skipped", error = format!("{e:#}")));
+            return Ok(());
+        }
+    };
+    let mut errors = 0;
+    for finding in &findings {
+        if finding.severity.is_error() {
+            errors += 1;
+            ui.warn(&t!("epubcheck-error", message = finding.message.as_str()));
+        } else if finding.severity == epubcheck::Severity::Warning {
+            ui.warn(&t!("epubcheck-warning", message = finding.message.as_str()));
+        } else {
+            ui.info(&t!("epubcheck-info", message = finding.message.as_str()));
+        }
+    }
+    if errors == 0 {
+        ui.info(&t!("epubcheck-clean"));
+    } else if strict {
+        bail!(t!("epubcheck-errors", count = errors));
+    } else {
+        ui.warn(&t!("epubcheck-errors", count = errors));
+    }
+    Ok(())
+}
+
+/// The chapters' text in reading order, under the title and authors: for
+/// any reader, or for grepping and feeding to other tools.
+pub struct Text;
+
+impl BookFormat for Text {
+    fn name(&self) -> &'static str {
+        "txt"
+    }
+
+    fn extension(&self) -> &'static str {
+        "txt"
+    }
+
+    fn write(
+        &self,
+        book: &BookContext,
+        _streamed: Option<EpubStream>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut text = format!("{}\n", book.info.title);
+        let authors = book.info.author_names();
+        if !authors.is_empty() {
+            text.push_str(&authors.join(", "));
+            text.push('\n');
+        }
+        for chapter in book.chapters {
+            let path = book.workdir.oebps.join(&chapter.href);
+            let xhtml = fs::read_to_string(&path)
+                .with_context(|| t!("reading-file", path = path.display().to_string()))?;
+            text.push_str("\n\n");
+            text.push_str(&plain_text(&xhtml));
+        }
+        text.push('\n');
+        fs::write(book.dest, &text)
+            .with_context(|| t!("writing-file", path = book.dest.display().to_string()))?;
+        let name = book.dest.file_name().unwrap_or_default();
+        Ok(vec![(
+            name.to_string_lossy().into_owned(),
+            sha256_bytes(text.as_bytes()),
+        )])
+    }
+}
+
+/// Elements that start a paragraph of their own.
+const BLOCKS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "tr",
+    "ul",
+];
+
+/// The text of an XHTML document: a paragraph per block, list items
+/// bulleted, whitespace collapsed except in `pre`.
+fn plain_text(xhtml: &str) -> String {
+    let mut out = String::new();
+    // Inside the head, a script or a style; inside a pre.
+    let (mut hidden, mut pre) = (0usize, 0usize);
+    let mut bullet = false;
+    for token in Tokenizer::new(xhtml) {
+        match &token {
+            Token::Start {
+                name, self_closing, ..
+            } => {
+                let name = name.to_ascii_lowercase();
+                match name.as_str() {
+                    "head" | "script" | "style" if !self_closing => hidden += 1,
+                    "br" => out.push('\n'),
+                    _ if BLOCKS.contains(&name.as_str()) => {
+                        end_paragraph(&mut out);
+                        pre += usize::from(name == "pre" && !self_closing);
+                        bullet |= name == "li";
+                    }
+                    _ => {}
+                }
+            }
+            Token::End(name) => {
+                let name = name.to_ascii_lowercase();
+                match name.as_str() {
+                    "head" | "script" | "style" => hidden = hidden.saturating_sub(1),
+                    _ if BLOCKS.contains(&name.as_str()) => {
+                        end_paragraph(&mut out);
+                        if name == "pre" {
+                            pre = pre.saturating_sub(1);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Token::Text(text) if hidden == 0 => {
+                if pre > 0 {
+                    out.push_str(text);
+                    continue;
+                }
+                // No-break spaces stay.
+                let collapsed = text.split_ascii_whitespace().collect::<Vec<_>>().join(" ");
+                let spaced = |s: &str| s.starts_with(|c: char| c.is_ascii_whitespace());
+                let at_start = out.is_empty() || out.ends_with(['\n', ' ']);
+                if !at_start && spaced(text) && !collapsed.is_empty() {
+                    out.push(' ');
+                }
+                if bullet && !collapsed.is_empty() {
+                    out.push_str("- ");
+                    bullet = false;
+                }
+                out.push_str(&collapsed);
+                if text.ends_with(|c: char| c.is_ascii_whitespace()) && !collapsed.is_empty() {
+                    out.push(' ');
+                }
+            }
+            _ => {}
+        }
+    }
+    out.truncate(out.trim_end().len());
+    out
+}
+
+/// End the paragraph being written with a blank line.
+fn end_paragraph(out: &mut String) {
+    out.truncate(out.trim_end().len());
+    if !out.is_empty() {
+        out.push_str("\n\n");
+    }
+}
+
+/// Every format `--format` can name, the default first.
+pub static FORMATS: &[&dyn BookFormat] = &[&Epub, &Text];
+
+/// A format chosen by name (`--format`, SAFARIBOOKS_FORMAT or the config
+/// file's "format").
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Format(pub &'static dyn BookFormat);
+
+impl Default for Format {
+    fn default() -> Self {
+        Self(FORMATS[0])
+    }
+}
+
+impl std::fmt::Debug for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0.name())
+    }
+}
+
+impl PartialEq for Format {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.name() == other.0.name()
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.trim().to_ascii_lowercase();
+        match FORMATS.iter().find(|f| f.name() == name) {
+            Some(format) => Ok(Self(*format)),
+            None => {
+                let names: Vec<_> = FORMATS.iter().map(|f| f.name()).collect();
+                bail!(t!(
+                    "format-unknown",
+                    format = format!("{s:?}"),
+                    formats = names.join(", ")
+                ))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Format {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plain_text, BookContext, Format, FORMATS};
+    use crate::book::WrittenChapter;
+    use crate::config::{ByteSize, Settings};
+    use crate::display::Display;
+    use crate::epub::EpubSkeleton;
+    use crate::orly::BookInfo;
+    use std::fs;
+
+    #[test]
+    fn looks_formats_up_by_name() {
+        assert_eq!(Format::default().0.name(), "epub");
+        let epub: Format = "EPUB".parse().unwrap();
+        assert_eq!(epub.0.extension(), "epub");
+        let settings = Settings {
+            split_parts: Some(ByteSize(1 << 20)),
+            ..Default::default()
+        };
+        assert_eq!(epub.0.split_above(&settings), Some(1 << 20));
+        let txt: Format = "txt".parse().unwrap();
+        assert_eq!(txt.0.split_above(&settings), None);
+        let err = "mobi".parse::<Format>().unwrap_err().to_string();
+        assert!(err.contains("available: epub, txt"), "{err}");
+        assert!(FORMATS.iter().all(|f| f.name().parse::<Format>().is_ok()));
+    }
+
+    #[test]
+    fn only_epub_is_packaged_while_downloading() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-stream-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("Book.epub");
+        let settings = Settings::default();
+        let txt: Format = "txt".parse().unwrap();
+        assert!(txt.0.stream(&dest, &settings).unwrap().is_none());
+        let streamed = Format::default().0.stream(&dest, &settings).unwrap();
+        assert!(streamed.is_some());
+        assert!(dir.join("Book.epub.part").exists());
+        drop(streamed);
+        assert!(!dir.join("Book.epub.part").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flattens_xhtml_to_paragraphs() {
+        let xhtml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html><head><title>Ignored</title><style>p { color: red }</style></head>
+<body><h1>Chapter&#160;1</h1>
+<p>Some <em>emphasised</em>
+   text,<br/>then a line.</p>
+<ul><li><p>First</p></li><li>Second</li></ul>
+<pre>fn main() {
+    println!("hi");
+}</pre>
+<section><p>Last.</p></section></body></html>"#;
+        assert_eq!(
+            plain_text(xhtml),
+            "Chapter\u{a0}1\n\nSome emphasised text,\nthen a line.\n\n- First\n\n- Second\n\n\
+             fn main() {\n    println!(\"hi\");\n}\n\nLast."
+        );
+    }
+
+    #[test]
+    fn writes_the_chapters_as_text() {
+        let root = std::env::temp_dir().join(format!("safaribooks-txt-{}", std::process::id()));
+        let workdir = EpubSkeleton {
+            meta_inf: root.join("META-INF"),
+            oebps: root.join("OEBPS"),
+            epub: root.join("Book.txt"),
+            root: root.clone(),
+            language: "en".to_string(),
+            legacy_names: false,
+        };
+        workdir.create_dirs().unwrap();
+        workdir
+            .write_document("ch01.xhtml", "One", &[], "<h1>One</h1><p>First.</p>")
+            .unwrap();
+        workdir
+            .write_document("ch02.xhtml", "Two", &[], "<h1>Two</h1><p>Second.</p>")
+            .unwrap();
+        let info: BookInfo = serde_json::from_value(serde_json::json!({
+            "title": "Rust",
+            "web_url": "/library/view/rust/123/",
+            "authors": [{ "name": "Ferris" }]
+        }))
+        .unwrap();
+        let chapters: Vec<WrittenChapter> = ["ch02.xhtml", "ch01.xhtml"]
+            .map(|href| WrittenChapter {
+                title: String::new(),
+                href: href.to_string(),
+            })
+            .into();
+        let book = BookContext {
+            ui: &Display::for_tests(),
+            settings: &Settings::default(),
+            book_id: "123",
+            info: &info,
+            workdir: &workdir,
+            chapters: &chapters,
+            dest: &workdir.epub,
+        };
+        let txt: Format = "txt".parse().unwrap();
+        let entries = txt.0.write(&book, None).unwrap();
+        let text = fs::read_to_string(&workdir.epub).unwrap();
+        assert_eq!(text, "Rust\nFerris\n\n\nTwo\n\nSecond.\n\nOne\n\nFirst.\n");
+        assert_eq!(entries[0].0, "Book.txt");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
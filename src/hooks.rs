@@ -0,0 +1,192 @@
+//! Post-download hooks: a JSON report POSTed to a webhook and/or a command
+//! run on the new EPUB, for chaining into bots, notifiers or sync scripts.
+
+use crate::display::Display;
+use crate::download::Outcome;
+use crate::i18n::t;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// How long a webhook may take to answer.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The webhook body.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Report {
+    /// "finished", "skipped" or "failed".
+    pub event: &'static str,
+    pub book_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Report {
+    pub fn new(book_id: &str, result: &Result<Outcome>) -> Self {
+        let (event, path, error) = match result {
+            Ok(Outcome::Finished(path)) => ("finished", Some(path), None),
+            Ok(Outcome::Skipped(path)) => ("skipped", Some(path), None),
+            Err(e) => ("failed", None, Some(format!("{e:#}"))),
+        };
+        Self {
+            event,
+            book_id: book_id.to_string(),
+            path: path.map(|p| p.display().to_string()),
+            error,
+        }
+    }
+}
+
+/// Run the configured hooks for a finished (or failed) download. Hook
+/// failures are reported but never change the outcome of the download.
+pub async fn fire(
+    ui: &Display,
+    webhook: Option<&str>,
+    command: Option<&str>,
+    book_id: &str,
+    result: &Result<Outcome>,
+) {
+    let report = Report::new(book_id, result);
+    if let Some(url) = webhook {
+        match post_webhook(url, &report).await {
+            Ok(()) => ui.info(&t!("webhook-notified")),
+            Err(e) => ui.warn(&t!("webhook-failed", error = format!("{e:#}"))),
+        }
+    }
+    // The command works on the new file, so it only runs when there is one.
+    if let (Some(command), Ok(Outcome::Finished(epub))) = (command, result) {
+        match run_command(command, epub, &report).await {
+            Ok(()) => ui.info(&t!("hook-finished")),
+            Err(e) => ui.warn(&t!("hook-failed", error = format!("{e:#}"))),
+        }
+    }
+}
+
+async fn post_webhook(url: &str, report: &Report) -> Result<()> {
+    // A plain client: the session cookies are none of the webhook's business.
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+    let res = client
+        .post(url)
+        .json(report)
+        .send()
+        .await
+        .with_context(|| t!("http-post", url = url))?;
+    if !res.status().is_success() {
+        bail!(t!(
+            "http-post-status",
+            url = url,
+            status = res.status().as_u16()
+        ));
+    }
+    Ok(())
+}
+
+/// Run `command` through the shell with the EPUB path as its argument;
+/// the report is also in SAFARIBOOKS_BOOK_ID and SAFARIBOOKS_EVENT.
+async fn run_command(command: &str, epub: &Path, report: &Report) -> Result<()> {
+    let mut cmd = shell(command, epub);
+    let status = cmd
+        .env("SAFARIBOOKS_BOOK_ID", &report.book_id)
+        .env("SAFARIBOOKS_EVENT", report.event)
+        .status()
+        .await
+        .with_context(|| t!("running-program", program = command))?;
+    if !status.success() {
+        bail!(t!(
+            "hook-exited",
+            command = command,
+            status = status.to_string()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn shell(command: &str, epub: &Path) -> Command {
+    // With /S, cmd drops only the outer quotes, so the path keeps its own.
+    let mut cmd = Command::new("cmd");
+    cmd.raw_arg(format!("/S /C \"{command} \"{}\"\"", epub.display()));
+    cmd
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str, epub: &Path) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(format!("{command} \"$@\""))
+        .arg("sh")
+        .arg(epub);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fire, Report};
+    use crate::display::Display;
+    use crate::download::Outcome;
+    use anyhow::anyhow;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn reports_outcomes() {
+        let finished = Report::new("123", &Ok(Outcome::Finished("Books/123.epub".into())));
+        assert_eq!(
+            serde_json::to_value(&finished).unwrap(),
+            json!({ "event": "finished", "book_id": "123", "path": "Books/123.epub" })
+        );
+        let failed = Report::new("123", &Err(anyhow!("Logged out")));
+        assert_eq!(
+            serde_json::to_value(&failed).unwrap(),
+            json!({ "event": "failed", "book_id": "123", "error": "Logged out" })
+        );
+    }
+
+    #[tokio::test]
+    async fn posts_the_webhook_and_runs_the_command() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        let app = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<Value>| async move {
+                sink.lock().unwrap().push(body);
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = std::env::temp_dir().join(format!("safaribooks-hooks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let epub = dir.join("My Book.epub");
+        let marker = dir.join("ran");
+        // Succeeds only when given the path (with its space) as one argument.
+        let command = cfg!(unix).then(|| {
+            format!(
+                "test \"$SAFARIBOOKS_EVENT\" = finished && touch \"{}\" && test -n",
+                marker.display()
+            )
+        });
+
+        let result = Ok(Outcome::Finished(epub));
+        fire(
+            &Display::for_tests(),
+            Some(&url),
+            command.as_deref(),
+            "123",
+            &result,
+        )
+        .await;
+        assert_eq!(received.lock().unwrap()[0]["event"], "finished");
+        assert_eq!(marker.exists(), cfg!(unix));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
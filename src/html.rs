@@ -0,0 +1,599 @@
+//! A lightweight HTML tokenizer and XHTML serializer.
+//!
+//! Chapter content served by the API is *almost* XHTML: named entities such as
+//! `&nbsp;`, unquoted attributes, void tags without a closing slash and the odd
+//! unclosed element all show up. Rather than pulling in a full HTML5 parser we
+//! tokenize, let the pipeline rewrite the token stream, and serialize back to
+//! well-formed XHTML (entities decoded, attributes quoted, elements balanced).
+//! Tokens are produced as the input is read (`Tokenizer`) and written as
+//! they come (`XhtmlWriter`), so even a reference chapter of many megabytes
+//! is never held as a tree or as a list of tokens.
+
+/// Elements that never have content in HTML.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Elements whose content is raw text (no nested tags, no entity decoding).
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Start {
+        name: String,
+        attrs: Vec<(String, String)>,
+        self_closing: bool,
+    },
+    End(String),
+    /// Decoded character data.
+    Text(String),
+    Comment(String),
+}
+
+impl Token {
+    /// Whether this is a start tag named `tag` (ASCII case-insensitive).
+    pub fn is_start(&self, tag: &str) -> bool {
+        matches!(self, Token::Start { name, .. } if name.eq_ignore_ascii_case(tag))
+    }
+
+    /// Whether this is an end tag named `tag` (ASCII case-insensitive).
+    pub fn is_end(&self, tag: &str) -> bool {
+        matches!(self, Token::End(name) if name.eq_ignore_ascii_case(tag))
+    }
+
+    /// Value of attribute `key` on a start tag.
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        match self {
+            Token::Start { attrs, .. } => attrs
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Set (or add) attribute `key` on a start tag; no-op for other tokens.
+    pub fn set_attr(&mut self, key: &str, value: &str) {
+        if let Token::Start { attrs, .. } = self {
+            match attrs.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+                Some((_, v)) => *v = value.to_string(),
+                None => attrs.push((key.to_string(), value.to_string())),
+            }
+        }
+    }
+}
+
+/// Split HTML into tokens. Never fails: anything unparseable becomes text.
+pub fn tokenize(html: &str) -> Vec<Token> {
+    Tokenizer::new(html).collect()
+}
+
+/// The tokens of an HTML document, produced as they are read so that a
+/// pipeline over a large chapter never holds all of them at once.
+pub struct Tokenizer<'a> {
+    rest: &'a str,
+    /// The content of a raw text element, after its start tag.
+    pending: Option<Token>,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(html: &'a str) -> Self {
+        Self {
+            rest: html,
+            pending: None,
+        }
+    }
+
+    /// Whether the input continues with character data (rather than markup).
+    fn at_text(&self) -> bool {
+        let rest = self.rest;
+        let markup = ["<!", "<?", "</"].iter().any(|m| rest.starts_with(m))
+            || rest.starts_with('<') && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic());
+        !rest.is_empty() && !markup
+    }
+
+    /// The character data up to the next '<' that isn't the very first
+    /// character, decoded.
+    fn text(&mut self) -> String {
+        let rest = self.rest;
+        let first = rest.chars().next().map_or(0, char::len_utf8);
+        let end = rest[first..].find('<').map_or(rest.len(), |i| i + first);
+        self.rest = &rest[end..];
+        decode_entities(&rest[..end])
+    }
+
+    /// Skip a doctype, processing instruction or nameless end tag, which
+    /// produce no token. Returns whether there was one.
+    fn skip_ignored(&mut self) -> bool {
+        let rest = self.rest;
+        let end = if (rest.starts_with("<!") || rest.starts_with("<?"))
+            && !rest.starts_with("<!--")
+            && !rest.starts_with("<![CDATA[")
+        {
+            rest.find('>').map_or(rest.len(), |i| i + 1)
+        } else if let Some(after) = rest.strip_prefix("</")
+            && let end = after.find('>').unwrap_or(after.len())
+            && after[..end].trim().is_empty()
+        {
+            (2 + end + 1).min(rest.len())
+        } else {
+            return false;
+        };
+        self.rest = &rest[end..];
+        true
+    }
+
+    /// `text` followed by any character data right after it (even across
+    /// markup that produces no token), as one token.
+    fn merged(&mut self, mut text: String) -> Token {
+        loop {
+            if self.at_text() {
+                text.push_str(&self.text());
+            } else if !self.skip_ignored() {
+                return Token::Text(text);
+            }
+        }
+    }
+}
+
+impl Iterator for Tokenizer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if let Some(token) = self.pending.take() {
+            return Some(token);
+        }
+        loop {
+            let rest = self.rest;
+            if rest.is_empty() {
+                return None;
+            }
+            if let Some(after) = rest.strip_prefix("<!--") {
+                let end = after.find("-->").unwrap_or(after.len());
+                self.rest = after.get(end + 3..).unwrap_or("");
+                return Some(Token::Comment(after[..end].to_string()));
+            } else if let Some(after) = rest.strip_prefix("<![CDATA[") {
+                let end = after.find("]]>").unwrap_or(after.len());
+                self.rest = after.get(end + 3..).unwrap_or("");
+                return Some(self.merged(after[..end].to_string()));
+            } else if self.skip_ignored() {
+                // Doctype / processing instruction: the serializer writes its own.
+            } else if let Some(after) = rest.strip_prefix("</") {
+                let end = after.find('>').unwrap_or(after.len());
+                self.rest = after.get(end + 1..).unwrap_or("");
+                return Some(Token::End(after[..end].trim().to_string()));
+            } else if rest.starts_with('<')
+                && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic())
+            {
+                let (token, after) = parse_start_tag(&rest[1..]);
+                self.rest = after;
+                if let Token::Start {
+                    name,
+                    self_closing: false,
+                    ..
+                } = &token
+                    && RAW_TEXT_ELEMENTS
+                        .iter()
+                        .any(|t| name.eq_ignore_ascii_case(t))
+                {
+                    let end = find_end_tag(self.rest, name);
+                    if end > 0 {
+                        self.pending = Some(Token::Text(self.rest[..end].to_string()));
+                    }
+                    self.rest = &self.rest[end..];
+                }
+                return Some(token);
+            } else {
+                let text = self.text();
+                return Some(self.merged(text));
+            }
+        }
+    }
+}
+
+/// Where the end tag of raw text element `name` starts in `text` (ASCII
+/// case-insensitive), or the end of `text` when it is never closed.
+fn find_end_tag(text: &str, name: &str) -> usize {
+    let close = format!("</{name}");
+    text.match_indices("</")
+        .map(|(at, _)| at)
+        .find(|&at| {
+            text.get(at..at + close.len())
+                .is_some_and(|tag| tag.eq_ignore_ascii_case(&close))
+        })
+        .unwrap_or(text.len())
+}
+
+/// Parse `name attr="v" ...>` (the leading '<' already consumed).
+fn parse_start_tag(input: &str) -> (Token, &str) {
+    let is_name_end = |c: char| c.is_whitespace() || c == '/' || c == '>';
+    let name_end = input.find(is_name_end).unwrap_or(input.len());
+    let name = input[..name_end].to_string();
+    let mut rest = &input[name_end..];
+    let mut attrs = Vec::new();
+    let mut self_closing = false;
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(after) = rest.strip_prefix("/>") {
+            self_closing = true;
+            rest = after;
+            break;
+        }
+        if let Some(after) = rest.strip_prefix('>') {
+            rest = after;
+            break;
+        }
+        if let Some(after) = rest.strip_prefix('/') {
+            rest = after;
+            continue;
+        }
+
+        let key_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        let key = rest[..key_end].to_string();
+        rest = rest[key_end..].trim_start();
+
+        let value = if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            if let Some(quote) = after.chars().next().filter(|c| *c == '"' || *c == '\'') {
+                let inner = &after[1..];
+                let end = inner.find(quote).unwrap_or(inner.len());
+                rest = inner.get(end + 1..).unwrap_or("");
+                decode_entities(&inner[..end])
+            } else {
+                let end = after
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .unwrap_or(after.len());
+                rest = &after[end..];
+                decode_entities(&after[..end])
+            }
+        } else {
+            // Boolean attribute: XHTML wants `key="key"`.
+            key.clone()
+        };
+        if !key.is_empty() && !attrs.iter().any(|(k, _): &(String, String)| *k == key) {
+            attrs.push((key, value));
+        }
+    }
+
+    (
+        Token::Start {
+            name,
+            attrs,
+            self_closing,
+        },
+        rest,
+    )
+}
+
+/// Serialize tokens as well-formed XHTML: void elements self-close, stray end
+/// tags are dropped and anything left open is closed at the end.
+pub fn serialize_xhtml(tokens: &[Token]) -> String {
+    let mut writer = XhtmlWriter::default();
+    tokens.iter().for_each(|token| writer.write(token));
+    writer.finish()
+}
+
+/// `serialize_xhtml` a token at a time, so tokens can be written as they
+/// are produced.
+#[derive(Default)]
+pub struct XhtmlWriter {
+    out: String,
+    /// Elements open at this point, outermost first.
+    open: Vec<String>,
+}
+
+impl XhtmlWriter {
+    pub fn write(&mut self, token: &Token) {
+        let out = &mut self.out;
+        match token {
+            Token::Start {
+                name,
+                attrs,
+                self_closing,
+            } => {
+                out.push('<');
+                out.push_str(name);
+                for (k, v) in attrs {
+                    out.push(' ');
+                    out.push_str(k);
+                    out.push_str("=\"");
+                    out.push_str(&escape_attr(v));
+                    out.push('"');
+                }
+                if *self_closing || is_void(name) {
+                    out.push_str("/>");
+                } else {
+                    out.push('>');
+                    self.open.push(name.clone());
+                }
+            }
+            Token::End(name) => {
+                if let Some(pos) = self.open.iter().rposition(|n| n.eq_ignore_ascii_case(name)) {
+                    for n in self.open.drain(pos..).rev() {
+                        close(out, &n);
+                    }
+                }
+            }
+            Token::Text(text) => out.push_str(&escape_text(text)),
+            Token::Comment(c) => {
+                // "--" is not allowed inside XML comments.
+                out.push_str(&format!("<!--{}-->", c.replace("--", "- -")));
+            }
+        }
+    }
+
+    /// The XHTML written, with whatever is still open closed.
+    pub fn finish(mut self) -> String {
+        for n in self.open.iter().rev() {
+            close(&mut self.out, n);
+        }
+        self.out
+    }
+}
+
+fn close(out: &mut String, name: &str) {
+    out.push_str("</");
+    out.push_str(name);
+    out.push('>');
+}
+
+/// Make headings form a hierarchy assistive technology can navigate: a
+/// heading may go any number of levels up but only one level down, so an
+/// `<h1>` followed by an `<h4>` becomes `<h1>`, `<h2>`. The first heading keeps
+/// its level, since a chapter is often a section of a larger part. Fed the
+/// document's tokens in order, one at a time.
+#[derive(Default)]
+pub struct HeadingLevels {
+    last: Option<u8>,
+    /// Open headings: (level in the source, level written).
+    open: Vec<(u8, u8)>,
+}
+
+impl HeadingLevels {
+    /// Fix `token`, the next of the document, if it is a heading tag.
+    pub fn fix(&mut self, token: &mut Token) {
+        fn level(name: &str) -> Option<u8> {
+            match name.as_bytes() {
+                [b'h' | b'H', n @ b'1'..=b'6'] => Some(n - b'0'),
+                _ => None,
+            }
+        }
+
+        match token {
+            Token::Start { name, .. } => {
+                if let Some(n) = level(name) {
+                    let fixed = self.last.map_or(n, |l| n.min(l + 1));
+                    self.last = Some(fixed);
+                    self.open.push((n, fixed));
+                    *name = format!("h{fixed}");
+                }
+            }
+            Token::End(name) => {
+                if let Some(n) = level(name)
+                    && let Some(pos) = self.open.iter().rposition(|(orig, _)| *orig == n)
+                {
+                    let (_, fixed) = self.open.remove(pos);
+                    *name = format!("h{fixed}");
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn is_void(name: &str) -> bool {
+    VOID_ELEMENTS.iter().any(|v| name.eq_ignore_ascii_case(v))
+}
+
+fn escape_text(s: &str) -> String {
+    if !s.contains(['&', '<', '>']) {
+        return s.to_string();
+    }
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_attr(s: &str) -> String {
+    escape_text(s).replace('"', "&quot;")
+}
+
+/// Decode numeric and common named character references.
+/// Unknown names are kept verbatim (and will be escaped on output).
+pub fn decode_entities(s: &str) -> String {
+    if !s.contains('&') {
+        return s.to_string();
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('&') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|&end| end > 0 && end <= 32)
+            .and_then(|end| decode_entity(&rest[1..=end]).map(|c| (c, end + 2)));
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    if let Some(num) = name.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    let c = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "shy" => '\u{ad}',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "deg" => '°',
+        "plusmn" => '±',
+        "times" => '×',
+        "divide" => '÷',
+        "middot" => '·',
+        "sect" => '§',
+        "para" => '¶',
+        "laquo" => '«',
+        "raquo" => '»',
+        "ndash" => '–',
+        "mdash" => '—',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "sbquo" => '‚',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "bdquo" => '„',
+        "hellip" => '…',
+        "bull" => '•',
+        "prime" => '′',
+        "Prime" => '″',
+        "larr" => '←',
+        "rarr" => '→',
+        "uarr" => '↑',
+        "darr" => '↓',
+        "harr" => '↔',
+        "lArr" => '⇐',
+        "rArr" => '⇒',
+        "hArr" => '⇔',
+        "le" => '≤',
+        "ge" => '≥',
+        "ne" => '≠',
+        "asymp" => '≈',
+        "infin" => '∞',
+        "minus" => '−',
+        "euro" => '€',
+        "pound" => '£',
+        "yen" => '¥',
+        "cent" => '¢',
+        "dagger" => '†',
+        "Dagger" => '‡',
+        "zwj" => '\u{200d}',
+        "zwnj" => '\u{200c}',
+        "ensp" => '\u{2002}',
+        "emsp" => '\u{2003}',
+        "thinsp" => '\u{2009}',
+        _ => return None,
+    };
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_entities, serialize_xhtml, tokenize, HeadingLevels, Token, Tokenizer};
+
+    fn roundtrip(html: &str) -> String {
+        serialize_xhtml(&tokenize(html))
+    }
+
+    #[test]
+    fn void_elements_self_close_and_attributes_get_quoted() {
+        assert_eq!(
+            roundtrip(r#"<p>a<br>b<img src=x.png alt='A "B"'></p>"#),
+            r#"<p>a<br/>b<img src="x.png" alt="A &quot;B&quot;"/></p>"#
+        );
+    }
+
+    #[test]
+    fn named_entities_become_characters() {
+        assert_eq!(
+            roundtrip("<p>a&nbsp;&mdash;&amp;b&unknown;</p>"),
+            "<p>a\u{a0}—&amp;b&amp;unknown;</p>"
+        );
+        assert_eq!(decode_entities("&#65;&#x42;"), "AB");
+    }
+
+    #[test]
+    fn unbalanced_markup_is_repaired() {
+        assert_eq!(
+            roundtrip("<div><p>text</div></span>"),
+            "<div><p>text</p></div>"
+        );
+        assert_eq!(
+            roundtrip("<section><p>open"),
+            "<section><p>open</p></section>"
+        );
+    }
+
+    #[test]
+    fn raw_text_elements_are_not_tokenized() {
+        let tokens = tokenize("<style>a > b { color: red }</style><p>x</p>");
+        assert_eq!(tokens[1], Token::Text("a > b { color: red }".to_string()));
+        assert!(tokens[3].is_start("p"));
+    }
+
+    #[test]
+    fn attribute_helpers() {
+        let mut tokens = tokenize(r#"<a HREF="ch01.html#x" class=link>"#);
+        assert_eq!(tokens[0].attr("href"), Some("ch01.html#x"));
+        tokens[0].set_attr("href", "ch01.xhtml#x");
+        tokens[0].set_attr("id", "y");
+        assert_eq!(
+            serialize_xhtml(&tokens),
+            r#"<a HREF="ch01.xhtml#x" class="link" id="y"></a>"#
+        );
+    }
+
+    #[test]
+    fn doctype_and_comments() {
+        assert_eq!(
+            roundtrip("<!DOCTYPE html><!-- a -- b --><p>x</p>"),
+            "<!-- a - - b --><p>x</p>"
+        );
+    }
+
+    #[test]
+    fn stray_less_than_is_text() {
+        assert_eq!(roundtrip("<p>1 < 2</p>"), "<p>1 &lt; 2</p>");
+    }
+
+    #[test]
+    fn headings_step_down_one_level_at_a_time() {
+        let mut tokens = tokenize("<h2>A</h2><h4>B</h4><h6>C</h6><h3>D</h3><h1>E</h1><h3>F</h3>");
+        let mut levels = HeadingLevels::default();
+        tokens.iter_mut().for_each(|token| levels.fix(token));
+        assert_eq!(
+            serialize_xhtml(&tokens),
+            "<h2>A</h2><h3>B</h3><h4>C</h4><h3>D</h3><h1>E</h1><h2>F</h2>"
+        );
+    }
+
+    #[test]
+    fn tokens_come_as_they_are_read() {
+        let mut tokens = Tokenizer::new("a<!DOCTYPE html>b<![CDATA[<c>]]>d<STYLE>p{}</style>");
+        // Text is one token across markup that produces none.
+        assert_eq!(tokens.next(), Some(Token::Text("ab".to_string())));
+        assert_eq!(tokens.next(), Some(Token::Text("<c>d".to_string())));
+        assert!(tokens.next().unwrap().is_start("style"));
+        assert_eq!(tokens.next(), Some(Token::Text("p{}".to_string())));
+        assert!(tokens.next().unwrap().is_end("style"));
+        assert_eq!(tokens.next(), None);
+    }
+}
@@ -1,32 +1,227 @@
-use crate::cookies::CookieStore;
-use anyhow::Result;
+use crate::api::{ApiResponse, OreillyApi};
+use crate::config::DEFAULT_JOBS;
+use crate::cookies::{CookieStore, DEFAULT_COOKIE_DOMAIN, JWT_COOKIE};
+use crate::i18n::t;
+use crate::orly::{token_refresh_url, DEFAULT_BASE_URL};
+use crate::partial;
+use crate::recorder::{self, Fixtures};
+use crate::throttle::{Rate, RateLimit, Throttle, STEALTH_PAUSE};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
 use reqwest::header::{
-    HeaderMap, HeaderValue, ACCEPT, ACCEPT_ENCODING, COOKIE, REFERER, USER_AGENT,
+    HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_LENGTH,
+    CONTENT_TYPE, COOKIE, IF_RANGE, LOCATION, RANGE, REFERER, RETRY_AFTER, SET_COOKIE, USER_AGENT,
 };
-use reqwest::Client;
+use reqwest::{Certificate, Client, Request, RequestBuilder, Response, Url};
+use serde::Deserialize;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Tracing target of the `--debug-http` request log.
+pub const HTTP_LOG_TARGET: &str = "safaribooks_rs::http";
+
+/// Headers worth logging with `--debug-http`.
+const LOGGED_HEADERS: &[HeaderName] = &[
+    COOKIE,
+    AUTHORIZATION,
+    REFERER,
+    CONTENT_TYPE,
+    CONTENT_LENGTH,
+    LOCATION,
+    RETRY_AFTER,
+    SET_COOKIE,
+];
+
+/// The User-Agent sent unless `--user-agent` says otherwise.
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 \
+     (KHTML, like Gecko) Chrome/122.0.0.0 Safari/537.36";
+
+/// `--user-agent random`: one of these, picked once per run.
+pub const RANDOM_USER_AGENT: &str = "random";
+const USER_AGENTS: &[&str] = &[
+    DEFAULT_USER_AGENT,
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+     (KHTML, like Gecko) Chrome/122.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 \
+     (KHTML, like Gecko) Version/17.3 Safari/605.1.15",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:123.0) Gecko/20100101 Firefox/123.0",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+     (KHTML, like Gecko) Chrome/122.0.0.0 Safari/537.36 Edg/122.0.0.0",
+];
+
+/// The User-Agent to send for the `--user-agent` setting: as given, one of
+/// the built-in browsers for "random", or the default.
+pub fn user_agent(configured: Option<&str>) -> &str {
+    match configured.map(str::trim) {
+        None | Some("") => DEFAULT_USER_AGENT,
+        Some(ua) if ua.eq_ignore_ascii_case(RANDOM_USER_AGENT) => {
+            USER_AGENTS[fastrand::usize(..USER_AGENTS.len())]
+        }
+        Some(ua) => ua,
+    }
+}
+
+/// A `--header` argument, "Name: value".
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue)> {
+    let Some((name, value)) = header.split_once(':') else {
+        bail!(t!("header-malformed", header = format!("{header:?}")));
+    };
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .with_context(|| t!("header-name-invalid", header = format!("{header:?}")))?;
+    let value = HeaderValue::from_str(value.trim())
+        .with_context(|| t!("header-value-invalid", header = format!("{header:?}")))?;
+    Ok((name, value))
+}
+
+/// A `--resolve` entry, "host:address" or curl's "host:port:address". The
+/// port is accepted for curl's sake but ignored: the override applies to
+/// the host on any port.
+fn parse_resolve(entry: &str) -> Result<(String, IpAddr)> {
+    let ip = |s: &str| {
+        s.trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .ok()
+    };
+    let parsed = entry.trim().split_once(':').and_then(|(host, rest)| {
+        let addr = ip(rest).or_else(|| {
+            let (port, addr) = rest.split_once(':')?;
+            port.parse::<u16>().ok()?;
+            ip(addr)
+        })?;
+        Some((host.to_ascii_lowercase(), addr)).filter(|_| !host.is_empty())
+    });
+    parsed.with_context(|| t!("resolve-malformed", entry = format!("{entry:?}")))
+}
+
+/// How long an unused connection is kept for the next request; long enough
+/// to span a slow chapter or a `--stealth` pause.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// Idle connections kept per host (HTTP/1.1 needs one per worker).
+const POOL_MAX_IDLE_PER_HOST: usize = 32;
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Attempts per request while the server keeps answering 429/503.
+const MAX_THROTTLED_ATTEMPTS: u32 = 6;
+
+/// Asks for fresh cookies once the session has expired mid-run (blocking;
+/// may prompt the user). `None` gives up and lets the request fail.
+pub type Reauth = Arc<dyn Fn() -> Option<CookieStore> + Send + Sync>;
+
+/// Which IP version connections use. By default both are tried, the
+/// resolver's first answer first, falling back to the other quickly if it
+/// does not connect (happy eyeballs).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    #[default]
+    Any,
+    /// IPv4 only (`--ipv4`), e.g. when the IPv6 route is broken.
+    Ipv4,
+    /// IPv6 only (`--ipv6`).
+    Ipv6,
+}
+
+/// How API and asset requests authenticate.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Session cookies only, like the original script.
+    #[default]
+    Cookies,
+    /// Cookies plus `Authorization: Bearer <orm-jwt>`, like the web app.
+    Bearer,
+}
+
+/// Where `fetch` puts a response body.
+#[derive(Clone, Copy)]
+enum Body<'a> {
+    Memory,
+    /// Stream a successful body to this partial file, resuming it if a
+    /// previous attempt left some of it; other bodies stay in memory.
+    File(&'a Path),
+}
 
 /// Minimal HTTP client wrapper.
-/// - Cookies are injected into the default `Cookie:` header.
+/// - Requests to the site's hosts get a `Cookie:` header holding only the
+///   cookies scoped to their URL; other hosts (CDNs, publishers' assets)
+///   never see the session.
 /// - A few "browser-like" headers are pre-set (matching the spirit of the Python script).
+/// - A 401 from the site triggers one token refresh and a retry, so long
+///   downloads survive the JWT expiring halfway through.
 pub struct HttpClient {
     client: Client,
-    /// Kept for tests and internal checks; **do not log** this in production logs.
-    cookie_header: String,
+    /// Session cookies; **do not log** their values in production logs.
+    /// Behind a lock because a token refresh replaces `orm-jwt` mid-download.
+    cookies: RwLock<CookieStore>,
+    /// Optional record/replay of every response (see `--record`/`--replay`).
+    fixtures: Option<Fixtures>,
+    /// Site root, e.g. "https://learning.oreilly.com" (see `--base-url`).
+    base_url: String,
+    /// Host of `base_url`.
+    site_host: String,
+    auth: AuthMode,
+    /// Serializes token refreshes so concurrent 401s refresh only once.
+    refresh_lock: tokio::sync::Mutex<()>,
+    /// Bumped whenever the cookies are replaced, so requests that failed
+    /// with the old ones know to just retry.
+    generation: AtomicU64,
+    /// Requests hold it shared; re-authentication holds it exclusively,
+    /// pausing every new request until fresh cookies are in.
+    gate: tokio::sync::RwLock<()>,
+    reauth: Option<Reauth>,
+    /// How the client connects; it is rebuilt when this changes.
+    transport: Transport,
+    /// Adapts concurrency and pacing to 429/503 responses.
+    throttle: Throttle,
+    /// Caps the bytes per second read across all responses.
+    rate_limit: Option<RateLimit>,
+}
+
+/// What the reqwest client is built with, besides the site.
+#[derive(Clone)]
+struct Transport {
+    /// Sent with every request (`--user-agent`).
+    user_agent: HeaderValue,
+    /// `--header`s, sent with every request too.
+    extra_headers: HeaderMap,
+    /// `--ca-cert`: roots trusted besides the system's, e.g. a corporate
+    /// proxy's.
+    ca_certs: Vec<Certificate>,
+    /// `--insecure`: accept any certificate.
+    insecure: bool,
+    /// `--resolve`: addresses used instead of asking DNS.
+    resolve: Vec<(String, IpAddr)>,
+    /// `--ipv4`/`--ipv6`.
+    ip_family: IpFamily,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self {
+            user_agent: HeaderValue::from_static(DEFAULT_USER_AGENT),
+            extra_headers: HeaderMap::new(),
+            ca_certs: Vec::new(),
+            insecure: false,
+            resolve: Vec::new(),
+            ip_family: IpFamily::Any,
+        }
+    }
 }
 
 impl HttpClient {
-    /// Build a HeaderMap with static browser-like values and an explicit Cookie header.
-    fn build_default_headers(cookie_header: &str) -> Result<HeaderMap> {
+    /// Build a HeaderMap with static browser-like values, then the
+    /// `--header`s.
+    fn build_default_headers(base_url: &str, transport: &Transport) -> HeaderMap {
         let mut headers = HeaderMap::new();
 
-        // User-Agent: a modern desktop UA string (no device-specific flags).
-        headers.insert(
-            USER_AGENT,
-            HeaderValue::from_static(
-                "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 \
-                 (KHTML, like Gecko) Chrome/122.0.0.0 Safari/537.36",
-            ),
-        );
+        // User-Agent: a desktop browser's (see `--user-agent`).
+        headers.insert(USER_AGENT, transport.user_agent.clone());
 
         // Accept: prefer HTML, XML; also allow images and generic types.
         headers.insert(
@@ -40,43 +235,576 @@ impl HttpClient {
         // (reqwest handles decompression automatically.)
         headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, deflate"));
 
-        // Referer: mirrors the original script's "login entry" intent (safe placeholder for now).
-        headers.insert(
-            REFERER,
-            HeaderValue::from_static("https://learning.oreilly.com/login/unified/?next=/home/"),
-        );
+        // Referer: the home page, where the web app makes its API calls
+        // from. Chapters and assets say which page they were linked from.
+        if let Ok(referer) = HeaderValue::from_str(&format!("{base_url}/home/")) {
+            headers.insert(REFERER, referer);
+        }
 
-        // Cookie: **all authentication lives here** (cookies-only flow).
-        // IMPORTANT: HeaderValue::from_str validates and rejects invalid bytes.
-        headers.insert(COOKIE, HeaderValue::from_str(cookie_header)?);
+        // `--header`: the user's headers replace ours of the same name.
+        let extra = &transport.extra_headers;
+        for name in extra.keys() {
+            headers.remove(name);
+        }
+        for (name, value) in extra {
+            headers.append(name, value.clone());
+        }
 
-        Ok(headers)
+        headers
     }
 
-    /// Create an HttpClient from a CookieStore (preferred path).
+    /// Create an HttpClient from a CookieStore.
     pub fn from_store(store: &CookieStore) -> Result<Self> {
-        let cookie_header = store.to_header_value();
-        Self::new(&cookie_header)
-    }
-
-    /// Create an HttpClient from a pre-rendered "Cookie: ..." value.
-    pub fn new(cookie_header: &str) -> Result<Self> {
-        let headers = Self::build_default_headers(cookie_header)?;
-        let client = Client::builder().default_headers(headers).build()?;
         Ok(Self {
-            client,
-            cookie_header: cookie_header.to_string(),
+            client: Self::build_client(DEFAULT_BASE_URL, &Transport::default())?,
+            transport: Transport::default(),
+            cookies: RwLock::new(store.clone()),
+            fixtures: None,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            site_host: "learning.oreilly.com".to_string(),
+            auth: AuthMode::default(),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            generation: AtomicU64::new(0),
+            gate: tokio::sync::RwLock::new(()),
+            reauth: None,
+            throttle: Throttle::new(DEFAULT_JOBS),
+            rate_limit: None,
         })
     }
 
-    /// Access the underlying reqwest client (read-only).
-    pub fn client(&self) -> &Client {
-        &self.client
+    fn build_client(base_url: &str, transport: &Transport) -> Result<Client> {
+        let headers = Self::build_default_headers(base_url, transport);
+        // One client serves every worker: its pool keeps the connections to
+        // the site and the CDN open between chapters, and over TLS they
+        // negotiate HTTP/2, multiplexing the workers' requests.
+        let mut builder = Client::builder()
+            .default_headers(headers)
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+            .tcp_keepalive(TCP_KEEPALIVE)
+            .http2_adaptive_window(true);
+        if !transport.ca_certs.is_empty() {
+            builder = builder.tls_certs_merge(transport.ca_certs.iter().cloned());
+        }
+        if transport.insecure {
+            builder = builder.tls_danger_accept_invalid_certs(true);
+        }
+        for (host, ip) in &transport.resolve {
+            // DNS has no ports; the URL's is used whatever this says.
+            builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+        }
+        // Binding to one family's unspecified address keeps connections to it.
+        builder = match transport.ip_family {
+            IpFamily::Any => builder,
+            IpFamily::Ipv4 => builder.local_address(IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+            IpFamily::Ipv6 => builder.local_address(IpAddr::from(Ipv6Addr::UNSPECIFIED)),
+        };
+        Ok(builder.build()?)
+    }
+
+    /// Talk to a white-labeled or proxied site instead of learning.oreilly.com.
+    /// Cookies exported without a domain are scoped to its host.
+    pub fn with_base_url(mut self, base_url: &str) -> Result<Self> {
+        let base_url = base_url.trim_end_matches('/');
+        let url = Url::parse(base_url)?;
+        let Some(host) = url
+            .host_str()
+            .filter(|_| matches!(url.scheme(), "http" | "https"))
+        else {
+            bail!(t!("base-url-invalid", url = base_url));
+        };
+        self.site_host = host.to_string();
+        self.base_url = base_url.to_string();
+        self.rebuild()?;
+        let domain = self.cookie_domain().to_string();
+        self.cookies.get_mut().unwrap().set_default_domain(&domain);
+        Ok(self)
+    }
+
+    /// Present the client as `user_agent` (see `--user-agent`).
+    pub fn with_user_agent(mut self, user_agent: &str) -> Result<Self> {
+        self.transport.user_agent = HeaderValue::from_str(user_agent)
+            .with_context(|| t!("user-agent-invalid", value = format!("{user_agent:?}")))?;
+        self.rebuild()?;
+        Ok(self)
+    }
+
+    /// Send `headers` ("Name: value") with every request (see `--header`).
+    pub fn with_headers(mut self, headers: &[String]) -> Result<Self> {
+        for header in headers {
+            let (name, value) = parse_header(header)?;
+            self.transport.extra_headers.append(name, value);
+        }
+        self.rebuild()?;
+        Ok(self)
+    }
+
+    /// Also trust the certificates in the PEM file `path` (see `--ca-cert`).
+    pub fn with_ca_cert(mut self, path: &Path) -> Result<Self> {
+        let pem = fs::read(path)
+            .with_context(|| t!("reading-file", path = path.display().to_string()))?;
+        let certs = Certificate::from_pem_bundle(&pem)
+            .with_context(|| t!("certificate-unusable", path = path.display().to_string()))?;
+        if certs.is_empty() {
+            bail!(t!("certificate-missing", path = path.display().to_string()));
+        }
+        self.transport.ca_certs.extend(certs);
+        self.rebuild()?;
+        Ok(self)
+    }
+
+    /// Accept any TLS certificate (see `--insecure`).
+    pub fn with_insecure(mut self, on: bool) -> Result<Self> {
+        self.transport.insecure = on;
+        self.rebuild()?;
+        Ok(self)
+    }
+
+    /// Connect to these addresses instead of the ones DNS gives
+    /// ("host:address" entries, see `--resolve`).
+    pub fn with_resolve(mut self, entries: &[String]) -> Result<Self> {
+        for entry in entries {
+            self.transport.resolve.push(parse_resolve(entry)?);
+        }
+        self.rebuild()?;
+        Ok(self)
+    }
+
+    /// Connect over `family` only (see `--ipv4`, `--ipv6`).
+    pub fn with_ip_family(mut self, family: IpFamily) -> Result<Self> {
+        self.transport.ip_family = family;
+        self.rebuild()?;
+        Ok(self)
+    }
+
+    /// Build the client again after its settings changed.
+    fn rebuild(&mut self) -> Result<()> {
+        self.client = Self::build_client(&self.base_url, &self.transport)?;
+        Ok(())
+    }
+
+    /// Domain of cookies exported without one: O'Reilly's, or a custom site's host.
+    fn cookie_domain(&self) -> &str {
+        if self.base_url == DEFAULT_BASE_URL {
+            DEFAULT_COOKIE_DOMAIN
+        } else {
+            &self.site_host
+        }
+    }
+
+    /// Ask `reauth` for new cookies when the session expires mid-run
+    /// instead of failing every remaining request.
+    pub fn with_reauth(mut self, reauth: Reauth) -> Self {
+        self.reauth = Some(reauth);
+        self
+    }
+
+    /// Allow at most `jobs` requests in flight (fewer while the server throttles us).
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.throttle = Throttle::new(jobs);
+        self
+    }
+
+    /// Start each request after a random pause (see `--stealth`).
+    pub fn with_stealth(mut self, on: bool) -> Self {
+        if on {
+            self.throttle = self.throttle.with_jitter(STEALTH_PAUSE);
+        }
+        self
+    }
+
+    /// Count the site as failing once more than `percent` of the recent
+    /// requests failed (see `--degrade-above`).
+    pub fn with_degrade_above(mut self, percent: u8) -> Self {
+        self.throttle = self.throttle.with_failure_limit(percent);
+        self
+    }
+
+    /// Whether the site is failing as a whole rather than for a request
+    /// here and there.
+    pub fn failing(&self) -> bool {
+        self.throttle.failing()
+    }
+
+    /// Resolves once the site is failing as a whole.
+    pub async fn until_failing(&self) {
+        self.throttle.until_failing().await;
+    }
+
+    /// Make one request at a time, with long pauses, until the site is
+    /// healthy again.
+    pub fn degrade(&self) {
+        self.throttle.degrade();
+    }
+
+    /// Read response bodies no faster than `rate` in total (see `--limit-rate`).
+    pub fn with_rate_limit(mut self, rate: Option<Rate>) -> Self {
+        self.rate_limit = rate.map(RateLimit::new);
+        self
+    }
+
+    pub fn with_auth(mut self, auth: AuthMode) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Save every response to, or serve every response from, a fixtures directory.
+    pub fn with_fixtures(mut self, fixtures: Fixtures) -> Self {
+        self.fixtures = Some(fixtures);
+        self
+    }
+
+    /// The `Cookie` header value for `url`. Empty when no cookie is scoped to the URL.
+    pub fn cookie_header_for(&self, url: &str) -> Result<String> {
+        let url = Url::parse(url)?;
+        Ok(self
+            .cookies
+            .read()
+            .unwrap()
+            .header_for(url.host_str().unwrap_or_default(), url.path()))
+    }
+
+    /// Expose the main-site cookie header for tests/diagnostics (do **not** log this in production).
+    #[cfg(test)]
+    pub fn cookie_header(&self) -> String {
+        self.cookies.read().unwrap().to_header_value()
+    }
+
+    /// The current API token (`orm-jwt` cookie of the site).
+    fn token(&self) -> Option<String> {
+        self.cookies
+            .read()
+            .unwrap()
+            .value_for(JWT_COOKIE, &self.site_host, "/")
+            .map(str::to_string)
+    }
+
+    /// Whether `url` belongs to the site (and may carry the bearer token).
+    fn is_site(&self, url: &str) -> bool {
+        let Ok(url) = Url::parse(url) else {
+            return false;
+        };
+        let host = url.host_str().unwrap_or_default();
+        host == self.site_host
+            || host == DEFAULT_COOKIE_DOMAIN
+            || host.ends_with(&format!(".{DEFAULT_COOKIE_DOMAIN}"))
+    }
+
+    /// Attach the cookies (and bearer token) scoped to `url`, when it is
+    /// one of the site's.
+    fn authorize(&self, mut req: RequestBuilder, url: &str) -> Result<RequestBuilder> {
+        if !self.is_site(url) {
+            return Ok(req);
+        }
+        let cookie_header = self.cookie_header_for(url)?;
+        if !cookie_header.is_empty() {
+            // CookieStore already encoded anything outside RFC 6265.
+            req = req.header(COOKIE, HeaderValue::from_str(&cookie_header)?);
+        }
+        if self.auth == AuthMode::Bearer
+            && let Some(token) = self.token()
+        {
+            req = req.header(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {token}"))?,
+            );
+        }
+        Ok(req)
+    }
+
+    /// Send `req`, logging both ends to the `--debug-http` target.
+    async fn execute(&self, req: Request) -> Result<Response> {
+        let (method, url) = (req.method().clone(), req.url().clone());
+        debug!(target: HTTP_LOG_TARGET, "--> {method} {url}{}", loggable_headers(req.headers()));
+        let started = Instant::now();
+        let res = match self.client.execute(req).await {
+            Ok(res) => res,
+            Err(e) => {
+                debug!(target: HTTP_LOG_TARGET, "<-- {method} {url} failed after {:?}: {e}", started.elapsed());
+                return Err(e.into());
+            }
+        };
+        debug!(
+            target: HTTP_LOG_TARGET,
+            "<-- {} {method} {url} ({:?}){}",
+            res.status().as_u16(),
+            started.elapsed(),
+            loggable_headers(res.headers())
+        );
+        Ok(res)
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// GET `url`, linked from `referer` if given; also tells whether the
+    /// response shows a logged-out session. Throttled responses (429/503)
+    /// are retried at the pace the throttle allows. With `Body::File` a
+    /// 200 body is streamed to the file and not kept.
+    async fn fetch(
+        &self,
+        url: &str,
+        referer: Option<&str>,
+        body: Body<'_>,
+    ) -> Result<(ApiResponse, bool)> {
+        let mut attempt = 1;
+        loop {
+            let _open = self.gate.read().await;
+            let _permit = self.throttle.acquire().await;
+            let resume = match body {
+                Body::File(part) => partial::resume_point(part),
+                Body::Memory => None,
+            };
+            let mut builder = self.client.get(url);
+            // A `--header` Referer still wins.
+            if let Some(referer) = referer
+                && !self.transport.extra_headers.contains_key(REFERER)
+            {
+                builder = builder.header(REFERER, referer);
+            }
+            if let Some((offset, validator)) = &resume {
+                builder = builder
+                    .header(RANGE, format!("bytes={offset}-"))
+                    .header(IF_RANGE, validator.as_str())
+                    .header(ACCEPT_ENCODING, "identity");
+            }
+            let req = self.authorize(builder, url)?.build()?;
+            let mut res = match self.execute(req).await {
+                Ok(res) => res,
+                Err(e) => {
+                    self.throttle.record_error();
+                    return Err(e);
+                }
+            };
+            let mut status = res.status().as_u16();
+            let retry_after = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+                .map(Duration::from_secs);
+            let logged_out = self.is_site(url) && is_logged_out(status, res.url());
+
+            if self.throttle.record(status, retry_after) && attempt < MAX_THROTTLED_ATTEMPTS {
+                attempt += 1;
+                continue;
+            }
+            let body = match body {
+                Body::File(part) if status == 416 && resume.is_some() => {
+                    // The partial file no longer matches; start over.
+                    partial::discard(part);
+                    continue;
+                }
+                Body::File(part) if status == 200 || status == 206 => {
+                    let offset = resume.map_or(0, |(offset, _)| offset);
+                    partial::write(&mut res, part, offset, self.rate_limit.as_ref()).await?;
+                    status = 200;
+                    Vec::new()
+                }
+                _ => match &self.rate_limit {
+                    Some(limit) => {
+                        let mut body = Vec::new();
+                        while let Some(chunk) = res.chunk().await? {
+                            limit.take(chunk.len()).await;
+                            body.extend_from_slice(&chunk);
+                        }
+                        body
+                    }
+                    None => res.bytes().await?.to_vec(),
+                },
+            };
+            return Ok((ApiResponse { status, body }, logged_out));
+        }
+    }
+
+    /// GET `url` with token refresh, re-login and fixtures, the body going to `body`.
+    async fn request(
+        &self,
+        url: &str,
+        referer: Option<&str>,
+        body: Body<'_>,
+    ) -> Result<ApiResponse> {
+        if let Some(Fixtures::Replay(dir)) = &self.fixtures {
+            let res = recorder::load(dir, url)?;
+            if let Body::File(path) = body
+                && res.status == 200
+            {
+                fs::write(path, &res.body)
+                    .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+            }
+            return Ok(res);
+        }
+
+        let mut seen = self.generation();
+        let (mut res, mut logged_out) = self.fetch(url, referer, body).await?;
+        if res.status == 401 && self.is_site(url) && self.refresh_token(seen).await? {
+            seen = self.generation();
+            (res, logged_out) = self.fetch(url, referer, body).await?;
+        }
+        // Still logged out: resume with fresh cookies rather than fail the book.
+        while logged_out && self.reauthenticate(seen).await? {
+            seen = self.generation();
+            (res, logged_out) = self.fetch(url, referer, body).await?;
+        }
+        // A redirect to the login page answers 200 with the login form;
+        // it is the 401 the API would have sent.
+        if logged_out && res.status < 300 {
+            res.status = 401;
+        }
+
+        if let Some(Fixtures::Record(dir)) = &self.fixtures {
+            let mut recorded = res.clone();
+            if let Body::File(path) = body
+                && res.status == 200
+            {
+                recorded.body = fs::read(path)
+                    .with_context(|| t!("reading-file", path = path.display().to_string()))?;
+            }
+            recorder::save(dir, url, &recorded)?;
+        }
+        Ok(res)
+    }
+
+    /// Trade the refresh cookie for a new `orm-jwt`. `seen` is the cookie
+    /// generation the failed request used: if the cookies were replaced
+    /// since, there is nothing to do. Returns whether a retry is worthwhile.
+    async fn refresh_token(&self, seen: u64) -> Result<bool> {
+        let _guard = self.refresh_lock.lock().await;
+        if self.generation() != seen {
+            return Ok(true);
+        }
+
+        let url = token_refresh_url(&self.base_url);
+        let req = self.authorize(self.client.post(&url), &url)?.build()?;
+        let res = self.execute(req).await?;
+        if !res.status().is_success() {
+            warn!("Token refresh failed with status {}", res.status());
+            return Ok(false);
+        }
+
+        let mut cookies = self.cookies.write().unwrap();
+        let mut refreshed = false;
+        for header in res.headers().get_all(SET_COOKIE) {
+            if let Ok(header) = header.to_str() {
+                refreshed |= cookies.apply_set_cookie(header, &self.site_host).as_deref()
+                    == Some(JWT_COOKIE);
+            }
+        }
+        if refreshed {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            info!("API token refreshed");
+        } else {
+            warn!("Token refresh returned no new {JWT_COOKIE}");
+        }
+        Ok(refreshed)
+    }
+
+    /// Pause all requests and ask for fresh cookies. Returns whether a
+    /// retry is worthwhile (new cookies, possibly from another request).
+    async fn reauthenticate(&self, seen: u64) -> Result<bool> {
+        let Some(reauth) = self.reauth.clone() else {
+            return Ok(false);
+        };
+        let _pause = self.gate.write().await;
+        if self.generation() != seen {
+            return Ok(true);
+        }
+        warn!("Session expired; requests paused until new cookies are provided");
+        let Some(mut store) = tokio::task::spawn_blocking(move || reauth()).await? else {
+            return Ok(false);
+        };
+        store.set_default_domain(self.cookie_domain());
+        *self.cookies.write().unwrap() = store;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        info!("Session cookies replaced; resuming");
+        Ok(true)
+    }
+}
+
+/// The `LOGGED_HEADERS` present in `headers`, one per line, with secrets redacted.
+fn loggable_headers(headers: &HeaderMap) -> String {
+    let mut out = String::new();
+    for name in LOGGED_HEADERS {
+        for value in headers.get_all(name) {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            out.push_str(&format!("\n    {name}: {}", redact(name, &value)));
+        }
+    }
+    out
+}
+
+/// Hide credentials but keep what helps diagnose authentication: cookie
+/// names, the auth scheme and `Set-Cookie` attributes.
+fn redact(name: &HeaderName, value: &str) -> String {
+    const HIDDEN: &str = "<redacted>";
+    if *name == COOKIE {
+        value
+            .split(';')
+            .map(|pair| match pair.split_once('=') {
+                Some((k, _)) => format!("{}={HIDDEN}", k.trim()),
+                None => pair.trim().to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    } else if *name == SET_COOKIE {
+        let (pair, attrs) = value.split_once(';').unwrap_or((value, ""));
+        let cookie = pair.split_once('=').map_or(pair, |(k, _)| k).trim();
+        if attrs.is_empty() {
+            format!("{cookie}={HIDDEN}")
+        } else {
+            format!("{cookie}={HIDDEN};{attrs}")
+        }
+    } else if *name == AUTHORIZATION {
+        match value.split_once(' ') {
+            Some((scheme, _)) => format!("{scheme} {HIDDEN}"),
+            None => HIDDEN.to_string(),
+        }
+    } else {
+        value.to_string()
+    }
+}
+
+/// A 401, or a redirect that landed on a login page.
+fn is_logged_out(status: u16, final_url: &Url) -> bool {
+    status == 401 || final_url.path().contains("/login")
+}
+
+impl OreillyApi for HttpClient {
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    async fn get(&self, url: &str) -> Result<ApiResponse> {
+        self.request(url, None, Body::Memory).await
     }
 
-    /// Expose the cookie header for tests/diagnostics (do **not** log this in production).
-    pub fn cookie_header(&self) -> &str {
-        &self.cookie_header
+    async fn get_from(&self, url: &str, referer: &str) -> Result<ApiResponse> {
+        self.request(url, Some(referer), Body::Memory).await
+    }
+
+    async fn download(&self, url: &str, referer: Option<&str>, dest: &Path) -> Result<u16> {
+        let res = self.request(url, referer, Body::File(dest)).await?;
+        Ok(res.status)
+    }
+
+    /// A HEAD request; fixtures have no headers to answer from.
+    async fn content_length(&self, url: &str) -> Result<Option<u64>> {
+        if self.fixtures.is_some() {
+            return Ok(None);
+        }
+        let _open = self.gate.read().await;
+        let _permit = self.throttle.acquire().await;
+        let req = self.authorize(self.client.head(url), url)?.build()?;
+        let res = self.execute(req).await?;
+        if !res.status().is_success() {
+            return Ok(None);
+        }
+        Ok(res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()))
     }
 }
 
@@ -97,6 +825,15 @@ mod tests {
         // We don't assert on internal reqwest headers here; the presence of the header value suffices.
     }
 
+    #[test]
+    fn builds_client_when_cookie_values_contain_control_chars() {
+        let v = json!({ "consent": "a\tb\u{7f}", "sess": "abc" });
+        let store = CookieStore::from_value(v).unwrap();
+        let hc = HttpClient::from_store(&store).unwrap();
+
+        assert_eq!(hc.cookie_header(), "consent=a%09b%7F; sess=abc");
+    }
+
     #[test]
     fn builds_client_with_cookie_header_from_list() {
         let v = json!([
@@ -108,4 +845,485 @@ mod tests {
 
         assert_eq!(hc.cookie_header(), "a=1; b=2");
     }
+
+    #[test]
+    fn cookie_header_depends_on_request_url() {
+        let v = json!([
+            {"name": "sess", "value": "abc", "domain": ".oreilly.com"},
+            {"name": "edge", "value": "1", "domain": "cdn.example.net"}
+        ]);
+        let store = CookieStore::from_value(v).unwrap();
+        let hc = HttpClient::from_store(&store).unwrap();
+
+        assert_eq!(
+            hc.cookie_header_for("https://learning.oreilly.com/api/v1/book/1/")
+                .unwrap(),
+            "sess=abc"
+        );
+        assert_eq!(
+            hc.cookie_header_for("https://cdn.example.net/a.png")
+                .unwrap(),
+            "edge=1"
+        );
+        assert!(hc.cookie_header_for("not a url").is_err());
+    }
+
+    #[test]
+    fn base_url_rescopes_cookies_without_domain() {
+        let v = json!([
+            {"name": "sess", "value": "abc"},
+            {"name": "edge", "value": "1", "domain": "cdn.example.net"}
+        ]);
+        let store = CookieStore::from_value(v).unwrap();
+        let hc = HttpClient::from_store(&store)
+            .unwrap()
+            .with_base_url("https://learning-oreilly-com.proxy.example.edu/")
+            .unwrap();
+
+        assert_eq!(
+            hc.base_url(),
+            "https://learning-oreilly-com.proxy.example.edu"
+        );
+        assert_eq!(
+            hc.cookie_header_for("https://learning-oreilly-com.proxy.example.edu/profile/")
+                .unwrap(),
+            "sess=abc"
+        );
+        assert_eq!(
+            hc.cookie_header_for("https://learning.oreilly.com/profile/")
+                .unwrap(),
+            ""
+        );
+
+        let hc = HttpClient::from_store(&store).unwrap();
+        assert!(hc.with_base_url("ftp://example.com").is_err());
+    }
+
+    #[test]
+    fn picks_the_user_agent() {
+        assert_eq!(user_agent(None), DEFAULT_USER_AGENT);
+        assert_eq!(user_agent(Some(" ")), DEFAULT_USER_AGENT);
+        assert_eq!(user_agent(Some("MyReader/1.0")), "MyReader/1.0");
+        assert!(USER_AGENTS.contains(&user_agent(Some("Random"))));
+
+        let store = CookieStore::default();
+        let hc = HttpClient::from_store(&store).unwrap();
+        assert!(hc.with_user_agent("bad\nagent").is_err());
+    }
+
+    #[test]
+    fn trusts_extra_certificates() {
+        const PEM: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBhDCCASugAwIBAgIUBQ63NHLaEp/hbbexhGPGrRLm9scwCgYIKoZIzj0EAwIw
+GDEWMBQGA1UEAwwNVGVzdCBQcm94eSBDQTAeFw0yNjEwMTUxMTE5MTlaFw0zNjEw
+MTIxMTE5MTlaMBgxFjAUBgNVBAMMDVRlc3QgUHJveHkgQ0EwWTATBgcqhkjOPQIB
+BggqhkjOPQMBBwNCAATunZcjAG5pTW2LzLY2xKQAz4DshD29b+TlfQc10jWwuCXs
+5NyKqn8ZmoAtOX9ln0VTncjkS1EpVaVX1DW7+H0Wo1MwUTAdBgNVHQ4EFgQUmK4G
+GTopTrvqLLJtg3Xnu1Rbss8wHwYDVR0jBBgwFoAUmK4GGTopTrvqLLJtg3Xnu1Rb
+ss8wDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiASOnmrrW9UbmE+
+MU9NneoHcTETNROVS0koRsoyrkOcNgIgM0mZ8u9tFOaYLSsIlWW2MdxXQA6XedZB
+j2ALQCZtmvA=
+-----END CERTIFICATE-----
+";
+        let dir = std::env::temp_dir().join(format!("safaribooks-ca-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (good, bad) = (dir.join("proxy.pem"), dir.join("empty.pem"));
+        fs::write(&good, PEM).unwrap();
+        fs::write(&bad, "not a certificate").unwrap();
+
+        let store = CookieStore::default();
+        let hc = HttpClient::from_store(&store).unwrap();
+        let hc = hc.with_ca_cert(&good).unwrap();
+        assert_eq!(hc.transport.ca_certs.len(), 1);
+        let hc = hc.with_insecure(true).unwrap();
+        assert!(hc.transport.insecure);
+        assert!(hc.with_ca_cert(&bad).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_resolve_overrides() {
+        let v4: IpAddr = "203.0.113.7".parse().unwrap();
+        let v6: IpAddr = "2001:db8::7".parse().unwrap();
+        for (entry, host, ip) in [
+            (
+                "cdn.oreillystatic.com:203.0.113.7",
+                "cdn.oreillystatic.com",
+                v4,
+            ),
+            (
+                "Learning.OReilly.com:443:203.0.113.7",
+                "learning.oreilly.com",
+                v4,
+            ),
+            (
+                "cdn.oreillystatic.com:2001:db8::7",
+                "cdn.oreillystatic.com",
+                v6,
+            ),
+            (
+                "cdn.oreillystatic.com:443:[2001:db8::7]",
+                "cdn.oreillystatic.com",
+                v6,
+            ),
+        ] {
+            assert_eq!(
+                parse_resolve(entry).unwrap(),
+                (host.to_string(), ip),
+                "{entry}"
+            );
+        }
+        for bad in [
+            "cdn.oreillystatic.com",
+            ":203.0.113.7",
+            "host:port:203.0.113.7",
+            "host:nowhere",
+        ] {
+            assert!(parse_resolve(bad).is_err(), "{bad}");
+        }
+
+        let hc = HttpClient::from_store(&CookieStore::default()).unwrap();
+        let hc = hc
+            .with_resolve(&["cdn.oreillystatic.com:203.0.113.7".to_string()])
+            .and_then(|c| c.with_ip_family(IpFamily::Ipv4))
+            .unwrap();
+        assert_eq!(hc.transport.resolve.len(), 1);
+        assert_eq!(hc.transport.ip_family, IpFamily::Ipv4);
+    }
+
+    #[test]
+    fn parses_extra_headers() {
+        let (name, value) = parse_header("X-Institution-Token:  abc 123 ").unwrap();
+        assert_eq!(name, "x-institution-token");
+        assert_eq!(value, "abc 123");
+        assert!(parse_header("no colon").is_err());
+        assert!(parse_header("Bad Name: x").is_err());
+
+        let extra = HeaderMap::from_iter([
+            (ACCEPT, HeaderValue::from_static("*/*")),
+            (
+                HeaderName::from_static("x-a"),
+                HeaderValue::from_static("1"),
+            ),
+        ]);
+        let transport = Transport {
+            extra_headers: extra,
+            ..Default::default()
+        };
+        let headers = HttpClient::build_default_headers(DEFAULT_BASE_URL, &transport);
+        assert_eq!(headers[ACCEPT], "*/*");
+        assert_eq!(headers["x-a"], "1");
+        assert_eq!(headers[USER_AGENT], DEFAULT_USER_AGENT);
+    }
+
+    #[test]
+    fn debug_log_redacts_secrets() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_static("orm-jwt=secret.jwt; groot_sessionid=sid"),
+        );
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret.jwt"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(USER_AGENT, HeaderValue::from_static("not logged"));
+        headers.append(
+            SET_COOKIE,
+            HeaderValue::from_static("orm-jwt=new.secret; Domain=.oreilly.com; Path=/"),
+        );
+
+        let logged = loggable_headers(&headers);
+        assert!(!logged.contains("secret"), "{logged}");
+        assert!(!logged.contains("=sid"));
+        assert!(logged.contains("cookie: orm-jwt=<redacted>; groot_sessionid=<redacted>"));
+        assert!(logged.contains("authorization: Bearer <redacted>"));
+        assert!(logged.contains("set-cookie: orm-jwt=<redacted>; Domain=.oreilly.com; Path=/"));
+        assert!(logged.contains("content-type: application/json"));
+        assert!(!logged.contains("user-agent"));
+    }
+
+    #[test]
+    fn detects_logged_out_responses() {
+        let url = |u: &str| Url::parse(u).unwrap();
+        assert!(is_logged_out(
+            401,
+            &url("https://learning.oreilly.com/api/v1/book/1/")
+        ));
+        assert!(is_logged_out(
+            200,
+            &url("https://www.oreilly.com/member/login/?next=/library/")
+        ));
+        assert!(!is_logged_out(
+            200,
+            &url("https://learning.oreilly.com/api/v1/book/1/")
+        ));
+        assert!(!is_logged_out(
+            403,
+            &url("https://learning.oreilly.com/api/v1/book/1/")
+        ));
+    }
+
+    #[test]
+    fn bearer_token_goes_to_site_hosts_only() {
+        let v = json!({ "orm-jwt": "tok", "groot_sessionid": "sid" });
+        let store = CookieStore::from_value(v).unwrap();
+        let hc = HttpClient::from_store(&store)
+            .unwrap()
+            .with_auth(AuthMode::Bearer);
+
+        let auth = |url: &str| {
+            let req = hc
+                .authorize(hc.client.get(url), url)
+                .unwrap()
+                .build()
+                .unwrap();
+            req.headers()
+                .get(AUTHORIZATION)
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(
+            auth("https://learning.oreilly.com/api/v1/book/1/").as_deref(),
+            Some("Bearer tok")
+        );
+        assert_eq!(
+            auth("https://api.oreilly.com/x").as_deref(),
+            Some("Bearer tok")
+        );
+        assert_eq!(auth("https://cdn.example.net/a.png"), None);
+
+        let hc = HttpClient::from_store(&store).unwrap();
+        let req = hc
+            .authorize(
+                hc.client.get("https://learning.oreilly.com/"),
+                "https://learning.oreilly.com/",
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(req.headers().get(AUTHORIZATION).is_none());
+    }
+
+    #[test]
+    fn cookies_go_to_site_hosts_only() {
+        let v = json!([
+            {"name": "sess", "value": "abc", "domain": ".oreilly.com"},
+            {"name": "edge", "value": "1", "domain": "cdn.example.net"}
+        ]);
+        let store = CookieStore::from_value(v).unwrap();
+        let hc = HttpClient::from_store(&store).unwrap();
+
+        let cookie = |url: &str| {
+            let req = hc
+                .authorize(hc.client.get(url), url)
+                .unwrap()
+                .build()
+                .unwrap();
+            req.headers()
+                .get(COOKIE)
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(
+            cookie("https://learning.oreilly.com/api/v1/book/1/").as_deref(),
+            Some("sess=abc")
+        );
+        assert_eq!(
+            cookie("https://api.oreilly.com/x").as_deref(),
+            Some("sess=abc")
+        );
+        assert_eq!(cookie("https://cdn.example.net/a.png"), None);
+    }
+
+    #[tokio::test]
+    async fn sends_the_referring_page() {
+        use axum::{http::HeaderMap, routing::get, Router};
+
+        let echo = |headers: HeaderMap| async move {
+            headers
+                .get(REFERER)
+                .map(|v| v.to_str().unwrap().to_string())
+                .unwrap_or_default()
+        };
+        let app = Router::new().route("/asset", get(echo));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/asset", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let hc = HttpClient::from_store(&CookieStore::default()).unwrap();
+        let page = "https://learning.oreilly.com/library/view/x/123/ch01.html";
+        assert_eq!(
+            hc.get(&url).await.unwrap().text(),
+            "https://learning.oreilly.com/home/"
+        );
+        assert_eq!(hc.get_from(&url, page).await.unwrap().text(), page);
+
+        let hc = hc
+            .with_headers(&["Referer: https://example.edu/".into()])
+            .unwrap();
+        assert_eq!(
+            hc.get_from(&url, page).await.unwrap().text(),
+            "https://example.edu/"
+        );
+    }
+
+    /// Serve `app` on a free local port; returns its base URL.
+    async fn mock_site(app: axum::Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        base
+    }
+
+    fn has_cookie(headers: &axum::http::HeaderMap, cookie: &str) -> bool {
+        headers
+            .get(COOKIE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.split("; ").any(|c| c == cookie))
+    }
+
+    #[tokio::test]
+    async fn login_check_sees_the_redirect_to_the_login_page() {
+        use crate::orly::{check_login, LoginStatus};
+        use axum::{http::HeaderMap, response::Redirect, routing::get, Json, Router};
+
+        let profile = |headers: HeaderMap| async move {
+            if has_cookie(&headers, "orm-jwt=good") {
+                Ok("<html>Profile</html>")
+            } else {
+                Err(Redirect::to("/member/login/?next=/profile/"))
+            }
+        };
+        let app = Router::new()
+            .route("/profile/", get(profile))
+            .route("/member/login/", get(|| async { "<form>Sign in</form>" }))
+            .route(
+                "/api/v2/me/",
+                get(|| async { Json(json!({ "email": "reader@example.com" })) }),
+            );
+        let base = mock_site(app).await;
+
+        let hc = HttpClient::from_store(&CookieStore::default())
+            .unwrap()
+            .with_base_url(&base)
+            .unwrap();
+        assert!(matches!(
+            check_login(&hc).await.unwrap(),
+            LoginStatus::LoggedOut
+        ));
+
+        let store = CookieStore::from_value(json!({ "orm-jwt": "good" })).unwrap();
+        let hc = HttpClient::from_store(&store)
+            .unwrap()
+            .with_base_url(&base)
+            .unwrap();
+        assert!(matches!(
+            check_login(&hc).await.unwrap(),
+            LoginStatus::LoggedIn(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn waits_out_retry_after_and_follows_chapter_pages() {
+        use crate::orly::{fetch_chapters, BookInfo};
+        use axum::{extract::RawQuery, http::StatusCode, response::IntoResponse, routing::get};
+        use std::sync::atomic::AtomicUsize;
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let chapters = move |RawQuery(query): RawQuery, headers: axum::http::HeaderMap| {
+            let hit = counter.fetch_add(1, Ordering::SeqCst);
+            // The API's "next" links are absolute.
+            let host = headers[axum::http::header::HOST]
+                .to_str()
+                .unwrap()
+                .to_string();
+            async move {
+                let chapter = |name: &str| json!({ "title": name, "filename": format!("{name}.html"), "content": "" });
+                match (hit, query.as_deref()) {
+                    (0, _) => {
+                        (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, "1")], "").into_response()
+                    }
+                    (_, None) => axum::Json(json!({
+                        "next": format!("http://{host}/api/v1/book/1/chapter/?page=2"),
+                        "results": [chapter("ch01"), chapter("ch02")]
+                    }))
+                    .into_response(),
+                    (_, Some(_)) => axum::Json(json!({
+                        "next": null,
+                        "results": [chapter("ch03")]
+                    }))
+                    .into_response(),
+                }
+            }
+        };
+        let app = axum::Router::new().route("/api/v1/book/1/chapter/", get(chapters));
+        let base = mock_site(app).await;
+
+        let hc = HttpClient::from_store(&CookieStore::default())
+            .unwrap()
+            .with_base_url(&base)
+            .unwrap();
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "Paged",
+            "web_url": format!("{base}/library/view/paged/1/"),
+            "chapters": format!("{base}/api/v1/book/1/chapter/")
+        }))
+        .unwrap();
+        let started = Instant::now();
+        let chapters = fetch_chapters(&hc, &info, "1").await.unwrap();
+        let names: Vec<_> = chapters.iter().map(|c| c.title.as_str()).collect();
+        assert_eq!(names, ["ch01", "ch02", "ch03"]);
+        // The throttled request, its retry and the second page.
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn pauses_for_new_cookies_when_the_session_expires() {
+        use axum::{http::HeaderMap, http::StatusCode, routing::get, routing::post, Router};
+        use std::sync::atomic::AtomicUsize;
+
+        let book = |headers: HeaderMap| async move {
+            if has_cookie(&headers, "orm-jwt=fresh") {
+                Ok(r#"{"title": "Resumed"}"#)
+            } else {
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        };
+        let app = Router::new()
+            .route("/api/v1/book/1", get(book))
+            .route("/api/v1/book/2", get(book))
+            // The refresh cookie has expired too.
+            .route(
+                "/member/auth/refresh/",
+                post(|| async { StatusCode::FORBIDDEN }),
+            );
+        let base = mock_site(app).await;
+
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        let reauth: Reauth = Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            // As if the user took a moment to paste new cookies.
+            std::thread::sleep(Duration::from_millis(100));
+            Some(CookieStore::from_value(json!({ "orm-jwt": "fresh" })).unwrap())
+        });
+        let store = CookieStore::from_value(json!({ "orm-jwt": "stale" })).unwrap();
+        let hc = HttpClient::from_store(&store)
+            .unwrap()
+            .with_base_url(&base)
+            .unwrap()
+            .with_reauth(reauth);
+
+        let (one, two) = (
+            format!("{base}/api/v1/book/1"),
+            format!("{base}/api/v1/book/2"),
+        );
+        let (one, two) = tokio::join!(hc.get(&one), hc.get(&two));
+        for res in [one.unwrap(), two.unwrap()] {
+            assert_eq!(res.status, 200);
+            assert!(res.text().contains("Resumed"));
+        }
+        // Both requests waited for the one prompt.
+        assert_eq!(asked.load(Ordering::SeqCst), 1);
+    }
 }
@@ -0,0 +1,230 @@
+//! Console messages in the user's language: Fluent resources compiled in
+//! from `locales/`, the language picked with `--lang` or from LC_ALL,
+//! LC_MESSAGES or LANG. English stands in for anything a translation lacks.
+
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use std::sync::OnceLock;
+use unic_langid::LanguageIdentifier;
+
+/// Shipped languages and their messages, English first.
+const LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.ftl")),
+    ("es", include_str!("../locales/es.ftl")),
+];
+
+type Bundle = FluentBundle<FluentResource>;
+
+/// The chosen language's messages, then English's.
+static BUNDLES: OnceLock<Vec<Bundle>> = OnceLock::new();
+
+/// English's messages, for those before `init`: an argument clap rejects
+/// is reported before the language is known, and must not settle it.
+static ENGLISH: OnceLock<Vec<Bundle>> = OnceLock::new();
+
+/// `t!("id")` or `t!("id", name = value, ...)`: the message `id` in the
+/// user's language, with its `$name` variables filled in.
+#[macro_export]
+macro_rules! t {
+    ($id:literal) => {
+        $crate::i18n::tr($id, None)
+    };
+    ($id:literal, $($name:ident = $value:expr),+ $(,)?) => {{
+        let mut args = fluent_bundle::FluentArgs::new();
+        $(args.set(stringify!($name), $value);)+
+        $crate::i18n::tr($id, Some(&args))
+    }};
+}
+pub use t;
+
+/// The shipped language for a tag such as "es", "es-MX" or the
+/// environment's "es_ES.UTF-8".
+fn shipped(tag: &str) -> Option<&'static str> {
+    let tag = tag.split(['.', '@']).next()?.replace('_', "-");
+    let lang: LanguageIdentifier = tag.parse().ok()?;
+    LOCALES
+        .iter()
+        .map(|(code, _)| *code)
+        .find(|code| *code == lang.language.as_str())
+}
+
+/// `--lang`: one of the shipped languages.
+pub fn parse_lang(tag: &str) -> Result<String, String> {
+    shipped(tag).map(String::from).ok_or_else(|| {
+        let codes: Vec<_> = LOCALES.iter().map(|(code, _)| *code).collect();
+        format!(
+            "no translation for {tag:?}; available: {}",
+            codes.join(", ")
+        )
+    })
+}
+
+/// The language the environment asks for, as POSIX looks it up (LC_ALL,
+/// then LC_MESSAGES, then LANG), when it is one we ship.
+fn from_env(get: impl Fn(&str) -> Option<String>) -> Option<&'static str> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .into_iter()
+        .find_map(|name| get(name).filter(|v| !v.is_empty()))
+        .and_then(|tag| shipped(&tag))
+}
+
+/// `lang`'s messages, then English's when `lang` is another language.
+fn bundles(lang: &str) -> Vec<Bundle> {
+    let fallback = LOCALES.iter().take(1).filter(|(code, _)| *code != lang);
+    LOCALES
+        .iter()
+        .filter(|(code, _)| *code == lang)
+        .chain(fallback)
+        .map(|(code, source)| {
+            let mut bundle = Bundle::new_concurrent(vec![code.parse().expect("valid language")]);
+            // Unicode isolation marks would only clutter a terminal.
+            bundle.set_use_isolating(false);
+            let resource = FluentResource::try_new(source.to_string())
+                .unwrap_or_else(|(_, errors)| panic!("locales/{code}.ftl: {errors:?}"));
+            bundle
+                .add_resource(resource)
+                .unwrap_or_else(|errors| panic!("locales/{code}.ftl: {errors:?}"));
+            bundle
+        })
+        .collect()
+}
+
+/// Use `lang` (`--lang`), or else the environment's language, for the rest
+/// of the run. Messages before this, and in tests, are in English.
+pub fn init(lang: Option<&str>) {
+    let lang = lang
+        .and_then(shipped)
+        .or_else(|| from_env(|name| std::env::var(name).ok()))
+        .unwrap_or("en");
+    let _ = BUNDLES.set(bundles(lang));
+}
+
+fn format(bundles: &[Bundle], id: &str, args: Option<&FluentArgs>) -> String {
+    for bundle in bundles {
+        if let Some(pattern) = bundle.get_message(id).and_then(|m| m.value()) {
+            let mut errors = Vec::new();
+            return bundle
+                .format_pattern(pattern, args, &mut errors)
+                .into_owned();
+        }
+    }
+    id.to_string()
+}
+
+/// The message `id`; see `t!`.
+pub fn tr(id: &str, args: Option<&FluentArgs>) -> String {
+    let bundles = BUNDLES
+        .get()
+        .unwrap_or_else(|| ENGLISH.get_or_init(|| bundles("en")));
+    format(bundles, id, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bundles, format, from_env, parse_lang, LOCALES};
+    use fluent_bundle::FluentArgs;
+    use std::collections::BTreeSet;
+
+    /// The messages a resource defines: its unindented "id = ..." lines.
+    fn ids(source: &str) -> BTreeSet<&str> {
+        source
+            .lines()
+            .filter(|line| line.starts_with(|c: char| c.is_ascii_lowercase()))
+            .filter_map(|line| line.split_once(" ="))
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    #[test]
+    fn every_locale_has_every_message() {
+        let english = ids(LOCALES[0].1);
+        for (code, source) in LOCALES {
+            assert_eq!(ids(source), english, "locales/{code}.ftl");
+        }
+
+        // And every message the code asks for exists.
+        let src = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+        for file in std::fs::read_dir(src).unwrap() {
+            let path = file.unwrap().path();
+            let text = std::fs::read_to_string(&path).unwrap();
+            for (at, call) in text.match_indices("t!(\"") {
+                let before = text[..at].chars().next_back();
+                if before.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                    continue; // format!(" and the like
+                }
+                let id = text[at + call.len()..].split('"').next().unwrap();
+                if id != "id" {
+                    assert!(english.contains(id), "{}: {id}", path.display());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn formats_in_the_chosen_language() {
+        let mut args = FluentArgs::new();
+        args.set("count", 1);
+        args.set("path", "notes.md");
+        let en = bundles("en");
+        let es = bundles("es");
+        assert_eq!(
+            format(&en, "annotations-written", Some(&args)),
+            "1 annotation written to notes.md"
+        );
+        assert_eq!(
+            format(&es, "annotations-written", Some(&args)),
+            "1 anotación escrita en notes.md"
+        );
+        args.set("count", 3);
+        assert_eq!(
+            format(&es, "annotations-written", Some(&args)),
+            "3 anotaciones escritas en notes.md"
+        );
+        assert_eq!(format(&es, "no-such-message", None), "no-such-message");
+
+        let mut args = FluentArgs::new();
+        for name in ["title", "authors", "publisher", "issued", "pages"] {
+            args.set(name, "x");
+        }
+        assert_eq!(
+            format(&en, "book-details", Some(&args)),
+            "Title: x\n Authors: x\n Publisher: x\n Issued: x\n Pages: x"
+        );
+
+        // Errors too, braces and all.
+        let mut args = FluentArgs::new();
+        args.set("name", "isbn13");
+        args.set("fields", "title, isbn");
+        assert_eq!(
+            format(&en, "template-unknown-field", Some(&args)),
+            "unknown field {isbn13} (available: title, isbn)"
+        );
+        args.set("name", "SAFARIBOOKS_JOBS");
+        assert_eq!(
+            format(&es, "env-not-number", Some(&args)),
+            "SAFARIBOOKS_JOBS debe ser un número"
+        );
+    }
+
+    #[test]
+    fn picks_the_language() {
+        assert_eq!(parse_lang("es-MX").as_deref(), Ok("es"));
+        assert!(parse_lang("xx").unwrap_err().contains("en, es"));
+
+        let env = |vars: &'static [(&str, &str)]| {
+            from_env(|name| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            })
+        };
+        assert_eq!(env(&[("LANG", "es_ES.UTF-8")]), Some("es"));
+        assert_eq!(
+            env(&[("LC_ALL", "en_US.UTF-8"), ("LANG", "es_ES.UTF-8")]),
+            Some("en")
+        );
+        assert_eq!(env(&[("LC_ALL", ""), ("LC_MESSAGES", "es_AR")]), Some("es"));
+        assert_eq!(env(&[("LANG", "C")]), None);
+        assert_eq!(env(&[("LANG", "fr_FR.UTF-8")]), None);
+    }
+}
@@ -0,0 +1,199 @@
+//! Downloads left by the original Python safaribooks.py: the book directory
+//! it was writing (see `--legacy-names` for its layout) is taken over, its
+//! chapters and images kept, and only the rest fetched.
+
+use crate::epub::EpubSkeleton;
+use crate::i18n::t;
+use crate::orly::Chapter;
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+/// In the head of every chapter page safaribooks.py writes.
+const PAGE_MARKERS: &[&str] = &[
+    "#sbo-rt-content *{text-indent:0pt!important;}",
+    "http://www.w3.org/MarkUp/SCHEMA/xhtml2.xsd",
+];
+
+fn is_python_page(xhtml: &str) -> bool {
+    PAGE_MARKERS.iter().any(|marker| xhtml.contains(marker))
+}
+
+/// How much of a page is read to find the markers, which are in its head.
+const HEAD_BYTES: u64 = 8 * 1024;
+
+/// Whether `dir` is a book directory safaribooks.py was writing, told by
+/// its layout: chapters first, then Styles/StyleNN.css, and content.opf and
+/// toc.ncx at the end but never a nav document or our Styles/layout.css.
+/// Until its stylesheets are in, the head of the first page decides.
+pub fn is_python_download(dir: &Path) -> bool {
+    let oebps = dir.join("OEBPS");
+    let Ok(entries) = fs::read_dir(&oebps) else {
+        return false;
+    };
+    let first = entries
+        .filter_map(|e| e.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "xhtml"))
+        .filter(|path| !path.ends_with("nav.xhtml"))
+        .min();
+    let Some(first) = first else {
+        return false;
+    };
+    if oebps.join("nav.xhtml").exists() || oebps.join("Styles/layout.css").exists() {
+        return false;
+    }
+    if oebps.join("content.opf").is_file() && oebps.join("toc.ncx").is_file() {
+        return true;
+    }
+    if has_numbered_styles(&oebps.join("Styles")) {
+        return true;
+    }
+    let Ok(file) = fs::File::open(first) else {
+        return false;
+    };
+    let mut head = Vec::new();
+    file.take(HEAD_BYTES).read_to_end(&mut head).is_ok()
+        && is_python_page(&String::from_utf8_lossy(&head))
+}
+
+/// Whether `styles` holds stylesheets named the way safaribooks.py numbered
+/// them: Style00.css, Style01.css and so on.
+fn has_numbered_styles(styles: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(styles) else {
+        return false;
+    };
+    entries.filter_map(|e| e.ok()).any(|entry| {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        name.strip_prefix("Style")
+            .and_then(|rest| rest.strip_suffix(".css"))
+            .is_some_and(|n| n.len() >= 2 && n.bytes().all(|b| b.is_ascii_digit()))
+    })
+}
+
+/// Whether safaribooks.py got as far as writing the EPUB.
+pub fn is_finished(skeleton: &EpubSkeleton) -> bool {
+    fs::metadata(&skeleton.epub).is_ok_and(|m| m.is_file() && m.len() > 0)
+}
+
+/// Make the directory ready to be resumed: its stylesheets are numbered
+/// in an order ours need not follow, so they are fetched again.
+pub fn prepare(skeleton: &EpubSkeleton) -> Result<()> {
+    let styles = skeleton.oebps.join("Styles");
+    let Ok(entries) = fs::read_dir(&styles) else {
+        return Ok(());
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("Style") && name.ends_with(".css") {
+            fs::remove_file(&path)
+                .with_context(|| t!("removing-file", path = path.display().to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+/// The content of `chapter` as safaribooks.py saved it at `href`, with its
+/// images pointing where the API has them again; None when it saved none.
+pub fn saved_chapter(skeleton: &EpubSkeleton, href: &str, chapter: &Chapter) -> Option<String> {
+    let page = fs::read_to_string(skeleton.oebps.join(href)).ok()?;
+    if !is_python_page(&page) {
+        return None;
+    }
+    let start = page.find("<body")?;
+    let start = start + page[start..].find('>')? + 1;
+    let end = page.rfind("</body>")?;
+    let mut content = page.get(start..end)?.to_string();
+    // It pointed every image at Images/<its file name>.
+    for image in &chapter.images {
+        let name = image.rsplit('/').next().unwrap_or(image);
+        content = content.replace(&format!("=\"Images/{name}\""), &format!("=\"{image}\""));
+    }
+    Some(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_python_download, prepare, saved_chapter};
+    use crate::epub::EpubSkeleton;
+    use crate::orly::Chapter;
+    use std::fs;
+
+    #[test]
+    fn takes_over_a_python_download() {
+        let root = std::env::temp_dir().join(format!("safaribooks-legacy-{}", std::process::id()));
+        let skeleton = EpubSkeleton {
+            meta_inf: root.join("META-INF"),
+            oebps: root.join("OEBPS"),
+            epub: root.join("123.epub"),
+            root: root.clone(),
+            language: "en".to_string(),
+            legacy_names: true,
+        };
+        fs::create_dir_all(skeleton.oebps.join("Styles")).unwrap();
+        assert!(!is_python_download(&root));
+
+        fs::write(
+            skeleton.oebps.join("ch01.xhtml"),
+            "<!DOCTYPE html>\n<html><head>\n<link href=\"Styles/Style00.css\" rel=\"stylesheet\" type=\"text/css\" />\n\
+             <style type=\"text/css\">body{margin:1em;background-color:transparent!important;}\
+             #sbo-rt-content *{text-indent:0pt!important;}</style>\n</head>\n\
+             <body><div id=\"sbo-rt-content\"><p>Saved</p><img src=\"Images/fig1.png\"/></div></body>\n</html>",
+        )
+        .unwrap();
+        fs::write(skeleton.oebps.join("Styles/Style00.css"), "p {}").unwrap();
+        assert!(is_python_download(&root));
+
+        prepare(&skeleton).unwrap();
+        assert!(!skeleton.oebps.join("Styles/Style00.css").exists());
+
+        let chapter: Chapter = serde_json::from_value(serde_json::json!({
+            "title": "One",
+            "filename": "ch01.html",
+            "content": "https://learning.oreilly.com/api/v1/book/123/chapter-content/ch01.html",
+            "images": ["assets/fig1.png"]
+        }))
+        .unwrap();
+        assert_eq!(
+            saved_chapter(&skeleton, "ch01.xhtml", &chapter).as_deref(),
+            Some("<div id=\"sbo-rt-content\"><p>Saved</p><img src=\"assets/fig1.png\"/></div>")
+        );
+        assert_eq!(saved_chapter(&skeleton, "ch02.xhtml", &chapter), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn tells_a_python_download_by_its_layout() {
+        let root =
+            std::env::temp_dir().join(format!("safaribooks-legacy-layout-{}", std::process::id()));
+        let oebps = root.join("OEBPS");
+        fs::create_dir_all(oebps.join("Styles")).unwrap();
+        // No markers in the page: only the layout can tell.
+        fs::write(
+            oebps.join("ch01.xhtml"),
+            "<html><body><p>One</p></body></html>",
+        )
+        .unwrap();
+        assert!(!is_python_download(&root));
+
+        fs::write(oebps.join("Styles/Style00.css"), "p {}").unwrap();
+        assert!(is_python_download(&root));
+
+        fs::remove_file(oebps.join("Styles/Style00.css")).unwrap();
+        fs::write(oebps.join("content.opf"), "<package/>").unwrap();
+        fs::write(oebps.join("toc.ncx"), "<ncx/>").unwrap();
+        assert!(is_python_download(&root));
+
+        // Ours with --legacy-names: numbered stylesheets too, but a nav
+        // document and our own stylesheet.
+        fs::write(oebps.join("Styles/Style00.css"), "p {}").unwrap();
+        fs::write(oebps.join("Styles/layout.css"), "").unwrap();
+        fs::write(oebps.join("nav.xhtml"), "<html/>").unwrap();
+        assert!(!is_python_download(&root));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
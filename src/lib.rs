@@ -0,0 +1,49 @@
+//! Download O'Reilly Learning books (and video course transcripts) as
+//! EPUBs. The `safaribooks-rs` binary is the command line over these
+//! modules; they are public so the benchmarks under `benches/` can drive
+//! the pipeline's stages directly.
+
+pub mod annotations;
+pub mod api;
+pub mod book;
+pub mod bookid;
+pub mod calibre;
+pub mod catalog;
+pub mod checksum;
+pub mod cli;
+pub mod code;
+pub mod config;
+pub mod cookies;
+pub mod display;
+pub mod download;
+pub mod epub;
+pub mod epubcheck;
+pub mod figures;
+pub mod format;
+pub mod hooks;
+pub mod html;
+pub mod http_client;
+pub mod i18n;
+pub mod legacy;
+pub mod library;
+pub mod logfile;
+pub mod mirror;
+pub mod notify;
+pub mod orly;
+pub mod package;
+pub mod partial;
+pub mod progress;
+pub mod recorder;
+pub mod secrets;
+pub mod series;
+pub mod serve;
+pub mod sidecar;
+pub mod split;
+pub mod state;
+pub mod svg;
+pub mod table;
+pub mod throttle;
+pub mod transcript;
+pub mod update;
+pub mod validate;
+pub mod watch;
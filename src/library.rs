@@ -0,0 +1,311 @@
+//! Books already in the output directory.
+
+use crate::html::{decode_entities, tokenize};
+use crate::i18n::t;
+use crate::orly::iso_datetime;
+use crate::state::Downloaded;
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use zip::ZipArchive;
+
+/// A downloaded book, described by its package document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LibraryBook {
+    pub path: PathBuf,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub identifiers: Vec<String>,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    pub description: Option<String>,
+    pub subjects: Vec<String>,
+    /// The calibre series and the book's position in it.
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    /// Zip entry and media type of the cover image, if the OPF names one.
+    pub cover: Option<(String, String)>,
+    pub modified: SystemTime,
+}
+
+impl LibraryBook {
+    /// Read the cover image out of the EPUB.
+    pub fn read_cover(&self) -> Result<Option<Vec<u8>>> {
+        let Some((entry, _)) = &self.cover else {
+            return Ok(None);
+        };
+        let file = File::open(&self.path)
+            .with_context(|| t!("opening-file", path = self.path.display().to_string()))?;
+        let mut zip = ZipArchive::new(file)?;
+        let mut entry = zip.by_name(entry)?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+impl LibraryBook {
+    /// The book as `list --downloaded` shows it when there is no download
+    /// state to go by.
+    pub fn to_downloaded(&self) -> Downloaded {
+        let isbn = self.identifiers.iter().find_map(|id| {
+            id.strip_prefix("urn:isbn:")
+                .or_else(|| id.strip_prefix("isbn:"))
+        });
+        Downloaded {
+            // Ours come first, as the plain book ID.
+            book_id: self
+                .identifiers
+                .first()
+                .filter(|id| !id.contains(':'))
+                .cloned(),
+            title: self.title.clone(),
+            isbn: isbn.map(str::to_string),
+            epub: self.path.clone(),
+            size: fs::metadata(&self.path).map_or(0, |m| m.len()),
+            downloaded: iso_datetime(self.modified),
+            early_release: false,
+            updated: None,
+        }
+    }
+}
+
+/// Every readable EPUB below `root`, sorted by path. Files that aren't
+/// EPUBs (or are damaged) are left out.
+pub fn scan(root: &Path) -> Vec<LibraryBook> {
+    epub_files(root)
+        .into_iter()
+        .filter_map(|path| read_book(&path).ok())
+        .collect()
+}
+
+/// Describe the EPUB at `path` from its package document.
+pub fn read_book(path: &Path) -> Result<LibraryBook> {
+    let (opf_path, opf) = package_document(path)?;
+    let text = |tag| elements(&opf, tag).into_iter().next();
+    let title = text("dc:title").unwrap_or_else(|| {
+        path.file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    // EPUB 3 marks the cover item; EPUB 2 names its id in <meta name="cover">.
+    let tokens = tokenize(&opf);
+    let cover_id = tokens
+        .iter()
+        .find(|t| t.is_start("meta") && t.attr("name") == Some("cover"))
+        .and_then(|t| t.attr("content"));
+    let cover = tokens
+        .iter()
+        .filter(|t| t.is_start("item"))
+        .find(|t| {
+            t.attr("properties")
+                .is_some_and(|p| p.split_whitespace().any(|p| p == "cover-image"))
+                || (cover_id.is_some() && t.attr("id") == cover_id)
+        })
+        .and_then(|t| {
+            let href = decode_entities(t.attr("href")?);
+            let dir = opf_path.rsplit_once('/').map_or("", |(dir, _)| dir);
+            let entry = if dir.is_empty() {
+                href
+            } else {
+                format!("{dir}/{href}")
+            };
+            Some((
+                entry,
+                t.attr("media-type").unwrap_or("image/jpeg").to_string(),
+            ))
+        });
+    let calibre = |name| {
+        tokens
+            .iter()
+            .find(|t| t.is_start("meta") && t.attr("name") == Some(name))
+            .and_then(|t| t.attr("content"))
+            .map(decode_entities)
+    };
+
+    Ok(LibraryBook {
+        path: path.to_path_buf(),
+        title,
+        authors: elements(&opf, "dc:creator"),
+        identifiers: elements(&opf, "dc:identifier"),
+        language: text("dc:language"),
+        publisher: text("dc:publisher"),
+        date: text("dc:date"),
+        description: text("dc:description"),
+        subjects: elements(&opf, "dc:subject"),
+        series: calibre("calibre:series"),
+        series_index: calibre("calibre:series_index").and_then(|i| i.parse().ok()),
+        cover,
+        modified: fs::metadata(path)?.modified()?,
+    })
+}
+
+/// EPUBs below `root` whose identifiers name this book: the bookid itself or
+/// its ISBN, however the file or its directory happens to be named.
+pub fn find_existing(root: &Path, bookid: &str, isbn: Option<&str>) -> Vec<PathBuf> {
+    let wanted: Vec<String> = [Some(bookid), isbn]
+        .into_iter()
+        .flatten()
+        .map(normalize_identifier)
+        .filter(|id| !id.is_empty())
+        .collect();
+    let mut found = Vec::new();
+    for path in epub_files(root) {
+        // Unreadable or foreign files are not ours to judge.
+        let Ok(ids) = epub_identifiers(&path) else {
+            continue;
+        };
+        if ids
+            .iter()
+            .any(|id| wanted.contains(&normalize_identifier(id)))
+        {
+            found.push(path);
+        }
+    }
+    found
+}
+
+/// All `*.epub` files below `dir`, sorted.
+fn epub_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("epub"))
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// The `dc:identifier` values in the package document of an EPUB.
+fn epub_identifiers(path: &Path) -> Result<Vec<String>> {
+    let (_, opf) = package_document(path)?;
+    Ok(elements(&opf, "dc:identifier"))
+}
+
+/// Zip path and text of the package document (OPF) of an EPUB.
+fn package_document(path: &Path) -> Result<(String, String)> {
+    let file =
+        File::open(path).with_context(|| t!("opening-file", path = path.display().to_string()))?;
+    let mut zip = ZipArchive::new(file)
+        .with_context(|| t!("reading-file", path = path.display().to_string()))?;
+    let container = read_entry(&mut zip, "META-INF/container.xml")?;
+    let opf_path =
+        attribute(&container, "full-path").with_context(|| t!("container-no-rootfile"))?;
+    let opf = read_entry(&mut zip, &opf_path)?;
+    Ok((opf_path, opf))
+}
+
+fn read_entry(zip: &mut ZipArchive<File>, name: &str) -> Result<String> {
+    let mut entry = zip
+        .by_name(name)
+        .with_context(|| t!("zip-entry-missing", name = name))?;
+    let mut text = String::new();
+    entry.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// Value of the first `name="..."` attribute in `xml`.
+fn attribute(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("{name}=\""))? + name.len() + 2;
+    let len = xml[start..].find('"')?;
+    Some(xml[start..start + len].to_string())
+}
+
+/// Text of every `<tag ...>text</tag>` element in `xml`, entities decoded.
+fn elements(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{tag}");
+    let close = format!("</{tag}>");
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(gt) = rest.find('>') else { break };
+        let Some(end) = rest.find(&close) else { break };
+        if gt < end {
+            values.push(decode_entities(rest[gt + 1..end].trim()));
+        }
+        rest = &rest[end..];
+    }
+    values
+}
+
+/// "urn:isbn:978-1-4919-5869-8" and "9781491958698" compare equal.
+fn normalize_identifier(id: &str) -> String {
+    let id = id.trim();
+    let id = id
+        .strip_prefix("urn:isbn:")
+        .or_else(|| id.strip_prefix("isbn:"))
+        .unwrap_or(id);
+    id.chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_existing, scan};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn write_epub(path: &std::path::Path, identifiers: &[&str]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        let opts = SimpleFileOptions::default();
+        zip.start_file("META-INF/container.xml", opts).unwrap();
+        zip.write_all(br#"<rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>"#)
+            .unwrap();
+        zip.start_file("OEBPS/content.opf", opts).unwrap();
+        for id in identifiers {
+            writeln!(zip, "<dc:identifier id=\"x\">{id}</dc:identifier>").unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn finds_books_by_id_or_isbn() {
+        let root = std::env::temp_dir().join(format!("safaribooks-library-{}", std::process::id()));
+        fs::create_dir_all(root.join("Someone/Old Name")).unwrap();
+        let by_isbn = root.join("Someone/Old Name/book.epub");
+        write_epub(&by_isbn, &["999", "urn:isbn:978-1-4919-5869-8"]);
+        let other = root.join("other.epub");
+        write_epub(&other, &["456"]);
+        fs::write(root.join("broken.epub"), b"not a zip").unwrap();
+
+        assert_eq!(
+            find_existing(&root, "9781491958698", None),
+            vec![by_isbn.clone()]
+        );
+        assert_eq!(
+            find_existing(&root, "123", Some("9781491958698")),
+            vec![by_isbn]
+        );
+        assert_eq!(find_existing(&root, "456", None), vec![other]);
+        assert!(find_existing(&root, "789", None).is_empty());
+
+        let listed: Vec<_> = scan(&root).iter().map(|b| b.to_downloaded()).collect();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].book_id.as_deref(), Some("999"));
+        assert_eq!(listed[0].isbn.as_deref(), Some("978-1-4919-5869-8"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+//! Size-capped log file: once it grows past a limit it is rotated to
+//! `<name>.1` (older ones shift to `.2`, ...), so long runs can't fill the disk.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Rotate once the current file reaches this size.
+pub const MAX_LOG_BYTES: u64 = 10 * 1024 * 1024;
+/// Rotated files kept next to the current one.
+pub const LOG_BACKUPS: usize = 2;
+
+pub struct RotatingLog {
+    path: PathBuf,
+    max_bytes: u64,
+    backups: usize,
+    /// Open file and its size; None once closed.
+    state: Mutex<Option<(File, u64)>>,
+}
+
+impl RotatingLog {
+    /// Create (truncate) the log at `path`, creating its directory.
+    pub fn create(path: &Path, max_bytes: u64, backups: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = File::create(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            backups,
+            state: Mutex::new(Some((file, 0))),
+        })
+    }
+
+    fn backup_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    /// Shift `.1` -> `.2` ... and move the current file to `.1`.
+    fn rotate(&self) -> io::Result<File> {
+        if self.backups == 0 {
+            return File::create(&self.path);
+        }
+        for n in (1..self.backups).rev() {
+            let from = self.backup_path(n);
+            if from.exists() {
+                fs::rename(&from, self.backup_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.backup_path(1))?;
+        File::create(&self.path)
+    }
+
+    /// Close the file and delete it with its rotated copies.
+    pub fn remove(&self) -> io::Result<()> {
+        self.state.lock().unwrap().take();
+        for n in 1..=self.backups {
+            let _ = fs::remove_file(self.backup_path(n));
+        }
+        fs::remove_file(&self.path)
+    }
+}
+
+impl Write for &RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let Some((file, size)) = state.as_mut() else {
+            // Closed: drop late events silently.
+            return Ok(buf.len());
+        };
+        if *size > 0 && *size + buf.len() as u64 > self.max_bytes {
+            *file = self.rotate()?;
+            *size = 0;
+        }
+        file.write_all(buf)?;
+        *size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.state.lock().unwrap().as_mut() {
+            Some((file, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Shares a `RotatingLog` between the tracing layer and its owner.
+#[derive(Clone)]
+pub struct LogWriter(pub Arc<RotatingLog>);
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = &'a RotatingLog;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RotatingLog;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn rotates_and_removes() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-log-{}", std::process::id()));
+        let path = dir.join("nested").join("info_1.log");
+        let log = RotatingLog::create(&path, 10, 2).unwrap();
+
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            (&log).write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddd\n");
+        assert_eq!(fs::read_to_string(log.backup_path(1)).unwrap(), "cccccc\n");
+        assert_eq!(fs::read_to_string(log.backup_path(2)).unwrap(), "bbbbbb\n");
+        assert!(!log.backup_path(3).exists());
+
+        log.remove().unwrap();
+        assert!(!path.exists() && !log.backup_path(1).exists());
+        // Writes after removal are dropped rather than recreating the file.
+        (&log).write_all(b"late\n").unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
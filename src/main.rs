@@ -1,88 +1,815 @@
-mod cli;
-mod config;
-mod cookies;
-mod display;
-mod epub;
-mod http_client;
-mod orly;
-
 use clap::Parser;
-use cli::Args;
+use cli::{Args, Command};
+use config::{Naming, Settings};
 use cookies::CookieStore;
-use display::Display;
-use epub::EpubSkeleton;
-use http_client::HttpClient;
-use orly::{check_login, fetch_book_info};
+use display::{ColorChoice, Display, DisplayBuilder, Verbosity};
+use download::{Downloader, ExtrasOnly};
+use epub::MetadataOverrides;
+use http_client::{HttpClient, Reauth};
+use i18n::t;
+use orly::{check_login, AccountInfo, LoginStatus};
+use recorder::Fixtures;
+use safaribooks_rs::{
+    annotations, book, catalog, checksum, cli, config, cookies, display, download, epub,
+    http_client, i18n, library, orly, recorder, secrets, serve, state, update, watch,
+};
+use state::StateDb;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let mut ui = Display::new(&args.bookid);
+    i18n::init(args.lang.as_deref());
 
-    let cookies_path = config::cookies_file();
-    if !cookies_path.exists() {
-        ui.error_and_exit(
-            "cookies.json not found.\n\
-             This version requires an existing authenticated session.",
-        );
+    // Settings come first: they decide where the log goes.
+    let settings = match Settings::load(&args) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", t!("invalid-settings", error = format!("{e:#}")));
+            std::process::exit(1);
+        }
+    };
+    let naming = match settings.naming() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("{}", t!("invalid-settings", error = format!("{e:#}")));
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(command) = &args.command {
+        // Only the server runs long enough to want a log file.
+        let log_file = match command {
+            Command::Serve { .. } => Some(settings.log_file("serve")),
+            Command::Watch { .. } => Some(settings.log_file("watch")),
+            _ => None,
+        };
+        let (ui, _log_guard) = match display_for(&args, "").log_file(log_file).init() {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("{e:#}");
+                std::process::exit(1);
+            }
+        };
+        match command {
+            Command::Verify { path } => verify_command(&ui, path),
+            Command::Catalog => catalog_command(&ui, &settings.output_dir()),
+            Command::Rebuild { dir } => {
+                let metadata = metadata_overrides(&args, &settings);
+                let state = open_state(&ui);
+                let rebuilt =
+                    download::rebuild(&ui, &settings, state.as_ref(), &naming, &metadata, dir);
+                match rebuilt.await {
+                    Ok(epub) => ui.info(&t!("done", path = epub.display().to_string())),
+                    Err(e) => ui.error_and_exit(&format!("{e:#}")),
+                }
+            }
+            Command::Chapters { bookid, json } => {
+                let client = connect(&ui, &args, &settings).await;
+                chapters_command(&ui, &client, bookid, *json, settings.jobs()).await;
+            }
+            Command::Annotations {
+                bookid,
+                format,
+                output,
+            } => {
+                let client = connect(&ui, &args, &settings).await;
+                annotations_command(&ui, &client, bookid, *format, output.as_deref()).await;
+            }
+            Command::List {
+                history,
+                playlists,
+                downloaded,
+            } => {
+                if *downloaded {
+                    downloaded_command(&ui, &args, &settings).await;
+                }
+                if *history || *playlists {
+                    let client = connect(&ui, &args, &settings).await;
+                    list_command(&ui, &client, *history, *playlists).await;
+                }
+            }
+            Command::Serve { listen } => {
+                let downloader = queue_downloader(&ui, &args, settings, naming).await;
+                if let Err(e) = serve::run(*listen, &downloader, &ui).await {
+                    ui.error_and_exit(&format!("{e:#}"));
+                }
+            }
+            Command::Watch { path, interval } => {
+                let downloader = queue_downloader(&ui, &args, settings, naming).await;
+                let source = watch::QueueSource::new(path);
+                let interval = std::time::Duration::from_secs((*interval).max(1));
+                if let Err(e) = watch::run(&source, &downloader, &ui, interval).await {
+                    ui.error_and_exit(&format!("{e:#}"));
+                }
+            }
+            Command::SelfUpdate { check } => match update::run(*check).await {
+                Ok(update::Update::UpToDate(v)) => ui.info(&t!("update-none", version = v)),
+                Ok(update::Update::Available(v)) => ui.info(&t!("update-available", version = v)),
+                Ok(update::Update::Installed(v)) => ui.info(&t!("update-installed", version = v)),
+                Err(e) => ui.error_and_exit(&t!("update-failed", error = format!("{e:#}"))),
+            },
+        }
+        return;
     }
+    let given: Vec<String> = args.bookids.iter().map(|b| b.id.clone()).collect();
+    let (bookids, duplicates) = download::dedup_queue(&given);
+    // One log per book; several books share one for the run.
+    let log_name = match bookids.as_slice() {
+        [bookid] => bookid.as_str(),
+        _ => "batch",
+    };
+    let (mut ui, _log_guard) = match display_for(&args, log_name)
+        .log_file(Some(settings.log_file(log_name)))
+        .init()
+    {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    };
 
-    // Load cookies
-    let store = match CookieStore::load_from(&cookies_path) {
-        Ok(c) => c,
-        Err(e) => ui.error_and_exit(&format!("Failed to read cookies.json: {e}")),
+    if !duplicates.is_empty() {
+        ui.warn(&t!(
+            "duplicate-books",
+            count = duplicates.len(),
+            books = duplicates.join(", ")
+        ));
+    }
+    if settings.base_url() != orly::DEFAULT_BASE_URL {
+        ui.info(&t!("using-site", url = settings.base_url()));
+    }
+    if let Some(profile) = &settings.profile {
+        ui.info(&t!("using-profile", profile = profile.as_str()));
+    }
+
+    let metadata = metadata_overrides(&args, &settings);
+    if bookids.len() > 1 && metadata.names_values() {
+        ui.error_and_exit(&t!("metadata-needs-one-book"));
+    }
+    if bookids.len() > 1 && args.output.is_some() {
+        ui.error_and_exit(&t!("output-needs-one-book"));
+    }
+    if args.output.as_deref() == Some(Path::new(download::STDOUT))
+        && settings.split_parts().is_some()
+    {
+        ui.error_and_exit(&t!("split-parts-to-stdout"));
+    }
+
+    let client = connect(&ui, &args, &settings).await;
+    let downloader = Downloader {
+        client,
+        settings,
+        naming,
+        metadata,
+        calibre: args.add_to_calibre.clone(),
+        assume_yes: args.yes,
+        state: open_state(&ui),
+        output: args.output.clone(),
+        cancel: cancel_on_ctrl_c(&ui),
+        only: ExtrasOnly {
+            cover: args.cover_only,
+            metadata: args.metadata_only,
+        },
     };
+    if let [bookid] = bookids.as_slice() {
+        match downloader.download(&mut ui, bookid).await {
+            Ok(_) => ui.finish_log(downloader.settings.preserve_log()),
+            Err(e) => {
+                suggest_books(&ui, &downloader.client, &args, bookid, &e).await;
+                ui.error_and_exit(&format!("{e:#}"))
+            }
+        }
+        return;
+    }
 
-    if store.is_empty() {
-        ui.error_and_exit("cookies.json is valid JSON but contains no cookies.");
+    let parallel = downloader.settings.parallel_books();
+    let results = downloader.download_all(&ui, &bookids, parallel).await;
+    if downloader.cancel.is_cancelled() {
+        ui.error_and_exit(&t!("download-cancelled"));
     }
+    let mut failed = Vec::new();
+    for (bookid, result) in &results {
+        if let Err(e) = result {
+            suggest_books(&ui, &downloader.client, &args, bookid, e).await;
+            failed.push(bookid.as_str());
+        }
+    }
+    if failed.is_empty() {
+        ui.info(&t!("all-books-downloaded", count = results.len()));
+        ui.finish_log(downloader.settings.preserve_log());
+    } else {
+        ui.error_and_exit(&t!(
+            "books-failed",
+            failed = failed.len(),
+            count = results.len(),
+            books = failed.join(", ")
+        ));
+    }
+}
 
-    let names = store.cookie_names();
-    ui.info(&format!(
-        "Loaded {} cookies: {}",
-        store.len(),
-        names.join(", ")
-    ));
+/// When `bookid` failed for not existing, the catalog's best matches for
+/// the title in its URL (or for the ID itself).
+async fn suggest_books(
+    ui: &Display,
+    client: &HttpClient,
+    args: &Args,
+    bookid: &str,
+    error: &anyhow::Error,
+) {
+    const SUGGESTIONS: usize = 5;
+    let Some(book) = args.bookids.iter().find(|b| b.id == bookid) else {
+        return;
+    };
+    if error.downcast_ref::<orly::BookNotFound>().is_none() {
+        return;
+    }
+    let found = match orly::search(client, &book.search_terms(), SUGGESTIONS).await {
+        Ok(found) => found,
+        Err(e) => {
+            ui.warn(&t!("search-failed", error = format!("{e:#}")));
+            return;
+        }
+    };
+    let found: Vec<String> = found
+        .iter()
+        .filter_map(|item| Some(format!("{}  {}", item.id()?, item.title.as_deref()?)))
+        .collect();
+    if found.is_empty() {
+        return;
+    }
+    ui.info(&t!("book-suggestions", book = bookid));
+    for line in found {
+        ui.info(&format!("  {line}"));
+    }
+}
 
+/// The `--set-*` corrections to the book's metadata.
+fn metadata_overrides(args: &Args, settings: &Settings) -> MetadataOverrides {
+    MetadataOverrides {
+        title: args.set_title.clone(),
+        authors: args.set_author.clone(),
+        series: args.series.clone(),
+        series_index: args.series_index,
+        series_from_title: settings.series_from_title(),
+        tags: args.tags.clone(),
+    }
+}
+
+/// The downloader of the long-running modes, which take book IDs as they come.
+async fn queue_downloader(
+    ui: &Display,
+    args: &Args,
+    settings: Settings,
+    naming: Naming,
+) -> Downloader {
+    let client = connect(ui, args, &settings).await;
+    // Overrides describe one book; queued books keep their own metadata.
+    let metadata = MetadataOverrides {
+        series_from_title: settings.series_from_title(),
+        ..MetadataOverrides::default()
+    };
+    Downloader {
+        client,
+        settings,
+        naming,
+        metadata,
+        calibre: args.add_to_calibre.clone(),
+        // Queued books were asked for explicitly, and nobody is there to answer.
+        assume_yes: true,
+        state: open_state(ui),
+        output: None,
+        // Ctrl-C stops the server or watcher itself.
+        cancel: CancellationToken::new(),
+        only: ExtrasOnly::default(),
+    }
+}
+
+/// A token cancelled by the first Ctrl-C, so downloads stop with their
+/// progress saved; a second one quits at once.
+fn cancel_on_ctrl_c(ui: &Display) -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    let ui = ui.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        ui.warn(&t!("cancelling"));
+        token.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    cancel
+}
+
+/// The download-state database; without it downloads still work, they
+/// just aren't recorded.
+fn open_state(ui: &Display) -> Option<StateDb> {
+    let path = config::state_db_path();
+    match StateDb::open(&path) {
+        Ok(db) => Some(db),
+        Err(e) => {
+            ui.warn(&t!("state-unavailable", error = format!("{e:#}")));
+            None
+        }
+    }
+}
+
+/// Load the session and check it is logged in; exits when it isn't.
+async fn connect(ui: &Display, args: &Args, settings: &Settings) -> HttpClient {
+    // Replaying recorded responses needs no session at all.
+    let (store, source) = if args.replay.is_some() {
+        (CookieStore::default(), None)
+    } else {
+        let (store, source) = load_cookies(ui, settings);
+        (store, Some(source))
+    };
+
+    if settings.insecure() {
+        ui.warn(&t!("insecure-warning"));
+    }
     // Build the HTTP client with our cookies (no network calls yet).
-    let client = match HttpClient::from_store(&store) {
+    let mut client = match HttpClient::from_store(&store)
+        .and_then(|c| c.with_base_url(settings.base_url()))
+        .and_then(|c| c.with_user_agent(http_client::user_agent(settings.user_agent.as_deref())))
+        .and_then(|c| c.with_headers(settings.headers.as_deref().unwrap_or_default()))
+        .and_then(|c| match &settings.ca_cert {
+            Some(pem) => c.with_ca_cert(pem),
+            None => Ok(c),
+        })
+        .and_then(|c| c.with_insecure(settings.insecure()))
+        .and_then(|c| c.with_resolve(settings.resolve.as_deref().unwrap_or_default()))
+        .and_then(|c| c.with_ip_family(settings.ip_family.unwrap_or_default()))
+        .map(|c| {
+            c.with_auth(settings.auth())
+                .with_jobs(settings.jobs())
+                .with_stealth(settings.stealth())
+                .with_degrade_above(settings.degrade_above())
+                .with_rate_limit(settings.limit_rate)
+        }) {
         Ok(c) => c,
-        Err(e) => ui.error_and_exit(&format!("Failed to build HTTP client: {e}")),
+        Err(e) => ui.error_and_exit(&t!("http-client-failed", error = e.to_string())),
     };
-    ui.info("HTTP client initialized with cookies (no requests performed).");
+    if let Some(dir) = &args.record {
+        ui.info(&t!("recording-to", path = dir.display().to_string()));
+        client = client.with_fixtures(Fixtures::Record(dir.clone()));
+    } else if let Some(dir) = &args.replay {
+        ui.info(&t!("replaying-from", path = dir.display().to_string()));
+        client = client.with_fixtures(Fixtures::Replay(dir.clone()));
+    }
+    ui.info(&t!("http-client-ready"));
 
     // Check whether the cookies work (are we logged in?).
     match check_login(&client).await {
-        Ok(true) => ui.info("Login confirmed..."),
-        Ok(false) => ui.error_and_exit(
-            "Logged out. Cookies could be stale or invalid.\n\
-            Try refreshing your cookies.json and trying again.",
-        ),
-        Err(e) => ui.error_and_exit(&format!("Login check failed: {e}")),
+        Ok(LoginStatus::LoggedIn(account)) => {
+            ui.info(&t!("login-confirmed"));
+            report_account(ui, &account);
+            if let Some(source) = &source
+                && settings.use_keyring()
+            {
+                remember_cookies(ui, settings, &store, source);
+            }
+            if let Some(source) = source {
+                client = client.with_reauth(reauth_prompt(ui.clone(), source));
+            }
+        }
+        Ok(LoginStatus::LoggedOut) if source == Some(CookieSource::Keyring) => {
+            ui.error_and_exit(&t!("logged-out-keyring"))
+        }
+        Ok(LoginStatus::LoggedOut) => ui.error_and_exit(&t!("logged-out")),
+        Err(e) => ui.error_and_exit(&t!("login-check-failed", error = e.to_string())),
     };
+    client
+}
+
+/// Output settings shared by downloads and subcommands.
+fn display_for(args: &Args, book_id: &str) -> DisplayBuilder {
+    Display::builder(book_id)
+        .debug_http(args.debug_http)
+        .verbosity(Verbosity::from_flags(args.quiet, args.verbose))
+        .format(args.output_format)
+        .stderr(args.output.as_deref() == Some(Path::new(download::STDOUT)))
+        .color(if args.no_color {
+            ColorChoice::Never
+        } else {
+            args.color
+        })
+}
+
+/// `catalog`: write the OPDS feed of the books root.
+fn catalog_command(ui: &Display, root: &Path) {
+    match catalog::write_catalog(root) {
+        Ok((path, count)) => ui.info(&t!(
+            "catalog-written",
+            count = count,
+            path = path.display().to_string()
+        )),
+        Err(e) => ui.error_and_exit(&t!("catalog-failed", error = format!("{e:#}"))),
+    }
+}
 
-    // Retrieve book info.
-    ui.info("Retrieving book info...");
-    let bookinfo = match fetch_book_info(&client, &args.bookid).await {
+/// `chapters <bookid>`: the chapter list with estimated sizes, on stdout.
+async fn chapters_command(
+    ui: &Display,
+    client: &HttpClient,
+    bookid: &str,
+    json: bool,
+    jobs: usize,
+) {
+    let info = match orly::fetch_book_info(client, bookid).await {
         Ok(info) => info,
-        Err(e) => ui.error_and_exit(&format!("Failed to fetch book info: {}", e)),
+        Err(e) => ui.error_and_exit(&t!("book-info-failed", error = format!("{e:#}"))),
+    };
+    let chapters = match orly::fetch_chapters(client, &info, bookid).await {
+        Ok(chapters) => chapters,
+        Err(e) => ui.error_and_exit(&t!("chapter-list-failed", error = format!("{e:#}"))),
+    };
+    let summary = book::summarize_chapters(client, &chapters, jobs).await;
+    if json {
+        match serde_json::to_string_pretty(&summary) {
+            Ok(text) => println!("{text}"),
+            Err(e) => ui.error_and_exit(&format!("{e:#}")),
+        }
+        return;
+    }
+    println!("{}", info.title);
+    for c in &summary {
+        println!(
+            "{:>4}  {:<24}  {:>10}  {}",
+            c.index,
+            c.filename,
+            book::human_size(c.estimated_bytes),
+            c.title
+        );
+    }
+    let total: u64 = summary.iter().map(|c| c.estimated_bytes).sum();
+    println!(
+        "{}",
+        t!(
+            "chapters-total",
+            count = summary.len(),
+            size = book::human_size(total)
+        )
+    );
+}
+
+/// `annotations <bookid>`: export the reader's highlights and notes.
+async fn annotations_command(
+    ui: &Display,
+    client: &HttpClient,
+    bookid: &str,
+    format: annotations::ExportFormat,
+    output: Option<&Path>,
+) {
+    let title = match orly::fetch_book_info(client, bookid).await {
+        Ok(info) => info.title,
+        Err(e) => ui.error_and_exit(&t!("book-info-failed", error = format!("{e:#}"))),
     };
-    ui.info(&format!("{:#?}", bookinfo));
+    let notes = match orly::fetch_annotations(client, bookid).await {
+        Ok(notes) => notes,
+        Err(e) => ui.error_and_exit(&t!("annotations-fetch-failed", error = format!("{e:#}"))),
+    };
+    let text = match annotations::export(&title, &notes, format) {
+        Ok(text) => text,
+        Err(e) => ui.error_and_exit(&format!("{e:#}")),
+    };
+    match output {
+        Some(path) => match std::fs::write(path, text) {
+            Ok(()) => ui.info(&t!(
+                "annotations-written",
+                count = notes.len(),
+                path = path.display().to_string()
+            )),
+            Err(e) => ui.error_and_exit(&t!(
+                "writing-failed",
+                path = path.display().to_string(),
+                error = e.to_string()
+            )),
+        },
+        None => print!("{text}"),
+    }
+}
+
+/// `list`: titles from the account, one "ID  # title" line each on stdout.
+async fn list_command(ui: &Display, client: &HttpClient, history: bool, playlists: bool) {
+    let line = |item: &orly::ListedItem| {
+        let id = item.id()?;
+        let title = item.title.as_deref().unwrap_or("");
+        Some(match item.format.as_deref() {
+            Some(format) if format != "book" => format!("{id}  # {title} ({format})"),
+            _ => format!("{id}  # {title}"),
+        })
+    };
+    if history {
+        match orly::fetch_history(client).await {
+            Ok(items) => {
+                println!("# {}", t!("reading-history"));
+                items.iter().filter_map(line).for_each(|l| println!("{l}"));
+            }
+            Err(e) => ui.error_and_exit(&t!("history-failed", error = format!("{e:#}"))),
+        }
+    }
+    if playlists {
+        match orly::fetch_playlists(client).await {
+            Ok(lists) => {
+                for list in lists {
+                    println!("# {}", t!("playlist", name = list.name));
+                    list.content
+                        .iter()
+                        .filter_map(line)
+                        .for_each(|l| println!("{l}"));
+                }
+            }
+            Err(e) => ui.error_and_exit(&t!("playlists-failed", error = format!("{e:#}"))),
+        }
+    }
+}
+
+/// `list --downloaded`: the library, from the download state or else a
+/// scan of the output directory. Early Releases are checked for updates.
+async fn downloaded_command(ui: &Display, args: &Args, settings: &Settings) {
+    let recorded = match open_state(ui).map(|db| db.downloaded()) {
+        Some(Ok(books)) => books,
+        Some(Err(e)) => {
+            ui.warn(&t!("state-read-failed", error = format!("{e:#}")));
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    let books = if recorded.is_empty() {
+        let root = settings.output_dir();
+        ui.info(&t!("scanning-library", path = root.display().to_string()));
+        library::scan(&root)
+            .iter()
+            .map(library::LibraryBook::to_downloaded)
+            .collect()
+    } else {
+        recorded
+    };
+
+    let mut updated = HashSet::new();
+    if books.iter().any(|b| b.early_release) {
+        let client = connect(ui, args, settings).await;
+        for book in books.iter().filter(|b| b.early_release) {
+            let Some(bookid) = &book.book_id else {
+                continue;
+            };
+            match orly::fetch_book_info(&client, bookid).await {
+                Ok(info)
+                    if state::update_available(
+                        book.updated.as_deref(),
+                        info.updated.as_deref(),
+                    ) =>
+                {
+                    updated.insert(bookid.clone());
+                }
+                Ok(_) => {}
+                Err(e) => ui.warn(&t!(
+                    "update-check-failed",
+                    book = bookid.as_str(),
+                    error = format!("{e:#}")
+                )),
+            }
+        }
+    }
+
+    println!("# {}", t!("downloaded"));
+    for book in &books {
+        let mut details = Vec::new();
+        if let Some(isbn) = &book.isbn {
+            details.push(format!("ISBN {isbn}"));
+        }
+        details.push(book::human_size(book.size));
+        details.push(
+            book.downloaded
+                .get(..10)
+                .unwrap_or(&book.downloaded)
+                .to_string(),
+        );
+        if book.early_release {
+            let id = book.book_id.as_deref().unwrap_or_default();
+            details.push(if updated.contains(id) {
+                t!("early-release-update")
+            } else {
+                t!("early-release")
+            });
+        }
+        let id = book.book_id.as_deref().unwrap_or("?");
+        println!("{id}  # {}  [{}]", book.title, details.join(" | "));
+    }
+}
+
+/// `verify <path>`: re-check a download against its checksum manifest.
+fn verify_command(ui: &Display, path: &Path) {
+    let reports = match checksum::verify(path) {
+        Ok(r) => r,
+        Err(e) => ui.error_and_exit(&format!("{e:#}")),
+    };
+    let mut damaged = 0;
+    for report in &reports {
+        for problem in &report.problems {
+            ui.warn(problem);
+        }
+        if report.problems.is_empty() {
+            ui.info(&t!(
+                "verified",
+                path = report.epub.display().to_string(),
+                count = report.checked
+            ));
+        } else {
+            damaged += 1;
+        }
+    }
+    if damaged > 0 {
+        ui.error_and_exit(&t!(
+            "verification-failed",
+            damaged = damaged,
+            count = reports.len()
+        ));
+    }
+}
 
-    let skeleton = EpubSkeleton::plan(&config::books_root(), &bookinfo.title, &args.bookid);
-    ui.set_output_dir(skeleton.root.clone());
+/// Days before expiry from which the subscription gets a warning.
+const EXPIRY_WARNING_DAYS: i64 = 14;
 
-    // Create directories and required files
-    if let Err(e) = (|| -> anyhow::Result<()> {
-        skeleton.create_dirs()?;
-        skeleton.write_mimetype()?;
-        skeleton.write_container_xml()?;
-        Ok(())
-    })() {
-        ui.error_and_exit(&format!("EPUB skeleton creation failed: {e}"));
+/// Show who we are logged in as and when the subscription runs out.
+fn report_account(ui: &Display, account: &AccountInfo) {
+    if let Some(email) = &account.email {
+        ui.info(&t!("account", email = email.as_str()));
     }
-    ui.info("EPUB skeleton ready (mimetype + META-INF/container.xml + OEBPS/).");
+    let Some(sub) = &account.subscription else {
+        return;
+    };
+    ui.info(&t!(
+        "subscription",
+        kind = sub.kind.clone().unwrap_or_else(|| t!("unknown")),
+        expires = sub.expires.clone().unwrap_or_else(|| t!("not-available"))
+    ));
+    match sub.days_until_expiry(SystemTime::now()) {
+        Some(days) if days < 0 => ui.warn(&t!("subscription-expired")),
+        Some(days) if days <= EXPIRY_WARNING_DAYS => {
+            ui.warn(&t!("subscription-expiring", days = days))
+        }
+        _ => {}
+    }
+}
+
+/// Where the session cookies were read from.
+#[derive(Debug, Clone, PartialEq)]
+enum CookieSource {
+    Inline,
+    Stdin,
+    File(PathBuf),
+    Keyring,
+}
+
+impl CookieSource {
+    fn label(&self) -> String {
+        match self {
+            Self::Inline => t!("cookie-source-inline"),
+            Self::Stdin => "<stdin>".to_string(),
+            Self::File(path) => path.display().to_string(),
+            Self::Keyring => t!("cookie-source-keyring"),
+        }
+    }
+}
+
+/// Load the session cookies, exiting with a helpful message when unusable.
+/// Sources, by precedence: inline cookies (SAFARIBOOKS_COOKIES / config),
+/// a cookies file ("-" for stdin), the OS keyring, or cookies.json next to
+/// the executable.
+fn load_cookies(ui: &Display, settings: &Settings) -> (CookieStore, CookieSource) {
+    let mut source = match (&settings.cookies, settings.cookies_file.as_deref()) {
+        (Some(_), _) => CookieSource::Inline,
+        (None, Some(path)) if path == Path::new("-") => CookieSource::Stdin,
+        (None, Some(path)) => CookieSource::File(path.to_path_buf()),
+        (None, None) => CookieSource::File(settings.default_cookies_file()),
+    };
+
+    // Without an explicit source, prefer cookies saved by an earlier run.
+    let mut stored = None;
+    if settings.use_keyring() && settings.cookies.is_none() && settings.cookies_file.is_none() {
+        match secrets::load_cookies(settings.profile.as_deref()) {
+            Ok(Some(store)) => {
+                source = CookieSource::Keyring;
+                stored = Some(store);
+            }
+            Ok(None) => {}
+            Err(e) => ui.warn(&t!("keyring-unavailable", error = e.to_string())),
+        }
+    }
+    let label = source.label();
+
+    if let CookieSource::File(path) = &source
+        && !path.exists()
+    {
+        ui.error_and_exit(&t!("cookies-not-found", source = label.as_str()));
+    }
+
+    // Load cookies
+    let loaded = match (&source, stored) {
+        (_, Some(store)) => Ok(store),
+        (CookieSource::Inline, _) => CookieStore::from_raw(settings.cookies.as_deref().unwrap()),
+        (CookieSource::Stdin, _) => CookieStore::load_from_reader(std::io::stdin().lock()),
+        (CookieSource::File(path), _) => CookieStore::load_from(path),
+        (CookieSource::Keyring, None) => unreachable!("keyring source always has a store"),
+    };
+    let store = match loaded {
+        Ok(c) => c,
+        Err(e) => ui.error_and_exit(&t!(
+            "cookies-read-failed",
+            source = label.as_str(),
+            error = e.to_string()
+        )),
+    };
+
+    if store.is_empty() {
+        ui.error_and_exit(&t!("no-cookies", source = label.as_str()));
+    }
+
+    let names = store.cookie_names();
+    ui.info(&t!(
+        "cookies-loaded",
+        count = store.len(),
+        source = label.as_str(),
+        names = names.join(", ")
+    ));
+
+    let encoded = store.encoded_values();
+    if !encoded.is_empty() {
+        ui.warn(&t!("cookies-encoded", names = encoded.join(", ")));
+    }
+    let invalid = store.invalid_names();
+    if !invalid.is_empty() {
+        ui.warn(&t!("cookies-invalid-names", names = invalid.join(", ")));
+    }
+
+    // Catch incomplete exports before the login check fails generically.
+    let missing = store.missing_required();
+    if !missing.is_empty() {
+        ui.error_and_exit(&t!(
+            "cookies-missing",
+            source = label.as_str(),
+            names = missing.join(", "),
+            host = settings.site_host()
+        ));
+    }
+    (store, source)
+}
+
+/// Keep cookies that just proved valid in the OS keyring for later runs.
+fn remember_cookies(ui: &Display, settings: &Settings, store: &CookieStore, source: &CookieSource) {
+    if *source == CookieSource::Keyring {
+        return;
+    }
+    match secrets::save_cookies(settings.profile.as_deref(), store) {
+        Ok(()) => {
+            ui.info(&t!("cookies-saved-keyring"));
+            if let CookieSource::File(path) = source {
+                ui.info(&t!(
+                    "cookies-file-unneeded",
+                    path = path.display().to_string()
+                ));
+            }
+        }
+        Err(e) => ui.warn(&t!("keyring-save-failed", error = e.to_string())),
+    }
+}
 
-    ui.info("Initialization complete.");
-    ui.info("No network operations performed in this version.");
+/// Ask for fresh cookies when the session expires mid-download: re-read the
+/// cookies file once the user has updated it, or take pasted cookies.
+fn reauth_prompt(ui: Display, source: CookieSource) -> Reauth {
+    Arc::new(move || loop {
+        let answer = match &source {
+            CookieSource::File(path) => {
+                let question = t!("session-expired-file", path = path.display().to_string());
+                match ui.prompt(&question)? {
+                    a if a.eq_ignore_ascii_case("q") => return None,
+                    _ => CookieStore::load_from(path),
+                }
+            }
+            _ => match ui.prompt(&t!("session-expired-paste"))? {
+                a if a.is_empty() => return None,
+                a => CookieStore::from_raw(&a),
+            },
+        };
+        match answer {
+            Ok(store) if store.missing_required().is_empty() => {
+                ui.info(&t!("cookies-reloaded"));
+                return Some(store);
+            }
+            Ok(store) => ui.warn(&t!(
+                "cookies-still-missing",
+                names = store.missing_required().join(", ")
+            )),
+            Err(e) => ui.warn(&t!("cookies-unreadable", error = e.to_string())),
+        }
+    })
 }
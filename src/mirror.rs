@@ -0,0 +1,213 @@
+//! `--mirror`: every response a book is built from (API JSON, chapter HTML,
+//! stylesheets, images) kept untouched under raw/ in the book directory, so
+//! a later version can rebuild the EPUB without downloading it again
+//! (`rebuild`).
+
+use crate::api::{ApiResponse, OreillyApi};
+use crate::i18n::t;
+use crate::orly::iso_datetime;
+use crate::recorder;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::debug;
+
+/// The mirror, inside the book directory.
+pub const RAW_DIR: &str = "raw";
+
+/// What the mirror is of, in raw/mirror.json.
+const INFO_FILE: &str = "mirror.json";
+
+/// The responses, in the `--record` layout, in raw/responses/.
+const RESPONSES_DIR: &str = "responses";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MirrorInfo {
+    pub book_id: String,
+    /// Site the URLs of the responses are on.
+    pub base_url: String,
+    /// When the mirror was started, ISO 8601.
+    pub mirrored: String,
+}
+
+/// Start (or continue) the mirror in `book_root` and return the directory
+/// its responses go to.
+pub fn start(book_root: &Path, book_id: &str, base_url: &str) -> Result<PathBuf> {
+    let raw = book_root.join(RAW_DIR);
+    let responses = raw.join(RESPONSES_DIR);
+    fs::create_dir_all(&responses)
+        .with_context(|| t!("creating-directory", path = responses.display().to_string()))?;
+    let info = MirrorInfo {
+        book_id: book_id.to_string(),
+        base_url: base_url.to_string(),
+        mirrored: iso_datetime(SystemTime::now()),
+    };
+    let path = raw.join(INFO_FILE);
+    fs::write(&path, serde_json::to_string_pretty(&info)? + "\n")
+        .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+    Ok(responses)
+}
+
+/// The mirror in `book_root` and where its responses are.
+pub fn open(book_root: &Path) -> Result<(MirrorInfo, PathBuf)> {
+    let raw = book_root.join(RAW_DIR);
+    let path = raw.join(INFO_FILE);
+    let text = fs::read_to_string(&path)
+        .with_context(|| t!("mirror-missing", path = book_root.display().to_string()))?;
+    let info = serde_json::from_str(&text)
+        .with_context(|| t!("reading-file", path = path.display().to_string()))?;
+    Ok((info, raw.join(RESPONSES_DIR)))
+}
+
+/// An API that passes every request through to `api`, saving each
+/// response in `dir` first when there is one.
+pub struct Mirror<'a, A> {
+    api: &'a A,
+    dir: Option<PathBuf>,
+}
+
+impl<'a, A: OreillyApi> Mirror<'a, A> {
+    pub fn new(api: &'a A, dir: Option<PathBuf>) -> Self {
+        Self { api, dir }
+    }
+
+    fn save(&self, url: &str, res: &ApiResponse) -> Result<()> {
+        match &self.dir {
+            Some(dir) => recorder::save(dir, url, res).with_context(|| t!("mirror-save-failed")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<A: OreillyApi> OreillyApi for Mirror<'_, A> {
+    fn base_url(&self) -> &str {
+        self.api.base_url()
+    }
+
+    async fn get(&self, url: &str) -> Result<ApiResponse> {
+        let res = self.api.get(url).await?;
+        self.save(url, &res)?;
+        Ok(res)
+    }
+
+    async fn get_from(&self, url: &str, referer: &str) -> Result<ApiResponse> {
+        let res = self.api.get_from(url, referer).await?;
+        self.save(url, &res)?;
+        Ok(res)
+    }
+
+    /// Only a 200 has a body worth keeping; other statuses are saved
+    /// without one, so a rebuild fails the same way.
+    async fn download(&self, url: &str, referer: Option<&str>, dest: &Path) -> Result<u16> {
+        let status = self.api.download(url, referer, dest).await?;
+        if self.dir.is_some() {
+            let body = if status == 200 {
+                fs::read(dest)
+                    .with_context(|| t!("reading-file", path = dest.display().to_string()))?
+            } else {
+                Vec::new()
+            };
+            self.save(url, &ApiResponse { status, body })?;
+        }
+        Ok(status)
+    }
+
+    async fn content_length(&self, url: &str) -> Result<Option<u64>> {
+        self.api.content_length(url).await
+    }
+}
+
+/// The API as a mirror saw it, for rebuilding offline. A request it never
+/// saw answers 404, like a resource the site no longer has.
+pub struct Offline {
+    dir: PathBuf,
+    base_url: String,
+}
+
+impl Offline {
+    pub fn new(info: &MirrorInfo, dir: PathBuf) -> Self {
+        Self {
+            dir,
+            base_url: info.base_url.clone(),
+        }
+    }
+}
+
+impl OreillyApi for Offline {
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    async fn get(&self, url: &str) -> Result<ApiResponse> {
+        recorder::load(&self.dir, url).or_else(|e| {
+            debug!("{e:#}");
+            Ok(ApiResponse {
+                status: 404,
+                body: Vec::new(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, start, Mirror, MirrorInfo, Offline, INFO_FILE, RAW_DIR};
+    use crate::api::{FixtureApi, OreillyApi};
+    use crate::recorder;
+    use std::fs;
+
+    #[tokio::test]
+    async fn keeps_every_response() {
+        let root = std::env::temp_dir().join(format!("safaribooks-mirror-{}", std::process::id()));
+        let api = FixtureApi::default()
+            .with(
+                "https://example.com/api/v1/book/1/",
+                200,
+                r#"{"title":"T"}"#,
+            )
+            .with("https://example.com/a.css", 200, "p { margin: 0 }");
+        let dir = start(&root, "1", api.base_url()).unwrap();
+        let info: MirrorInfo =
+            serde_json::from_slice(&fs::read(root.join(RAW_DIR).join(INFO_FILE)).unwrap()).unwrap();
+        assert_eq!(info.book_id, "1");
+
+        let mirror = Mirror::new(&api, Some(dir.clone()));
+        mirror
+            .get("https://example.com/api/v1/book/1/")
+            .await
+            .unwrap();
+        let css = root.join("a.css");
+        assert_eq!(
+            mirror
+                .download("https://example.com/a.css", None, &css)
+                .await
+                .unwrap(),
+            200
+        );
+        assert_eq!(
+            mirror
+                .download("https://example.com/gone.png", None, &css)
+                .await
+                .unwrap(),
+            404
+        );
+
+        let saved = recorder::load(&dir, "https://example.com/api/v1/book/1/").unwrap();
+        assert_eq!(saved.body, br#"{"title":"T"}"#);
+        let saved = recorder::load(&dir, "https://example.com/a.css").unwrap();
+        assert_eq!(saved.body, b"p { margin: 0 }");
+        let saved = recorder::load(&dir, "https://example.com/gone.png").unwrap();
+        assert_eq!((saved.status, saved.body.len()), (404, 0));
+
+        let (info, dir) = open(&root).unwrap();
+        let offline = Offline::new(&info, dir);
+        let res = offline.get("https://example.com/a.css").await.unwrap();
+        assert_eq!((res.status, res.body), (200, b"p { margin: 0 }".to_vec()));
+        let res = offline.get("https://example.com/never.css").await.unwrap();
+        assert_eq!(res.status, 404);
+        assert!(open(&root.join("a.css")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
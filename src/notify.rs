@@ -0,0 +1,76 @@
+//! `--notify`: a desktop notification when a download ends, for runs left
+//! unattended in the background.
+
+use crate::display::Display;
+use crate::download::Outcome;
+use crate::i18n::t;
+use anyhow::Result;
+use std::time::Duration;
+
+/// How long the notification stays up, where the desktop honours it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Summary and body of the notification for `result`.
+pub fn message(book_id: &str, result: &Result<Outcome>) -> (String, String) {
+    let name = t!("notification-book", book = book_id);
+    match result {
+        Ok(Outcome::Finished(path)) => (
+            t!("notification-finished"),
+            format!("{name}\n{}", path.display()),
+        ),
+        Ok(Outcome::Skipped(path)) => (
+            t!("notification-skipped"),
+            format!("{name}\n{}", path.display()),
+        ),
+        Err(e) => (t!("notification-download-failed"), format!("{name}\n{e:#}")),
+    }
+}
+
+/// Show the notification; a desktop without a notification service only
+/// gets a warning.
+pub async fn send(ui: &Display, book_id: &str, result: &Result<Outcome>) {
+    let (summary, body) = message(book_id, result);
+    // The D-Bus round trip blocks.
+    let shown = tokio::task::spawn_blocking(move || {
+        notify_rust::Notification::new()
+            .appname(env!("CARGO_PKG_NAME"))
+            .summary(&summary)
+            .body(&body)
+            .timeout(TIMEOUT)
+            .show()
+            .map(drop)
+    })
+    .await;
+    match shown {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => ui.warn(&t!("notification-failed", error = e.to_string())),
+        Err(e) => ui.warn(&t!("notification-failed", error = e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::message;
+    use crate::download::Outcome;
+    use anyhow::anyhow;
+
+    #[test]
+    fn describes_outcomes() {
+        let finished = Ok(Outcome::Finished("Books/Rust (123)/123.epub".into()));
+        assert_eq!(
+            message("123", &finished),
+            (
+                "Download finished".to_string(),
+                "Book 123\nBooks/Rust (123)/123.epub".to_string()
+            )
+        );
+        let failed = Err(anyhow!("Logged out"));
+        assert_eq!(
+            message("123", &failed),
+            (
+                "Download failed".to_string(),
+                "Book 123\nLogged out".to_string()
+            )
+        );
+    }
+}
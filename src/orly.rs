@@ -1,51 +1,1202 @@
-use crate::http_client::HttpClient;
+use crate::annotations::Annotation;
+use crate::api::OreillyApi;
+use crate::i18n::t;
 use anyhow::{bail, Result};
-use serde::Deserialize;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub const PROFILE_URL: &str = "https://learning.oreilly.com/profile/";
+/// Site every URL is built on unless a custom base URL is configured.
+pub const DEFAULT_BASE_URL: &str = "https://learning.oreilly.com";
 
-/// Minimal subset of the book that we care about.
+pub fn profile_url(base: &str) -> String {
+    format!("{base}/profile/")
+}
+
+pub fn account_api_url(base: &str) -> String {
+    format!("{base}/api/v2/me/")
+}
+
+/// Where the web app trades the `orm-rt` refresh cookie for a new `orm-jwt`.
+pub fn token_refresh_url(base: &str) -> String {
+    format!("{base}/member/auth/refresh/")
+}
+
+/// A named person or organization (author, publisher, ...).
+#[derive(Debug, Clone, Deserialize)]
+pub struct Contributor {
+    pub name: String,
+}
+
+/// A topic/subject the book is filed under: an object with its name (and,
+/// in newer responses, how relevant it is to the book), or the bare name.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "TopicFields")]
+pub struct Topic {
+    pub name: String,
+    pub score: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TopicFields {
+    Name(String),
+    Object {
+        name: String,
+        #[serde(default)]
+        score: Option<f64>,
+    },
+}
+
+impl From<TopicFields> for Topic {
+    fn from(fields: TopicFields) -> Self {
+        match fields {
+            TopicFields::Name(name) => Self { name, score: None },
+            TopicFields::Object { name, score } => Self { name, score },
+        }
+    }
+}
+
+/// The series a book is in, as a name or as an object with its position.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "SeriesFields")]
+pub struct Series {
+    pub name: String,
+    pub index: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SeriesFields {
+    Name(String),
+    Object {
+        #[serde(alias = "title")]
+        name: String,
+        #[serde(default, alias = "position", alias = "number")]
+        index: Option<f64>,
+    },
+}
+
+impl From<SeriesFields> for Series {
+    fn from(fields: SeriesFields) -> Self {
+        match fields {
+            SeriesFields::Name(name) => Self { name, index: None },
+            SeriesFields::Object { name, index } => Self { name, index },
+        }
+    }
+}
+
+/// Book metadata as returned by the v1 book endpoint.
+/// Everything but the title and URL is optional, as older and newer
+/// products don't all carry the same fields.
 #[derive(Debug, Deserialize)]
 pub struct BookInfo {
     pub title: String,
     pub web_url: String,
+    /// Raw product format as reported by the API ("book", "video", ...).
+    #[serde(default = "default_format", alias = "content_format")]
+    pub format: String,
+    #[serde(default)]
+    pub authors: Vec<Contributor>,
+    #[serde(default)]
+    pub publishers: Vec<Contributor>,
+    /// Publication date, ISO 8601 (e.g. "2017-05-10").
+    #[serde(default)]
+    pub issued: Option<String>,
+    #[serde(default)]
+    pub isbn: Option<String>,
+    /// HTML description/blurb.
+    #[serde(default)]
+    pub description: Option<String>,
+    /// O'Reilly's taxonomy; older responses call it subjects, some carry both.
+    #[serde(default)]
+    pub topics: Vec<Topic>,
+    #[serde(default)]
+    pub subjects: Vec<Topic>,
+    #[serde(default)]
+    pub rights: Option<String>,
+    /// URL of the cover image; `cover_url` when the API leaves it out.
+    #[serde(default)]
+    pub cover: Option<String>,
+    /// Only some products carry it; `--series-from-title` guesses it for
+    /// the others.
+    #[serde(default)]
+    pub series: Option<Series>,
+    #[serde(default, alias = "pagecount")]
+    pub page_count: Option<u32>,
+    /// Language of the content, a BCP 47 tag ("en", "pt-BR", "ar").
+    #[serde(default)]
+    pub language: Option<String>,
+    /// An Early Release: chapters are still being added and revised.
+    #[serde(default, alias = "is_early_release")]
+    pub early_release: bool,
+    /// When the content last changed, ISO 8601.
+    #[serde(default, alias = "last_modified_time", alias = "updated_at")]
+    pub updated: Option<String>,
+    /// URL of the (paginated) chapter list.
+    #[serde(default)]
+    pub chapters: Option<String>,
+    /// URL of the nested table of contents.
+    #[serde(default)]
+    pub toc: Option<String>,
+    /// The API's response as it came, every field of it (`--sidecar`).
+    #[serde(skip)]
+    pub raw: serde_json::Value,
+}
+
+fn default_format() -> String {
+    "book".to_string()
+}
+
+/// The kind of product behind an identifier; each has its own pipeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProductType {
+    Book,
+    Video,
+    Audiobook,
+    /// Anything we don't know how to handle, with the raw API value.
+    Other(String),
+}
+
+impl ProductType {
+    /// Map the API `format`/`content_format` value to a product type.
+    pub fn from_format(format: &str) -> Self {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "book" | "ebook" | "epub" => Self::Book,
+            "video" | "course" => Self::Video,
+            "audiobook" | "audio" => Self::Audiobook,
+            other => Self::Other(other.to_string()),
+        }
+    }
+}
+
+impl BookInfo {
+    pub fn product_type(&self) -> ProductType {
+        ProductType::from_format(&self.format)
+    }
+
+    /// The content language; English when the API doesn't say.
+    pub fn language(&self) -> String {
+        match self.language.as_deref().map(str::trim) {
+            Some(lang) if !lang.is_empty() => lang.replace('_', "-"),
+            _ => "en".to_string(),
+        }
+    }
+
+    /// Author names, in API order.
+    pub fn author_names(&self) -> Vec<String> {
+        self.authors.iter().map(|a| a.name.clone()).collect()
+    }
+
+    /// The first listed publisher, if any.
+    pub fn publisher(&self) -> Option<&str> {
+        self.publishers.first().map(|p| p.name.as_str())
+    }
+
+    /// The topics and subjects as the book's subjects (calibre's tags):
+    /// the most relevant first, each once whatever its case.
+    pub fn subject_names(&self) -> Vec<String> {
+        let mut topics: Vec<&Topic> = self.topics.iter().chain(&self.subjects).collect();
+        // Stable, so topics without a score keep their API order, last.
+        topics.sort_by(|a, b| {
+            b.score
+                .unwrap_or(f64::NEG_INFINITY)
+                .total_cmp(&a.score.unwrap_or(f64::NEG_INFINITY))
+        });
+        let mut names: Vec<String> = Vec::new();
+        for topic in topics {
+            let name = topic.name.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !name.is_empty() && !names.iter().any(|n| n.eq_ignore_ascii_case(&name)) {
+                names.push(name);
+            }
+        }
+        names
+    }
+}
+
+/// Account details from the account API. All fields are best-effort.
+#[derive(Debug, Default, Deserialize)]
+pub struct AccountInfo {
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub subscription: Option<Subscription>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Subscription {
+    /// Plan name, e.g. "Individual" or "Enterprise".
+    #[serde(default, alias = "type", alias = "subscription_type")]
+    pub kind: Option<String>,
+    /// Expiry date, ISO 8601 (only the date part is used).
+    #[serde(default, alias = "expiration_date", alias = "expires_at")]
+    pub expires: Option<String>,
+}
+
+impl Subscription {
+    /// Whole days from `now` until expiry; negative once expired.
+    /// None if there is no (parseable) expiry date.
+    pub fn days_until_expiry(&self, now: SystemTime) -> Option<i64> {
+        let expires = self.expires.as_deref()?;
+        let mut parts = expires.get(..10)?.split('-');
+        let y: i64 = parts.next()?.parse().ok()?;
+        let m: i64 = parts.next()?.parse().ok()?;
+        let d: i64 = parts.next()?.parse().ok()?;
+        let today = now.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64 / 86_400;
+        Some(days_from_civil(y, m, d) - today)
+    }
+}
+
+/// `now` as a UTC "YYYY-MM-DD" date.
+pub fn iso_date(now: SystemTime) -> String {
+    let days = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64 / 86_400);
+    let (y, m, d) = civil_from_days(days);
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+/// `time` as a UTC RFC 3339 timestamp, e.g. "2024-02-28T12:00:00Z".
+pub fn iso_datetime(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let of_day = secs % 86_400;
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        iso_date(time),
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60
+    )
 }
 
-/// Check whether cookies keep us logged in by fetching the profile page.
-/// Returns:
-/// - Ok(true)  => HTTP 200 (assume logged in)
-/// - Ok(false) => Redirect or 401/403 (assume not logged in)
-/// - Err(..)   => Network/other error
-pub async fn check_login(client: &HttpClient) -> Result<bool> {
-    let res = client.client().get(PROFILE_URL).send().await?;
-    let status = res.status();
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (
+        if m <= 2 {
+            era * 400 + yoe + 1
+        } else {
+            era * 400 + yoe
+        },
+        m,
+        d,
+    )
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's algorithm).
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
 
-    if status.is_redirection() {
-        Ok(false)
-    } else if status == 200 {
-        Ok(true)
-    } else {
-        bail!("Profile request returned unexpected status {}", status)
+/// Outcome of the login check.
+#[derive(Debug)]
+pub enum LoginStatus {
+    LoggedOut,
+    LoggedIn(AccountInfo),
+}
+
+/// Check whether cookies keep us logged in by fetching the profile page,
+/// then read the account details. Returns:
+/// - Ok(LoggedIn)  => HTTP 200 (account details empty if the API didn't answer)
+/// - Ok(LoggedOut) => Redirect or 401/403 (assume not logged in)
+/// - Err(..)       => Network/other error
+pub async fn check_login<A: OreillyApi>(api: &A) -> Result<LoginStatus> {
+    let res = api.get(&profile_url(api.base_url())).await?;
+    let status = res.status;
+
+    if (300..400).contains(&status) || status == 401 || status == 403 {
+        return Ok(LoginStatus::LoggedOut);
+    } else if status != 200 {
+        bail!(t!("profile-status", status = status))
     }
+
+    // The details are informative only; never fail the login check over them.
+    let account = match api.get(&account_api_url(api.base_url())).await {
+        Ok(res) if res.status == 200 => res.json::<AccountInfo>().unwrap_or_default(),
+        _ => AccountInfo::default(),
+    };
+    Ok(LoginStatus::LoggedIn(account))
 }
 
 /// Build the v1 API URL for the book.
-pub fn book_api_url(bookid: &str) -> String {
-    format!("https://learning.oreilly.com/api/v1/book/{bookid}")
+pub fn book_api_url(base: &str, bookid: &str) -> String {
+    format!("{base}/api/v1/book/{bookid}")
+}
+
+/// The cover image of the book, as the website links it.
+pub fn cover_url(base: &str, bookid: &str) -> String {
+    format!("{base}/library/cover/{bookid}/")
+}
+
+/// The book API has no book by that ID; `search` may find the intended one.
+#[derive(Debug)]
+pub struct BookNotFound;
+
+impl std::fmt::Display for BookNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&t!("book-not-found"))
+    }
 }
 
-/// Fetch book metadata from the website.
-pub async fn fetch_book_info(client: &HttpClient, bookid: &str) -> Result<BookInfo> {
-    let url = book_api_url(bookid);
-    let res = client.client().get(url).send().await?;
-    let status = res.status();
+impl std::error::Error for BookNotFound {}
+
+/// Fetch book metadata from the website. Some recent titles are only
+/// served by the v2 API; when v1 says 404 or 410, that is asked too.
+pub async fn fetch_book_info<A: OreillyApi>(api: &A, bookid: &str) -> Result<BookInfo> {
+    let url = book_api_url(api.base_url(), bookid);
+    let res = api.get(&url).await?;
+    let status = res.status;
 
     if status == 200 {
-        let info = res.json::<BookInfo>().await?;
+        let raw = res.json::<serde_json::Value>()?;
+        let mut info: BookInfo = serde_json::from_value(raw.clone())?;
+        info.raw = raw;
         return Ok(info);
     }
+    if status == 404 || status == 410 {
+        let res = api.get(&epub_api_url(api.base_url(), bookid)).await?;
+        return match res.status {
+            200 => {
+                let raw = res.json::<serde_json::Value>()?;
+                let mut info = serde_json::from_value::<EpubInfo>(raw.clone())?
+                    .into_book_info(api.base_url(), bookid);
+                info.raw = raw;
+                Ok(info)
+            }
+            404 | 410 => Err(BookNotFound.into()),
+            status => bail!(t!("v2-status", status = status)),
+        };
+    }
+    bail!(t!("unexpected-status", status = status))
+}
+
+/// Build the v2 API URL for the book.
+pub fn epub_api_url(base: &str, bookid: &str) -> String {
+    format!("{base}/api/v2/epubs/urn:orm:book:{bookid}/")
+}
+
+/// Book metadata as returned by the v2 epubs endpoint, which names some
+/// fields differently and leaves others out.
+#[derive(Debug, Deserialize)]
+struct EpubInfo {
+    title: String,
+    #[serde(default)]
+    web_url: Option<String>,
+    #[serde(default)]
+    authors: Vec<Contributor>,
+    #[serde(default)]
+    publishers: Vec<Contributor>,
+    #[serde(default)]
+    publication_date: Option<String>,
+    #[serde(default)]
+    isbn: Option<String>,
+    /// Keyed by media type: "text/html", "text/plain".
+    #[serde(default)]
+    descriptions: HashMap<String, String>,
+    #[serde(default)]
+    topics: Vec<Topic>,
+    #[serde(default)]
+    series: Option<Series>,
+    #[serde(default)]
+    page_count: Option<u32>,
+    #[serde(default)]
+    language: Option<String>,
+    #[serde(default)]
+    is_early_release: bool,
+    #[serde(default)]
+    last_modified_time: Option<String>,
+}
+
+impl EpubInfo {
+    /// The same metadata as the v1 endpoint would give. The chapters
+    /// still come from the v1 chapter list.
+    fn into_book_info(mut self, base: &str, bookid: &str) -> BookInfo {
+        let description = ["text/html", "text/plain"]
+            .into_iter()
+            .find_map(|kind| self.descriptions.remove(kind));
+        BookInfo {
+            title: self.title,
+            web_url: self
+                .web_url
+                .unwrap_or_else(|| format!("{base}/library/view/-/{bookid}/")),
+            format: default_format(),
+            authors: self.authors,
+            publishers: self.publishers,
+            // "2023-05-16T00:00:00Z" -> "2023-05-16"
+            issued: self
+                .publication_date
+                .map(|d| d.get(..10).unwrap_or(&d).to_string()),
+            isbn: self.isbn,
+            description,
+            topics: self.topics,
+            subjects: Vec::new(),
+            rights: None,
+            cover: None,
+            series: self.series,
+            page_count: self.page_count,
+            language: self.language,
+            early_release: self.is_early_release,
+            updated: self.last_modified_time,
+            chapters: None,
+            toc: None,
+            raw: serde_json::Value::Null,
+        }
+    }
+}
+
+/// The catalog search for `query`, its `limit` best matches.
+pub fn search_api_url(base: &str, query: &str, limit: usize) -> String {
+    let mut url = Url::parse(&format!("{base}/api/v2/search/")).expect("valid base URL");
+    url.query_pairs_mut()
+        .append_pair("query", query)
+        .append_pair("limit", &limit.to_string());
+    url.into()
+}
+
+/// Titles in the catalog matching `query`, best first.
+pub async fn search<A: OreillyApi>(api: &A, query: &str, limit: usize) -> Result<Vec<ListedItem>> {
+    let url = search_api_url(api.base_url(), query, limit);
+    let res = api.get(&url).await?;
+    if res.status != 200 {
+        bail!(t!(
+            "unexpected-status-for",
+            status = res.status,
+            url = url.as_str()
+        ));
+    }
+    let items = match res.json::<Listing<ListedItem>>()? {
+        Listing::Paged { results, .. } | Listing::Bare(results) => results,
+    };
+    Ok(items.into_iter().take(limit).collect())
+}
+
+/// One chapter entry of the chapter-list endpoint.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Chapter {
+    pub title: String,
+    /// File name of the chapter inside the book, e.g. "ch01.html".
+    pub filename: String,
+    /// URL of the chapter's HTML content.
+    pub content: String,
+    /// Base URL that relative image paths resolve against.
+    #[serde(default)]
+    pub asset_base_url: String,
+    /// Image paths referenced by the chapter, relative to `asset_base_url`.
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub stylesheets: Vec<Stylesheet>,
+}
+
+/// A stylesheet required by a chapter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Stylesheet {
+    pub url: String,
+    /// Path of the stylesheet inside the book, e.g. "epub.css".
+    #[serde(default)]
+    pub full_path: String,
+}
+
+/// One page of the paginated chapter-list endpoint.
+#[derive(Debug, Deserialize)]
+pub struct ChapterPage {
+    #[serde(default)]
+    pub next: Option<String>,
+    pub results: Vec<Chapter>,
+}
+
+/// Build the chapter-list URL for the book.
+pub fn chapters_api_url(base: &str, bookid: &str) -> String {
+    format!("{base}/api/v1/book/{bookid}/chapter/")
+}
+
+/// Follow the `next` links starting at `first_url` until exhaustion,
+/// concatenating all pages in order. `fetch_page` performs the actual request,
+/// which keeps this logic testable without the network.
+pub async fn paginate_chapters<F, Fut>(first_url: String, mut fetch_page: F) -> Result<Vec<Chapter>>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<ChapterPage>>,
+{
+    let mut chapters = Vec::new();
+    let mut seen = HashSet::new();
+    let mut next = Some(first_url);
+
+    while let Some(url) = next {
+        // A server bug looping back to a previous page must not hang us.
+        if !seen.insert(url.clone()) {
+            bail!(t!("chapter-pagination-loop", url = url));
+        }
+        let page = fetch_page(url).await?;
+        chapters.extend(page.results);
+        next = page.next;
+    }
+    Ok(chapters)
+}
+
+/// Fetch the complete, ordered chapter list of a book.
+pub async fn fetch_chapters<A: OreillyApi>(
+    api: &A,
+    info: &BookInfo,
+    bookid: &str,
+) -> Result<Vec<Chapter>> {
+    let first = info
+        .chapters
+        .clone()
+        .unwrap_or_else(|| chapters_api_url(api.base_url(), bookid));
+    paginate_chapters(first, |url| async move {
+        let res = api.get(&url).await?;
+        let status = res.status;
+
+        if status == 200 {
+            return res.json::<ChapterPage>();
+        }
+        bail!(t!("unexpected-status", status = status))
+    })
+    .await
+}
+
+/// Fetch the raw HTML of a chapter, as the reader on the book's page
+/// `book_page` would.
+pub async fn fetch_chapter_content<A: OreillyApi>(
+    api: &A,
+    book_page: &str,
+    chapter: &Chapter,
+) -> Result<String> {
+    let res = api.get_from(&chapter.content, book_page).await?;
+    let status = res.status;
+
+    if status == 200 {
+        return Ok(res.text());
+    }
+    bail!(t!(
+        "chapter-status",
+        status = status,
+        chapter = chapter.filename.as_str()
+    ))
+}
+
+/// Download an asset (image, stylesheet, ...) used on the page `referer`
+/// to `dest`.
+pub async fn download_asset<A: OreillyApi>(
+    api: &A,
+    url: &str,
+    referer: Option<&str>,
+    dest: &Path,
+) -> Result<()> {
+    let status = api.download(url, referer, dest).await?;
+
+    if status == 200 {
+        return Ok(());
+    }
+    bail!(t!("asset-status", status = status, url = url))
+}
+
+/// One node of the book/course table of contents (`/toc/` endpoint).
+/// For video courses, top-level entries are lessons and leaves are clips.
+#[derive(Debug, Deserialize)]
+pub struct TocEntry {
+    #[serde(default)]
+    pub id: String,
+    pub label: String,
+    /// Chapter URL or path the entry points to (books only).
+    #[serde(default)]
+    pub href: String,
+    /// Anchor inside the chapter, without '#'.
+    #[serde(default)]
+    pub fragment: String,
+    #[serde(default)]
+    pub children: Vec<TocEntry>,
+}
+
+/// Build the table-of-contents URL for the book or course.
+pub fn toc_api_url(base: &str, bookid: &str) -> String {
+    format!("{base}/api/v1/book/{bookid}/toc/")
+}
+
+/// Fetch the nested table of contents, preferring the URL advertised by the book info.
+pub async fn fetch_toc<A: OreillyApi>(
+    api: &A,
+    info: &BookInfo,
+    bookid: &str,
+) -> Result<Vec<TocEntry>> {
+    let url = info
+        .toc
+        .clone()
+        .unwrap_or_else(|| toc_api_url(api.base_url(), bookid));
+    let res = api.get(&url).await?;
+    let status = res.status;
+
+    if status == 200 {
+        return res.json::<Vec<TocEntry>>();
+    }
+    bail!(t!("unexpected-status", status = status))
+}
+
+/// Build the transcript URL (WebVTT) of a single video clip.
+pub fn transcript_api_url(base: &str, clip_id: &str) -> String {
+    format!("{base}/api/v1/videoclips/{clip_id}/transcript/")
+}
+
+/// Fetch the WebVTT transcript of a clip.
+/// Returns Ok(None) when the clip has no transcript (HTTP 404).
+pub async fn fetch_transcript<A: OreillyApi>(api: &A, clip_id: &str) -> Result<Option<String>> {
+    let res = api
+        .get(&transcript_api_url(api.base_url(), clip_id))
+        .await?;
+    let status = res.status;
+
+    if status == 200 {
+        return Ok(Some(res.text()));
+    }
     if status == 404 {
-        bail!("Book not found (HTTP 404). Please double-check the book ID provided")
+        return Ok(None);
+    }
+    bail!(t!("unexpected-status", status = status))
+}
+
+/// The account's reading history, most recent first.
+pub fn history_api_url(base: &str) -> String {
+    format!("{base}/api/v2/me/history/")
+}
+
+/// The account's playlists with their contents.
+pub fn playlists_api_url(base: &str) -> String {
+    format!("{base}/api/v3/collections/")
+}
+
+/// A title in the reading history or a playlist. The endpoints name it in
+/// different ways, so every field is optional; `id` picks the book ID.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListedItem {
+    /// "urn:orm:book:<id>" and the like.
+    #[serde(default)]
+    pub ourn: Option<String>,
+    #[serde(default, alias = "identifier")]
+    pub archive_id: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default, alias = "content_format", alias = "content_type")]
+    pub format: Option<String>,
+    #[serde(default, alias = "url")]
+    pub web_url: Option<String>,
+}
+
+impl ListedItem {
+    /// The ID to pass to a download: the archive ID, else the last part of
+    /// the URN, else the ID in ".../library/view/<slug>/<id>/".
+    pub fn id(&self) -> Option<&str> {
+        let from_url = || {
+            let url = self.web_url.as_deref()?;
+            let (_, rest) = url.split_once("/library/view/")?;
+            rest.split('/').nth(1).filter(|id| !id.is_empty())
+        };
+        self.archive_id
+            .as_deref()
+            .or_else(|| self.ourn.as_deref().and_then(|u| u.rsplit(':').next()))
+            .or_else(from_url)
+            .filter(|id| !id.is_empty())
+    }
+}
+
+/// A playlist ("collection") of the account.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Playlist {
+    pub name: String,
+    #[serde(default, alias = "items")]
+    pub content: Vec<ListedItem>,
+}
+
+/// A listing endpoint answers with either a bare array or `{results, next}` pages.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Listing<T> {
+    Paged {
+        results: Vec<T>,
+        #[serde(default)]
+        next: Option<String>,
+    },
+    Bare(Vec<T>),
+}
+
+/// Every entry of a listing endpoint, following `next` links.
+async fn fetch_listing<A: OreillyApi, T: serde::de::DeserializeOwned>(
+    api: &A,
+    first_url: String,
+) -> Result<Vec<T>> {
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+    let mut next = Some(first_url);
+    while let Some(url) = next {
+        if !seen.insert(url.clone()) {
+            bail!(t!("listing-pagination-loop", url = url.as_str()));
+        }
+        let res = api.get(&url).await?;
+        if res.status != 200 {
+            bail!(t!(
+                "unexpected-status-for",
+                status = res.status,
+                url = url.as_str()
+            ));
+        }
+        next = match res.json::<Listing<T>>()? {
+            Listing::Paged { results, next } => {
+                entries.extend(results);
+                next
+            }
+            Listing::Bare(results) => {
+                entries.extend(results);
+                None
+            }
+        };
+    }
+    Ok(entries)
+}
+
+/// The reader's highlights and notes on a book.
+pub fn annotations_api_url(base: &str, bookid: &str) -> String {
+    format!("{base}/api/v1/book/{bookid}/annotations/")
+}
+
+/// Every highlight and note the account made in `bookid`.
+pub async fn fetch_annotations<A: OreillyApi>(api: &A, bookid: &str) -> Result<Vec<Annotation>> {
+    fetch_listing(api, annotations_api_url(api.base_url(), bookid)).await
+}
+
+/// Where the account last was in a book in the web reader.
+pub fn position_api_url(base: &str, bookid: &str) -> String {
+    format!("{base}/api/v1/book/{bookid}/position/")
+}
+
+/// The last reading position in a book.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReadingPosition {
+    /// The chapter's file name ("ch03.html") or URL.
+    #[serde(alias = "chapter", alias = "filename")]
+    pub chapter_filename: String,
+    /// Anchor inside the chapter, without '#'.
+    #[serde(default, alias = "anchor")]
+    pub fragment: Option<String>,
+    /// Share of the book read, 0.0 to 1.0.
+    #[serde(default, alias = "percentage")]
+    pub progress: Option<f64>,
+    /// When the position was saved, ISO 8601.
+    #[serde(default, alias = "last_read", alias = "updated_at")]
+    pub updated: Option<String>,
+}
+
+/// Fetch the last reading position; Ok(None) when the book was never opened.
+pub async fn fetch_reading_position<A: OreillyApi>(
+    api: &A,
+    bookid: &str,
+) -> Result<Option<ReadingPosition>> {
+    let res = api.get(&position_api_url(api.base_url(), bookid)).await?;
+    match res.status {
+        200 => Ok(Some(res.json()?)),
+        404 | 204 => Ok(None),
+        status => bail!(t!("unexpected-status", status = status)),
+    }
+}
+
+/// Titles recently opened on the account, most recent first.
+pub async fn fetch_history<A: OreillyApi>(api: &A) -> Result<Vec<ListedItem>> {
+    fetch_listing(api, history_api_url(api.base_url())).await
+}
+
+/// The account's playlists.
+pub async fn fetch_playlists<A: OreillyApi>(api: &A) -> Result<Vec<Playlist>> {
+    fetch_listing(api, playlists_api_url(api.base_url())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        book_api_url, check_login, epub_api_url, fetch_annotations, fetch_book_info, fetch_history,
+        fetch_playlists, fetch_reading_position, iso_date, iso_datetime, paginate_chapters, search,
+        search_api_url, BookInfo, BookNotFound, ChapterPage, ListedItem, LoginStatus, ProductType,
+        Subscription, DEFAULT_BASE_URL,
+    };
+    use crate::api::FixtureApi;
+    use anyhow::anyhow;
+    use serde_json::json;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const PROFILE_URL: &str = "https://learning.oreilly.com/profile/";
+    const ACCOUNT_API_URL: &str = "https://learning.oreilly.com/api/v2/me/";
+
+    const PAGE_1: &str = include_str!("../tests/fixtures/chapters_page_1.json");
+    const PAGE_2: &str = include_str!("../tests/fixtures/chapters_page_2.json");
+
+    #[tokio::test]
+    async fn check_login_maps_statuses() {
+        let api = FixtureApi::default().with(PROFILE_URL, 200, "<html></html>");
+        assert!(matches!(
+            check_login(&api).await.unwrap(),
+            LoginStatus::LoggedIn(_)
+        ));
+
+        let api = FixtureApi::default().with(PROFILE_URL, 302, "");
+        assert!(matches!(
+            check_login(&api).await.unwrap(),
+            LoginStatus::LoggedOut
+        ));
+
+        let api = FixtureApi::default().with(PROFILE_URL, 500, "");
+        assert!(check_login(&api).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn check_login_reads_account_details() {
+        let api = FixtureApi::default()
+            .with(PROFILE_URL, 200, "<html></html>")
+            .with(
+                ACCOUNT_API_URL,
+                200,
+                r#"{"email": "reader@example.com",
+                    "subscription": {"type": "Individual", "expiration_date": "2030-01-31T00:00:00Z"}}"#,
+            );
+        let LoginStatus::LoggedIn(account) = check_login(&api).await.unwrap() else {
+            panic!("expected to be logged in");
+        };
+        assert_eq!(account.email.as_deref(), Some("reader@example.com"));
+        let sub = account.subscription.unwrap();
+        assert_eq!(sub.kind.as_deref(), Some("Individual"));
+        assert_eq!(sub.expires.as_deref(), Some("2030-01-31T00:00:00Z"));
+    }
+
+    #[test]
+    fn days_until_expiry_counts_calendar_days() {
+        let sub = Subscription {
+            kind: None,
+            expires: Some("2024-03-01".to_string()),
+        };
+        // 2024-02-28T12:00:00Z; 2024 is a leap year.
+        let now = UNIX_EPOCH + Duration::from_secs(1_709_121_600);
+        assert_eq!(sub.days_until_expiry(now), Some(2));
+
+        let expired = Subscription {
+            kind: None,
+            expires: Some("2024-02-01".to_string()),
+        };
+        assert_eq!(expired.days_until_expiry(now), Some(-27));
+        assert_eq!(Subscription::default().days_until_expiry(now), None);
+    }
+
+    #[test]
+    fn iso_date_formats_utc_days() {
+        // 2024-02-28T12:00:00Z
+        let now = UNIX_EPOCH + Duration::from_secs(1_709_121_600);
+        assert_eq!(iso_date(now), "2024-02-28");
+        assert_eq!(
+            iso_date(UNIX_EPOCH + Duration::from_secs(951_825_600)),
+            "2000-02-29"
+        );
+    }
+
+    #[test]
+    fn iso_datetime_formats_utc_seconds() {
+        // 2024-02-28T12:01:01Z
+        let now = UNIX_EPOCH + Duration::from_secs(1_709_121_661);
+        assert_eq!(iso_datetime(now), "2024-02-28T12:01:01Z");
+        assert_eq!(iso_datetime(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    }
+
+    #[tokio::test]
+    async fn searches_the_catalog() {
+        let url = search_api_url(DEFAULT_BASE_URL, "learning python 5th", 2);
+        assert_eq!(
+            url,
+            "https://learning.oreilly.com/api/v2/search/?query=learning+python+5th&limit=2"
+        );
+        let api = FixtureApi::default().with(
+            &url,
+            200,
+            json!({
+                "results": [
+                    { "archive_id": "9781449355722", "title": "Learning Python, 5th Edition" },
+                    { "archive_id": "9781098171292", "title": "Learning Python, 6th Edition" },
+                    { "archive_id": "9781492051367", "title": "Python Pocket Reference" }
+                ],
+                "next": null
+            })
+            .to_string(),
+        );
+        let found = search(&api, "learning python 5th", 2).await.unwrap();
+        let ids: Vec<_> = found.iter().filter_map(|item| item.id()).collect();
+        assert_eq!(ids, ["9781449355722", "9781098171292"]);
+        assert!(search(&api, "other", 2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_v2_api() {
+        let api = FixtureApi::default()
+            .with(&book_api_url(DEFAULT_BASE_URL, "123"), 410, "")
+            .with(
+                &epub_api_url(DEFAULT_BASE_URL, "123"),
+                200,
+                json!({
+                    "title": "Newer Book",
+                    "publication_date": "2025-03-04T00:00:00Z",
+                    "authors": [{ "name": "A. Author" }],
+                    "publishers": [{ "name": "O'Reilly Media, Inc." }],
+                    "descriptions": { "text/plain": "Plain", "text/html": "<p>Rich</p>" },
+                    "language": "en",
+                    "is_early_release": true
+                })
+                .to_string(),
+            );
+        let info = fetch_book_info(&api, "123").await.unwrap();
+        assert_eq!(info.title, "Newer Book");
+        assert_eq!(
+            info.web_url,
+            "https://learning.oreilly.com/library/view/-/123/"
+        );
+        assert_eq!(info.issued.as_deref(), Some("2025-03-04"));
+        assert_eq!(info.author_names(), ["A. Author"]);
+        assert_eq!(info.description.as_deref(), Some("<p>Rich</p>"));
+        assert_eq!(info.product_type(), ProductType::Book);
+        assert!(info.early_release);
+        assert!(info.chapters.is_none());
+
+        let api = FixtureApi::default()
+            .with(&book_api_url(DEFAULT_BASE_URL, "123"), 404, "")
+            .with(&epub_api_url(DEFAULT_BASE_URL, "123"), 500, "");
+        let err = fetch_book_info(&api, "123").await.unwrap_err();
+        assert!(err.downcast_ref::<BookNotFound>().is_none());
+    }
+
+    #[tokio::test]
+    async fn fetch_book_info_reports_missing_books() {
+        let api = FixtureApi::default();
+        let err = fetch_book_info(&api, "0000").await.unwrap_err();
+        assert!(err.to_string().contains("Book not found"));
+        assert!(err.downcast_ref::<BookNotFound>().is_some());
+
+        let api = FixtureApi::default().with(
+            &book_api_url(DEFAULT_BASE_URL, "123"),
+            200,
+            r#"{"title": "T", "web_url": "https://learning.oreilly.com/library/view/t/123/"}"#,
+        );
+        assert_eq!(fetch_book_info(&api, "123").await.unwrap().title, "T");
+    }
+
+    #[tokio::test]
+    async fn paginates_until_next_is_null() {
+        let mut requested = Vec::new();
+        let chapters = paginate_chapters(
+            "https://learning.oreilly.com/api/v1/book/9781491946237/chapter/".to_string(),
+            |url| {
+                requested.push(url.clone());
+                let body = if url.ends_with("?page=2") {
+                    PAGE_2
+                } else {
+                    PAGE_1
+                };
+                async move { Ok(serde_json::from_str::<ChapterPage>(body)?) }
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(requested.len(), 2);
+        let names: Vec<_> = chapters.iter().map(|c| c.filename.as_str()).collect();
+        assert_eq!(names, vec!["preface01.html", "ch01.html", "ch02.html"]);
+        assert_eq!(chapters[2].title, "2. An Array of Sequences");
+    }
+
+    #[tokio::test]
+    async fn pagination_stops_on_loop() {
+        let err = paginate_chapters("https://example.com/chapter/".to_string(), |_| async {
+            Ok(serde_json::from_value::<ChapterPage>(json!({
+                "next": "https://example.com/chapter/",
+                "results": []
+            }))?)
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("loops back"));
+    }
+
+    #[tokio::test]
+    async fn pagination_propagates_page_errors() {
+        let err = paginate_chapters("https://example.com/chapter/".to_string(), |_| async {
+            Err::<ChapterPage, _>(anyhow!("Got status: 500"))
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("500"));
+    }
+
+    #[test]
+    fn product_type_from_format() {
+        assert_eq!(ProductType::from_format("book"), ProductType::Book);
+        assert_eq!(ProductType::from_format("Video"), ProductType::Video);
+        assert_eq!(
+            ProductType::from_format("audiobook"),
+            ProductType::Audiobook
+        );
+        assert_eq!(
+            ProductType::from_format("live-event"),
+            ProductType::Other("live-event".to_string())
+        );
+    }
+
+    #[test]
+    fn book_info_reads_content_format_and_defaults_to_book() {
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "A Course",
+            "web_url": "https://learning.oreilly.com/videos/a-course/123/",
+            "content_format": "video"
+        }))
+        .unwrap();
+        assert_eq!(info.product_type(), ProductType::Video);
+
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "A Book",
+            "web_url": "https://learning.oreilly.com/library/view/a-book/456/"
+        }))
+        .unwrap();
+        assert_eq!(info.product_type(), ProductType::Book);
+        assert!(info.authors.is_empty());
+        assert_eq!(info.page_count, None);
+    }
+
+    #[test]
+    fn book_info_reads_full_payload() {
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "Fluent Python",
+            "web_url": "https://learning.oreilly.com/library/view/fluent-python/9781491946237/",
+            "format": "book",
+            "authors": [{ "name": "Luciano Ramalho" }],
+            "publishers": [{ "id": 1, "name": "O'Reilly Media, Inc." }],
+            "issued": "2015-07-30",
+            "isbn": "9781491946008",
+            "description": "<p>Python's simplicity...</p>",
+            "subjects": [{ "name": "Python", "slug": "python" }],
+            "rights": "Copyright © 2015 Luciano Ramalho",
+            "pagecount": 792,
+            "chapters": "https://learning.oreilly.com/api/v1/book/9781491946237/chapter/",
+            "toc": "https://learning.oreilly.com/api/v1/book/9781491946237/toc/"
+        }))
+        .unwrap();
+        assert_eq!(info.author_names(), vec!["Luciano Ramalho"]);
+        assert_eq!(info.publisher(), Some("O'Reilly Media, Inc."));
+        assert_eq!(info.issued.as_deref(), Some("2015-07-30"));
+        assert_eq!(info.isbn.as_deref(), Some("9781491946008"));
+        assert_eq!(info.subject_names(), ["Python"]);
+        assert_eq!(info.page_count, Some(792));
+        assert!(info.chapters.as_deref().unwrap().ends_with("/chapter/"));
+        assert!(info.toc.as_deref().unwrap().ends_with("/toc/"));
+    }
+
+    #[test]
+    fn topics_and_subjects_become_subject_names() {
+        let info: BookInfo = serde_json::from_value(json!({
+            "title": "T",
+            "web_url": "https://learning.oreilly.com/library/view/t/1/",
+            "topics": [
+                { "name": "Web  Development", "score": 0.2 },
+                { "name": "Python", "slug": "python", "score": 0.9 }
+            ],
+            "subjects": ["Django", "python", " "]
+        }))
+        .unwrap();
+        assert_eq!(
+            info.subject_names(),
+            ["Python", "Web Development", "Django"]
+        );
+    }
+
+    #[test]
+    fn listed_items_find_their_id() {
+        let item = |v: serde_json::Value| serde_json::from_value::<ListedItem>(v).unwrap();
+        assert_eq!(
+            item(json!({ "archive_id": "9781491958698", "ourn": "urn:orm:book:x" })).id(),
+            Some("9781491958698")
+        );
+        assert_eq!(
+            item(json!({ "ourn": "urn:orm:video:9780135" })).id(),
+            Some("9780135")
+        );
+        assert_eq!(
+            item(
+                json!({ "url": "https://learning.oreilly.com/library/view/rust/9781098/ch01.html" })
+            )
+            .id(),
+            Some("9781098")
+        );
+        assert_eq!(item(json!({ "title": "No ID" })).id(), None);
+    }
+
+    #[tokio::test]
+    async fn fetches_history_and_playlists() {
+        let api = FixtureApi::default()
+            .with(
+                "https://learning.oreilly.com/api/v2/me/history/",
+                200,
+                json!({
+                    "results": [{ "archive_id": "1", "title": "One" }],
+                    "next": "https://learning.oreilly.com/api/v2/me/history/?page=2"
+                })
+                .to_string(),
+            )
+            .with(
+                "https://learning.oreilly.com/api/v2/me/history/?page=2",
+                200,
+                json!({ "results": [{ "ourn": "urn:orm:book:2" }], "next": null }).to_string(),
+            )
+            .with(
+                "https://learning.oreilly.com/api/v3/collections/",
+                200,
+                json!([{ "name": "Rust", "content": [{ "ourn": "urn:orm:book:3" }] }]).to_string(),
+            );
+        let history = fetch_history(&api).await.unwrap();
+        let ids: Vec<_> = history.iter().filter_map(ListedItem::id).collect();
+        assert_eq!(ids, ["1", "2"]);
+        let playlists = fetch_playlists(&api).await.unwrap();
+        assert_eq!(playlists[0].name, "Rust");
+        assert_eq!(playlists[0].content[0].id(), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn fetches_annotations() {
+        let api = FixtureApi::default().with(
+            "https://learning.oreilly.com/api/v1/book/1/annotations/",
+            200,
+            json!({ "results": [{ "chapter_title": "Intro", "highlight": "Hi" }] }).to_string(),
+        );
+        let notes = fetch_annotations(&api, "1").await.unwrap();
+        assert_eq!(notes[0].quote.as_deref(), Some("Hi"));
+    }
+
+    #[tokio::test]
+    async fn fetches_reading_position() {
+        let api = FixtureApi::default().with(
+            "https://learning.oreilly.com/api/v1/book/1/position/",
+            200,
+            json!({ "chapter": "ch03.html", "anchor": "idm42", "percentage": 0.4 }).to_string(),
+        );
+        let pos = fetch_reading_position(&api, "1").await.unwrap().unwrap();
+        assert_eq!(pos.chapter_filename, "ch03.html");
+        assert_eq!(pos.fragment.as_deref(), Some("idm42"));
+        assert_eq!(pos.progress, Some(0.4));
+        // Never opened.
+        assert_eq!(fetch_reading_position(&api, "2").await.unwrap(), None);
     }
-    bail!("Got status: {}", status)
 }
@@ -0,0 +1,423 @@
+use crate::checksum::{sha256_bytes, write_manifest};
+use crate::epub::{media_type_for, EpubSkeleton};
+use crate::i18n::t;
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use zip::write::{PreparedZipFile, SimpleFileOptions, ZipFileBuilder};
+use zip::{CompressionMethod, ZipWriter};
+
+/// How the entries of the EPUB are compressed (`--compression`,
+/// `--store-images`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// Deflate level from 1 to 9; 0 stores every entry.
+    pub level: u8,
+    /// Store images, which are compressed already, instead of deflating them.
+    pub store_images: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            level: 6,
+            store_images: false,
+        }
+    }
+}
+
+impl Compression {
+    /// Whether the entry `name` is stored rather than deflated.
+    fn stores(&self, name: &str) -> bool {
+        self.level == 0 || (self.store_images && media_type_for(name).starts_with("image/"))
+    }
+
+    /// How the entry `name` is written.
+    fn options(&self, name: &str) -> SimpleFileOptions {
+        if self.stores(name) {
+            SimpleFileOptions::default().compression_method(CompressionMethod::Stored)
+        } else {
+            SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .compression_level(Some(i64::from(self.level)))
+        }
+    }
+}
+
+/// Zip the skeleton directory into an `.epub` file at `dest`.
+/// `mimetype` goes first and uncompressed as required by OCF; everything
+/// else under META-INF/ and OEBPS/ is compressed as `compression` says. A
+/// checksum manifest of every entry is written next to it; the entries and
+/// their digests are returned.
+pub fn package_epub(
+    skeleton: &EpubSkeleton,
+    dest: &Path,
+    compression: Compression,
+) -> Result<Vec<(String, String)>> {
+    let mut zip = start_epub(dest)?;
+    let mut digests = vec![mimetype_digest()];
+    for dir in [&skeleton.meta_inf, &skeleton.oebps] {
+        for path in collect_files(dir)? {
+            let name = archive_name(&skeleton.root, &path);
+            let digest = add_file(&mut zip, &name, &path, compression)?;
+            digests.push((name, digest));
+        }
+    }
+
+    zip.finish()?;
+    write_manifest(dest, &digests)?;
+    Ok(digests)
+}
+
+const MIMETYPE: &[u8] = b"application/epub+zip";
+
+/// Create the archive at `path` with its `mimetype` entry.
+fn start_epub(path: &Path) -> Result<ZipWriter<File>> {
+    let file = File::create(path)
+        .with_context(|| t!("creating-file", path = path.display().to_string()))?;
+    let mut zip = ZipWriter::new(file);
+    zip.start_file(
+        "mimetype",
+        SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(MIMETYPE)?;
+    Ok(zip)
+}
+
+fn mimetype_digest() -> (String, String) {
+    ("mimetype".to_string(), sha256_bytes(MIMETYPE))
+}
+
+/// Add the file at `path` as the entry `name`, returning its digest.
+fn add_file(
+    zip: &mut ZipWriter<File>,
+    name: &str,
+    path: &Path,
+    compression: Compression,
+) -> Result<String> {
+    let data =
+        fs::read(path).with_context(|| t!("reading-file", path = path.display().to_string()))?;
+    zip.start_file(name, compression.options(name))?;
+    zip.write_all(&data)?;
+    Ok(sha256_bytes(&data))
+}
+
+/// An EPUB packaged while its book is still being downloaded: each asset is
+/// compressed into it, on a blocking thread, as soon as it is saved, which
+/// leaves only the chapters and package documents to `finish`. It is written
+/// to a ".part" file next to its destination, removed again if the download
+/// never gets to `finish`.
+pub struct EpubStream {
+    dest: PathBuf,
+    part: PathBuf,
+    compression: Compression,
+    /// Taken by `finish`; an addition still running when the download was
+    /// cancelled finds it gone.
+    inner: Arc<Mutex<Option<Streamed>>>,
+}
+
+struct Streamed {
+    zip: ZipWriter<File>,
+    /// The entries added so far, in archive order.
+    entries: Vec<StreamedEntry>,
+}
+
+/// A file as it was when it went into the archive.
+struct StreamedEntry {
+    name: String,
+    path: PathBuf,
+    digest: String,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl StreamedEntry {
+    /// Whether the file is still what went into the archive: assets are
+    /// rewritten (SVG references) or removed (duplicates, inlined images)
+    /// after they are saved.
+    fn is_current(&self) -> bool {
+        fs::metadata(&self.path)
+            .is_ok_and(|m| m.len() == self.len && m.modified().ok() == self.modified)
+    }
+}
+
+impl EpubStream {
+    /// Start the EPUB that `finish` moves to `dest`.
+    pub fn create(dest: &Path, compression: Compression) -> Result<Self> {
+        let mut part = dest.as_os_str().to_os_string();
+        part.push(".part");
+        let part = PathBuf::from(part);
+        let zip = start_epub(&part)?;
+        Ok(Self {
+            dest: dest.to_path_buf(),
+            part,
+            compression,
+            inner: Arc::new(Mutex::new(Some(Streamed {
+                zip,
+                entries: Vec::new(),
+            }))),
+        })
+    }
+
+    /// Compress the file at `path`, below the book directory `root`, into
+    /// the archive. Nothing is lost when this fails: `finish` adds every
+    /// file not in the archive yet, and reports what still fails then.
+    pub async fn add(&self, root: &Path, path: &Path) {
+        let name = archive_name(root, path);
+        let options = self.compression.options(&name);
+        let path = path.to_path_buf();
+        let inner = Arc::clone(&self.inner);
+        let _ = tokio::task::spawn_blocking(move || -> Result<()> {
+            let (file, entry) = prepare(name, path, options)?;
+            if let Some(streamed) = inner.lock().unwrap().as_mut() {
+                streamed.zip.add_prepared_file(file)?;
+                streamed.entries.push(entry);
+            }
+            Ok(())
+        })
+        .await;
+    }
+
+    /// Add what the download wrote since, drop what it removed or changed,
+    /// and move the EPUB into place with its checksum manifest. Returns the
+    /// entries and their digests, like `package_epub`; the order of those
+    /// streamed is the order they were saved in.
+    pub fn finish(self, skeleton: &EpubSkeleton) -> Result<Vec<(String, String)>> {
+        let Some(streamed) = self.inner.lock().unwrap().take() else {
+            bail!(t!(
+                "epub-already-finished",
+                path = self.dest.display().to_string()
+            ));
+        };
+        let digests = self.write_rest(streamed, skeleton);
+        if digests.is_err() {
+            let _ = fs::remove_file(&self.part);
+        }
+        digests
+    }
+
+    fn write_rest(
+        &self,
+        streamed: Streamed,
+        skeleton: &EpubSkeleton,
+    ) -> Result<Vec<(String, String)>> {
+        let Streamed { mut zip, entries } = streamed;
+        let mut digests = vec![mimetype_digest()];
+        let mut added = HashSet::new();
+        for entry in entries {
+            if entry.is_current() {
+                added.insert(entry.name.clone());
+                digests.push((entry.name, entry.digest));
+            } else {
+                // Out of the central directory; its bytes stay behind, unused.
+                zip.hide_file(&entry.name)?;
+            }
+        }
+        for dir in [&skeleton.meta_inf, &skeleton.oebps] {
+            for path in collect_files(dir)? {
+                let name = archive_name(&skeleton.root, &path);
+                if added.contains(&name) {
+                    continue;
+                }
+                let digest = add_file(&mut zip, &name, &path, self.compression)?;
+                digests.push((name, digest));
+            }
+        }
+
+        zip.finish()?;
+        fs::rename(&self.part, &self.dest)
+            .with_context(|| t!("writing-file", path = self.dest.display().to_string()))?;
+        write_manifest(&self.dest, &digests)?;
+        Ok(digests)
+    }
+}
+
+impl Drop for EpubStream {
+    fn drop(&mut self) {
+        // Never finished: the download failed or was cancelled.
+        if let Some(streamed) = self.inner.lock().unwrap().take() {
+            drop(streamed);
+            let _ = fs::remove_file(&self.part);
+        }
+    }
+}
+
+/// Read and compress the file at `path` as the entry `name`.
+fn prepare(
+    name: String,
+    path: PathBuf,
+    options: SimpleFileOptions,
+) -> Result<(PreparedZipFile, StreamedEntry)> {
+    let metadata = fs::metadata(&path)
+        .with_context(|| t!("reading-file", path = path.display().to_string()))?;
+    let data =
+        fs::read(&path).with_context(|| t!("reading-file", path = path.display().to_string()))?;
+    let mut builder = ZipFileBuilder::new(&name, options)?;
+    builder.write_all(&data)?;
+    let entry = StreamedEntry {
+        digest: sha256_bytes(&data),
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+        name,
+        path,
+    };
+    Ok((builder.finish()?, entry))
+}
+
+/// Recursively list files below `dir`, sorted for reproducible archives,
+/// leaving out partial downloads.
+fn collect_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .with_context(|| t!("reading-directory", path = dir.display().to_string()))?
+        .collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            files.extend(collect_files(&path)?);
+        } else if path
+            .extension()
+            .is_some_and(|e| e == "part" || e == "partinfo")
+        {
+            // An interrupted download, not part of the book.
+            continue;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Archive entry name: path relative to the book root, always '/'-separated.
+fn archive_name(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{package_epub, Compression, EpubStream};
+    use crate::epub::EpubSkeleton;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use zip::{CompressionMethod, ZipArchive};
+
+    #[test]
+    fn compresses_as_configured() {
+        let root = std::env::temp_dir().join(format!("safaribooks-package-{}", std::process::id()));
+        let skeleton = EpubSkeleton {
+            meta_inf: root.join("META-INF"),
+            oebps: root.join("OEBPS"),
+            epub: root.join("Book.epub"),
+            root: root.clone(),
+            language: "en".to_string(),
+            legacy_names: false,
+        };
+        skeleton.create_dirs().unwrap();
+        skeleton.write_container_xml().unwrap();
+        fs::write(skeleton.oebps.join("ch01.xhtml"), "<p/>".repeat(100)).unwrap();
+        fs::write(skeleton.oebps.join("cover.jpg"), [0xff; 100]).unwrap();
+
+        let methods = |compression| {
+            package_epub(&skeleton, &skeleton.epub, compression).unwrap();
+            let mut zip = ZipArchive::new(File::open(&skeleton.epub).unwrap()).unwrap();
+            ["mimetype", "OEBPS/ch01.xhtml", "OEBPS/cover.jpg"]
+                .map(|name| zip.by_name(name).unwrap().compression())
+        };
+        use CompressionMethod::{Deflated, Stored};
+        assert_eq!(
+            methods(Compression::default()),
+            [Stored, Deflated, Deflated]
+        );
+        let store_images = Compression {
+            level: 9,
+            store_images: true,
+        };
+        assert_eq!(methods(store_images), [Stored, Deflated, Stored]);
+        let store = Compression {
+            level: 0,
+            store_images: false,
+        };
+        assert_eq!(methods(store), [Stored, Stored, Stored]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_assets_as_they_are_saved() {
+        let root = std::env::temp_dir().join(format!("safaribooks-stream-{}", std::process::id()));
+        let skeleton = EpubSkeleton {
+            meta_inf: root.join("META-INF"),
+            oebps: root.join("OEBPS"),
+            epub: root.join("Book.epub"),
+            root: root.clone(),
+            language: "en".to_string(),
+            legacy_names: false,
+        };
+        skeleton.create_dirs().unwrap();
+        skeleton.write_container_xml().unwrap();
+        let images = skeleton.oebps.join("Images");
+        fs::create_dir_all(&images).unwrap();
+        for name in ["a.png", "b.png", "c.svg"] {
+            fs::write(images.join(name), name).unwrap();
+        }
+
+        let epub = EpubStream::create(&skeleton.epub, Compression::default()).unwrap();
+        for name in ["a.png", "b.png", "c.svg"] {
+            epub.add(&root, &images.join(name)).await;
+        }
+        assert!(!skeleton.epub.exists());
+        // A duplicate is dropped, a figure rewritten, the chapters written.
+        fs::remove_file(images.join("b.png")).unwrap();
+        fs::write(images.join("c.svg"), "<svg/>").unwrap();
+        fs::write(skeleton.oebps.join("ch01.xhtml"), "<p/>").unwrap();
+        let digests = epub.finish(&skeleton).unwrap();
+
+        let mut zip = ZipArchive::new(File::open(&skeleton.epub).unwrap()).unwrap();
+        let names: Vec<String> = (0..zip.len())
+            .map(|i| zip.by_index(i).unwrap().name().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "mimetype",
+                "OEBPS/Images/a.png",
+                "META-INF/container.xml",
+                "OEBPS/Images/c.svg",
+                "OEBPS/ch01.xhtml"
+            ]
+        );
+        assert_eq!(
+            digests
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            names
+        );
+        let mut svg = String::new();
+        zip.by_name("OEBPS/Images/c.svg")
+            .unwrap()
+            .read_to_string(&mut svg)
+            .unwrap();
+        assert_eq!(svg, "<svg/>");
+        assert!(!root.join("Book.epub.part").exists());
+
+        // One never finished leaves nothing behind.
+        let abandoned = root.join("Other.epub");
+        let epub = EpubStream::create(&abandoned, Compression::default()).unwrap();
+        epub.add(&root, &images.join("a.png")).await;
+        drop(epub);
+        assert!(!root.join("Other.epub.part").exists());
+        assert!(!abandoned.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
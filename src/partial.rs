@@ -0,0 +1,167 @@
+//! Partial downloads (`<file>.part`) that a later run resumes with a
+//! `Range` request instead of starting again from byte zero.
+
+use crate::i18n::t;
+use crate::throttle::RateLimit;
+use anyhow::{bail, Context, Result};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_RANGE, ETAG, LAST_MODIFIED};
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Sidecar of a partial download: what the server called the full file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct PartInfo {
+    /// ETag (or Last-Modified) of the response, sent back as `If-Range`
+    /// so a changed file is downloaded whole rather than spliced.
+    validator: String,
+    /// Full size in bytes, when the server told.
+    total: Option<u64>,
+}
+
+fn info_path(part: &Path) -> PathBuf {
+    let mut name = part.file_name().unwrap_or_default().to_os_string();
+    name.push("info");
+    part.with_file_name(name)
+}
+
+/// Where to resume `part` from, with the `If-Range` validator to send.
+/// Only partial files with a recorded validator can be resumed.
+pub fn resume_point(part: &Path) -> Option<(u64, String)> {
+    let len = fs::metadata(part).ok()?.len();
+    let raw = fs::read_to_string(info_path(part)).ok()?;
+    let info: PartInfo = serde_json::from_str(&raw).ok()?;
+    (len > 0).then_some((len, info.validator))
+}
+
+/// Forget a partial download, e.g. after the server refused the range.
+pub fn discard(part: &Path) {
+    let _ = fs::remove_file(part);
+    let _ = fs::remove_file(info_path(part));
+}
+
+/// "bytes 100-999/1000" -> (100, Some(1000)); the total may be "*".
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let start = range.split_once('-')?.0.trim().parse().ok()?;
+    Some((start, total.trim().parse().ok()))
+}
+
+fn header_u64(headers: &HeaderMap, name: reqwest::header::HeaderName) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Write the body of a 200 (whole file) or 206 (the rest from `offset`)
+/// response to `part`, paced by `limit`, then check it reached the advertised size.
+pub async fn write(
+    res: &mut Response,
+    part: &Path,
+    offset: u64,
+    limit: Option<&RateLimit>,
+) -> Result<()> {
+    let headers = res.headers();
+    let (start, total) = if res.status().as_u16() == 206 {
+        headers
+            .get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_content_range)
+            .with_context(|| t!("range-missing"))?
+    } else {
+        (0, header_u64(headers, CONTENT_LENGTH))
+    };
+    if start != offset && start != 0 {
+        bail!(t!(
+            "resumed-elsewhere",
+            start = start.to_string(),
+            offset = offset.to_string()
+        ));
+    }
+
+    let file = if start == 0 {
+        let validator = headers
+            .get(ETAG)
+            .or_else(|| headers.get(LAST_MODIFIED))
+            .and_then(|v| v.to_str().ok());
+        match validator {
+            Some(validator) => {
+                let info = PartInfo {
+                    validator: validator.to_string(),
+                    total,
+                };
+                let path = info_path(part);
+                fs::write(&path, serde_json::to_vec(&info)?)
+                    .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+            }
+            None => {
+                let _ = fs::remove_file(info_path(part));
+            }
+        }
+        File::create(part)
+    } else {
+        OpenOptions::new().append(true).open(part)
+    }
+    .with_context(|| t!("opening-file", path = part.display().to_string()))?;
+
+    let mut out = BufWriter::with_capacity(64 * 1024, file);
+    while let Some(chunk) = res.chunk().await? {
+        if let Some(limit) = limit {
+            limit.take(chunk.len()).await;
+        }
+        out.write_all(&chunk)
+            .with_context(|| t!("writing-file", path = part.display().to_string()))?;
+    }
+    out.flush()
+        .with_context(|| t!("writing-file", path = part.display().to_string()))?;
+    drop(out);
+
+    let written = fs::metadata(part)
+        .with_context(|| t!("reading-file", path = part.display().to_string()))?
+        .len();
+    if let Some(total) = total
+        && written != total
+    {
+        bail!(t!(
+            "download-stopped",
+            written = written.to_string(),
+            total = total.to_string()
+        ));
+    }
+    let _ = fs::remove_file(info_path(part));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{info_path, parse_content_range, resume_point, PartInfo};
+    use std::fs;
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 100-999/1000"),
+            Some((100, Some(1000)))
+        );
+        assert_eq!(parse_content_range("bytes 5-9/*"), Some((5, None)));
+        assert_eq!(parse_content_range("items 1-2/3"), None);
+    }
+
+    #[test]
+    fn resumes_only_with_a_validator() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-partial-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let part = dir.join("fig.png.part");
+        fs::write(&part, b"12345").unwrap();
+        assert_eq!(resume_point(&part), None);
+
+        let info = PartInfo {
+            validator: "\"abc\"".to_string(),
+            total: Some(10),
+        };
+        fs::write(info_path(&part), serde_json::to_vec(&info).unwrap()).unwrap();
+        assert_eq!(info_path(&part), dir.join("fig.png.partinfo"));
+        assert_eq!(resume_point(&part), Some((5, "\"abc\"".to_string())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
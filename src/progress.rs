@@ -0,0 +1,160 @@
+//! How far a download has got and, from its recent pace, how long the rest
+//! will take.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The pace of a book is measured over the last this long: long enough to
+/// smooth over the odd slow asset, short enough to follow a rate-limit
+/// pause as it happens and to recover once it is over.
+pub const BOOK_WINDOW: Duration = Duration::from_secs(60);
+
+/// Books take minutes each; a batch is paced over more of them.
+pub const BATCH_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// No estimate before this long: the first steps say little of the pace.
+const WARM_UP: Duration = Duration::from_secs(5);
+
+/// How often the time left is reported.
+const REPORT_EVERY: Duration = Duration::from_secs(15);
+
+/// Steps done (chapters and assets of a book, or books of a batch) out of
+/// a total that can still grow as more are found.
+#[derive(Debug)]
+pub struct Progress {
+    total: usize,
+    done: usize,
+    window: Duration,
+    /// When so many steps were done, the oldest first, within the window
+    /// (the last one is kept however old it gets).
+    samples: VecDeque<(Instant, usize)>,
+    reported: Instant,
+}
+
+impl Progress {
+    pub fn new(total: usize, window: Duration) -> Self {
+        Self::starting_at(total, window, Instant::now())
+    }
+
+    fn starting_at(total: usize, window: Duration, now: Instant) -> Self {
+        Self {
+            total,
+            done: 0,
+            window,
+            samples: VecDeque::from([(now, 0)]),
+            reported: now,
+        }
+    }
+
+    /// More steps to do, found on the way.
+    pub fn add(&mut self, steps: usize) {
+        self.total += steps;
+    }
+
+    pub fn step(&mut self, steps: usize) {
+        self.step_at(steps, Instant::now());
+    }
+
+    fn step_at(&mut self, steps: usize, now: Instant) {
+        self.done += steps;
+        self.samples.push_back((now, self.done));
+        while self.samples.len() > 1
+            && self
+                .samples
+                .front()
+                .is_some_and(|&(at, _)| now.saturating_duration_since(at) > self.window)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn done(&self) -> usize {
+        self.done
+    }
+
+    pub fn total(&self) -> usize {
+        self.total.max(self.done)
+    }
+
+    /// The time left at the recent pace; None until there is one.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.remaining_at(Instant::now())
+    }
+
+    fn remaining_at(&self, now: Instant) -> Option<Duration> {
+        let left = self.total.saturating_sub(self.done);
+        if left == 0 {
+            return Some(Duration::ZERO);
+        }
+        let &(since, then) = self.samples.front()?;
+        // Up to now, not the last step: a pause slows the pace while it lasts.
+        let elapsed = now.saturating_duration_since(since);
+        let steps = self.done - then;
+        if steps == 0 || elapsed < WARM_UP {
+            return None;
+        }
+        Some(elapsed.mul_f64(left as f64 / steps as f64))
+    }
+
+    /// Whether it is time to report the time left again.
+    pub fn report_due(&mut self) -> bool {
+        let now = Instant::now();
+        if now.saturating_duration_since(self.reported) < REPORT_EVERY {
+            return false;
+        }
+        self.reported = now;
+        true
+    }
+}
+
+/// A duration as "1h 05m", "3m 20s" or "45s".
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s:02}s"),
+        (h, m, _) => format!("{h}h {m:02}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_duration, Progress, BOOK_WINDOW};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn estimates_from_the_recent_pace() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut progress = Progress::starting_at(100, BOOK_WINDOW, start);
+        progress.step_at(1, at(1));
+        assert_eq!(progress.remaining_at(at(1)), None);
+
+        // Ten steps in ten seconds: ninety more take ninety.
+        progress.step_at(9, at(10));
+        assert_eq!(progress.remaining_at(at(10)), Some(Duration::from_secs(90)));
+        // A rate-limit pause slows the pace while it lasts...
+        assert_eq!(
+            progress.remaining_at(at(20)),
+            Some(Duration::from_secs(180))
+        );
+        // ...and is forgotten once it is out of the window.
+        for n in 0..40 {
+            progress.step_at(1, at(100 + n));
+        }
+        let left = progress.remaining_at(at(140)).unwrap();
+        assert!(left < Duration::from_secs(90), "{left:?}");
+
+        progress.add(10);
+        assert_eq!(progress.total(), 110);
+        progress.step_at(60, at(141));
+        assert_eq!(progress.remaining_at(at(141)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(200)), "3m 20s");
+        assert_eq!(format_duration(Duration::from_secs(3900)), "1h 05m");
+    }
+}
@@ -0,0 +1,115 @@
+use crate::api::ApiResponse;
+use crate::i18n::t;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where HTTP responses are saved to (`--record`) or served from (`--replay`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fixtures {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+/// Sidecar describing a recorded response; the body lives next to it.
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedMeta {
+    url: String,
+    status: u16,
+}
+
+/// Stable, filesystem-safe file stem for a URL: a readable prefix plus
+/// a 64-bit FNV-1a hash so distinct URLs never collide.
+fn fixture_key(url: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in url.bytes() {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    let readable: String = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(80)
+        .collect();
+    format!("{readable}-{hash:016x}")
+}
+
+/// Save a response under `dir` as `<key>.json` (metadata) + `<key>.body`.
+pub fn save(dir: &Path, url: &str, res: &ApiResponse) -> Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| t!("creating-directory", path = dir.display().to_string()))?;
+    let key = fixture_key(url);
+    let meta = RecordedMeta {
+        url: url.to_string(),
+        status: res.status,
+    };
+    let meta_path = dir.join(format!("{key}.json"));
+    fs::write(&meta_path, serde_json::to_vec_pretty(&meta)?)
+        .with_context(|| t!("writing-file", path = meta_path.display().to_string()))?;
+    let body_path = dir.join(format!("{key}.body"));
+    fs::write(&body_path, &res.body)
+        .with_context(|| t!("writing-file", path = body_path.display().to_string()))?;
+    Ok(())
+}
+
+/// Load a previously recorded response for `url` from `dir`.
+pub fn load(dir: &Path, url: &str) -> Result<ApiResponse> {
+    let key = fixture_key(url);
+    let meta_path = dir.join(format!("{key}.json"));
+    let raw = fs::read(&meta_path).with_context(|| {
+        t!(
+            "no-recorded-response",
+            url = url,
+            path = dir.display().to_string()
+        )
+    })?;
+    let meta: RecordedMeta = serde_json::from_slice(&raw)?;
+    let body_path = dir.join(format!("{key}.body"));
+    let body = fs::read(&body_path)
+        .with_context(|| t!("reading-file", path = body_path.display().to_string()))?;
+    Ok(ApiResponse {
+        status: meta.status,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fixture_key, load, save};
+    use crate::api::ApiResponse;
+    use std::fs;
+
+    #[test]
+    fn keys_are_readable_and_distinct() {
+        let a = fixture_key("https://learning.oreilly.com/api/v1/book/123/chapter/?page=1");
+        let b = fixture_key("https://learning.oreilly.com/api/v1/book/123/chapter/?page=2");
+        assert!(a.starts_with("learning_oreilly_com_api_v1_book_123_chapter"));
+        assert_ne!(a, b);
+        assert_eq!(
+            a,
+            fixture_key("https://learning.oreilly.com/api/v1/book/123/chapter/?page=1")
+        );
+    }
+
+    #[test]
+    fn save_then_load_roundtrips() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-rec-{}", std::process::id()));
+        let url = "https://learning.oreilly.com/api/v1/book/123";
+        let res = ApiResponse {
+            status: 404,
+            body: vec![0, 159, 146, 150],
+        };
+        save(&dir, url, &res).unwrap();
+
+        let back = load(&dir, url).unwrap();
+        assert_eq!(back.status, 404);
+        assert_eq!(back.body, res.body);
+
+        let err = load(&dir, "https://learning.oreilly.com/other").unwrap_err();
+        assert!(err.to_string().contains("No recorded response"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
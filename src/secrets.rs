@@ -0,0 +1,50 @@
+//! Session cookies kept in the OS keyring (Secret Service, macOS Keychain or
+//! Windows Credential Manager) so they aren't left in plaintext on disk.
+
+use crate::cookies::CookieStore;
+use anyhow::Result;
+use keyring::{Entry, Error};
+
+/// Keyring service name every entry is filed under.
+pub const SERVICE: &str = "safaribooks-rs";
+
+/// Keyring account holding the session cookies.
+const COOKIES_ACCOUNT: &str = "cookies";
+
+/// Keyring account for the cookies of `profile` ("cookies@work").
+fn cookies_account(profile: Option<&str>) -> String {
+    match profile {
+        Some(name) => format!("{COOKIES_ACCOUNT}@{name}"),
+        None => COOKIES_ACCOUNT.to_string(),
+    }
+}
+
+fn cookies_entry(profile: Option<&str>) -> Result<Entry> {
+    Ok(Entry::new(SERVICE, &cookies_account(profile))?)
+}
+
+/// Cookies saved by a previous run; `None` when nothing is stored.
+pub fn load_cookies(profile: Option<&str>) -> Result<Option<CookieStore>> {
+    match cookies_entry(profile)?.get_password() {
+        Ok(raw) => Ok(Some(CookieStore::from_raw(&raw)?)),
+        Err(Error::NoEntry) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Save (or replace) the session cookies.
+pub fn save_cookies(profile: Option<&str>, store: &CookieStore) -> Result<()> {
+    cookies_entry(profile)?.set_password(&store.to_json())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cookies_account;
+
+    #[test]
+    fn profiles_get_their_own_account() {
+        assert_eq!(cookies_account(None), "cookies");
+        assert_eq!(cookies_account(Some("work")), "cookies@work");
+    }
+}
@@ -0,0 +1,103 @@
+//! `--series-from-title`: the series a book belongs to, guessed from its
+//! title. O'Reilly names its series in the title ("Head First Go", "Python
+//! in a Nutshell", "Kafka: The Definitive Guide"); a book outside of them
+//! but in its second edition or later makes a series of its editions.
+
+/// Series named at the start of the title.
+const PREFIXES: &[&str] = &["Head First"];
+
+/// Series named at the end of the title, with the name calibre is given.
+const SUFFIXES: &[(&str, &str)] = &[
+    (": The Definitive Guide", "The Definitive Guide"),
+    (" in a Nutshell", "In a Nutshell"),
+    (" Pocket Reference", "Pocket Reference"),
+    (" Pocket Guide", "Pocket Guide"),
+    (" Cookbook", "Cookbook"),
+];
+
+/// The series of the book titled `title` and its position in it, if the
+/// title tells.
+pub fn from_title(title: &str) -> Option<(String, Option<f64>)> {
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    let (base, edition) = match split_edition(&title) {
+        Some((base, edition)) => (base, Some(edition)),
+        None => (title.as_str(), None),
+    };
+    let lower = base.to_lowercase();
+    for prefix in PREFIXES {
+        if lower.starts_with(&format!("{} ", prefix.to_lowercase())) {
+            return Some((prefix.to_string(), None));
+        }
+    }
+    for (suffix, series) in SUFFIXES {
+        if lower.len() > suffix.len() && lower.ends_with(&suffix.to_lowercase()) {
+            return Some((series.to_string(), None));
+        }
+    }
+    edition
+        .filter(|&n| n > 1)
+        .map(|n| (base.to_string(), Some(n as f64)))
+}
+
+/// The title without its ", 2nd Edition" (or " (Second Edition)"), and the
+/// edition's number.
+fn split_edition(title: &str) -> Option<(&str, u32)> {
+    // ASCII-only, so it keeps the title's byte offsets.
+    let lower = title.to_ascii_lowercase();
+    let stripped = lower.trim_end_matches(')').trim_end();
+    let head = stripped.strip_suffix("edition")?.trim_end();
+    let at = head.rfind(|c: char| c.is_whitespace() || c == ',' || c == '(')? + 1;
+    let edition = edition_number(&head[at..])?;
+    let base = title[..at].trim_end_matches(|c: char| c.is_whitespace() || c == ',' || c == '(');
+    (!base.is_empty()).then_some((base, edition))
+}
+
+/// 2 from "2nd" or "second".
+fn edition_number(word: &str) -> Option<u32> {
+    const ORDINALS: &[&str] = &[
+        "first", "second", "third", "fourth", "fifth", "sixth", "seventh", "eighth", "ninth",
+        "tenth",
+    ];
+    if let Some(n) = ORDINALS.iter().position(|o| *o == word) {
+        return Some(n as u32 + 1);
+    }
+    let digits = word.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let suffix = &word[digits.len()..];
+    matches!(suffix, "st" | "nd" | "rd" | "th")
+        .then(|| digits.parse().ok())
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_title;
+
+    #[test]
+    fn guesses_the_series_from_the_title() {
+        let series = |title| from_title(title);
+        assert_eq!(series("Head First Go"), Some(("Head First".into(), None)));
+        assert_eq!(
+            series("Head First Java, 3rd Edition"),
+            Some(("Head First".into(), None))
+        );
+        assert_eq!(
+            series("Kafka: The Definitive Guide, 2nd Edition"),
+            Some(("The Definitive Guide".into(), None))
+        );
+        assert_eq!(
+            series("Python in a Nutshell"),
+            Some(("In a Nutshell".into(), None))
+        );
+        assert_eq!(
+            series("Fluent Python, 2nd Edition"),
+            Some(("Fluent Python".into(), Some(2.0)))
+        );
+        assert_eq!(
+            series("Programming Rust (Second Edition)"),
+            Some(("Programming Rust".into(), Some(2.0)))
+        );
+        assert_eq!(series("Learning Go, 1st Edition"), None);
+        assert_eq!(series("Cookbook"), None);
+        assert_eq!(series("Designing Data-Intensive Applications"), None);
+    }
+}
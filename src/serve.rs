@@ -0,0 +1,357 @@
+//! `serve`: the books root over HTTP (with its OPDS feed) plus a small REST
+//! API to queue downloads, for running the tool as a personal library service.
+
+use crate::bookid;
+use crate::catalog::{url_path, write_catalog, CATALOG_FILE};
+use crate::display::{Display, DownloadEvent};
+use crate::download::{Downloader, Outcome};
+use crate::i18n::t;
+use anyhow::{Context, Result};
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tower_http::services::ServeDir;
+
+/// Where `serve` listens unless told otherwise: this machine only.
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Finished,
+    Skipped,
+    Failed,
+}
+
+/// A queued download, as the API reports it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Job {
+    pub id: usize,
+    pub book_id: String,
+    pub status: JobStatus,
+    /// URL of the EPUB relative to the server root, once there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub href: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Chapters written so far, while it runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<Progress>,
+}
+
+/// How far a running download is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Progress {
+    pub chapter: usize,
+    pub chapters: usize,
+}
+
+/// Downloads asked for through the API, worked through in order.
+#[derive(Clone)]
+pub struct Queue {
+    jobs: Arc<Mutex<Vec<Job>>>,
+    tx: mpsc::UnboundedSender<usize>,
+}
+
+impl Queue {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<usize>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let queue = Self {
+            jobs: Arc::default(),
+            tx,
+        };
+        (queue, rx)
+    }
+
+    pub fn push(&self, book_id: &str) -> Job {
+        let mut jobs = self.jobs.lock().expect("queue lock");
+        let job = Job {
+            id: jobs.len() + 1,
+            book_id: book_id.to_string(),
+            status: JobStatus::Queued,
+            href: None,
+            error: None,
+            progress: None,
+        };
+        jobs.push(job.clone());
+        // The worker only stops with the server.
+        let _ = self.tx.send(job.id);
+        job
+    }
+
+    pub fn get(&self, id: usize) -> Option<Job> {
+        let jobs = self.jobs.lock().expect("queue lock");
+        id.checked_sub(1).and_then(|i| jobs.get(i)).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.lock().expect("queue lock").clone()
+    }
+
+    fn update(&self, id: usize, f: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock().expect("queue lock");
+        if let Some(job) = id.checked_sub(1).and_then(|i| jobs.get_mut(i)) {
+            f(job);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    queue: Queue,
+}
+
+#[derive(Deserialize)]
+struct NewDownload {
+    book_id: String,
+}
+
+/// The HTTP routes: `/api/downloads` (GET lists, POST `{"book_id": ...}`
+/// queues), `/api/downloads/{id}`, and the books root as static files.
+pub fn router(root: &Path, queue: Queue) -> Router {
+    Router::new()
+        .route(
+            "/",
+            get(|| async { Redirect::to(&format!("/{CATALOG_FILE}")) }),
+        )
+        .route("/api/downloads", get(list_downloads).post(queue_download))
+        .route("/api/downloads/{id}", get(get_download))
+        .fallback_service(ServeDir::new(root))
+        .with_state(AppState { queue })
+}
+
+async fn list_downloads(State(state): State<AppState>) -> Json<Vec<Job>> {
+    Json(state.queue.list())
+}
+
+async fn get_download(State(state): State<AppState>, UrlPath(id): UrlPath<usize>) -> Response {
+    match state.queue.get(id) {
+        Some(job) => Json(job).into_response(),
+        None => (StatusCode::NOT_FOUND, "No such download\n").into_response(),
+    }
+}
+
+async fn queue_download(State(state): State<AppState>, Json(req): Json<NewDownload>) -> Response {
+    let book = match bookid::parse(&req.book_id) {
+        Ok(book) => book,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{e}\n")).into_response(),
+    };
+    (StatusCode::ACCEPTED, Json(state.queue.push(&book.id))).into_response()
+}
+
+/// Serve the output directory on `listen` and download queued books one at
+/// a time, refreshing the catalog after each.
+pub async fn run(listen: SocketAddr, downloader: &Downloader, ui: &Display) -> Result<()> {
+    let root = downloader.settings.output_dir();
+    std::fs::create_dir_all(&root)
+        .with_context(|| t!("creating-directory", path = root.display().to_string()))?;
+    write_catalog(&root)?;
+
+    let (queue, mut rx) = Queue::new();
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| t!("listening-failed", listen = listen.to_string()))?;
+    ui.info(&t!(
+        "serving",
+        path = root.display().to_string(),
+        listen = listen.to_string(),
+        catalog = CATALOG_FILE
+    ));
+    let server = axum::serve(listener, router(&root, queue.clone()));
+
+    let worker = async {
+        while let Some(id) = rx.recv().await {
+            let Some(job) = queue.get(id) else {
+                continue;
+            };
+            queue.update(id, |j| j.status = JobStatus::Running);
+            let mut book_ui = ui.clone();
+            let events = book_ui.subscribe();
+            // The display goes with the download, so the events end with it.
+            let download = async move { downloader.download(&mut book_ui, &job.book_id).await };
+            let (result, ()) = tokio::join!(download, track_progress(&queue, id, events));
+            queue.update(id, |j| match result {
+                Ok(Outcome::Finished(path)) => {
+                    j.status = JobStatus::Finished;
+                    j.href = href(&root, &path);
+                }
+                Ok(Outcome::Skipped(path)) => {
+                    j.status = JobStatus::Skipped;
+                    j.href = href(&root, &path);
+                }
+                Err(e) => {
+                    ui.warn(&t!(
+                        "book-failed",
+                        book = j.book_id.as_str(),
+                        error = format!("{e:#}")
+                    ));
+                    j.status = JobStatus::Failed;
+                    j.error = Some(format!("{e:#}"));
+                }
+            });
+            if let Err(e) = write_catalog(&root) {
+                ui.warn(&t!("catalog-update-failed", error = format!("{e:#}")));
+            }
+        }
+    };
+
+    tokio::select! {
+        result = async { server.await } => result.with_context(|| t!("server-failed")),
+        () = worker => Ok(()),
+    }
+}
+
+/// Keep job `id`'s progress up to date from its download's events.
+async fn track_progress(queue: &Queue, id: usize, mut events: broadcast::Receiver<DownloadEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(DownloadEvent::ChapterDone { index, total, .. }) => queue.update(id, |j| {
+                j.progress = Some(Progress {
+                    chapter: index,
+                    chapters: total,
+                })
+            }),
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+fn href(root: &Path, path: &Path) -> Option<String> {
+    path.strip_prefix(root)
+        .ok()
+        .map(|rel| format!("/{}", url_path(rel)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        queue_download, router, track_progress, AppState, JobStatus, NewDownload, Progress, Queue,
+    };
+    use crate::display::{Display, DownloadEvent};
+    use axum::extract::State;
+    use axum::Json;
+    use serde_json::{json, Value};
+    use std::fs;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn queues_the_book_a_request_names() {
+        let (queue, _rx) = Queue::new();
+        let post = |book_id: &str| {
+            let state = State(AppState {
+                queue: queue.clone(),
+            });
+            let req = Json(NewDownload {
+                book_id: book_id.to_string(),
+            });
+            async move { queue_download(state, req).await.status() }
+        };
+        assert_eq!(post("978-1-491-95869-8").await, 202);
+        assert_eq!(post("urn:orm:book:0636920049555").await, 202);
+        assert_eq!(
+            post("https://learning.oreilly.com/library/view/x/9781449355722/").await,
+            202
+        );
+        for bad in ["", "../etc", "9781491958698/"] {
+            assert_eq!(post(bad).await, 400, "{bad:?}");
+        }
+        let ids: Vec<String> = queue.list().into_iter().map(|j| j.book_id).collect();
+        assert_eq!(ids, ["9781491958698", "0636920049555", "9781449355722"]);
+    }
+
+    #[tokio::test]
+    async fn queues_downloads_and_serves_files() {
+        let root = std::env::temp_dir().join(format!("safaribooks-serve-{}", std::process::id()));
+        fs::create_dir_all(root.join("Book (1)")).unwrap();
+        fs::write(root.join("Book (1)/1.epub"), b"epub").unwrap();
+
+        let (queue, mut rx) = Queue::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let app = router(&root, queue.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let http = reqwest::Client::new();
+        let res = http
+            .post(format!("{base}/api/downloads"))
+            .json(&json!({ "book_id": "9781491958698" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 202);
+        let job: Value = res.json().await.unwrap();
+        assert_eq!(
+            job,
+            json!({ "id": 1, "book_id": "9781491958698", "status": "queued" })
+        );
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(queue.get(1).unwrap().status, JobStatus::Queued);
+
+        let res = http
+            .post(format!("{base}/api/downloads"))
+            .json(&json!({ "book_id": "../etc" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 400);
+
+        let list: Value = http
+            .get(format!("{base}/api/downloads"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(list.as_array().map(Vec::len), Some(1));
+        let missing = http
+            .get(format!("{base}/api/downloads/7"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), 404);
+
+        let file = http
+            .get(format!("{base}/Book%20(1)/1.epub"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(file.status(), 200);
+        assert_eq!(file.bytes().await.unwrap().as_ref(), b"epub");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn follows_the_chapters_of_a_running_download() {
+        let (queue, _rx) = Queue::new();
+        queue.push("9781491958698");
+        let mut ui = Display::for_tests();
+        let events = ui.subscribe();
+        ui.event(&DownloadEvent::ChapterDone {
+            index: 2,
+            total: 5,
+            title: "Two".to_string(),
+        });
+        drop(ui);
+        track_progress(&queue, 1, events).await;
+        assert_eq!(
+            queue.get(1).unwrap().progress,
+            Some(Progress {
+                chapter: 2,
+                chapters: 5
+            })
+        );
+    }
+}
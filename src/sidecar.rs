@@ -0,0 +1,265 @@
+//! `--sidecar`: metadata written next to the EPUB for library managers and
+//! scripts. metadata.json carries the API's full description of the book
+//! and where the EPUB came from; metadata.opf is the file calibre reads
+//! when adding a book with its metadata. Both point at the cover, copied
+//! out of the EPUB beside them.
+
+use crate::catalog::cover_extension;
+use crate::checksum::sha256_file;
+use crate::epub::xml_escape;
+use crate::i18n::t;
+use crate::library::{read_book, LibraryBook};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A sidecar file `--sidecar` can write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SidecarFormat {
+    /// metadata.json: API metadata, cover and provenance.
+    Json,
+    /// metadata.opf: calibre's OPF metadata.
+    Opf,
+}
+
+/// Where an EPUB came from.
+pub struct Provenance<'a> {
+    pub book_id: &'a str,
+    /// The book's page on the website.
+    pub web_url: &'a str,
+    /// The API the book was downloaded from, or the `--mirror` it was
+    /// rebuilt from.
+    pub source: &'a str,
+    /// When, ISO 8601.
+    pub created: &'a str,
+    /// The API's description of the book, as it came.
+    pub api: &'a serde_json::Value,
+}
+
+/// Write the `formats` asked for next to `epub`, returning their paths.
+pub fn write(
+    epub: &Path,
+    formats: &[SidecarFormat],
+    provenance: &Provenance,
+) -> Result<Vec<PathBuf>> {
+    let book =
+        read_book(epub).with_context(|| t!("reading-file", path = epub.display().to_string()))?;
+    let dir = epub.parent().unwrap_or(Path::new("."));
+    let cover = write_cover(&book, dir)?;
+    let mut written = Vec::new();
+    for format in formats {
+        let (name, contents) = match format {
+            SidecarFormat::Json => (
+                "metadata.json",
+                serde_json::to_string_pretty(&json(&book, cover.as_deref(), provenance)?)? + "\n",
+            ),
+            SidecarFormat::Opf => ("metadata.opf", opf(&book, cover.as_deref(), provenance)),
+        };
+        let path = dir.join(name);
+        fs::write(&path, contents)
+            .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Copy the cover out of the EPUB as cover.<ext>, returning its name.
+fn write_cover(book: &LibraryBook, dir: &Path) -> Result<Option<String>> {
+    let (Some((_, media_type)), Some(data)) = (&book.cover, book.read_cover()?) else {
+        return Ok(None);
+    };
+    let name = format!("cover.{}", cover_extension(media_type));
+    let path = dir.join(&name);
+    fs::write(&path, data)
+        .with_context(|| t!("writing-file", path = path.display().to_string()))?;
+    Ok(Some(name))
+}
+
+fn json(
+    book: &LibraryBook,
+    cover: Option<&str>,
+    provenance: &Provenance,
+) -> Result<serde_json::Value> {
+    let epub = book.path.file_name().unwrap_or_default().to_string_lossy();
+    Ok(serde_json::json!({
+        "book_id": provenance.book_id,
+        "title": book.title,
+        "authors": book.authors,
+        "identifiers": book.identifiers,
+        "language": book.language,
+        "publisher": book.publisher,
+        "date": book.date,
+        "description": book.description,
+        "subjects": book.subjects,
+        "series": book.series,
+        "series_index": book.series_index,
+        "cover": cover,
+        "epub": epub,
+        "sha256": sha256_file(&book.path)?,
+        "provenance": {
+            "tool": "safaribooks-rs",
+            "version": env!("CARGO_PKG_VERSION"),
+            "web_url": provenance.web_url,
+            "source": provenance.source,
+            "created": provenance.created,
+        },
+        "api": provenance.api,
+    }))
+}
+
+/// The OPF 2.0 package calibre writes next to each book of its library,
+/// metadata only.
+fn opf(book: &LibraryBook, cover: Option<&str>, provenance: &Provenance) -> String {
+    let mut metadata = format!(
+        "    <dc:identifier opf:scheme=\"OREILLY\" id=\"book_id\">{}</dc:identifier>\n",
+        xml_escape(provenance.book_id)
+    );
+    for id in &book.identifiers {
+        if let Some(isbn) = id
+            .strip_prefix("urn:isbn:")
+            .or_else(|| id.strip_prefix("isbn:"))
+        {
+            metadata.push_str(&format!(
+                "    <dc:identifier opf:scheme=\"ISBN\">{}</dc:identifier>\n",
+                xml_escape(isbn)
+            ));
+        }
+    }
+    metadata.push_str(&format!(
+        "    <dc:title>{}</dc:title>\n",
+        xml_escape(&book.title)
+    ));
+    for author in &book.authors {
+        metadata.push_str(&format!(
+            "    <dc:creator opf:role=\"aut\">{}</dc:creator>\n",
+            xml_escape(author)
+        ));
+    }
+    for (tag, value) in [
+        ("dc:publisher", &book.publisher),
+        ("dc:date", &book.date),
+        ("dc:language", &book.language),
+        ("dc:description", &book.description),
+    ] {
+        if let Some(value) = value {
+            metadata.push_str(&format!("    <{tag}>{}</{tag}>\n", xml_escape(value)));
+        }
+    }
+    for subject in &book.subjects {
+        metadata.push_str(&format!(
+            "    <dc:subject>{}</dc:subject>\n",
+            xml_escape(subject)
+        ));
+    }
+    if let Some(series) = &book.series {
+        metadata.push_str(&format!(
+            "    <meta name=\"calibre:series\" content=\"{}\"/>\n",
+            xml_escape(series)
+        ));
+        if let Some(index) = book.series_index {
+            metadata.push_str(&format!(
+                "    <meta name=\"calibre:series_index\" content=\"{index}\"/>\n"
+            ));
+        }
+    }
+    metadata.push_str(&format!(
+        "    <dc:source>{}</dc:source>\n    <meta name=\"calibre:timestamp\" content=\"{}\"/>\n",
+        xml_escape(provenance.web_url),
+        xml_escape(provenance.created)
+    ));
+    let guide = cover.map_or_else(String::new, |cover| {
+        format!(
+            "  <guide>\n    <reference type=\"cover\" title=\"Cover\" href=\"{}\"/>\n  </guide>\n",
+            xml_escape(cover)
+        )
+    });
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" unique-identifier=\"book_id\" version=\"2.0\">\n\
+         \x20 <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">\n\
+         {metadata}\
+         \x20 </metadata>\n\
+         {guide}\
+         </package>\n"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write, Provenance, SidecarFormat};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    #[test]
+    fn writes_json_and_opf_with_the_cover() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-sidecar-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let epub = dir.join("Book.epub");
+        let mut zip = ZipWriter::new(File::create(&epub).unwrap());
+        let opts = SimpleFileOptions::default();
+        zip.start_file("META-INF/container.xml", opts).unwrap();
+        zip.write_all(
+            br#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#,
+        )
+        .unwrap();
+        zip.start_file("OEBPS/content.opf", opts).unwrap();
+        zip.write_all(
+            br#"<package><metadata>
+<dc:identifier>123</dc:identifier><dc:identifier>urn:isbn:9781491958698</dc:identifier>
+<dc:title>Rust &amp; You</dc:title><dc:creator>Jane Doe</dc:creator>
+<dc:language>en</dc:language><dc:subject>Rust</dc:subject>
+<meta name="calibre:series" content="Rust &amp; Friends"/><meta name="calibre:series_index" content="2"/>
+</metadata><manifest>
+<item id="cover" href="Images/cover.png" media-type="image/png" properties="cover-image"/>
+</manifest></package>"#,
+        )
+        .unwrap();
+        zip.start_file("OEBPS/Images/cover.png", opts).unwrap();
+        zip.write_all(b"\x89PNG").unwrap();
+        zip.finish().unwrap();
+
+        let api = serde_json::json!({ "title": "Rust & You", "pagecount": 300 });
+        let provenance = Provenance {
+            book_id: "123",
+            web_url: "https://learning.oreilly.com/library/view/-/123/",
+            source: "https://learning.oreilly.com",
+            created: "2026-10-15T12:00:00Z",
+            api: &api,
+        };
+        let written = write(
+            &epub,
+            &[SidecarFormat::Json, SidecarFormat::Opf],
+            &provenance,
+        )
+        .unwrap();
+        assert_eq!(
+            written,
+            [dir.join("metadata.json"), dir.join("metadata.opf")]
+        );
+        assert_eq!(fs::read(dir.join("cover.png")).unwrap(), b"\x89PNG");
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("metadata.json")).unwrap()).unwrap();
+        assert_eq!(json["title"], "Rust & You");
+        assert_eq!(json["cover"], "cover.png");
+        assert_eq!(json["api"]["pagecount"], 300);
+        assert_eq!(json["series"], "Rust & Friends");
+        assert_eq!(json["series_index"], 2.0);
+        assert_eq!(json["provenance"]["created"], "2026-10-15T12:00:00Z");
+        assert_eq!(json["sha256"].as_str().unwrap().len(), 64);
+
+        let opf = fs::read_to_string(dir.join("metadata.opf")).unwrap();
+        assert!(opf.contains("<dc:title>Rust &amp; You</dc:title>"));
+        assert!(opf.contains("<dc:identifier opf:scheme=\"ISBN\">9781491958698</dc:identifier>"));
+        assert!(opf.contains("<dc:creator opf:role=\"aut\">Jane Doe</dc:creator>"));
+        assert!(opf.contains("<meta name=\"calibre:series\" content=\"Rust &amp; Friends\"/>"));
+        assert!(opf.contains("<meta name=\"calibre:series_index\" content=\"2\"/>"));
+        assert!(opf.contains("<reference type=\"cover\" title=\"Cover\" href=\"cover.png\"/>"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
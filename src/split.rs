@@ -0,0 +1,454 @@
+//! `--split-parts`: very large books written again as several smaller
+//! EPUBs, for readers and devices that cannot open files that big.
+
+use crate::checksum::manifest_path;
+use crate::epub::{EpubSkeleton, Landmark, ManifestItem, NavPoint, PackageMetadata};
+use crate::html::{serialize_xhtml, tokenize, Token};
+use crate::i18n::t;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+
+/// Where the parts are laid out, under the book directory.
+const PARTS_DIR: &str = "parts";
+
+/// Lay the book out again as parts of at most `max` bytes of files each,
+/// when it is larger than that, and return how many parts there are.
+/// Parts of an earlier run are removed first, whether or not this one
+/// splits.
+pub fn write_parts(
+    skeleton: &EpubSkeleton,
+    meta: &PackageMetadata,
+    manifest: &[ManifestItem],
+    spine: &[String],
+    toc: &[NavPoint],
+    max: Option<u64>,
+) -> Result<usize> {
+    remove_layouts(skeleton)?;
+    remove_old_epubs(skeleton)?;
+    let Some(max) = max else {
+        return Ok(0);
+    };
+    let size = |href: &str| fs::metadata(skeleton.oebps.join(href)).map_or(0, |m| m.len());
+    if manifest.iter().map(|item| size(&item.href)).sum::<u64>() <= max {
+        return Ok(0);
+    }
+
+    let parts = partition(skeleton, manifest, spine, max, size);
+    if parts.len() < 2 {
+        return Ok(0);
+    }
+    let documents: HashSet<&str> = manifest
+        .iter()
+        .filter(|item| spine.contains(&item.id))
+        .map(|item| item.href.as_str())
+        .collect();
+    for (n, part) in parts.iter().enumerate() {
+        let n = n + 1;
+        let items: Vec<ManifestItem> = manifest
+            .iter()
+            .filter(|item| part.contains(item.href.as_str()))
+            .cloned()
+            .collect();
+        let spine: Vec<String> = spine
+            .iter()
+            .filter(|id| items.iter().any(|item| &item.id == *id))
+            .cloned()
+            .collect();
+        let mut meta = meta.clone();
+        meta.identifier = format!("{}-part{n}", meta.identifier);
+        if meta.series.is_none() {
+            // Readers that know series keep the parts together, in order.
+            meta.series = Some(meta.title.clone());
+            meta.series_index = Some(n as f64);
+        }
+        meta.title = format!("{} (Part {n} of {})", meta.title, parts.len());
+        let context = PartContext {
+            documents: &documents,
+            here: part,
+        };
+        let part = part_skeleton(skeleton, n);
+        write_part(skeleton, &part, &meta, &items, &spine, toc, &context)
+            .with_context(|| t!("writing-part", part = n))?;
+    }
+    Ok(parts.len())
+}
+
+/// Delete the unpacked parts, once packaged or before laying them out again.
+pub fn remove_layouts(skeleton: &EpubSkeleton) -> Result<()> {
+    let dir = skeleton.root.join(PARTS_DIR);
+    if dir.exists() {
+        fs::remove_dir_all(&dir)
+            .with_context(|| t!("removing-directory", path = dir.display().to_string()))?;
+    }
+    Ok(())
+}
+
+/// Remove the part EPUBs (and their checksum manifests) packaged before.
+fn remove_old_epubs(skeleton: &EpubSkeleton) -> Result<()> {
+    let Some(dir) = skeleton.epub.parent() else {
+        return Ok(());
+    };
+    let stem = skeleton
+        .epub
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let prefix = format!("{stem} - Part ");
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_part = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".epub"))
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+        if is_part {
+            let path = entry.path();
+            fs::remove_file(&path)
+                .with_context(|| t!("removing-file", path = path.display().to_string()))?;
+            let _ = fs::remove_file(manifest_path(&path));
+        }
+    }
+    Ok(())
+}
+
+/// The parts laid out by the last `write_parts`, in order.
+pub fn parts(skeleton: &EpubSkeleton) -> Vec<EpubSkeleton> {
+    (1..)
+        .map(|n| part_skeleton(skeleton, n))
+        .take_while(|part| part.root.is_dir())
+        .collect()
+}
+
+/// Part `n` of the book: laid out under parts/<n>/, packaged next to the
+/// whole book as "<name> - Part <n>.epub".
+fn part_skeleton(skeleton: &EpubSkeleton, n: usize) -> EpubSkeleton {
+    let stem = skeleton
+        .epub
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let mut part = skeleton.relocated(skeleton.root.join(PARTS_DIR).join(n.to_string()));
+    part.epub = skeleton
+        .epub
+        .with_file_name(format!("{stem} - Part {n}.epub"));
+    part
+}
+
+/// Split the spine into runs of documents whose files, with the images
+/// they show and the files every part needs, fit in `max` bytes. A
+/// document larger than that on its own still gets a part. Each part is
+/// the set of hrefs it holds.
+fn partition<'a>(
+    skeleton: &EpubSkeleton,
+    manifest: &'a [ManifestItem],
+    spine: &[String],
+    max: u64,
+    size: impl Fn(&str) -> u64,
+) -> Vec<HashSet<&'a str>> {
+    let is_image = |item: &ManifestItem| item.media_type.starts_with("image/");
+    // Stylesheets and SVG figures name what they use by file name, like
+    // the chapters do.
+    let referrers: Vec<String> = manifest
+        .iter()
+        .filter(|item| item.media_type == "text/css" || item.media_type == "image/svg+xml")
+        .filter_map(|item| fs::read_to_string(skeleton.oebps.join(&item.href)).ok())
+        .collect();
+    let shared: HashSet<&str> = manifest
+        .iter()
+        .filter(|item| !spine.contains(&item.id))
+        .filter(|item| {
+            !is_image(item)
+                || referrers
+                    .iter()
+                    .any(|text| text.contains(basename(&item.href)))
+        })
+        .map(|item| item.href.as_str())
+        .collect();
+    let images: Vec<&str> = manifest
+        .iter()
+        .filter(|item| is_image(item) && !shared.contains(item.href.as_str()))
+        .map(|item| item.href.as_str())
+        .collect();
+    let base: u64 = shared.iter().map(|href| size(href)).sum();
+
+    let mut parts = Vec::new();
+    let mut current = shared.clone();
+    let mut current_size = base;
+    let mut has_documents = false;
+    for id in spine {
+        let Some(document) = manifest.iter().find(|item| &item.id == id) else {
+            continue;
+        };
+        let text = fs::read_to_string(skeleton.oebps.join(&document.href)).unwrap_or_default();
+        let shown: Vec<&str> = images
+            .iter()
+            .copied()
+            .filter(|href| text.contains(basename(href)))
+            .collect();
+        let cost = |part: &HashSet<&str>| {
+            size(&document.href)
+                + shown
+                    .iter()
+                    .filter(|href| !part.contains(*href))
+                    .map(|href| size(href))
+                    .sum::<u64>()
+        };
+        if has_documents && current_size + cost(&current) > max {
+            parts.push(std::mem::replace(&mut current, shared.clone()));
+            current_size = base;
+        }
+        current_size += cost(&current);
+        current.insert(document.href.as_str());
+        current.extend(shown);
+        has_documents = true;
+    }
+    if has_documents {
+        parts.push(current);
+    }
+    parts
+}
+
+fn basename(href: &str) -> &str {
+    href.rsplit('/').next().unwrap_or(href)
+}
+
+/// Which documents a part holds, out of all of the book's.
+struct PartContext<'a> {
+    documents: &'a HashSet<&'a str>,
+    here: &'a HashSet<&'a str>,
+}
+
+impl PartContext<'_> {
+    /// Whether `href` points into a document of another part.
+    fn elsewhere(&self, href: &str) -> bool {
+        let file = href.split('#').next().unwrap_or(href);
+        self.documents.contains(file) && !self.here.contains(file)
+    }
+}
+
+fn write_part(
+    skeleton: &EpubSkeleton,
+    part: &EpubSkeleton,
+    meta: &PackageMetadata,
+    items: &[ManifestItem],
+    spine: &[String],
+    toc: &[NavPoint],
+    context: &PartContext,
+) -> Result<()> {
+    part.create_dirs()?;
+    part.write_mimetype()?;
+    part.write_container_xml()?;
+    for item in items {
+        let from = skeleton.oebps.join(&item.href);
+        let to = part.oebps.join(&item.href);
+        if let Some(dir) = to.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| t!("creating-directory", path = dir.display().to_string()))?;
+        }
+        if spine.contains(&item.id) {
+            let xhtml = fs::read_to_string(&from)
+                .with_context(|| t!("reading-file", path = from.display().to_string()))?;
+            fs::write(&to, unlink(&xhtml, context))
+                .with_context(|| t!("writing-file", path = to.display().to_string()))?;
+        } else {
+            // Linking saves copying the images of a book this big.
+            fs::hard_link(&from, &to)
+                .or_else(|_| fs::copy(&from, &to).map(drop))
+                .with_context(|| t!("copying-file", path = from.display().to_string()))?;
+        }
+    }
+
+    let nav = part_toc(toc, context);
+    let landmarks: Vec<Landmark> = items
+        .iter()
+        .find(|item| spine.first() == Some(&item.id))
+        .map(|item| Landmark {
+            kind: "bodymatter",
+            title: "Start of content".to_string(),
+            href: item.href.clone(),
+        })
+        .into_iter()
+        .collect();
+    part.write_toc(meta, &nav, &landmarks, &[])?;
+    part.write_opf(meta, items, spine)
+}
+
+/// The entries of `toc` within the part. Entries elsewhere are left out,
+/// the ones under them moving up in their place.
+fn part_toc(toc: &[NavPoint], context: &PartContext) -> Vec<NavPoint> {
+    let mut kept = Vec::new();
+    for point in toc {
+        let children = part_toc(&point.children, context);
+        if context.elsewhere(&point.href) {
+            kept.extend(children);
+        } else {
+            kept.push(NavPoint {
+                title: point.title.clone(),
+                href: point.href.clone(),
+                children,
+            });
+        }
+    }
+    kept
+}
+
+/// `xhtml` with the links into documents of other parts turned into plain
+/// text, as a book cannot link into another.
+fn unlink(xhtml: &str, context: &PartContext) -> String {
+    let (Some(start), Some(end)) = (xhtml.find("<body>"), xhtml.rfind("</body>")) else {
+        return xhtml.to_string();
+    };
+    let start = start + "<body>".len();
+    let mut tokens = tokenize(&xhtml[start..end]);
+    let mut changed = false;
+    for token in &mut tokens {
+        let away = token.is_start("a") && token.attr("href").is_some_and(|h| context.elsewhere(h));
+        if let Token::Start { attrs, .. } = token
+            && away
+        {
+            attrs.retain(|(key, _)| !key.eq_ignore_ascii_case("href"));
+            changed = true;
+        }
+    }
+    if !changed {
+        return xhtml.to_string();
+    }
+    format!(
+        "{}{}{}",
+        &xhtml[..start],
+        serialize_xhtml(&tokens),
+        &xhtml[end..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parts, write_parts};
+    use crate::epub::{EpubSkeleton, ManifestItem, NavPoint, PackageMetadata};
+    use std::fs;
+
+    fn item(id: &str, href: &str, media_type: &str) -> ManifestItem {
+        ManifestItem {
+            id: id.to_string(),
+            href: href.to_string(),
+            media_type: media_type.to_string(),
+        }
+    }
+
+    fn point(title: &str, href: &str, children: Vec<NavPoint>) -> NavPoint {
+        NavPoint {
+            title: title.to_string(),
+            href: href.to_string(),
+            children,
+        }
+    }
+
+    #[test]
+    fn splits_by_size_with_a_toc_per_part() {
+        let root = std::env::temp_dir().join(format!("safaribooks-split-{}", std::process::id()));
+        let skeleton = EpubSkeleton {
+            meta_inf: root.join("META-INF"),
+            oebps: root.join("OEBPS"),
+            epub: root.join("Book.epub"),
+            root: root.clone(),
+            language: "en".to_string(),
+            legacy_names: false,
+        };
+        skeleton.create_dirs().unwrap();
+        fs::create_dir_all(skeleton.oebps.join("Images")).unwrap();
+        fs::create_dir_all(skeleton.oebps.join("Styles")).unwrap();
+        let body = "x".repeat(400);
+        skeleton
+            .write_document(
+                "ch01.xhtml",
+                "One",
+                &[],
+                &format!(r#"<p>{body}<img src="Images/a.png"/><a href="ch02.xhtml#s">on</a></p>"#),
+            )
+            .unwrap();
+        skeleton
+            .write_document(
+                "ch02.xhtml",
+                "Two",
+                &[],
+                &format!(r#"<p id="s">{body}</p>"#),
+            )
+            .unwrap();
+        skeleton
+            .write_document(
+                "ch03.xhtml",
+                "Three",
+                &[],
+                &format!(r#"<p>{body}<img src="Images/a.png"/><a href="ch02.xhtml">back</a></p>"#),
+            )
+            .unwrap();
+        fs::write(skeleton.oebps.join("Images/a.png"), vec![0; 300]).unwrap();
+        fs::write(skeleton.oebps.join("Styles/a.css"), "p { margin: 0; }").unwrap();
+        let manifest = vec![
+            item("ch01", "ch01.xhtml", "application/xhtml+xml"),
+            item("ch02", "ch02.xhtml", "application/xhtml+xml"),
+            item("ch03", "ch03.xhtml", "application/xhtml+xml"),
+            item("asset_0001", "Images/a.png", "image/png"),
+            item("css", "Styles/a.css", "text/css"),
+        ];
+        let spine = ["ch01", "ch02", "ch03"].map(String::from);
+        let toc = vec![
+            point("One", "ch01.xhtml", vec![]),
+            point(
+                "Two",
+                "ch02.xhtml",
+                vec![point("S", "ch02.xhtml#s", vec![])],
+            ),
+            point("Three", "ch03.xhtml", vec![]),
+        ];
+        let meta = PackageMetadata {
+            identifier: "1234".to_string(),
+            title: "Book".to_string(),
+            authors: vec![],
+            language: "en".to_string(),
+            source: None,
+            isbn: None,
+            publisher: None,
+            date: None,
+            description: None,
+            subjects: vec![],
+            rights: None,
+            series: None,
+            series_index: None,
+        };
+
+        // Each chapter is ~800 bytes; two fit together, but not with the image.
+        let split = |max| write_parts(&skeleton, &meta, &manifest, &spine, &toc, max).unwrap();
+        assert_eq!(split(Some(1 << 20)), 0);
+        assert_eq!(split(Some(2200)), 2);
+        let written = parts(&skeleton);
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[1].epub, root.join("Book - Part 2.epub"));
+
+        let opf = fs::read_to_string(written[0].oebps.join("content.opf")).unwrap();
+        assert!(opf.contains("<dc:title>Book (Part 1 of 2)</dc:title>"));
+        assert!(opf.contains(">1234-part1<"));
+        assert!(opf.contains(r#"<meta name="calibre:series_index" content="1"/>"#));
+        assert!(opf.contains(r#"href="Styles/a.css""#) && opf.contains(r#"href="Images/a.png""#));
+        assert!(!opf.contains("ch03"));
+        let first = fs::read_to_string(written[0].oebps.join("ch01.xhtml")).unwrap();
+        assert!(first.contains(r##"<a href="ch02.xhtml#s">on</a>"##));
+
+        let nav = fs::read_to_string(written[1].oebps.join("nav.xhtml")).unwrap();
+        assert!(nav.contains(r#"href="ch03.xhtml""#));
+        assert!(!nav.contains("ch01.xhtml") && !nav.contains("ch02.xhtml"));
+        let third = fs::read_to_string(written[1].oebps.join("ch03.xhtml")).unwrap();
+        assert!(third.contains("<a>back</a>"));
+        assert!(written[1].oebps.join("Images/a.png").is_file());
+
+        // Without splitting, nothing of the last run is left behind.
+        fs::write(&written[1].epub, b"old").unwrap();
+        assert_eq!(split(None), 0);
+        assert!(parts(&skeleton).is_empty());
+        assert!(!written[1].epub.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+//! The download-state database: one SQLite file in the data directory
+//! recording every book downloaded, where it went, the files it is made of
+//! with their checksums, and how each download ended.
+
+use crate::epub::media_type_for;
+use crate::i18n::t;
+use crate::orly::{iso_datetime, BookInfo};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Each step upgrades the tables by one version (`PRAGMA user_version`).
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE books (
+        book_id  TEXT PRIMARY KEY,
+        title    TEXT NOT NULL,
+        isbn     TEXT,
+        format   TEXT NOT NULL,
+        dir      TEXT NOT NULL,
+        epub     TEXT,
+        size     INTEGER,
+        started  TEXT NOT NULL,
+        finished TEXT,
+        outcome  TEXT NOT NULL,
+        error    TEXT
+    );
+    CREATE TABLE chapters (
+        book_id TEXT NOT NULL REFERENCES books(book_id) ON DELETE CASCADE,
+        href    TEXT NOT NULL,
+        sha256  TEXT NOT NULL,
+        PRIMARY KEY (book_id, href)
+    );
+    CREATE TABLE assets (
+        book_id TEXT NOT NULL REFERENCES books(book_id) ON DELETE CASCADE,
+        href    TEXT NOT NULL,
+        sha256  TEXT NOT NULL,
+        PRIMARY KEY (book_id, href)
+    );
+    ",
+    // What `list --downloaded` needs to tell an Early Release has changed.
+    "
+    ALTER TABLE books ADD COLUMN early_release INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE books ADD COLUMN updated TEXT;
+    ",
+];
+
+/// How the last download of a book ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Started and not finished: still running, or killed.
+    Running,
+    Finished,
+    Failed,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Finished => "finished",
+            Self::Failed => "failed",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "finished" => Self::Finished,
+            "failed" => Self::Failed,
+            _ => Self::Running,
+        }
+    }
+}
+
+/// What the database knows of a book's last download.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookRecord {
+    pub epub: Option<PathBuf>,
+    /// Size of the EPUB in bytes when it was written.
+    pub size: Option<u64>,
+    /// When the download finished (or failed), ISO 8601.
+    pub finished: Option<String>,
+    pub status: Status,
+    pub error: Option<String>,
+}
+
+impl BookRecord {
+    /// The EPUB, if the download finished and the file is still there unchanged.
+    pub fn complete_epub(&self) -> Option<&Path> {
+        let epub = self.epub.as_deref()?;
+        let size = fs::metadata(epub).ok()?.len();
+        (self.status == Status::Finished && self.size == Some(size)).then_some(epub)
+    }
+}
+
+/// A book in the library, for `list --downloaded`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Downloaded {
+    /// None for an EPUB found on disk that doesn't name one.
+    pub book_id: Option<String>,
+    pub title: String,
+    pub isbn: Option<String>,
+    pub epub: PathBuf,
+    pub size: u64,
+    /// When it was downloaded, ISO 8601.
+    pub downloaded: String,
+    pub early_release: bool,
+    /// When the content had last changed at download time.
+    pub updated: Option<String>,
+}
+
+/// Whether an Early Release changed since it was downloaded: its current
+/// update time is later than the one recorded (ISO 8601 compares as text).
+pub fn update_available(recorded: Option<&str>, current: Option<&str>) -> bool {
+    match (recorded, current) {
+        (Some(recorded), Some(current)) => current > recorded,
+        (None, Some(_)) => true,
+        (_, None) => false,
+    }
+}
+
+/// The database, shared by every download of the run.
+pub struct StateDb {
+    conn: Mutex<Connection>,
+}
+
+impl StateDb {
+    /// Open (creating it if needed) the database at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| t!("creating-directory", path = dir.display().to_string()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| t!("opening-file", path = path.display().to_string()))?;
+        // Downloads running side by side in other processes wait their turn.
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            conn.execute_batch(&format!(
+                "BEGIN; {migration} PRAGMA user_version = {}; COMMIT;",
+                i + 1
+            ))
+            .with_context(|| {
+                t!(
+                    "upgrading-state",
+                    path = path.display().to_string(),
+                    version = i + 1
+                )
+            })?;
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Record that a download of `book_id` into `dir` started.
+    pub fn started(&self, book_id: &str, info: &BookInfo, dir: &Path) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO books
+                 (book_id, title, isbn, format, dir, started, outcome, early_release, updated)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (book_id) DO UPDATE SET
+                 title = excluded.title, isbn = excluded.isbn, format = excluded.format,
+                 dir = excluded.dir, started = excluded.started, finished = NULL,
+                 outcome = excluded.outcome, error = NULL,
+                 early_release = excluded.early_release, updated = excluded.updated",
+            params![
+                book_id,
+                info.title,
+                info.isbn,
+                info.format,
+                dir.to_string_lossy(),
+                iso_datetime(SystemTime::now()),
+                Status::Running.as_str(),
+                info.early_release,
+                info.updated,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Record the packaged `epub` and its entries (archive path, SHA-256).
+    pub fn finished(&self, book_id: &str, epub: &Path, entries: &[(String, String)]) -> Result<()> {
+        let size = fs::metadata(epub)
+            .with_context(|| t!("reading-file", path = epub.display().to_string()))?
+            .len();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE books SET epub = ?2, size = ?3, finished = ?4, outcome = ?5, error = NULL
+             WHERE book_id = ?1",
+            params![
+                book_id,
+                epub.to_string_lossy(),
+                size as i64,
+                iso_datetime(SystemTime::now()),
+                Status::Finished.as_str(),
+            ],
+        )?;
+        tx.execute("DELETE FROM chapters WHERE book_id = ?1", [book_id])?;
+        tx.execute("DELETE FROM assets WHERE book_id = ?1", [book_id])?;
+        for (name, sha256) in entries {
+            let Some(href) = name.strip_prefix("OEBPS/") else {
+                continue;
+            };
+            let table = if media_type_for(href) == "application/xhtml+xml" {
+                "chapters"
+            } else {
+                "assets"
+            };
+            tx.execute(
+                &format!("INSERT INTO {table} (book_id, href, sha256) VALUES (?1, ?2, ?3)"),
+                params![book_id, href, sha256],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Record why the last download of `book_id` failed.
+    pub fn failed(&self, book_id: &str, error: &str) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE books SET finished = ?2, outcome = ?3, error = ?4 WHERE book_id = ?1",
+            params![
+                book_id,
+                iso_datetime(SystemTime::now()),
+                Status::Failed.as_str(),
+                error
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Every finished download, by title.
+    pub fn downloaded(&self) -> Result<Vec<Downloaded>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT book_id, title, isbn, epub, size, finished, early_release, updated
+             FROM books WHERE outcome = ?1 ORDER BY title COLLATE NOCASE",
+        )?;
+        let rows = stmt.query_map([Status::Finished.as_str()], |row| {
+            Ok(Downloaded {
+                book_id: Some(row.get(0)?),
+                title: row.get(1)?,
+                isbn: row.get(2)?,
+                epub: PathBuf::from(row.get::<_, String>(3)?),
+                size: row.get::<_, i64>(4)? as u64,
+                downloaded: row.get(5)?,
+                early_release: row.get(6)?,
+                updated: row.get(7)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn book(&self, book_id: &str) -> Result<Option<BookRecord>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn
+            .query_row(
+                "SELECT epub, size, finished, outcome, error FROM books WHERE book_id = ?1",
+                [book_id],
+                |row| {
+                    Ok(BookRecord {
+                        epub: row.get::<_, Option<String>>(0)?.map(PathBuf::from),
+                        size: row.get::<_, Option<i64>>(1)?.map(|s| s as u64),
+                        finished: row.get(2)?,
+                        status: Status::parse(&row.get::<_, String>(3)?),
+                        error: row.get(4)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update_available, StateDb, Status};
+    use crate::orly::BookInfo;
+    use std::fs;
+
+    #[test]
+    fn records_downloads() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-statedb-{}", std::process::id()));
+        let db = StateDb::open(&dir.join("state.sqlite3")).unwrap();
+        let info: BookInfo = serde_json::from_value(serde_json::json!({
+            "title": "State",
+            "web_url": "https://learning.oreilly.com/library/view/x/123/",
+            "isbn": "9781491958698"
+        }))
+        .unwrap();
+        assert!(db.book("123").unwrap().is_none());
+
+        let book_dir = dir.join("State (123)");
+        db.started("123", &info, &book_dir).unwrap();
+        let record = db.book("123").unwrap().unwrap();
+        assert_eq!(record.status, Status::Running);
+        assert_eq!(record.complete_epub(), None);
+
+        let epub = dir.join("123.epub");
+        fs::write(&epub, b"zip").unwrap();
+        let entries = [
+            ("mimetype".to_string(), "a".to_string()),
+            ("OEBPS/ch01.xhtml".to_string(), "b".to_string()),
+            ("OEBPS/Images/f.png".to_string(), "c".to_string()),
+        ];
+        db.finished("123", &epub, &entries).unwrap();
+        let record = db.book("123").unwrap().unwrap();
+        assert_eq!(record.status, Status::Finished);
+        assert!(record.finished.is_some());
+        assert_eq!(record.complete_epub(), Some(epub.as_path()));
+        let conn = db.conn.lock().unwrap();
+        let count = |table: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |r| r.get(0))
+                .unwrap()
+        };
+        assert_eq!((count("chapters"), count("assets")), (1, 1));
+        drop(conn);
+
+        // A changed file is no longer the finished download.
+        fs::write(&epub, b"truncated zip").unwrap();
+        assert_eq!(db.book("123").unwrap().unwrap().complete_epub(), None);
+
+        db.started("123", &info, &book_dir).unwrap();
+        db.failed("123", "Logged out").unwrap();
+        let record = db.book("123").unwrap().unwrap();
+        assert_eq!(record.status, Status::Failed);
+        assert_eq!(record.error.as_deref(), Some("Logged out"));
+        // Reopening keeps the data.
+        drop(db);
+        let db = StateDb::open(&dir.join("state.sqlite3")).unwrap();
+        assert!(db.book("123").unwrap().is_some());
+        assert!(db.downloaded().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lists_finished_downloads() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-statels-{}", std::process::id()));
+        let db = StateDb::open(&dir.join("state.sqlite3")).unwrap();
+        let info: BookInfo = serde_json::from_value(serde_json::json!({
+            "title": "Early",
+            "web_url": "https://learning.oreilly.com/library/view/x/9/",
+            "is_early_release": true,
+            "last_modified_time": "2026-01-02T03:04:05Z"
+        }))
+        .unwrap();
+        let epub = dir.join("9.epub");
+        fs::write(&epub, b"zip").unwrap();
+        db.started("9", &info, &dir).unwrap();
+        db.finished("9", &epub, &[]).unwrap();
+        db.started("10", &info, &dir).unwrap();
+
+        let books = db.downloaded().unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].book_id.as_deref(), Some("9"));
+        assert_eq!(books[0].size, 3);
+        assert!(books[0].early_release);
+        assert_eq!(books[0].updated.as_deref(), Some("2026-01-02T03:04:05Z"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn detects_early_release_updates() {
+        let jan = Some("2026-01-02T03:04:05Z");
+        let feb = Some("2026-02-01T00:00:00Z");
+        assert!(update_available(jan, feb));
+        assert!(!update_available(feb, jan));
+        assert!(!update_available(jan, jan));
+        assert!(update_available(None, jan));
+        assert!(!update_available(jan, None));
+    }
+}
@@ -0,0 +1,171 @@
+//! SVG figures: the images they refer to, which have to be in the book
+//! too, and `--rasterize-svg` for readers that cannot show SVG at all.
+
+use crate::html::{tokenize, Token};
+use crate::i18n::t;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The external files `svg` refers to (`href` or `xlink:href`), each once;
+/// fragments within the figure, data: URIs and links are not files.
+pub fn references(svg: &str) -> Vec<String> {
+    let mut found: Vec<String> = Vec::new();
+    for token in tokenize(svg) {
+        if !matches!(&token, Token::Start { .. }) || token.is_start("a") {
+            continue;
+        }
+        for key in ["xlink:href", "href"] {
+            let Some(value) = token.attr(key).map(str::trim) else {
+                continue;
+            };
+            let scheme = value.split_once(':').map(|(s, _)| s);
+            let file = !value.is_empty()
+                && !value.starts_with('#')
+                && scheme.is_none_or(|s| s.contains('/') || s == "http" || s == "https");
+            if file && !found.iter().any(|f| f == value) {
+                found.push(value.to_string());
+            }
+        }
+    }
+    found
+}
+
+/// `svg` with the references in `local` pointed at their new values.
+pub fn rewrite(svg: &str, local: &HashMap<String, String>) -> String {
+    let mut out = svg.to_string();
+    for (from, to) in local {
+        for quote in ['"', '\''] {
+            out = out.replace(
+                &format!("={quote}{from}{quote}"),
+                &format!("={quote}{to}{quote}"),
+            );
+        }
+    }
+    out
+}
+
+/// Whether the file at `path` is an SVG, for assets served without an
+/// extension.
+pub fn is_svg_file(path: &Path) -> bool {
+    let mut head = [0; 1024];
+    let Ok(n) = File::open(path).and_then(|mut f| f.read(&mut head)) else {
+        return false;
+    };
+    let head = String::from_utf8_lossy(&head[..n]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    (head.starts_with("<?xml") || head.starts_with("<!--") || head.starts_with("<svg"))
+        && head.contains("<svg")
+}
+
+/// librsvg's `rsvg-convert`, which `--rasterize-svg` draws SVG figures with.
+pub struct Rasterizer {
+    program: PathBuf,
+}
+
+impl Rasterizer {
+    /// `rsvg-convert` on PATH.
+    pub fn locate() -> Result<Self> {
+        Self::locate_in(std::env::var_os("PATH"))
+    }
+
+    fn locate_in(path_var: Option<OsString>) -> Result<Self> {
+        let name = if cfg!(windows) {
+            "rsvg-convert.exe"
+        } else {
+            "rsvg-convert"
+        };
+        path_var
+            .iter()
+            .flat_map(std::env::split_paths)
+            .map(|dir| dir.join(name))
+            .find(|candidate| candidate.is_file())
+            .map(|program| Self { program })
+            .with_context(|| t!("rsvg-not-found"))
+    }
+
+    /// Draw `svg` into the PNG `png`.
+    pub fn convert(&self, svg: &Path, png: &Path) -> Result<()> {
+        let output = Command::new(&self.program)
+            .args(["--format", "png", "--output"])
+            .arg(png)
+            .arg(svg)
+            .output()
+            .with_context(|| {
+                t!(
+                    "running-program",
+                    program = self.program.display().to_string()
+                )
+            })?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let last = stderr.lines().rev().find(|l| !l.trim().is_empty());
+            bail!(t!(
+                "program-failed",
+                program = "rsvg-convert",
+                status = output.status.to_string(),
+                detail = last.map(|l| format!(": {}", l.trim())).unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_svg_file, references, rewrite, Rasterizer};
+    use std::collections::HashMap;
+    use std::fs;
+
+    const FIGURE: &str = r##"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">
+<defs><linearGradient id="g"/></defs>
+<image xlink:href="photo.png" width="10" height="10"/>
+<use href="icons.svg#gear"/>
+<use href="#g"/>
+<image href="data:image/png;base64,AAAA"/>
+<a href="https://www.oreilly.com/"><text>Site</text></a>
+<image href='photo.png'/>
+</svg>
+"##;
+
+    #[test]
+    fn finds_and_rewrites_references() {
+        assert_eq!(references(FIGURE), ["photo.png", "icons.svg#gear"]);
+        let local = HashMap::from([
+            ("photo.png".to_string(), "1a2b.png".to_string()),
+            ("icons.svg#gear".to_string(), "3c4d.svg#gear".to_string()),
+        ]);
+        let rewritten = rewrite(FIGURE, &local);
+        assert!(rewritten.contains(r#"<image xlink:href="1a2b.png""#));
+        assert!(rewritten.contains(r##"<use href="3c4d.svg#gear"/>"##));
+        assert!(rewritten.contains("<image href='1a2b.png'/>"));
+        assert!(rewritten.contains(r##"<use href="#g"/>"##));
+    }
+
+    #[test]
+    fn recognizes_svg_without_an_extension() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-svg-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("figure"), FIGURE).unwrap();
+        fs::write(dir.join("photo"), [0x89, b'P', b'N', b'G']).unwrap();
+        assert!(is_svg_file(&dir.join("figure")));
+        assert!(!is_svg_file(&dir.join("photo")));
+        assert!(!is_svg_file(&dir.join("missing")));
+
+        assert!(Rasterizer::locate_in(Some(dir.clone().into())).is_err());
+        let name = if cfg!(windows) {
+            "rsvg-convert.exe"
+        } else {
+            "rsvg-convert"
+        };
+        fs::write(dir.join(name), b"").unwrap();
+        let found = Rasterizer::locate_in(Some(dir.clone().into())).unwrap();
+        assert_eq!(found.program, dir.join(name));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
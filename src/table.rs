@@ -0,0 +1,541 @@
+//! `--table-style`: what happens to a table too wide for the screen, where
+//! e-readers otherwise cut it off at the right edge.
+
+use crate::code::{Drawing, CHAR_WIDTH, LINE_HEIGHT, PADDING};
+use crate::epub::xml_escape;
+use crate::html::Token;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// Tables with more columns than this are wide.
+const MAX_COLUMNS: usize = 6;
+
+/// Tables that cannot be narrower than this many characters (the longest
+/// word of each column side by side) are wide.
+const MAX_WIDTH: usize = 60;
+
+/// Columns in each part of a split table, besides the first.
+const SPLIT_COLUMNS: usize = 3;
+
+/// Widest column of a drawn table, in characters; longer text wraps.
+const CELL_COLUMNS: usize = 40;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TableStyle {
+    /// Let wide tables scroll sideways inside the page.
+    #[default]
+    Scroll,
+    /// Split wide tables into narrower ones, each repeating the first column.
+    Split,
+    /// Draw wide tables as images that scale to the screen.
+    Image,
+}
+
+/// The CSS for wide tables, whatever the style: tables that cannot be
+/// split or drawn scroll.
+pub const STYLESHEET: &str = ".table-scroll { overflow-x: auto; max-width: 100%; }\n\
+     img.table { display: block; max-width: 100%; height: auto; }\n";
+
+/// A table cell: its tokens from start to end tag.
+struct Cell {
+    tokens: Vec<Token>,
+    header: bool,
+}
+
+impl Cell {
+    fn text(&self) -> String {
+        collapse(&self.tokens)
+    }
+}
+
+struct Row {
+    /// The thead, tbody or tfoot it is in.
+    section: Option<String>,
+    cells: Vec<Cell>,
+}
+
+struct Table {
+    start: Token,
+    /// The caption, start and end tags included.
+    caption: Vec<Token>,
+    rows: Vec<Row>,
+}
+
+impl Table {
+    fn columns(&self) -> usize {
+        self.rows.iter().map(|r| r.cells.len()).max().unwrap_or(0)
+    }
+
+    /// Text of each column, cell by cell.
+    fn column_texts(&self) -> Vec<Vec<String>> {
+        let mut columns = vec![Vec::new(); self.columns()];
+        for row in &self.rows {
+            for (n, cell) in row.cells.iter().enumerate() {
+                columns[n].push(cell.text());
+            }
+        }
+        columns
+    }
+
+    fn is_wide(&self) -> bool {
+        let narrowest: usize = self
+            .column_texts()
+            .iter()
+            .map(|cells| {
+                cells
+                    .iter()
+                    .flat_map(|t| t.split_whitespace())
+                    .map(|w| w.chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .sum();
+        self.columns() > MAX_COLUMNS || narrowest > MAX_WIDTH
+    }
+}
+
+/// Lay out the wide tables of a chapter in `style`, adding the tables it
+/// draws to `drawings`.
+pub fn restyle(tokens: Vec<Token>, style: TableStyle, drawings: &mut Vec<Drawing>) -> Vec<Token> {
+    let mut tables = Tables::new(style);
+    let mut out = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        tables.push(token, drawings, &mut out);
+    }
+    tables.finish(drawings, &mut out);
+    out
+}
+
+/// `restyle` a token at a time. Only a table is held back, until its end
+/// tag, since whether it is wide depends on all of its cells.
+pub struct Tables {
+    style: TableStyle,
+    /// How many `<table>` are open.
+    depth: usize,
+    /// The table being read, start and end tags included.
+    markup: Vec<Token>,
+}
+
+impl Tables {
+    pub fn new(style: TableStyle) -> Self {
+        Tables {
+            style,
+            depth: 0,
+            markup: Vec::new(),
+        }
+    }
+
+    /// Lay out `token`, pushing what it becomes, if anything yet, to `out`.
+    pub fn push(&mut self, token: Token, drawings: &mut Vec<Drawing>, out: &mut Vec<Token>) {
+        if opens("table", &token) {
+            self.depth += 1;
+        } else if self.depth > 0 && token.is_end("table") {
+            self.depth -= 1;
+        } else if self.depth == 0 {
+            out.push(token);
+            return;
+        }
+        self.markup.push(token);
+        if self.depth == 0 {
+            lay_out(std::mem::take(&mut self.markup), self.style, drawings, out);
+        }
+    }
+
+    /// Push what is still held back: a table never closed.
+    pub fn finish(self, drawings: &mut Vec<Drawing>, out: &mut Vec<Token>) {
+        if !self.markup.is_empty() {
+            lay_out(self.markup, self.style, drawings, out);
+        }
+    }
+}
+
+/// Push the table of `markup` as it is if it is narrow, and in `style` if
+/// it is wide.
+fn lay_out(
+    markup: Vec<Token>,
+    style: TableStyle,
+    drawings: &mut Vec<Drawing>,
+    out: &mut Vec<Token>,
+) {
+    match parse(&markup) {
+        Some(table) if !table.is_wide() => out.extend(markup),
+        Some(table) if style == TableStyle::Split => split(table, out),
+        Some(table) if style == TableStyle::Image => draw(table, drawings, out),
+        // Spanning cells, nested tables: too irregular to split or draw.
+        _ => {
+            out.push(start("div", &[("class", "table-scroll")]));
+            out.extend(markup);
+            out.push(Token::End("div".to_string()));
+        }
+    }
+}
+
+fn opens(tag: &str, token: &Token) -> bool {
+    token.is_start(tag)
+        && !matches!(
+            token,
+            Token::Start {
+                self_closing: true,
+                ..
+            }
+        )
+}
+
+fn start(name: &str, attrs: &[(&str, &str)]) -> Token {
+    Token::Start {
+        name: name.to_string(),
+        attrs: attrs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        self_closing: false,
+    }
+}
+
+/// The text of `tokens` with whitespace collapsed.
+fn collapse(tokens: &[Token]) -> String {
+    let text: String = tokens
+        .iter()
+        .filter_map(|t| match t {
+            Token::Text(text) => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The rows of a table from its markup; None unless every cell spans one
+/// row and one column and holds no table.
+fn parse(markup: &[Token]) -> Option<Table> {
+    let mut table = Table {
+        start: markup.first()?.clone(),
+        caption: Vec::new(),
+        rows: Vec::new(),
+    };
+    let mut section = None;
+    let mut i = 1;
+    while i + 1 < markup.len() {
+        let token = &markup[i];
+        match token {
+            Token::Start { name, .. } if ["thead", "tbody", "tfoot"].contains(&name.as_str()) => {
+                section = Some(name.clone());
+            }
+            Token::End(name) if ["thead", "tbody", "tfoot"].contains(&name.as_str()) => {
+                section = None;
+            }
+            Token::Start { name, .. } if name == "caption" || name == "colgroup" => {
+                let end = close(markup, i, name)?;
+                if name == "caption" {
+                    table.caption = markup[i..=end].to_vec();
+                }
+                i = end;
+            }
+            Token::Start { name, .. } if name == "tr" => {
+                table.rows.push(Row {
+                    section: section.clone(),
+                    cells: Vec::new(),
+                });
+            }
+            Token::Start { name, .. } if name == "td" || name == "th" => {
+                if token.attr("colspan").is_some_and(|v| v.trim() != "1")
+                    || token.attr("rowspan").is_some_and(|v| v.trim() != "1")
+                {
+                    return None;
+                }
+                let end = close(markup, i, name)?;
+                let tokens = markup[i..=end].to_vec();
+                if tokens[1..].iter().any(|t| t.is_start("table")) {
+                    return None;
+                }
+                table.rows.last_mut()?.cells.push(Cell {
+                    header: name == "th",
+                    tokens,
+                });
+                i = end;
+            }
+            Token::End(name) if name == "tr" => {}
+            Token::Text(text) if text.trim().is_empty() => {}
+            Token::Comment(_) => {}
+            _ => return None,
+        }
+        i += 1;
+    }
+    Some(table)
+}
+
+/// Index of the end tag closing the `name` element opened at `open`.
+fn close(markup: &[Token], open: usize, name: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in markup.iter().enumerate().skip(open) {
+        if opens(name, token) {
+            depth += 1;
+        } else if token.is_end(name) {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+/// Write `table` as tables of the first column plus `SPLIT_COLUMNS` others.
+fn split(table: Table, out: &mut Vec<Token>) {
+    let columns = table.columns();
+    let mut first = true;
+    for from in (1..columns.max(2)).step_by(SPLIT_COLUMNS) {
+        let mut open = table.start.clone();
+        if !first && let Token::Start { attrs, .. } = &mut open {
+            attrs.retain(|(k, _)| k != "id");
+        }
+        out.push(open);
+        if first {
+            out.extend(table.caption.iter().cloned());
+        }
+        let mut section: Option<&String> = None;
+        for row in &table.rows {
+            if row.section.as_ref() != section {
+                if let Some(name) = section {
+                    out.push(Token::End(name.clone()));
+                }
+                if let Some(name) = &row.section {
+                    out.push(start(name, &[]));
+                }
+                section = row.section.as_ref();
+            }
+            out.push(start("tr", &[]));
+            let cells = row
+                .cells
+                .iter()
+                .enumerate()
+                .filter(|(n, _)| *n == 0 || (from..from + SPLIT_COLUMNS).contains(n));
+            for (_, cell) in cells {
+                out.extend(cell.tokens.iter().cloned());
+            }
+            out.push(Token::End("tr".to_string()));
+        }
+        if let Some(name) = section {
+            out.push(Token::End(name.clone()));
+        }
+        out.push(Token::End("table".to_string()));
+        first = false;
+    }
+}
+
+/// Replace `table` with a drawing of it; the text stays in the alt text,
+/// row by row.
+fn draw(table: Table, drawings: &mut Vec<Drawing>, out: &mut Vec<Token>) {
+    let href = format!("Images/table-{:04}.svg", drawings.len() + 1);
+    let alt: Vec<String> = table
+        .rows
+        .iter()
+        .map(|row| {
+            row.cells
+                .iter()
+                .map(Cell::text)
+                .collect::<Vec<_>>()
+                .join(" | ")
+        })
+        .collect();
+    let mut attrs = vec![
+        ("src".to_string(), href.clone()),
+        ("alt".to_string(), alt.join("\n")),
+        ("class".to_string(), "table".to_string()),
+    ];
+    if let Some(id) = table.start.attr("id") {
+        attrs.push(("id".to_string(), id.to_string()));
+    }
+    let img = Token::Start {
+        name: "img".to_string(),
+        attrs,
+        self_closing: true,
+    };
+    if table.caption.len() >= 2 {
+        out.push(start("figure", &[]));
+        out.push(img);
+        out.push(start("figcaption", &[]));
+        out.extend(table.caption[1..table.caption.len() - 1].iter().cloned());
+        out.push(Token::End("figcaption".to_string()));
+        out.push(Token::End("figure".to_string()));
+    } else {
+        out.push(img);
+    }
+    drawings.push(Drawing {
+        href,
+        svg: svg(&table),
+    });
+}
+
+/// `text` in lines of at most `width` characters, broken between words
+/// where it can be.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().collect();
+        while word.len() > width {
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            lines.push(word.drain(..width).collect());
+        }
+        let word: String = word.into_iter().collect();
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&word);
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// An SVG drawing the grid of `table`, header cells in bold.
+fn svg(table: &Table) -> String {
+    let widths: Vec<usize> = table
+        .column_texts()
+        .iter()
+        .map(|cells| {
+            cells
+                .iter()
+                .map(|t| t.chars().count())
+                .max()
+                .unwrap_or(0)
+                .clamp(1, CELL_COLUMNS)
+        })
+        .collect();
+    let rows: Vec<Vec<(Vec<String>, bool)>> = table
+        .rows
+        .iter()
+        .map(|row| {
+            row.cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, &width)| (wrap(&cell.text(), width), cell.header))
+                .collect()
+        })
+        .collect();
+    let cell_width = |columns: usize| columns * CHAR_WIDTH + 2 * PADDING;
+    let width: usize = widths.iter().map(|&w| cell_width(w)).sum();
+    let heights: Vec<usize> = rows
+        .iter()
+        .map(|cells| {
+            let lines = cells.iter().map(|(l, _)| l.len()).max().unwrap_or(1);
+            lines * LINE_HEIGHT + PADDING
+        })
+        .collect();
+    let height: usize = heights.iter().sum();
+
+    let mut svg = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">\n\
+         <rect width=\"100%\" height=\"100%\" fill=\"#fff\" stroke=\"#888\"/>\n\
+         <g font-family=\"sans-serif\" font-size=\"10\" fill=\"#222\">\n"
+    );
+    let mut y = 0;
+    for (cells, row_height) in rows.iter().zip(&heights) {
+        if y > 0 {
+            svg.push_str(&format!(
+                "<line x1=\"0\" y1=\"{y}\" x2=\"{width}\" y2=\"{y}\" stroke=\"#888\"/>\n"
+            ));
+        }
+        let mut x = 0;
+        for ((lines, header), &columns) in cells.iter().zip(&widths) {
+            let weight = if *header { " font-weight=\"bold\"" } else { "" };
+            for (n, line) in lines.iter().enumerate() {
+                svg.push_str(&format!(
+                    "<text x=\"{}\" y=\"{}\"{weight}>{}</text>\n",
+                    x + PADDING,
+                    y + (n + 1) * LINE_HEIGHT,
+                    xml_escape(line)
+                ));
+            }
+            x += cell_width(columns);
+        }
+        y += row_height;
+    }
+    let mut x = 0;
+    for &columns in &widths[..widths.len().saturating_sub(1)] {
+        x += cell_width(columns);
+        svg.push_str(&format!(
+            "<line x1=\"{x}\" y1=\"0\" x2=\"{x}\" y2=\"{height}\" stroke=\"#888\"/>\n"
+        ));
+    }
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{restyle, wrap, TableStyle};
+    use crate::html::{serialize_xhtml, tokenize};
+
+    const WIDE: &str = "<table id=\"t\"><caption>Flags</caption>\
+        <thead><tr><th>Flag</th><th>A</th><th>B</th><th>C</th><th>D</th><th>E</th><th>F</th></tr></thead>\
+        <tbody><tr><td>-x</td><td>1</td><td>2</td><td>3</td><td>4</td><td>5</td><td>6</td></tr></tbody>\
+        </table>";
+
+    fn restyled(html: &str, style: TableStyle) -> (String, usize) {
+        let mut drawings = Vec::new();
+        let tokens = restyle(tokenize(html), style, &mut drawings);
+        (serialize_xhtml(&tokens), drawings.len())
+    }
+
+    #[test]
+    fn narrow_tables_are_kept() {
+        let html = "<table><tr><td>a</td><td>b</td></tr></table>";
+        for style in [TableStyle::Scroll, TableStyle::Split, TableStyle::Image] {
+            assert_eq!(restyled(html, style), (html.to_string(), 0));
+        }
+    }
+
+    #[test]
+    fn wide_tables_scroll() {
+        let (out, _) = restyled(WIDE, TableStyle::Scroll);
+        assert_eq!(out, format!("<div class=\"table-scroll\">{WIDE}</div>"));
+        // A spanning cell cannot be split: it scrolls instead.
+        let spanning = WIDE.replace("<td>-x</td>", "<td colspan=\"2\">-x</td>");
+        let (out, _) = restyled(&spanning, TableStyle::Split);
+        assert_eq!(out, format!("<div class=\"table-scroll\">{spanning}</div>"));
+    }
+
+    #[test]
+    fn splits_wide_tables() {
+        let (out, drawings) = restyled(WIDE, TableStyle::Split);
+        assert_eq!(drawings, 0);
+        assert_eq!(
+            out,
+            "<table id=\"t\"><caption>Flags</caption>\
+             <thead><tr><th>Flag</th><th>A</th><th>B</th><th>C</th></tr></thead>\
+             <tbody><tr><td>-x</td><td>1</td><td>2</td><td>3</td></tr></tbody></table>\
+             <table><thead><tr><th>Flag</th><th>D</th><th>E</th><th>F</th></tr></thead>\
+             <tbody><tr><td>-x</td><td>4</td><td>5</td><td>6</td></tr></tbody></table>"
+        );
+    }
+
+    #[test]
+    fn draws_wide_tables() {
+        let mut drawings = Vec::new();
+        let tokens = restyle(tokenize(WIDE), TableStyle::Image, &mut drawings);
+        assert_eq!(
+            serialize_xhtml(&tokens),
+            "<figure><img src=\"Images/table-0001.svg\" \
+             alt=\"Flag | A | B | C | D | E | F\n-x | 1 | 2 | 3 | 4 | 5 | 6\" class=\"table\" id=\"t\"/>\
+             <figcaption>Flags</figcaption></figure>"
+        );
+        assert_eq!(drawings[0].href, "Images/table-0001.svg");
+        assert!(drawings[0]
+            .svg
+            .contains(r#"<text x="8" y="12" font-weight="bold">Flag</text>"#));
+        assert_eq!(
+            wrap("a long sentence in a cell", 8),
+            ["a long", "sentence", "in a", "cell"]
+        );
+        assert_eq!(wrap("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+    }
+}
@@ -0,0 +1,465 @@
+//! Adaptive request throttling. When the server answers 429 (Too Many
+//! Requests) or 503, concurrency is halved and a delay between request starts
+//! is doubled; after a run of successful responses both ramp back up.
+//!
+//! With `--stealth` each request also starts after a random pause.
+//!
+//! When more than `--degrade-above` percent of the recent requests fail
+//! (5xx, 429 or no response at all), the site is failing as a whole: the
+//! downloader then degrades the throttle to one request at a time with
+//! long pauses, until a whole window of requests goes by without failing.
+//!
+//! Separately, `RateLimit` caps the bytes per second read across all
+//! responses (`--limit-rate`).
+
+use crate::config::parse_bytes;
+use crate::i18n::t;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// Smallest delay between request starts once throttled.
+const MIN_DELAY: Duration = Duration::from_millis(250);
+/// Largest delay between request starts.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// Cap on a server-provided `Retry-After`.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+/// Successful responses in a row before stepping back up.
+const RAMP_UP_AFTER: u32 = 20;
+/// How far ahead of the rate an idle limiter lets reads run.
+const RATE_BURST: Duration = Duration::from_millis(500);
+/// Range of the pause before each request with `--stealth`.
+pub const STEALTH_PAUSE: RangeInclusive<Duration> =
+    Duration::from_millis(800)..=Duration::from_millis(4000);
+/// The last this many requests tell whether the site is failing.
+const FAILURE_WINDOW: usize = 40;
+/// Fewer outcomes than this say nothing yet.
+const FAILURE_MIN_SAMPLES: usize = 20;
+/// Smallest delay between request starts once degraded.
+const DEGRADED_DELAY: Duration = Duration::from_secs(5);
+
+struct State {
+    /// Current concurrency limit, 1..=max.
+    limit: usize,
+    in_flight: usize,
+    /// Minimum spacing between request starts.
+    delay: Duration,
+    /// Earliest time the next request may start.
+    next_start: Instant,
+    /// Successful responses since the last adjustment.
+    streak: u32,
+    /// Whether each of the last `FAILURE_WINDOW` requests failed, oldest first.
+    outcomes: VecDeque<bool>,
+    /// One request at a time, `DEGRADED_DELAY` apart, until the site is
+    /// healthy again.
+    degraded: bool,
+}
+
+impl State {
+    fn outcome(&mut self, failed: bool) {
+        if self.outcomes.len() == FAILURE_WINDOW {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(failed);
+    }
+
+    fn failing(&self, limit: u8) -> bool {
+        let failed = self.outcomes.iter().filter(|&&failed| failed).count();
+        self.outcomes.len() >= FAILURE_MIN_SAMPLES
+            && failed * 100 > usize::from(limit) * self.outcomes.len()
+    }
+}
+
+pub struct Throttle {
+    max: usize,
+    /// Random pause between request starts, on top of the delay.
+    jitter: Option<RangeInclusive<Duration>>,
+    /// The percentage of failed requests above which the site is failing;
+    /// 100 never is.
+    failure_limit: u8,
+    state: Mutex<State>,
+    notify: Notify,
+    /// Woken when the site starts failing.
+    failing: Notify,
+}
+
+/// A slot for one request in flight; released on drop.
+pub struct Permit<'a> {
+    throttle: &'a Throttle,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.throttle.state.lock().unwrap().in_flight -= 1;
+        self.throttle.notify.notify_one();
+    }
+}
+
+impl Throttle {
+    /// Allow up to `max` concurrent requests with no delay until the server pushes back.
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            max,
+            jitter: None,
+            failure_limit: 100,
+            state: Mutex::new(State {
+                limit: max,
+                in_flight: 0,
+                delay: Duration::ZERO,
+                next_start: Instant::now(),
+                streak: 0,
+                outcomes: VecDeque::with_capacity(FAILURE_WINDOW),
+                degraded: false,
+            }),
+            notify: Notify::new(),
+            failing: Notify::new(),
+        }
+    }
+
+    /// Space request starts by a random pause within `jitter` (at least).
+    pub fn with_jitter(mut self, jitter: RangeInclusive<Duration>) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Count the site as failing once more than `percent` of the recent
+    /// requests failed.
+    pub fn with_failure_limit(mut self, percent: u8) -> Self {
+        self.failure_limit = percent.min(100);
+        self
+    }
+
+    /// The spacing before the next request start.
+    fn spacing(&self, delay: Duration) -> Duration {
+        let Some(jitter) = &self.jitter else {
+            return delay;
+        };
+        let (low, high) = (jitter.start().as_millis(), jitter.end().as_millis());
+        let pause = fastrand::u64(low as u64..=(high as u64).max(low as u64));
+        delay.max(Duration::from_millis(pause))
+    }
+
+    /// Wait until a request may start under the current limit and delay.
+    pub async fn acquire(&self) -> Permit<'_> {
+        loop {
+            let wait = {
+                let mut s = self.state.lock().unwrap();
+                let now = Instant::now();
+                if s.in_flight >= s.limit {
+                    None
+                } else if s.next_start <= now {
+                    s.in_flight += 1;
+                    s.next_start = now + self.spacing(s.delay);
+                    return Permit { throttle: self };
+                } else {
+                    Some(s.next_start - now)
+                }
+            };
+            match wait {
+                Some(d) => tokio::time::sleep(d).await,
+                None => self.notify.notified().await,
+            }
+        }
+    }
+
+    /// Feed back a response status. Returns true when the server throttled
+    /// the request, which should then be retried (`acquire` waits as needed).
+    pub fn record(&self, status: u16, retry_after: Option<Duration>) -> bool {
+        let mut s = self.state.lock().unwrap();
+        self.outcome(&mut s, status == 429 || status >= 500);
+        if status == 429 || status == 503 {
+            let (old_limit, old_delay) = (s.limit, s.delay);
+            s.streak = 0;
+            s.limit = (s.limit / 2).max(1);
+            s.delay = (s.delay * 2).clamp(MIN_DELAY, MAX_DELAY);
+            let pause = retry_after.unwrap_or(s.delay).min(MAX_RETRY_AFTER);
+            s.next_start = s.next_start.max(Instant::now() + pause);
+            warn!(
+                "HTTP {status}: slowing down (concurrency {old_limit} -> {}, delay {old_delay:?} -> {:?}, pause {pause:?})",
+                s.limit, s.delay
+            );
+            return true;
+        }
+
+        s.streak += 1;
+        if !s.degraded && s.streak >= RAMP_UP_AFTER && (s.limit < self.max || !s.delay.is_zero()) {
+            s.streak = 0;
+            s.limit = (s.limit + 1).min(self.max);
+            s.delay = if s.delay / 2 < MIN_DELAY {
+                Duration::ZERO
+            } else {
+                s.delay / 2
+            };
+            info!(
+                "Ramping back up (concurrency {}, delay {:?})",
+                s.limit, s.delay
+            );
+            drop(s);
+            self.notify.notify_one();
+        }
+        false
+    }
+
+    /// Feed back a request that got no response at all.
+    pub fn record_error(&self) {
+        let mut s = self.state.lock().unwrap();
+        self.outcome(&mut s, true);
+    }
+
+    /// Count one request's outcome: wake `until_failing` once the site
+    /// fails, or once degraded, recover after a whole window without it.
+    fn outcome(&self, s: &mut State, failed: bool) {
+        s.outcome(failed);
+        if s.failing(self.failure_limit) {
+            self.failing.notify_waiters();
+        } else if s.degraded && s.outcomes.len() == FAILURE_WINDOW {
+            s.degraded = false;
+            s.streak = 0;
+            info!("The site is answering again; ramping back up from here");
+        }
+    }
+
+    /// Whether more than the failure limit of the recent requests failed.
+    pub fn failing(&self) -> bool {
+        self.state.lock().unwrap().failing(self.failure_limit)
+    }
+
+    /// Resolves once the site is failing.
+    pub async fn until_failing(&self) {
+        loop {
+            let notified = self.failing.notified();
+            tokio::pin!(notified);
+            // Registered before checking, so no wakeup falls in between.
+            notified.as_mut().enable();
+            if self.failing() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Fall back to one request at a time, `DEGRADED_DELAY` apart, until a
+    /// whole window of requests goes by without the site failing; the
+    /// failures so far are forgotten.
+    pub fn degrade(&self) {
+        let mut s = self.state.lock().unwrap();
+        s.degraded = true;
+        s.limit = 1;
+        s.delay = s.delay.max(DEGRADED_DELAY);
+        s.outcomes.clear();
+        warn!("Degraded to one request at a time, {:?} apart", s.delay);
+    }
+
+    #[cfg(test)]
+    fn degraded(&self) -> bool {
+        self.state.lock().unwrap().degraded
+    }
+
+    #[cfg(test)]
+    fn limits(&self) -> (usize, Duration) {
+        let s = self.state.lock().unwrap();
+        (s.limit, s.delay)
+    }
+}
+
+/// A transfer rate in bytes per second, written like curl's `--limit-rate`
+/// (see `parse_bytes`), e.g. "2M".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = parse_bytes(s).with_context(|| t!("rate-invalid"))?;
+        if bytes == 0 {
+            bail!(t!("rate-zero", rate = format!("{s:?}")));
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl TryFrom<String> for Rate {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Paces reads so all responses together stay under a byte rate.
+pub struct RateLimit {
+    bytes_per_sec: u64,
+    /// When the bytes read so far will have been "paid for".
+    paid_until: Mutex<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(rate: Rate) -> Self {
+        Self {
+            bytes_per_sec: rate.0.max(1),
+            paid_until: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Account for `bytes` just read, sleeping when reads run ahead of the rate.
+    pub async fn take(&self, bytes: usize) {
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        let wait = {
+            let mut paid_until = self.paid_until.lock().unwrap();
+            let now = Instant::now();
+            *paid_until = (*paid_until).max(now) + cost;
+            paid_until.saturating_duration_since(now + RATE_BURST)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Rate, RateLimit, Throttle, DEGRADED_DELAY, FAILURE_MIN_SAMPLES, FAILURE_WINDOW, MIN_DELAY,
+        RAMP_UP_AFTER,
+    };
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!("2M".parse::<Rate>().unwrap(), Rate(2 << 20));
+        assert_eq!("500k".parse::<Rate>().unwrap(), Rate(500 << 10));
+        assert_eq!("1.5KB".parse::<Rate>().unwrap(), Rate(1536));
+        assert_eq!("4096".parse::<Rate>().unwrap(), Rate(4096));
+        assert!("2X".parse::<Rate>().is_err());
+        assert!("0".parse::<Rate>().is_err());
+        assert!("fast".parse::<Rate>().is_err());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_paces_reads() {
+        let limit = RateLimit::new(Rate(10_000));
+        let start = Instant::now();
+        // The burst allowance covers the first half second's worth.
+        limit.take(5_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        limit.take(2_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[test]
+    fn backs_off_and_ramps_up() {
+        let t = Throttle::new(8);
+        assert!(!t.record(200, None));
+        assert_eq!(t.limits(), (8, Duration::ZERO));
+
+        assert!(t.record(429, None));
+        assert_eq!(t.limits(), (4, MIN_DELAY));
+        assert!(t.record(503, None));
+        assert_eq!(t.limits(), (2, MIN_DELAY * 2));
+
+        for _ in 0..RAMP_UP_AFTER {
+            t.record(200, None);
+        }
+        assert_eq!(t.limits(), (3, MIN_DELAY));
+        for _ in 0..RAMP_UP_AFTER * 10 {
+            t.record(200, None);
+        }
+        assert_eq!(t.limits(), (8, Duration::ZERO));
+    }
+
+    #[test]
+    fn detects_systemic_failure_and_degrades() {
+        let t = Throttle::new(8).with_failure_limit(50);
+        // Too few requests to tell.
+        for _ in 0..FAILURE_MIN_SAMPLES - 1 {
+            t.record_error();
+        }
+        assert!(!t.failing());
+        // Missing assets (404) are not the site failing.
+        for _ in 0..30 {
+            t.record(404, None);
+        }
+        assert!(!t.failing());
+        for _ in 0..12 {
+            t.record(502, None);
+            t.record_error();
+        }
+        assert!(t.failing());
+
+        t.degrade();
+        assert!(t.degraded() && !t.failing());
+        assert_eq!(t.limits(), (1, DEGRADED_DELAY));
+        for _ in 0..FAILURE_WINDOW - 1 {
+            t.record(200, None);
+        }
+        assert!(t.degraded());
+        assert_eq!(t.limits(), (1, DEGRADED_DELAY));
+        // A whole window without failing: it ramps back up from there.
+        t.record(200, None);
+        assert!(!t.degraded());
+        for _ in 0..RAMP_UP_AFTER {
+            t.record(200, None);
+        }
+        assert_eq!(t.limits(), (2, DEGRADED_DELAY / 2));
+
+        // 100% never counts as failing.
+        let t = Throttle::new(8);
+        for _ in 0..FAILURE_MIN_SAMPLES * 2 {
+            t.record_error();
+        }
+        assert!(!t.failing());
+    }
+
+    #[tokio::test]
+    async fn wakes_once_the_site_fails() {
+        let t = std::sync::Arc::new(Throttle::new(4).with_failure_limit(50));
+        let waiter = tokio::spawn({
+            let t = t.clone();
+            async move { t.until_failing().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        for _ in 0..FAILURE_MIN_SAMPLES {
+            t.record_error();
+        }
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn jitter_spaces_requests() {
+        let t = Throttle::new(4).with_jitter(Duration::from_millis(30)..=Duration::from_millis(60));
+        let start = Instant::now();
+        let _a = t.acquire().await;
+        let _b = t.acquire().await;
+        let _c = t.acquire().await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(60), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn never_exceeds_the_limit() {
+        let t = Throttle::new(2);
+        let a = t.acquire().await;
+        let _b = t.acquire().await;
+        let blocked = tokio::time::timeout(Duration::from_millis(20), t.acquire()).await;
+        assert!(blocked.is_err());
+
+        drop(a);
+        let c = tokio::time::timeout(Duration::from_millis(20), t.acquire()).await;
+        assert!(c.is_ok());
+    }
+}
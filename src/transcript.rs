@@ -0,0 +1,272 @@
+use crate::annotations;
+use crate::api::OreillyApi;
+use crate::book::{BookOptions, BuildStats, WrittenChapter};
+use crate::display::{Display, DownloadEvent};
+use crate::epub::{xml_escape, EpubSkeleton, ManifestItem, NavPoint, PackageMetadata};
+use crate::i18n::t;
+use crate::orly::{fetch_toc, fetch_transcript, iso_date, BookInfo, TocEntry};
+use anyhow::Result;
+use std::time::SystemTime;
+
+/// Paragraphs are split at the first sentence end past this many characters.
+const PARAGRAPH_SOFT_LIMIT: usize = 600;
+
+/// Turn a WebVTT transcript into readable paragraphs.
+/// Drops the header, cue identifiers, timings, NOTE/STYLE blocks and inline
+/// tags (e.g. `<v Speaker>`), then re-flows the cue text into prose.
+pub fn vtt_to_paragraphs(vtt: &str) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    let mut current = String::new();
+
+    let lines: Vec<&str> = vtt.lines().map(str::trim).collect();
+    let mut blocks = lines
+        .split(|line| line.is_empty())
+        .filter(|block| !block.is_empty())
+        .peekable();
+    // "WEBVTT" and the header fields under it ("Kind: captions", ...).
+    if blocks.peek().is_some_and(|block| {
+        block[0]
+            .trim_start_matches('\u{feff}')
+            .starts_with("WEBVTT")
+    }) {
+        blocks.next();
+    }
+    for block in blocks {
+        if ["NOTE", "STYLE", "REGION"]
+            .iter()
+            .any(|kind| block[0].starts_with(kind))
+        {
+            continue;
+        }
+        // A cue: its identifier (any text, if there is one), its timings,
+        // then its text.
+        let cue_text = match block.iter().position(|line| line.contains("-->")) {
+            Some(timings) if timings <= 1 => &block[timings + 1..],
+            _ => block,
+        };
+        for line in cue_text.iter().filter(|line| !line.contains("-->")) {
+            let text = strip_tags(line);
+            if text.is_empty() {
+                continue;
+            }
+            if !current.is_empty() {
+                current.push(' ');
+            }
+            current.push_str(&text);
+
+            let ends_sentence = text.ends_with(['.', '?', '!']);
+            if ends_sentence && current.len() >= PARAGRAPH_SOFT_LIMIT {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current);
+    }
+    paragraphs
+}
+
+/// Remove `<...>` cue markup and collapse internal whitespace.
+fn strip_tags(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut in_tag = false;
+    for ch in line.chars() {
+        match ch {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => out.push(ch),
+            _ => {}
+        }
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Download every clip transcript of a video course and write them as
+/// one XHTML document per lesson, with clips as sections. Returns the
+/// table of contents mirroring the lesson structure.
+pub async fn build_transcript_epub<A: OreillyApi>(
+    api: &A,
+    ui: &Display,
+    bookid: &str,
+    info: &BookInfo,
+    skeleton: &EpubSkeleton,
+    opts: &BookOptions,
+) -> Result<BuildStats> {
+    let toc = fetch_toc(api, info, bookid).await?;
+    ui.info(&t!("course-lessons", count = toc.len()));
+    ui.add_work(toc.len());
+
+    let mut manifest = Vec::new();
+    let mut spine = Vec::new();
+    let mut nav = Vec::new();
+    let mut written = Vec::new();
+
+    for (i, lesson) in toc.iter().enumerate() {
+        opts.check_cancelled()?;
+        let id = format!("lesson_{:03}", i + 1);
+        let href = format!("{id}.xhtml");
+
+        let mut body = format!("<h1>{}</h1>\n", xml_escape(&lesson.label));
+        let mut children = Vec::new();
+        if lesson.children.is_empty() {
+            // A lesson without clips is a clip of its own.
+            body.push_str(&clip_body(api, ui, lesson).await?);
+        } else {
+            let mut counter = 0;
+            for clip in &lesson.children {
+                children.push(write_clip(api, ui, clip, &href, 2, &mut counter, &mut body).await?);
+            }
+        }
+
+        skeleton.write_xhtml(&href, &lesson.label, &body)?;
+        manifest.push(ManifestItem {
+            id: id.clone(),
+            href: href.clone(),
+            media_type: "application/xhtml+xml".to_string(),
+        });
+        spine.push(id);
+        written.push(WrittenChapter {
+            title: lesson.label.clone(),
+            href: href.clone(),
+        });
+        nav.push(NavPoint {
+            title: lesson.label.clone(),
+            href,
+            children,
+        });
+        ui.info(&t!(
+            "lesson-done",
+            index = i + 1,
+            total = toc.len(),
+            title = lesson.label.as_str()
+        ));
+        ui.event(&DownloadEvent::ChapterDone {
+            index: i + 1,
+            total: toc.len(),
+            title: lesson.label.clone(),
+        });
+        ui.work_done(1);
+    }
+
+    let mut meta = PackageMetadata::from_book_info(bookid, info);
+    meta.apply(&opts.metadata);
+    if !opts.annotations.is_empty() {
+        let (item, point) = annotations::write_appendix(skeleton, &opts.annotations)?;
+        spine.push(item.id.clone());
+        manifest.push(item);
+        nav.push(point);
+    }
+    if opts.credits {
+        let item = skeleton.write_colophon(&meta, &iso_date(SystemTime::now()))?;
+        spine.push(item.id.clone());
+        manifest.push(item);
+    }
+    skeleton.write_toc(&meta, &nav, &[], &[])?;
+    skeleton.write_opf(&meta, &manifest, &spine)?;
+    Ok(BuildStats {
+        chapters: toc.len(),
+        written,
+        ..BuildStats::default()
+    })
+}
+
+/// Append a clip section (and its nested clips) to `body`, returning its nav point.
+async fn write_clip<A: OreillyApi>(
+    api: &A,
+    ui: &Display,
+    clip: &TocEntry,
+    href: &str,
+    level: usize,
+    counter: &mut usize,
+    body: &mut String,
+) -> Result<NavPoint> {
+    *counter += 1;
+    let anchor = format!("clip-{}", counter);
+    let h = level.min(6);
+    body.push_str(&format!(
+        "<section id=\"{anchor}\">\n<h{h}>{}</h{h}>\n",
+        xml_escape(&clip.label)
+    ));
+    body.push_str(&clip_body(api, ui, clip).await?);
+
+    let mut children = Vec::new();
+    for child in &clip.children {
+        children.push(Box::pin(write_clip(api, ui, child, href, level + 1, counter, body)).await?);
+    }
+    body.push_str("</section>\n");
+
+    Ok(NavPoint {
+        title: clip.label.clone(),
+        href: format!("{href}#{anchor}"),
+        children,
+    })
+}
+
+/// Render the transcript paragraphs of a single clip.
+async fn clip_body<A: OreillyApi>(api: &A, ui: &Display, clip: &TocEntry) -> Result<String> {
+    if clip.id.is_empty() {
+        return Ok(String::new());
+    }
+    match fetch_transcript(api, &clip.id).await? {
+        Some(vtt) => Ok(vtt_to_paragraphs(&vtt)
+            .iter()
+            .map(|p| format!("<p>{}</p>\n", xml_escape(p)))
+            .collect()),
+        None => {
+            ui.warn(&t!("no-transcript", lesson = clip.label.as_str()));
+            Ok("<p><em>No transcript available.</em></p>\n".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::vtt_to_paragraphs;
+
+    #[test]
+    fn strips_header_timings_and_cue_ids() {
+        let vtt = "WEBVTT\n\n1\n00:00:00.000 --> 00:00:02.000\nHello and welcome.\n\n\
+                   2\n00:00:02.000 --> 00:00:04.000\nLet's get started.\n";
+        assert_eq!(
+            vtt_to_paragraphs(vtt),
+            vec!["Hello and welcome. Let's get started.".to_string()]
+        );
+    }
+
+    #[test]
+    fn skips_note_blocks_and_voice_tags() {
+        let vtt = "WEBVTT\n\nNOTE this is a comment\nspanning lines\n\n\
+                   00:00:00.000 --> 00:00:02.000\n<v Instructor>Hi <i>there</i></v>\n";
+        assert_eq!(vtt_to_paragraphs(vtt), vec!["Hi there".to_string()]);
+    }
+
+    #[test]
+    fn drops_named_cue_ids_and_header_fields() {
+        let vtt = "WEBVTT - Lesson 1\nKind: captions\nLanguage: en\n\n\
+                   intro\n00:00:00.000 --> 00:00:02.000\nWelcome back.\n\n\
+                   4f1c9a2e-7b3d-4e0a-9c55-1a2b3c4d5e6f\n00:00:02.000 --> 00:00:04.000\n\
+                   The answer is\n\n\
+                   00:00:04.000 --> 00:00:05.000\n42\n";
+        assert_eq!(
+            vtt_to_paragraphs(vtt),
+            vec!["Welcome back. The answer is 42".to_string()]
+        );
+    }
+
+    #[test]
+    fn splits_long_text_at_sentence_end() {
+        let sentence = "word ".repeat(130).trim().to_string() + ".";
+        let vtt = format!(
+            "WEBVTT\n\n00:00:00.000 --> 00:00:01.000\n{sentence}\n\n\
+             00:00:01.000 --> 00:00:02.000\nNext part.\n"
+        );
+        let paragraphs = vtt_to_paragraphs(&vtt);
+        assert_eq!(paragraphs.len(), 2);
+        assert_eq!(paragraphs[1], "Next part.");
+    }
+
+    #[test]
+    fn empty_transcript_has_no_paragraphs() {
+        assert!(vtt_to_paragraphs("WEBVTT\n\n").is_empty());
+    }
+}
@@ -0,0 +1,370 @@
+//! `self-update`: replace this executable with the latest release's build
+//! for the platform, once it matches the SHA-256 published beside it and
+//! its minisign signature verifies against the release key built in.
+//!
+//! Each release publishes, per platform, `safaribooks-rs-{ARCH}-{OS}{EXE_SUFFIX}`
+//! (`std::env::consts`, e.g. "safaribooks-rs-x86_64-linux" or
+//! "safaribooks-rs-x86_64-windows.exe") with two assets beside it:
+//! `<name>.sha256`, a `sha256sum` line, and `<name>.minisig`, from
+//! `minisign -S -l -m <name>`. Signatures must be made without prehashing
+//! (`-l`): the whole binary is signed.
+
+use crate::checksum::sha256_bytes;
+use crate::i18n::t;
+use anyhow::{anyhow, bail, Context, Result};
+use aws_lc_rs::signature::{UnparsedPublicKey, ED25519};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use reqwest::header::ACCEPT;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// The project's latest release, from the GitHub API.
+const RELEASES_URL: &str = "https://api.github.com/repos/Farzat07/safaribooks-rs/releases/latest";
+const TIMEOUT: Duration = Duration::from_secs(300);
+/// The minisign public key releases are signed with (the base64 line of
+/// `minisign.pub`), set when the release build is made. Without one this
+/// build can check for updates but installs none.
+const RELEASE_KEY: Option<&str> = option_env!("SAFARIBOOKS_RELEASE_KEY");
+/// minisign's algorithm tag for an Ed25519 signature over the whole file;
+/// "ED" is a signature over its BLAKE2b hash.
+const ED25519_TAG: &[u8] = b"Ed";
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// What `self-update` found or did.
+#[derive(Debug, PartialEq)]
+pub enum Update {
+    /// This is the latest version.
+    UpToDate(String),
+    /// A newer version exists (`--check`).
+    Available(String),
+    /// The newer version replaced this executable.
+    Installed(String),
+}
+
+/// The release binary for this platform, e.g. "safaribooks-rs-x86_64-linux"
+/// or "safaribooks-rs-x86_64-windows.exe"; its checksum and signature are
+/// the same name plus ".sha256" and ".minisig".
+fn asset_name() -> String {
+    use std::env::consts::{ARCH, EXE_SUFFIX, OS};
+    format!("safaribooks-rs-{ARCH}-{OS}{EXE_SUFFIX}")
+}
+
+/// Whether the release tagged `latest` ("v1.2.3" or "1.2.3") is newer than
+/// version `current`.
+fn is_newer(latest: &str, current: &str) -> bool {
+    let parts = |v: &str| -> Vec<u64> {
+        let v = v.trim().trim_start_matches('v');
+        let core = v.split(['-', '+']).next().unwrap_or_default();
+        core.split('.').map(|p| p.parse().unwrap_or(0)).collect()
+    };
+    parts(latest) > parts(current)
+}
+
+/// The binary, checksum and signature assets for `name`; a build without
+/// a checksum or a signature is never installed.
+fn pick<'a>(release: &'a Release, name: &str) -> Result<(&'a Asset, &'a Asset, &'a Asset)> {
+    let find = |name: &str| release.assets.iter().find(|a| a.name == name);
+    let binary = find(name).with_context(|| {
+        t!(
+            "release-no-build",
+            release = release.tag_name.as_str(),
+            asset = name
+        )
+    })?;
+    let checksum = find(&format!("{name}.sha256")).with_context(|| {
+        t!(
+            "release-no-checksum",
+            release = release.tag_name.as_str(),
+            asset = name
+        )
+    })?;
+    let signature = find(&format!("{name}.minisig")).with_context(|| {
+        t!(
+            "release-no-signature",
+            release = release.tag_name.as_str(),
+            asset = name
+        )
+    })?;
+    Ok((binary, checksum, signature))
+}
+
+/// The digest from a `sha256sum`-style line ("<hex>  <file>").
+fn parse_checksum(text: &str) -> Result<String> {
+    let digest = text.split_whitespace().next().unwrap_or_default();
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!(t!(
+            "checksum-malformed",
+            text = format!("{:?}", text.trim())
+        ));
+    }
+    Ok(digest.to_ascii_lowercase())
+}
+
+/// The base64 payload of a minisign line, which must be `len` bytes long.
+fn decode_line(line: Option<&str>, len: usize) -> Option<Vec<u8>> {
+    let bytes = BASE64.decode(line?.trim()).ok()?;
+    (bytes.len() == len).then_some(bytes)
+}
+
+/// Check `data` against the minisign `signature` file with public `key`
+/// (the base64 line of a `minisign.pub`, or the whole file): the signature
+/// over the file must come from that key, and so must the one over its
+/// trusted comment.
+fn verify_signature(key: &str, signature: &str, data: &[u8]) -> Result<()> {
+    // "Ed", key ID (8 bytes), Ed25519 public key (32 bytes).
+    let key = key
+        .lines()
+        .rfind(|l| !l.trim().is_empty() && !l.starts_with("untrusted comment:"));
+    let key = decode_line(key, 42)
+        .filter(|k| k.starts_with(ED25519_TAG))
+        .with_context(|| t!("release-key-malformed"))?;
+    let (key_id, key) = key[2..].split_at(8);
+    let key = UnparsedPublicKey::new(&ED25519, key);
+
+    // untrusted comment, base64("Ed" or "ED", key ID, signature),
+    // "trusted comment: ...", base64(signature over signature + comment).
+    let mut lines = signature.lines().filter(|l| !l.trim().is_empty());
+    let _untrusted = lines.next();
+    let file_signature = decode_line(lines.next(), 74);
+    let comment = lines
+        .next()
+        .and_then(|l| l.strip_prefix("trusted comment: "));
+    let global_signature = decode_line(lines.next(), 64);
+    let (Some(file_signature), Some(comment), Some(global_signature)) =
+        (file_signature, comment, global_signature)
+    else {
+        bail!(t!("signature-malformed"));
+    };
+    let (tag, rest) = file_signature.split_at(2);
+    let (signed_by, file_signature) = rest.split_at(8);
+    if tag != ED25519_TAG {
+        bail!(t!(
+            "signature-prehashed",
+            tag = String::from_utf8_lossy(tag).into_owned()
+        ));
+    }
+    if signed_by != key_id {
+        bail!(t!("signature-other-key"));
+    }
+    key.verify(data, file_signature)
+        .map_err(|_| anyhow!(t!("signature-invalid")))?;
+    key.verify(
+        &[file_signature, comment.as_bytes()].concat(),
+        &global_signature,
+    )
+    .map_err(|_| anyhow!(t!("signature-comment-invalid")))?;
+    Ok(())
+}
+
+/// Check for a newer release and, unless `check_only`, install it.
+pub async fn run(check_only: bool) -> Result<Update> {
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("safaribooks-rs/", env!("CARGO_PKG_VERSION")))
+        .timeout(TIMEOUT)
+        .build()?;
+    let release: Release = client
+        .get(RELEASES_URL)
+        .header(ACCEPT, "application/vnd.github+json")
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .with_context(|| t!("http-get", url = RELEASES_URL))?
+        .json()
+        .await
+        .with_context(|| t!("release-feed-unreadable"))?;
+    let version = release.tag_name.trim_start_matches('v').to_string();
+    if !is_newer(&release.tag_name, env!("CARGO_PKG_VERSION")) {
+        return Ok(Update::UpToDate(env!("CARGO_PKG_VERSION").to_string()));
+    }
+    if check_only {
+        return Ok(Update::Available(version));
+    }
+
+    let Some(key) = RELEASE_KEY else {
+        bail!(t!("release-key-missing", version = version.as_str()));
+    };
+    let (binary, checksum, signature) = pick(&release, &asset_name())?;
+    async fn get(client: &reqwest::Client, url: &str) -> Result<Vec<u8>> {
+        let res = client.get(url).send().await;
+        let body = match res.and_then(|res| res.error_for_status()) {
+            Ok(res) => res.bytes().await.map(Vec::from),
+            Err(e) => Err(e),
+        };
+        body.with_context(|| t!("http-get", url = url))
+    }
+    let expected = parse_checksum(&String::from_utf8_lossy(
+        &get(&client, &checksum.browser_download_url).await?,
+    ))?;
+    let new = get(&client, &binary.browser_download_url).await?;
+    let actual = sha256_bytes(&new);
+    if actual != expected {
+        bail!(t!(
+            "checksum-mismatch",
+            asset = binary.name.as_str(),
+            expected = expected,
+            actual = actual
+        ));
+    }
+    let signature = get(&client, &signature.browser_download_url).await?;
+    verify_signature(key, &String::from_utf8_lossy(&signature), &new)
+        .with_context(|| t!("signature-rejected", asset = binary.name.as_str()))?;
+    let exe = std::env::current_exe().with_context(|| t!("locating-executable"))?;
+    replace_executable(&exe, &new)?;
+    Ok(Update::Installed(version))
+}
+
+/// Put `new` in place of the executable at `exe`: written beside it with
+/// the same permissions, then renamed over it. Windows cannot replace a
+/// running executable but can rename it, so there the old one is moved
+/// aside to "<name>.old" first (and removed by the next update).
+fn replace_executable(exe: &Path, new: &[u8]) -> Result<()> {
+    let name = exe.file_name().with_context(|| t!("executable-no-name"))?;
+    let with_suffix = |suffix: &str| {
+        let mut name = name.to_os_string();
+        name.push(suffix);
+        exe.with_file_name(name)
+    };
+    let staged = with_suffix(".new");
+    fs::write(&staged, new)
+        .with_context(|| t!("writing-file", path = staged.display().to_string()))?;
+    let installed = fs::metadata(exe)
+        .and_then(|m| fs::set_permissions(&staged, m.permissions()))
+        .and_then(|()| {
+            if cfg!(windows) {
+                let old = with_suffix(".old");
+                let _ = fs::remove_file(&old);
+                fs::rename(exe, &old)?;
+            }
+            fs::rename(&staged, exe)
+        });
+    if let Err(e) = installed {
+        let _ = fs::remove_file(&staged);
+        return Err(e).with_context(|| t!("replacing-file", path = exe.display().to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        asset_name, is_newer, parse_checksum, pick, replace_executable, verify_signature, Release,
+        BASE64,
+    };
+    use aws_lc_rs::rand::SystemRandom;
+    use aws_lc_rs::signature::{Ed25519KeyPair, KeyPair};
+    use base64::Engine;
+    use std::fs;
+
+    #[test]
+    fn compares_versions() {
+        assert!(is_newer("v0.2.0", "0.1.0"));
+        assert!(is_newer("0.1.10", "0.1.9"));
+        assert!(is_newer("v1.0.0-rc.1", "0.9.3"));
+        assert!(!is_newer("v0.1.0", "0.1.0"));
+        assert!(!is_newer("v0.0.9", "0.1.0"));
+    }
+
+    #[test]
+    fn picks_the_platform_build_its_checksum_and_signature() {
+        let name = asset_name();
+        let release: Release = serde_json::from_value(serde_json::json!({
+            "tag_name": "v9.0.0",
+            "assets": [
+                { "name": "safaribooks-rs-sparc-plan9", "browser_download_url": "https://x/a" },
+                { "name": name, "browser_download_url": "https://x/b" },
+                { "name": format!("{name}.sha256"), "browser_download_url": "https://x/b.sha256" },
+                { "name": format!("{name}.minisig"), "browser_download_url": "https://x/b.minisig" },
+                { "name": "safaribooks-rs-mips-haiku", "browser_download_url": "https://x/c" },
+                { "name": "safaribooks-rs-mips-haiku.sha256", "browser_download_url": "https://x/c.sha256" }
+            ]
+        }))
+        .unwrap();
+        let (binary, checksum, signature) = pick(&release, &name).unwrap();
+        assert_eq!(binary.browser_download_url, "https://x/b");
+        assert_eq!(checksum.browser_download_url, "https://x/b.sha256");
+        assert_eq!(signature.browser_download_url, "https://x/b.minisig");
+        let err = pick(&release, "safaribooks-rs-sparc-plan9").unwrap_err();
+        assert!(err.to_string().contains("no checksum"));
+        let err = pick(&release, "safaribooks-rs-mips-haiku").unwrap_err();
+        assert!(err.to_string().contains("no signature"));
+
+        let digest = "AB".repeat(32);
+        assert_eq!(
+            parse_checksum(&format!("{digest}  {name}\n")).unwrap(),
+            digest.to_lowercase()
+        );
+        assert!(parse_checksum("not-a-digest  file").is_err());
+    }
+
+    /// A minisign key pair: the public key line, and a signer producing
+    /// `.minisig` files with `tag` and key ID `id`.
+    fn minisign_key(id: [u8; 8]) -> (String, impl Fn(&[u8], &[u8], &str) -> String) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public = BASE64.encode([b"Ed", &id[..], pair.public_key().as_ref()].concat());
+        let sign = move |tag: &[u8], data: &[u8], comment: &str| {
+            let signature = pair.sign(data);
+            let global = pair.sign(&[signature.as_ref(), comment.as_bytes()].concat());
+            format!(
+                "untrusted comment: signature from minisign secret key\n{}\ntrusted comment: {comment}\n{}\n",
+                BASE64.encode([tag, &id[..], signature.as_ref()].concat()),
+                BASE64.encode(global.as_ref()),
+            )
+        };
+        (public, sign)
+    }
+
+    #[test]
+    fn verifies_minisign_signatures() {
+        let (key, sign) = minisign_key(*b"releases");
+        let binary = b"new build".as_slice();
+        let signature = sign(b"Ed", binary, "timestamp:1760000000\tfile:safaribooks-rs");
+        verify_signature(&key, &signature, binary).unwrap();
+        // The whole minisign.pub works as well as its key line.
+        let pub_file = format!("untrusted comment: minisign public key\n{key}\n");
+        verify_signature(&pub_file, &signature, binary).unwrap();
+
+        let err = verify_signature(&key, &signature, b"tampered build").unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{err}");
+        let forged = signature.replace("timestamp:", "timestamp:9");
+        let err = verify_signature(&key, &forged, binary).unwrap_err();
+        assert!(err.to_string().contains("trusted comment"), "{err}");
+        let (_, other) = minisign_key(*b"somebody");
+        let err = verify_signature(&key, &other(b"Ed", binary, "x"), binary).unwrap_err();
+        assert!(err.to_string().contains("key other than"), "{err}");
+        let (same_id, impostor) = minisign_key(*b"releases");
+        assert_ne!(same_id, key);
+        assert!(verify_signature(&key, &impostor(b"Ed", binary, "x"), binary).is_err());
+        let err = verify_signature(&key, &sign(b"ED", binary, "x"), binary).unwrap_err();
+        assert!(err.to_string().contains("-l"), "{err}");
+        assert!(verify_signature(&key, "untrusted comment: x\nnot base64\n", binary).is_err());
+        assert!(verify_signature("not a key", &signature, binary).is_err());
+    }
+
+    #[test]
+    fn replaces_the_executable() {
+        let dir = std::env::temp_dir().join(format!("safaribooks-update-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("safaribooks-rs");
+        fs::write(&exe, b"old").unwrap();
+        replace_executable(&exe, b"new").unwrap();
+        assert_eq!(fs::read(&exe).unwrap(), b"new");
+        assert!(!dir.join("safaribooks-rs.new").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,412 @@
+//! A quick structural check of the built package before it is zipped:
+//! not epubcheck, but enough to catch what would break on a reader.
+
+use crate::epub::{media_type_for, EpubSkeleton};
+use crate::html::{decode_entities, Token, Tokenizer};
+use crate::i18n::t;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Problems found in the package under `skeleton`, one message each.
+pub fn validate(skeleton: &EpubSkeleton) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    let container = skeleton.meta_inf.join("container.xml");
+    match fs::read_to_string(&container) {
+        Ok(raw) => {
+            if let Err(e) = check_well_formed(&raw) {
+                problems.push(t!(
+                    "problem-in",
+                    path = "META-INF/container.xml",
+                    problem = e
+                ));
+            }
+        }
+        Err(_) => problems.push(t!("file-missing", path = "META-INF/container.xml")),
+    }
+
+    let opf_path = skeleton.oebps.join("content.opf");
+    let opf = fs::read_to_string(&opf_path)
+        .with_context(|| t!("reading-file", path = opf_path.display().to_string()))?;
+    if let Err(e) = check_well_formed(&opf) {
+        problems.push(t!("problem-in", path = "content.opf", problem = e));
+    }
+
+    // id -> (href, media type), in manifest order.
+    let mut items: Vec<(String, String, String)> = Vec::new();
+    let mut spine = Vec::new();
+    for token in Tokenizer::new(&opf) {
+        if token.is_start("item") {
+            let attr = |k| decode_entities(token.attr(k).unwrap_or_default());
+            items.push((attr("id"), attr("href"), attr("media-type")));
+        } else if token.is_start("itemref") {
+            spine.push(decode_entities(token.attr("idref").unwrap_or_default()));
+        }
+    }
+    let by_id: HashMap<&str, (&str, &str)> = items
+        .iter()
+        .map(|(id, href, ty)| (id.as_str(), (href.as_str(), ty.as_str())))
+        .collect();
+    let listed: HashSet<&str> = items.iter().map(|(_, href, _)| href.as_str()).collect();
+
+    for (id, href, media_type) in &items {
+        let path = skeleton.oebps.join(href);
+        let Ok(data) = fs::read(&path) else {
+            problems.push(t!(
+                "manifest-item-missing",
+                id = format!("{id:?}"),
+                href = href.as_str()
+            ));
+            continue;
+        };
+        let expected = sniff(&data).unwrap_or_else(|| media_type_for(href));
+        if expected != "application/octet-stream" && media_type != expected {
+            problems.push(t!(
+                "media-type-mismatch",
+                href = href.as_str(),
+                declared = media_type.as_str(),
+                actual = expected
+            ));
+        }
+        if media_type == "application/xhtml+xml" {
+            let text = String::from_utf8_lossy(&data);
+            match check_well_formed(&text) {
+                Ok(()) => problems.extend(
+                    broken_links(&text, href, &listed)
+                        .into_iter()
+                        .map(|target| t!("broken-link", href = href.as_str(), target = target)),
+                ),
+                Err(e) => problems.push(t!("problem-in", path = href.as_str(), problem = e)),
+            }
+        }
+    }
+
+    if spine.is_empty() {
+        problems.push(t!("spine-empty"));
+    }
+    for idref in &spine {
+        match by_id.get(idref.as_str()) {
+            None => problems.push(t!("spine-item-unknown", id = format!("{idref:?}"))),
+            Some((href, ty)) if *ty != "application/xhtml+xml" => problems.push(t!(
+                "spine-item-not-xhtml",
+                id = format!("{idref:?}"),
+                href = *href,
+                media_type = *ty
+            )),
+            Some(_) => {}
+        }
+    }
+
+    for path in files_below(&skeleton.oebps) {
+        let href = path
+            .strip_prefix(&skeleton.oebps)
+            .unwrap_or(&path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let partial = href.ends_with(".part") || href.ends_with(".partinfo");
+        if href != "content.opf" && !partial && !listed.contains(href.as_str()) {
+            problems.push(t!("not-in-manifest", href = href));
+        }
+    }
+    Ok(problems)
+}
+
+fn files_below(dir: &Path) -> Vec<std::path::PathBuf> {
+    let mut files = Vec::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return files;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            files.extend(files_below(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Media type told by the first bytes, for the formats the books use.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Local targets of src/href attributes in `xhtml` (at `href` inside
+/// OEBPS/) that are not among the manifest hrefs.
+fn broken_links(xhtml: &str, href: &str, listed: &HashSet<&str>) -> Vec<String> {
+    let dir = href.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let mut broken = Vec::new();
+    for token in Tokenizer::new(xhtml) {
+        let Token::Start { .. } = token else {
+            continue;
+        };
+        for key in ["src", "href"] {
+            let Some(value) = token.attr(key) else {
+                continue;
+            };
+            let target = decode_entities(value.split('#').next().unwrap_or_default());
+            if target.is_empty() || target.contains(':') || target.starts_with('/') {
+                continue;
+            }
+            let resolved = normalize(dir, &target);
+            if !listed.contains(resolved.as_str()) && !broken.contains(&target) {
+                broken.push(target);
+            }
+        }
+    }
+    broken
+}
+
+/// Join a relative `target` onto `dir`, resolving "." and "..".
+fn normalize(dir: &str, target: &str) -> String {
+    let mut parts: Vec<&str> = dir.split('/').filter(|p| !p.is_empty()).collect();
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Check that `xml` is well-formed enough for an XML parser: balanced,
+/// properly nested tags, quoted attributes and only XML's own entities.
+pub fn check_well_formed(xml: &str) -> std::result::Result<(), String> {
+    let line = |at: usize| xml[..at].matches('\n').count() + 1;
+    let mut open: Vec<(&str, usize)> = Vec::new();
+    let mut i = 0;
+    while let Some(offset) = xml[i..].find(['<', '&']) {
+        let at = i + offset;
+        let rest = &xml[at..];
+        if rest.starts_with('&') {
+            check_entity(rest).map_err(|e| t!("xml-at-line", line = line(at), problem = e))?;
+            i = at + 1;
+            continue;
+        }
+        let skip_to = |end: &str| {
+            rest.find(end)
+                .map(|n| at + n + end.len())
+                .ok_or_else(|| t!("xml-unclosed", line = line(at), markup = &rest[..2]))
+        };
+        if rest.starts_with("<!--") {
+            i = skip_to("-->")?;
+        } else if rest.starts_with("<![CDATA[") {
+            i = skip_to("]]>")?;
+        } else if rest.starts_with("<?") {
+            i = skip_to("?>")?;
+        } else if rest.starts_with("<!") {
+            i = skip_to(">")?;
+        } else {
+            let end = tag_end(rest).ok_or_else(|| t!("xml-unclosed-tag", line = line(at)))?;
+            let tag = &rest[1..end];
+            if let Some(name) = tag.strip_prefix('/') {
+                let name = name.trim();
+                match open.pop() {
+                    Some((expected, _)) if expected == name => {}
+                    Some((expected, start)) => {
+                        return Err(t!(
+                            "xml-misnested",
+                            line = line(at),
+                            name = name,
+                            expected = expected,
+                            opened = line(start)
+                        ));
+                    }
+                    None => return Err(t!("xml-stray-end", line = line(at), name = name)),
+                }
+            } else {
+                let self_closing = tag.ends_with('/');
+                let name = tag
+                    .split(|c: char| c.is_whitespace() || c == '/')
+                    .next()
+                    .unwrap_or_default();
+                if name.is_empty() {
+                    return Err(t!("xml-stray-lt", line = line(at)));
+                }
+                check_attributes(&tag[name.len()..])
+                    .map_err(|e| t!("xml-in-tag", line = line(at), name = name, problem = e))?;
+                if !self_closing {
+                    open.push((name, at));
+                }
+            }
+            i = at + end + 1;
+        }
+    }
+    match open.pop() {
+        Some((name, at)) => Err(t!("xml-never-closed", line = line(at), name = name)),
+        None => Ok(()),
+    }
+}
+
+/// Offset of the '>' ending the tag at the start of `s`, skipping quoted values.
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '>') => return Some(i),
+            (None, '<') => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn check_attributes(attrs: &str) -> std::result::Result<(), String> {
+    let mut rest = attrs.trim_start();
+    while !rest.is_empty() && rest != "/" {
+        let Some(eq) = rest.find('=') else {
+            return Err(t!(
+                "xml-attribute-no-value",
+                attribute = rest.trim_end_matches('/')
+            ));
+        };
+        let value = rest[eq + 1..].trim_start();
+        let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            return Err(t!("xml-attribute-unquoted", attribute = rest[..eq].trim()));
+        };
+        let Some(len) = value[1..].find(quote) else {
+            return Err(t!(
+                "xml-attribute-unterminated",
+                attribute = rest[..eq].trim()
+            ));
+        };
+        rest = value[len + 2..].trim_start();
+    }
+    Ok(())
+}
+
+/// `s` starts with '&': it must be one of XML's five entities or a character reference.
+fn check_entity(s: &str) -> std::result::Result<(), String> {
+    let Some(end) = s[1..].find(';').filter(|&n| n <= 32) else {
+        return Err(t!("xml-bare-ampersand"));
+    };
+    let name = &s[1..end + 1];
+    let valid = match name.strip_prefix('#') {
+        Some(hex) if hex.starts_with(['x', 'X']) => {
+            !hex[1..].is_empty() && hex[1..].chars().all(|c| c.is_ascii_hexdigit())
+        }
+        Some(dec) => !dec.is_empty() && dec.chars().all(|c| c.is_ascii_digit()),
+        None => matches!(name, "amp" | "lt" | "gt" | "quot" | "apos"),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(t!("xml-undefined-entity", name = name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_well_formed, normalize, validate};
+    use crate::config::Naming;
+    use crate::epub::{EpubSkeleton, ManifestItem, NameLimit, PackageMetadata};
+    use crate::orly::BookInfo;
+    use std::fs;
+
+    #[test]
+    fn checks_well_formedness() {
+        assert!(
+            check_well_formed("<?xml version=\"1.0\"?><a x='1'><b/>&amp;&#160;<!-- < --></a>")
+                .is_ok()
+        );
+        for bad in [
+            "<p>&nbsp;</p>",
+            "<p><b></p></b>",
+            "<p>",
+            "<img src=x/>",
+            "</p>",
+            "a & b",
+        ] {
+            assert!(check_well_formed(bad).is_err(), "{bad:?}");
+        }
+        let err = check_well_formed("<html>\n<p>\n</html>").unwrap_err();
+        assert_eq!(err, "line 3: </html> closes <p> from line 2");
+        assert_eq!(normalize("Text", "../Images/a.png"), "Images/a.png");
+    }
+
+    #[test]
+    fn reports_package_problems() {
+        let info: BookInfo = serde_json::from_value(serde_json::json!({
+            "title": "Check",
+            "web_url": "https://learning.oreilly.com/library/view/x/123/"
+        }))
+        .unwrap();
+        let base =
+            std::env::temp_dir().join(format!("safaribooks-validate-{}", std::process::id()));
+        let skeleton = EpubSkeleton::plan(
+            &base,
+            &Naming::default(),
+            NameLimit::default(),
+            "123",
+            &info,
+        );
+        skeleton.create_dirs().unwrap();
+        skeleton.write_container_xml().unwrap();
+        skeleton
+            .write_xhtml(
+                "ch01.xhtml",
+                "One",
+                r#"<p><img src="Images/a.png" alt=""/></p>"#,
+            )
+            .unwrap();
+        fs::create_dir_all(skeleton.oebps.join("Images")).unwrap();
+        fs::write(
+            skeleton.oebps.join("Images/a.png"),
+            [0xFF, 0xD8, 0xFF, 0xE0],
+        )
+        .unwrap();
+        fs::write(skeleton.oebps.join("stray.txt"), "x").unwrap();
+        let item = |id: &str, href: &str, ty: &str| ManifestItem {
+            id: id.to_string(),
+            href: href.to_string(),
+            media_type: ty.to_string(),
+        };
+        let meta = PackageMetadata::from_book_info("123", &info);
+        skeleton.write_toc(&meta, &[], &[], &[]).unwrap();
+        skeleton
+            .write_opf(
+                &meta,
+                &[
+                    item("ch_0001", "ch01.xhtml", "application/xhtml+xml"),
+                    item("img", "Images/a.png", "image/png"),
+                    item("gone", "ch02.xhtml", "application/xhtml+xml"),
+                ],
+                &["ch_0001".to_string(), "missing".to_string()],
+            )
+            .unwrap();
+
+        let problems = validate(&skeleton).unwrap();
+        let expected = [
+            "Images/a.png: declared as image/png, looks like image/jpeg",
+            "manifest item \"gone\": ch02.xhtml does not exist",
+            "spine item \"missing\" is not in the manifest",
+            "stray.txt is not in the manifest",
+        ];
+        for e in expected {
+            assert!(problems.iter().any(|p| p == e), "{e:?} not in {problems:?}");
+        }
+        assert_eq!(problems.len(), expected.len(), "{problems:?}");
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+//! `watch`: stay running and download the book IDs that show up in a queue
+//! file (one per line, appended over time) or a queue directory (one file
+//! per request), recording how each download went.
+
+use crate::bookid;
+use crate::display::Display;
+use crate::download::{Downloader, Outcome};
+use crate::i18n::t;
+use crate::orly::iso_datetime;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Seconds between looks at the queue, by default.
+pub const DEFAULT_INTERVAL_SECS: u64 = 5;
+
+/// Results file kept inside a queue directory.
+const DIR_RESULTS: &str = "results.tsv";
+
+/// Where requests come from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueSource {
+    /// A text file of book IDs; `<file>.results` records the ones handled.
+    File(PathBuf),
+    /// A directory of request files, moved to done/ or failed/ once handled.
+    Dir(PathBuf),
+}
+
+/// Book IDs read from one place: the queue file, or one request file.
+#[derive(Debug, PartialEq)]
+pub struct Batch {
+    pub file: Option<PathBuf>,
+    pub book_ids: Vec<String>,
+}
+
+impl QueueSource {
+    pub fn new(path: &Path) -> Self {
+        if path.is_dir() {
+            Self::Dir(path.to_path_buf())
+        } else {
+            Self::File(path.to_path_buf())
+        }
+    }
+
+    fn results_path(&self) -> PathBuf {
+        match self {
+            Self::File(path) => {
+                let mut name = path.file_name().unwrap_or_default().to_os_string();
+                name.push(".results");
+                path.with_file_name(name)
+            }
+            Self::Dir(dir) => dir.join(DIR_RESULTS),
+        }
+    }
+
+    /// Requests not handled yet, oldest first.
+    pub fn pending(&self) -> Result<Vec<Batch>> {
+        match self {
+            Self::File(path) => {
+                // The file may not exist until the first request is written.
+                let text = fs::read_to_string(path).unwrap_or_default();
+                let done = self.handled()?;
+                let mut seen = HashSet::new();
+                let book_ids: Vec<String> = book_ids(&text)
+                    .into_iter()
+                    .filter(|id| !done.contains(id) && seen.insert(id.clone()))
+                    .collect();
+                Ok(if book_ids.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![Batch {
+                        file: None,
+                        book_ids,
+                    }]
+                })
+            }
+            Self::Dir(dir) => {
+                let mut files: Vec<PathBuf> = fs::read_dir(dir)
+                    .with_context(|| t!("reading-directory", path = dir.display().to_string()))?
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .filter(|p| p.is_file() && is_request_file(p))
+                    .collect();
+                files.sort();
+                let mut batches = Vec::new();
+                for file in files {
+                    let text = fs::read_to_string(&file)
+                        .with_context(|| t!("reading-file", path = file.display().to_string()))?;
+                    let mut ids = book_ids(&text);
+                    // An empty file named after the book is a request too.
+                    if ids.is_empty() {
+                        ids = book_ids(&file.file_stem().unwrap_or_default().to_string_lossy());
+                    }
+                    batches.push(Batch {
+                        file: Some(file),
+                        book_ids: ids,
+                    });
+                }
+                Ok(batches)
+            }
+        }
+    }
+
+    /// Book IDs with a line in the results file.
+    fn handled(&self) -> Result<HashSet<String>> {
+        let path = self.results_path();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| t!("reading-file", path = path.display().to_string()))
+            }
+        };
+        Ok(text
+            .lines()
+            .filter_map(|l| l.split('\t').nth(1))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Append "time, book id, status, path or error" to the results file.
+    pub fn record(&self, book_id: &str, result: &Result<Outcome>) -> Result<()> {
+        let (status, detail) = match result {
+            Ok(Outcome::Finished(path)) => ("finished", path.display().to_string()),
+            Ok(Outcome::Skipped(path)) => ("skipped", path.display().to_string()),
+            Err(e) => ("failed", format!("{e:#}").replace(['\t', '\n'], " ")),
+        };
+        let path = self.results_path();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| t!("opening-file", path = path.display().to_string()))?;
+        writeln!(
+            file,
+            "{}\t{book_id}\t{status}\t{detail}",
+            iso_datetime(SystemTime::now())
+        )
+        .with_context(|| t!("writing-file", path = path.display().to_string()))
+    }
+
+    /// Move a handled request file to done/ or failed/ in the queue directory.
+    pub fn finish(&self, batch: &Batch, ok: bool) -> Result<()> {
+        let (Self::Dir(dir), Some(file)) = (self, &batch.file) else {
+            return Ok(());
+        };
+        let target = dir.join(if ok { "done" } else { "failed" });
+        fs::create_dir_all(&target)
+            .with_context(|| t!("creating-directory", path = target.display().to_string()))?;
+        let dest = target.join(file.file_name().unwrap_or_default());
+        fs::rename(file, &dest)
+            .with_context(|| t!("moving-file", path = file.display().to_string()))
+    }
+}
+
+/// Request files: not hidden, not our results, not still being written.
+fn is_request_file(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    !name.starts_with('.')
+        && name != DIR_RESULTS
+        && !name.ends_with(".tmp")
+        && !name.ends_with(".part")
+}
+
+/// The book IDs in a request: one per line, '#' starts a comment.
+fn book_ids(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|l| l.split('#').next().unwrap_or_default().trim())
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Poll `source` every `interval` and download what appears, until killed.
+pub async fn run(
+    source: &QueueSource,
+    downloader: &Downloader,
+    ui: &Display,
+    interval: Duration,
+) -> Result<()> {
+    let (QueueSource::File(path) | QueueSource::Dir(path)) = source;
+    ui.info(&t!("watching", path = path.display().to_string()));
+    loop {
+        let batches = match source.pending() {
+            Ok(batches) => batches,
+            Err(e) => {
+                ui.warn(&t!("queue-read-failed", error = format!("{e:#}")));
+                Vec::new()
+            }
+        };
+        for batch in batches {
+            let mut ok = true;
+            for book_id in &batch.book_ids {
+                ui.info(&t!("queued-book", book = book_id.as_str()));
+                let mut book_ui = ui.clone();
+                // A line that names no book fails like a download would.
+                let result = match bookid::parse(book_id) {
+                    Ok(book) => downloader.download(&mut book_ui, &book.id).await,
+                    Err(e) => Err(anyhow!(e)),
+                };
+                if let Err(e) = &result {
+                    ok = false;
+                    ui.warn(&t!(
+                        "book-failed",
+                        book = book_id.as_str(),
+                        error = format!("{e:#}")
+                    ));
+                }
+                source.record(book_id, &result)?;
+            }
+            source.finish(&batch, ok)?;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Batch, QueueSource};
+    use crate::download::Outcome;
+    use anyhow::anyhow;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("safaribooks-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn queue_file_skips_recorded_ids() {
+        let dir = temp_dir("watch-file");
+        let queue = dir.join("queue.txt");
+        let source = QueueSource::new(&queue);
+        assert_eq!(source, QueueSource::File(queue.clone()));
+        assert!(source.pending().unwrap().is_empty());
+
+        fs::write(&queue, "123\n# later\n456  # urgent\n123\n").unwrap();
+        let ids = |source: &QueueSource| -> Vec<String> {
+            source
+                .pending()
+                .unwrap()
+                .into_iter()
+                .flat_map(|b| b.book_ids)
+                .collect()
+        };
+        assert_eq!(ids(&source), ["123", "456"]);
+
+        source
+            .record("123", &Ok(Outcome::Finished("Books/123.epub".into())))
+            .unwrap();
+        source.record("456", &Err(anyhow!("not\tfound"))).unwrap();
+        assert!(ids(&source).is_empty());
+        let results = fs::read_to_string(dir.join("queue.txt.results")).unwrap();
+        assert!(results.contains("\t456\tfailed\tnot found\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn queue_dir_moves_handled_requests() {
+        let dir = temp_dir("watch-dir");
+        fs::write(dir.join("9781491958698"), "").unwrap();
+        fs::write(dir.join("batch.txt"), "1\n2\n").unwrap();
+        fs::write(dir.join(".hidden"), "3").unwrap();
+        let source = QueueSource::new(&dir);
+
+        let batches = source.pending().unwrap();
+        assert_eq!(
+            batches,
+            [
+                Batch {
+                    file: Some(dir.join("9781491958698")),
+                    book_ids: vec!["9781491958698".to_string()],
+                },
+                Batch {
+                    file: Some(dir.join("batch.txt")),
+                    book_ids: vec!["1".to_string(), "2".to_string()],
+                },
+            ]
+        );
+        source.finish(&batches[0], true).unwrap();
+        source.finish(&batches[1], false).unwrap();
+        assert!(dir.join("done/9781491958698").exists());
+        assert!(dir.join("failed/batch.txt").exists());
+        assert!(source.pending().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+//! Golden EPUB tests. Each directory under tests/fixtures/golden/ is the
+//! `--mirror` of a book (recorded API JSON, chapter HTML and assets under
+//! raw/); it is rebuilt offline with `rebuild` and the EPUB compared entry
+//! by entry with its expected/ directory, whose ENTRIES file lists the
+//! archive's entries in order.
+//!
+//! After an intended change to the output, rewrite expected/ with
+//! `UPDATE_GOLDEN=1 cargo test --test golden` and review the diff.
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use zip::ZipArchive;
+
+const ENTRIES: &str = "ENTRIES";
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/golden")
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+/// Rebuild the mirrored book in `book` (a copy of its fixture) and return
+/// the EPUB's entries in archive order.
+fn rebuild(book: &Path, home: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_safaribooks-rs"));
+    // Nothing of the user's configuration or state may leak in.
+    for (name, _) in std::env::vars_os() {
+        if name.to_string_lossy().starts_with("SAFARIBOOKS_") {
+            command.env_remove(name);
+        }
+    }
+    let output = command
+        .args(["--lang", "en", "rebuild"])
+        .arg(book)
+        .env("HOME", home)
+        .env("XDG_CONFIG_HOME", home.join("config"))
+        .env("XDG_DATA_HOME", home.join("data"))
+        .env("APPDATA", home.join("config"))
+        .env("LOCALAPPDATA", home.join("data"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "rebuild failed:\n{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let epub = fs::read_dir(book)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "epub"))
+        .expect("rebuild wrote no EPUB");
+    let mut zip = ZipArchive::new(fs::File::open(&epub).unwrap()).unwrap();
+    (0..zip.len())
+        .map(|i| {
+            let mut file = zip.by_index(i).unwrap();
+            let mut body = Vec::new();
+            file.read_to_end(&mut body).unwrap();
+            (file.name().unwrap().to_string(), body)
+        })
+        .collect()
+}
+
+/// What of an entry may change from one build to the next: the colophon's
+/// download date and the version that built the book.
+fn normalize(body: &[u8]) -> Vec<u8> {
+    let Ok(text) = std::str::from_utf8(body) else {
+        return body.to_vec();
+    };
+    let mut text = text.replace(
+        concat!("safaribooks-rs ", env!("CARGO_PKG_VERSION")),
+        "safaribooks-rs VERSION",
+    );
+    while let Some(at) = text.find("Downloaded on 2") {
+        let date = at + "Downloaded on ".len();
+        text.replace_range(date..date + "YYYY-MM-DD".len(), "DATE");
+    }
+    text.into_bytes()
+}
+
+fn check_book(fixture: &Path, work: &Path) {
+    let name = fixture.file_name().unwrap().to_string_lossy().to_string();
+    let book = work.join(&name);
+    copy_dir(&fixture.join("raw"), &book.join("raw"));
+    let entries = rebuild(&book, &work.join("home"));
+    let expected = fixture.join("expected");
+
+    let listing: String = entries
+        .iter()
+        .map(|(name, _)| format!("{name}\n"))
+        .collect();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        let _ = fs::remove_dir_all(&expected);
+        fs::create_dir_all(&expected).unwrap();
+        fs::write(expected.join(ENTRIES), &listing).unwrap();
+        for (entry, body) in &entries {
+            let path = expected.join(entry);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, normalize(body)).unwrap();
+        }
+        return;
+    }
+
+    let golden = fs::read_to_string(expected.join(ENTRIES))
+        .unwrap_or_else(|e| panic!("{name}: no golden output ({e}); run with UPDATE_GOLDEN=1"));
+    assert_eq!(listing, golden, "{name}: the EPUB's entries changed");
+    for (entry, body) in &entries {
+        let want = fs::read(expected.join(entry)).unwrap();
+        let got = normalize(body);
+        if got != want {
+            match (String::from_utf8(got), String::from_utf8(want)) {
+                (Ok(got), Ok(want)) => assert_eq!(got, want, "{name}: {entry} changed"),
+                _ => panic!("{name}: {entry} changed"),
+            }
+        }
+    }
+}
+
+#[test]
+fn books_match_their_golden_epubs() {
+    let work = std::env::temp_dir().join(format!("safaribooks-golden-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&work);
+    let mut fixtures: Vec<PathBuf> = fs::read_dir(golden_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty());
+    for fixture in &fixtures {
+        check_book(fixture, &work);
+    }
+    fs::remove_dir_all(&work).unwrap();
+}